@@ -0,0 +1,123 @@
+//! Replays recent room history to peers that just joined.
+//!
+//! A newcomer asks one of its neighbors for the latest messages of a room
+//! over a direct stream. In encrypted rooms the reply is sealed with the room
+//! key, so only members holding the passphrase can read it.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use futures_lite::future::Boxed as BoxedFuture;
+use iroh::{endpoint::Connecting, protocol::ProtocolHandler, Endpoint, NodeId};
+use iroh_gossip::proto::TopicId;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument};
+
+use crate::{
+    crypto::RoomCipher,
+    history::{History, HistoryEntry},
+    node::{Access, Room},
+};
+
+/// ALPN of the backfill protocol.
+pub const ALPN: &[u8] = b"iroh-gossip-chat/backfill/2";
+
+/// Number of messages requested when joining a room.
+pub const DEFAULT_BACKFILL_LIMIT: usize = 50;
+
+/// Most entries served for a single request.
+const MAX_BACKFILL_ENTRIES: usize = 500;
+
+/// Upper bound for a request.
+const MAX_REQUEST_SIZE: usize = 1024;
+
+/// Upper bound for a reply.
+const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BackfillRequest {
+    topic: TopicId,
+    limit: usize,
+}
+
+/// Asks `node_id` for the latest `limit` messages of the room on `topic`.
+///
+/// The entries are as reported by that peer, they carry no signature of the
+/// original senders.
+#[instrument(skip(endpoint, cipher), fields(node_id = %node_id.fmt_short(), topic = %topic))]
+pub(crate) async fn request(
+    endpoint: &Endpoint,
+    node_id: NodeId,
+    topic: TopicId,
+    limit: usize,
+    cipher: Option<&RoomCipher>,
+) -> Result<Vec<HistoryEntry>> {
+    let conn = endpoint.connect(node_id, ALPN).await?;
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send.write_all(&postcard::to_stdvec(&BackfillRequest { topic, limit })?)
+        .await?;
+    send.finish()?;
+    let bytes = recv.read_to_end(MAX_RESPONSE_SIZE).await?;
+    conn.close(0u32.into(), b"bye");
+    let bytes = match cipher {
+        Some(cipher) => cipher.decrypt(&bytes)?,
+        None => bytes,
+    };
+    Ok(postcard::from_bytes(&bytes)?)
+}
+
+/// Serves backfill requests from the local history, for rooms we are in.
+#[derive(Clone)]
+pub(crate) struct Backfill {
+    pub(crate) rooms: Arc<Mutex<HashMap<TopicId, Room>>>,
+    pub(crate) history: Option<History>,
+    pub(crate) access: Arc<Access>,
+}
+
+impl std::fmt::Debug for Backfill {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Backfill")
+            .field("history", &self.history)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ProtocolHandler for Backfill {
+    fn accept(&self, connecting: Connecting) -> BoxedFuture<Result<()>> {
+        let this = self.clone();
+        Box::pin(async move {
+            let conn = connecting.await?;
+            let from = conn.remote_node_id()?;
+            if !this.access.permits(&from) {
+                debug!(from = %from.fmt_short(), "refused backfill to blocked node");
+                conn.close(1u32.into(), b"blocked");
+                return Ok(());
+            }
+            let (mut send, mut recv) = conn.accept_bi().await?;
+            let bytes = recv.read_to_end(MAX_REQUEST_SIZE).await?;
+            let BackfillRequest { topic, limit } = postcard::from_bytes(&bytes)?;
+            let limit = limit.min(MAX_BACKFILL_ENTRIES);
+
+            // Only answer for rooms we are in, and never leak history of
+            // other rooms.
+            let room = this.rooms.lock().unwrap().get(&topic).cloned();
+            let entries = match (&room, &this.history) {
+                (Some(_), Some(history)) => history.recent(Some(&topic.to_string()), limit)?,
+                _ => Vec::new(),
+            };
+            let bytes = postcard::to_stdvec(&entries)?;
+            let bytes = match room.as_ref().and_then(|room| room.cipher.as_ref()) {
+                Some(cipher) => cipher.encrypt(&bytes),
+                None => bytes,
+            };
+            debug!(topic = %topic, entries = entries.len(), "serving backfill");
+            send.write_all(&bytes).await?;
+            send.finish()?;
+            conn.closed().await;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,336 @@
+//! Declarative bots answering commands, schedules and item changes in the
+//! room, such as a `!weather` command running a script that fetches the
+//! forecast, or a daily reminder.
+
+use std::{process::Stdio, sync::Arc, time::Duration};
+
+use anyhow::{bail, ensure, Context, Result};
+use chrono::{Local, NaiveTime};
+use futures_lite::future::Boxed as BoxedFuture;
+use iroh_gossip::proto::TopicId;
+use serde_json::json;
+use tokio::{io::AsyncWriteExt, task::JoinHandle};
+use tracing::{debug, warn};
+
+use crate::{
+    config::TriggerConfig,
+    handler::{ChatMessage, DirectMessage, ItemChange, MessageHandler},
+    hooks, http, ChatNode, Event,
+};
+
+/// How long a `run` command may take to answer.
+const RUN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What fires a trigger.
+#[derive(Debug, Clone)]
+enum When {
+    Command(String),
+    Every(Duration),
+    At(NaiveTime),
+    Item(String),
+}
+
+#[derive(Debug, Clone)]
+struct Trigger {
+    when: When,
+    reply: Option<String>,
+    run: Option<String>,
+}
+
+impl Trigger {
+    fn new(config: &TriggerConfig) -> Result<Self> {
+        let when = match config {
+            TriggerConfig {
+                command: Some(command),
+                every_secs: None,
+                at: None,
+                item: None,
+                ..
+            } => When::Command(command.clone()),
+            TriggerConfig {
+                command: None,
+                every_secs: Some(secs),
+                at: None,
+                item: None,
+                ..
+            } => {
+                ensure!(*secs > 0, "trigger every_secs must be positive");
+                When::Every(Duration::from_secs(*secs))
+            }
+            TriggerConfig {
+                command: None,
+                every_secs: None,
+                at: Some(at),
+                item: None,
+                ..
+            } => When::At(
+                NaiveTime::parse_from_str(at, "%H:%M")
+                    .with_context(|| format!("invalid trigger time {at}, expected HH:MM"))?,
+            ),
+            TriggerConfig {
+                command: None,
+                every_secs: None,
+                at: None,
+                item: Some(item),
+                ..
+            } => When::Item(item.clone()),
+            _ => bail!("a trigger needs exactly one of command, every_secs, at and item"),
+        };
+        ensure!(
+            config.reply.is_some() || config.run.is_some(),
+            "a trigger needs a reply or a command to run"
+        );
+        Ok(Self {
+            when,
+            reply: config.reply.clone(),
+            run: config.run.clone(),
+        })
+    }
+
+    /// The arguments of `text` if it invokes this trigger's command.
+    fn command_args<'a>(&self, text: &'a str) -> Option<&'a str> {
+        let When::Command(command) = &self.when else {
+            return None;
+        };
+        let rest = text.trim().strip_prefix(command.as_str())?;
+        // `!weathers` is not `!weather`.
+        (rest.is_empty() || rest.starts_with(char::is_whitespace)).then(|| rest.trim())
+    }
+
+    /// Builds the answer to the event given as `json`, with `vars` for the
+    /// reply template. `None` if there is nothing to say.
+    async fn answer(
+        &self,
+        json: serde_json::Value,
+        mut vars: Vec<(&str, String)>,
+    ) -> Result<Option<String>> {
+        vars.push(("time", Local::now().format("%H:%M").to_string()));
+        let output = match &self.run {
+            Some(command) => Some(run(command, json, &vars).await?),
+            None => None,
+        };
+        let answer = match &self.reply {
+            Some(reply) => {
+                vars.push(("output", output.unwrap_or_default()));
+                fill(reply, &vars)
+            }
+            None => output.unwrap_or_default(),
+        };
+        Ok(Some(answer).filter(|answer| !answer.trim().is_empty()))
+    }
+}
+
+/// Answers the configured triggers, registered as a [`MessageHandler`].
+#[derive(Debug, Clone)]
+pub struct Bot {
+    triggers: Arc<Vec<Trigger>>,
+}
+
+impl Bot {
+    pub fn new(configs: &[TriggerConfig]) -> Result<Self> {
+        let triggers = configs.iter().map(Trigger::new).collect::<Result<_>>()?;
+        Ok(Self {
+            triggers: Arc::new(triggers),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.triggers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.triggers.is_empty()
+    }
+
+    /// Spawns the scheduled triggers, which post to every room we are in.
+    pub fn schedule(&self, node: ChatNode) -> Vec<JoinHandle<()>> {
+        let mut tasks = Vec::new();
+        for trigger in self.triggers.iter() {
+            let (trigger, node) = (trigger.clone(), node.clone());
+            match trigger.when {
+                When::Every(period) => tasks.push(tokio::spawn(async move {
+                    let start = tokio::time::Instant::now() + period;
+                    let mut interval = tokio::time::interval_at(start, period);
+                    loop {
+                        interval.tick().await;
+                        post_scheduled(&node, &trigger).await;
+                    }
+                })),
+                When::At(at) => tasks.push(tokio::spawn(async move {
+                    loop {
+                        tokio::time::sleep(until_next(at)).await;
+                        post_scheduled(&node, &trigger).await;
+                    }
+                })),
+                When::Command(_) | When::Item(_) => {}
+            }
+        }
+        tasks
+    }
+}
+
+impl MessageHandler for Bot {
+    fn on_message(&self, node: ChatNode, message: ChatMessage) -> BoxedFuture<Result<()>> {
+        for trigger in self.triggers.iter() {
+            let Some(args) = trigger.command_args(&message.text) else {
+                continue;
+            };
+            let vars = vec![
+                ("from", sender(&message.name, &message.from)),
+                ("args", args.to_string()),
+            ];
+            let trigger = trigger.clone();
+            let topic = message.topic;
+            let node = node.clone();
+            let json = http::event_json(&Event::from(message.clone()));
+            // Commands may take a while, answer without holding up events.
+            tokio::spawn(async move {
+                if let Some(answer) = answered(&trigger, json, vars).await {
+                    if let Err(err) = node.send_text(topic, answer, Vec::new()).await {
+                        warn!("failed to send bot answer: {err:#}");
+                    }
+                }
+            });
+        }
+        Box::pin(async { Ok(()) })
+    }
+
+    fn on_direct_message(&self, node: ChatNode, message: DirectMessage) -> BoxedFuture<Result<()>> {
+        for trigger in self.triggers.iter() {
+            let Some(args) = trigger.command_args(&message.text) else {
+                continue;
+            };
+            let vars = vec![
+                ("from", sender(&message.name, &message.from)),
+                ("args", args.to_string()),
+            ];
+            let trigger = trigger.clone();
+            let from = message.from;
+            let node = node.clone();
+            let json = http::event_json(&Event::from(message.clone()));
+            tokio::spawn(async move {
+                if let Some(answer) = answered(&trigger, json, vars).await {
+                    if let Err(err) = node.send_direct(from, answer).await {
+                        warn!("failed to send bot answer: {err:#}");
+                    }
+                }
+            });
+        }
+        Box::pin(async { Ok(()) })
+    }
+
+    fn on_item_change(&self, node: ChatNode, change: ItemChange) -> BoxedFuture<Result<()>> {
+        for trigger in self.triggers.iter() {
+            if !matches!(&trigger.when, When::Item(item) if *item == change.item) {
+                continue;
+            }
+            let vars = vec![
+                ("item", change.item.clone()),
+                ("old", change.old.clone()),
+                ("new", change.new.clone()),
+            ];
+            // Answer where a peer shared the change, everywhere for ours.
+            let topics = match change.source {
+                Some((topic, _)) => vec![topic],
+                None => node.rooms(),
+            };
+            let trigger = trigger.clone();
+            let json = http::event_json(&Event::from(change.clone()));
+            let node = node.clone();
+            tokio::spawn(async move {
+                if let Some(answer) = answered(&trigger, json, vars).await {
+                    post(&node, topics, answer).await;
+                }
+            });
+        }
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// The answer of `trigger`, logging failures.
+async fn answered(
+    trigger: &Trigger,
+    json: serde_json::Value,
+    vars: Vec<(&str, String)>,
+) -> Option<String> {
+    trigger
+        .answer(json, vars)
+        .await
+        .inspect_err(|err| warn!("bot trigger failed: {err:#}"))
+        .ok()
+        .flatten()
+}
+
+async fn post_scheduled(node: &ChatNode, trigger: &Trigger) {
+    let json = json!({ "type": "schedule", "time": Local::now().to_rfc3339() });
+    if let Some(answer) = answered(trigger, json, Vec::new()).await {
+        post(node, node.rooms(), answer).await;
+    }
+}
+
+async fn post(node: &ChatNode, topics: Vec<TopicId>, text: String) {
+    for topic in topics {
+        if let Err(err) = node.send_text(topic, text.clone(), Vec::new()).await {
+            warn!("failed to send bot answer: {err:#}");
+        }
+    }
+}
+
+/// Runs `command` with the shell, `json` on its stdin and `vars` as
+/// `BOT_*` environment variables, returning its trimmed stdout.
+async fn run(command: &str, json: serde_json::Value, vars: &[(&str, String)]) -> Result<String> {
+    let mut shell = hooks::shell(command);
+    for (name, value) in vars {
+        shell.env(format!("BOT_{}", name.to_uppercase()), value);
+    }
+    let mut child = shell
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("failed to start {command:?}"))?;
+    let mut stdin = child.stdin.take().context("no stdin")?;
+    if let Err(err) = stdin.write_all(format!("{json}\n").as_bytes()).await {
+        debug!("{command:?} did not read the event: {err}");
+    }
+    drop(stdin);
+    let output = tokio::time::timeout(RUN_TIMEOUT, child.wait_with_output())
+        .await
+        .with_context(|| format!("{command:?} timed out after {}s", RUN_TIMEOUT.as_secs()))??;
+    ensure!(
+        output.status.success(),
+        "{command:?} exited with {}",
+        output.status
+    );
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Replaces the `{name}` placeholders in `template`.
+fn fill(template: &str, vars: &[(&str, String)]) -> String {
+    vars.iter()
+        .fold(template.to_string(), |text, (name, value)| {
+            text.replace(&format!("{{{name}}}"), value)
+        })
+}
+
+fn sender(name: &Option<String>, from: &iroh::NodeId) -> String {
+    name.clone().unwrap_or_else(|| from.fmt_short())
+}
+
+/// Time until the next `at` in local time.
+fn until_next(at: NaiveTime) -> Duration {
+    let now = Local::now();
+    let mut date = now.date_naive();
+    loop {
+        let next = date
+            .and_time(at)
+            .and_local_timezone(Local)
+            .earliest()
+            .filter(|next| *next > now);
+        if let Some(next) = next {
+            return (next - now).to_std().unwrap_or_default();
+        }
+        // Already past today, or skipped by a DST change.
+        date = date.succ_opt().expect("date in range");
+    }
+}
@@ -0,0 +1,231 @@
+use std::{path::PathBuf, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::ticket::Tickets;
+
+/// A line typed at the chat prompt.
+#[derive(Debug)]
+pub enum Input {
+    /// Plain text for the current room.
+    Text(String),
+    /// `/join <ticket>...`: join another room through the nodes of one or
+    /// more tickets.
+    Join(Tickets),
+    /// `/rooms`: list the rooms we are in.
+    Rooms,
+    /// `/switch <topic>`: send to another joined room, matched by prefix.
+    Switch(String),
+    /// `/leave`: leave the current room, which a profile then no longer
+    /// rejoins on start.
+    Leave,
+    /// `/msg <peer> <text>`: send a private message to one peer, picked by
+    /// node id, name or short node id.
+    Msg { to: String, text: String },
+    /// `/send <path>`: share a file with the current room.
+    Send(PathBuf),
+    /// `/image <path>`: share an image for peers to show inline.
+    Image(PathBuf),
+    /// `/voice [secs]`: record a voice note and share it with the current
+    /// room.
+    Voice(Option<u32>),
+    /// `/play <hash>`: fetch and play a voice note, matched by hash prefix.
+    Play(String),
+    /// `/get <hash>`: download a shared file, matched by hash prefix.
+    Get(String),
+    /// `/set <item> <value>`: send a command to an openHAB item through
+    /// the nodes in the room that have openHAB access.
+    Set { item: String, value: String },
+    /// `/items`: list the items of our openHAB server.
+    Items,
+    /// `/ban <peer>`: as a moderator, ban a peer from all rooms.
+    Ban(String),
+    /// `/status`: show our addresses, relay, discovery and neighbors.
+    Status,
+    /// `/who`: list the peers in our rooms and when we last heard from them.
+    Who,
+    /// `/stats`: show the messages and bytes exchanged with each peer.
+    Stats,
+    /// `/reliable <text>`: send text to the current room until most peers
+    /// acknowledged it.
+    Reliable(String),
+    /// `/react [peer] <emoji>`: react to the latest message in the current
+    /// room, or to the latest one from `peer`.
+    React { peer: Option<String>, emoji: String },
+    /// `/reply <peer> <text>`: answer the latest message of a peer in the
+    /// current room.
+    Reply { to: String, text: String },
+    /// `/edit <text>`: replace the text of our latest message in the
+    /// current room.
+    Edit(String),
+    /// `/delete`: retract our latest message in the current room.
+    Delete,
+    /// `/search <query>`: find past messages containing all words of the
+    /// query.
+    Search(String),
+    /// `/mute`: stop desktop notifications for the current room, except
+    /// for mentions.
+    Mute,
+    /// `/unmute`: notify about the current room again.
+    Unmute,
+    /// `/ping <peer>`: measure the round-trip time to a peer.
+    Ping(String),
+}
+
+/// A line of JSON read from a pipe, such as `{"text": "hello"}`.
+///
+/// Lets scripts send text spanning several lines. The text is handled as if
+/// typed, so it may also be a command.
+#[derive(Debug, Deserialize)]
+struct JsonLine {
+    text: String,
+}
+
+impl Input {
+    /// Parses a line read from a pipe, either a JSON object or as typed.
+    pub fn from_piped_line(line: &str) -> Result<Self> {
+        if line.starts_with('{') {
+            let json: JsonLine = serde_json::from_str(line).context("invalid JSON input")?;
+            return json.text.parse();
+        }
+        line.parse()
+    }
+}
+
+impl FromStr for Input {
+    type Err = anyhow::Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let Some(command) = line.strip_prefix('/') else {
+            return Ok(Input::Text(line.to_string()));
+        };
+        let (name, rest) = command.split_once(' ').unwrap_or((command, ""));
+        let rest = rest.trim();
+        match name {
+            "join" if !rest.is_empty() => Ok(Input::Join(rest.parse()?)),
+            "join" => bail!("usage: /join <ticket>..."),
+            "rooms" => Ok(Input::Rooms),
+            "leave" => Ok(Input::Leave),
+            "items" => Ok(Input::Items),
+            "status" => Ok(Input::Status),
+            "who" => Ok(Input::Who),
+            "stats" => Ok(Input::Stats),
+            "search" if !rest.is_empty() => Ok(Input::Search(rest.to_string())),
+            "search" => bail!("usage: /search <query>"),
+            "mute" => Ok(Input::Mute),
+            "unmute" => Ok(Input::Unmute),
+            "ping" if !rest.is_empty() => Ok(Input::Ping(rest.to_string())),
+            "ping" => bail!("usage: /ping <peer>"),
+            "react" => match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                [emoji] => Ok(Input::React {
+                    peer: None,
+                    emoji: emoji.to_string(),
+                }),
+                [peer, emoji] => Ok(Input::React {
+                    peer: Some(peer.to_string()),
+                    emoji: emoji.to_string(),
+                }),
+                _ => bail!("usage: /react [peer] <emoji>"),
+            },
+            "reply" => match rest.split_once(' ') {
+                Some((to, text)) if !text.trim().is_empty() => Ok(Input::Reply {
+                    to: to.to_string(),
+                    text: text.trim().to_string(),
+                }),
+                _ => bail!("usage: /reply <peer> <text>"),
+            },
+            "edit" if !rest.is_empty() => Ok(Input::Edit(rest.to_string())),
+            "edit" => bail!("usage: /edit <text>"),
+            "delete" => Ok(Input::Delete),
+            "reliable" if !rest.is_empty() => Ok(Input::Reliable(rest.to_string())),
+            "reliable" => bail!("usage: /reliable <text>"),
+            "switch" if !rest.is_empty() => Ok(Input::Switch(rest.to_string())),
+            "switch" => bail!("usage: /switch <topic>"),
+            "msg" => match rest.split_once(' ') {
+                Some((to, text)) if !text.trim().is_empty() => Ok(Input::Msg {
+                    to: to.to_string(),
+                    text: text.trim().to_string(),
+                }),
+                _ => bail!("usage: /msg <peer> <text>"),
+            },
+            "send" if !rest.is_empty() => Ok(Input::Send(PathBuf::from(rest))),
+            "send" => bail!("usage: /send <path>"),
+            "image" if !rest.is_empty() => Ok(Input::Image(PathBuf::from(rest))),
+            "image" => bail!("usage: /image <path>"),
+            "voice" if rest.is_empty() => Ok(Input::Voice(None)),
+            "voice" => match rest.parse() {
+                Ok(secs) => Ok(Input::Voice(Some(secs))),
+                Err(_) => bail!("usage: /voice [secs]"),
+            },
+            "play" if !rest.is_empty() => Ok(Input::Play(rest.to_string())),
+            "play" => bail!("usage: /play <hash>"),
+            "get" if !rest.is_empty() => Ok(Input::Get(rest.to_string())),
+            "get" => bail!("usage: /get <hash>"),
+            "ban" if !rest.is_empty() => Ok(Input::Ban(rest.to_string())),
+            "ban" => bail!("usage: /ban <peer>"),
+            "set" => match rest.split_once(' ') {
+                Some((item, value)) if !value.trim().is_empty() => Ok(Input::Set {
+                    item: item.to_string(),
+                    value: value.trim().to_string(),
+                }),
+                _ => bail!("usage: /set <item> <value>"),
+            },
+            _ => bail!("unknown command /{name}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_lines_are_messages() {
+        let input = Input::from_str("hello /world");
+        assert!(matches!(input, Ok(Input::Text(text)) if text == "hello /world"));
+    }
+
+    #[test]
+    fn parses_commands_and_their_arguments() {
+        assert!(matches!(Input::from_str("/rooms"), Ok(Input::Rooms)));
+        let input = Input::from_str("/switch  ab12 ");
+        assert!(matches!(input, Ok(Input::Switch(prefix)) if prefix == "ab12"));
+    }
+
+    #[test]
+    fn reports_usage_and_unknown_commands() {
+        let err = Input::from_str("/join").unwrap_err();
+        assert!(err.to_string().starts_with("usage: /join"), "{err}");
+        assert!(Input::from_str("/join nonsense").is_err());
+        let err = Input::from_str("/dance").unwrap_err();
+        assert_eq!(err.to_string(), "unknown command /dance");
+    }
+
+    #[test]
+    fn piped_lines_may_be_json() {
+        let input = Input::from_piped_line(r#"{"text": "two\nlines"}"#);
+        assert!(matches!(input, Ok(Input::Text(text)) if text == "two\nlines"));
+        let input = Input::from_piped_line(r#"{"text": "/rooms"}"#);
+        assert!(matches!(input, Ok(Input::Rooms)));
+        assert!(Input::from_piped_line("{oops").is_err());
+        let input = Input::from_piped_line("plain");
+        assert!(matches!(input, Ok(Input::Text(text)) if text == "plain"));
+    }
+
+    #[test]
+    fn splits_reactions_and_replies() {
+        let input = Input::from_str("/react 👍");
+        assert!(matches!(input, Ok(Input::React { peer: None, emoji }) if emoji == "👍"));
+        let input = Input::from_str("/react alice 👍");
+        assert!(matches!(input, Ok(Input::React { peer: Some(peer), .. }) if peer == "alice"));
+        assert!(Input::from_str("/react").is_err());
+
+        let input = Input::from_str("/reply alice  see you ");
+        assert!(matches!(
+            input,
+            Ok(Input::Reply { to, text }) if to == "alice" && text == "see you"
+        ));
+        assert!(Input::from_str("/reply alice").is_err());
+    }
+}
@@ -0,0 +1,1220 @@
+use std::{
+    collections::BTreeMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use iroh::{NodeAddr, NodeId, RelayMap, RelayMode, RelayUrl};
+use iroh_gossip::proto::TopicId;
+use serde::{Deserialize, Deserializer};
+use url::Url;
+
+use crate::{
+    bot::Bot, keys, rules::Rules, voice, DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_PEER_TIMEOUT,
+};
+
+/// Settings that can be loaded from a TOML file with `--config`.
+///
+/// Every field is optional; environment variables take precedence over the
+/// values found in the file, and values given on the command line over
+/// both. Each setting has a variable named after its path, upper case,
+/// with `IROH_P2P_` in front and `__` between tables, such as
+/// `IROH_P2P_BIND_PORT=47001` or `IROH_P2P_OPENHAB__URL=http://nas:8080`.
+/// Values are read as TOML, e.g. `["Light1", "Temp"]` for a list, and as a
+/// string when that fails.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub name: Option<String>,
+    pub bind_port: Option<u16>,
+    /// IPv4 address to bind instead of all interfaces.
+    pub bind_v4: Option<Ipv4Addr>,
+    /// IPv6 address to bind instead of all interfaces.
+    pub bind_v6: Option<Ipv6Addr>,
+    pub ip_mode: IpMode,
+    pub secret_key_file: Option<PathBuf>,
+    /// Keep the node secret key in the platform keyring instead of
+    /// `secret_key_file`, and look up openHAB tokens missing from the
+    /// config there, as stored with `store-openhab-token`.
+    pub keyring: bool,
+    /// Encrypt the secret key file with a passphrase, asked for on startup
+    /// or taken from `SECRET_KEY_PASSPHRASE`, for nodes on shared machines.
+    pub encrypt_secret_key: bool,
+    /// SQLite database for the message history.
+    pub history_file: Option<PathBuf>,
+    /// Where shared images are saved, `iroh-gossip-chat` in the user's
+    /// download directory by default.
+    pub downloads_dir: Option<PathBuf>,
+    /// Directory of `.rhai` automation scripts, loaded on startup.
+    pub scripts_dir: Option<PathBuf>,
+    /// Address to serve the local HTTP API on, off when unset.
+    pub http_listen: Option<SocketAddr>,
+    /// Passphrase for end-to-end encrypting room traffic.
+    pub passphrase: Option<String>,
+    /// Name of the room to open. Together with the passphrase it determines
+    /// the topic, so only peers knowing both can find the room.
+    pub room: Option<String>,
+    /// Room to open instead of a random one, so a fleet of nodes sharing
+    /// this file meets without exchanging tickets. Given in hex.
+    #[serde(deserialize_with = "deserialize_topic")]
+    pub topic: Option<TopicId>,
+    /// Nodes to join the room through on startup, in addition to the ones
+    /// from a ticket.
+    pub bootstrap: Vec<BootstrapPeer>,
+    pub relay: RelayConfig,
+    pub discovery: DiscoveryConfig,
+    pub presence: PresenceConfig,
+    pub access: AccessConfig,
+    pub rate_limit: RateLimitConfig,
+    /// Largest encoded room message sent or accepted, in bytes.
+    pub max_message_size: Option<usize>,
+    /// Smart home server whose items this node shares and controls.
+    pub home: HomeBackend,
+    pub openhab: OpenHabConfig,
+    /// Further openHAB servers, as `[openhab_servers.<name>]` tables, whose
+    /// items are called `<name>:<item>`, such as `garage:Light1`. List
+    /// each server's `items`, as the default is only meant for testing.
+    pub openhab_servers: BTreeMap<String, OpenHabConfig>,
+    pub home_assistant: HomeAssistantConfig,
+    /// Tell peers which of their messages we displayed.
+    pub read_receipts: bool,
+    /// Chat messages that send commands to openHAB items, as `[[rules]]`.
+    pub rules: Vec<RuleConfig>,
+    /// What the `bot` subcommand answers, as `[[triggers]]`.
+    pub triggers: Vec<TriggerConfig>,
+    pub mqtt: MqttConfig,
+    pub matrix: MatrixConfig,
+    pub irc: IrcConfig,
+    pub telegram: TelegramConfig,
+    pub notifications: NotificationConfig,
+    pub hooks: HooksConfig,
+    /// URLs receiving events as JSON, as `[[webhooks]]`.
+    pub webhooks: Vec<WebhookConfig>,
+    pub inbound_webhook: InboundWebhookConfig,
+    /// Sandboxed WebAssembly plugins, as `[[plugins]]`.
+    pub plugins: Vec<PluginConfig>,
+    pub voice: VoiceConfig,
+}
+
+impl Config {
+    /// Loads the settings from the file at `path`, if any, and the
+    /// `IROH_P2P_*` environment variables.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let text = match path {
+            Some(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read config file {}", path.display()))?,
+            None => String::new(),
+        };
+        let overrides: Vec<_> = std::env::vars()
+            .filter_map(|(name, value)| Some((name.strip_prefix(ENV_PREFIX)?.to_string(), value)))
+            .collect();
+        let source = || match path {
+            Some(path) => format!("config file {}", path.display()),
+            None => "config".to_string(),
+        };
+        if overrides.is_empty() {
+            // Straight from the text, so errors point at the line.
+            return toml::from_str(&text).with_context(|| format!("failed to parse {}", source()));
+        }
+        let table: toml::Table =
+            toml::from_str(&text).with_context(|| format!("failed to parse {}", source()))?;
+        Self::with_env_overrides(table, overrides).with_context(|| {
+            format!(
+                "failed to parse {} with the {ENV_PREFIX}* variables",
+                source()
+            )
+        })
+    }
+
+    /// Deserializes `table` with the settings of the `(name, value)` pairs
+    /// of environment variables, without the prefix, applied on top.
+    fn with_env_overrides(
+        mut table: toml::Table,
+        overrides: Vec<(String, String)>,
+    ) -> Result<Self> {
+        for (name, value) in overrides {
+            let typed = parse_env_value(&value);
+            let is_string = typed.is_str();
+            set_env_override(&mut table, &name, typed)
+                .with_context(|| format!("invalid {ENV_PREFIX}{name}"))?;
+            // `PASSPHRASE=1234` reads as a number, so keep the text when only
+            // that makes the setting fit.
+            if !is_string && Self::deserialize(table.clone()).is_err() {
+                let mut as_string = table.clone();
+                set_env_override(&mut as_string, &name, toml::Value::String(value))?;
+                if Self::deserialize(as_string.clone()).is_ok() {
+                    table = as_string;
+                }
+            }
+        }
+        Ok(Self::deserialize(table)?)
+    }
+
+    /// Checks the settings for mistakes that would otherwise only surface
+    /// deep in startup, or not at all, and reports all of them at once.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, problem: String| {
+            if !ok {
+                problems.push(problem);
+            }
+        };
+
+        // URLs.
+        if self.openhab.is_configured() {
+            if let Err(err) = check_http_url(&self.openhab.url) {
+                check(false, format!("openhab.url {}: {err}", self.openhab.url));
+            }
+        }
+        for (name, server) in &self.openhab_servers {
+            check(
+                !name.is_empty() && !name.contains(':'),
+                format!("openhab_servers.{name}: names cannot be empty or contain ':'"),
+            );
+            if let Err(err) = check_http_url(&server.url) {
+                check(
+                    false,
+                    format!("openhab_servers.{name}.url {}: {err}", server.url),
+                );
+            }
+        }
+        if self.home == HomeBackend::HomeAssistant {
+            if let Err(err) = check_http_url(&self.home_assistant.url) {
+                check(
+                    false,
+                    format!("home_assistant.url {}: {err}", self.home_assistant.url),
+                );
+            }
+        }
+        for webhook in &self.webhooks {
+            check(
+                matches!(webhook.url.scheme(), "http" | "https"),
+                format!(
+                    "webhooks.url {}: expected an http or https URL",
+                    webhook.url
+                ),
+            );
+        }
+
+        // Ports.
+        check(
+            self.http_listen.is_none_or(|addr| addr.port() != 0),
+            "http_listen: port 0 would pick a random port, clients could not find the API"
+                .to_string(),
+        );
+        if let Some(addr) = self.http_listen {
+            check(
+                addr.ip().is_loopback() || self.inbound_webhook.token.is_some(),
+                format!(
+                    "http_listen: {addr} can be reached from other hosts, set \
+                     inbound_webhook.token so the API requires it"
+                ),
+            );
+        }
+        check(
+            self.mqtt.host.is_none() || self.mqtt.port != 0,
+            "mqtt.port: must be between 1 and 65535".to_string(),
+        );
+        check(
+            self.irc.server.is_none() || self.irc.port != 0,
+            "irc.port: must be between 1 and 65535".to_string(),
+        );
+
+        // Files.
+        let secret_key = self
+            .secret_key_file
+            .clone()
+            .or_else(keys::default_secret_key_path);
+        if let Some(path) = secret_key.filter(|path| path.exists()) {
+            if let Err(problem) = check_private_file(&path) {
+                check(
+                    false,
+                    format!("secret_key_file {}: {problem}", path.display()),
+                );
+            }
+        }
+        for (name, server) in std::iter::once(("openhab", &self.openhab)).chain(
+            self.openhab_servers
+                .iter()
+                .map(|(name, server)| (name.as_str(), server)),
+        ) {
+            if let Some(path) = &server.ca_cert {
+                check(
+                    path.is_file(),
+                    format!("{name}.ca_cert {}: no such file", path.display()),
+                );
+            }
+        }
+        for plugin in &self.plugins {
+            check(
+                plugin.path.is_file(),
+                format!("plugins.path {}: no such file", plugin.path.display()),
+            );
+        }
+
+        // Values out of range.
+        check(
+            self.max_message_size != Some(0),
+            "max_message_size: must be positive".to_string(),
+        );
+        check(
+            self.rate_limit.messages_per_sec >= 0.0,
+            "rate_limit.messages_per_sec: cannot be negative, use 0 to disable the limit"
+                .to_string(),
+        );
+        check(
+            self.rate_limit.messages_per_sec == 0.0 || self.rate_limit.burst >= 1.0,
+            "rate_limit.burst: must be at least 1, or no message gets through".to_string(),
+        );
+        check(
+            self.openhab.poll_interval_secs != Some(0),
+            "openhab.poll_interval_secs: must be positive".to_string(),
+        );
+        check(
+            self.openhab.things_interval_secs != Some(0),
+            "openhab.things_interval_secs: must be positive".to_string(),
+        );
+
+        // Conflicting and incomplete options.
+        check(
+            self.room.is_none() || self.topic.is_none(),
+            "room and topic: both choose the room, set only one".to_string(),
+        );
+        check(
+            self.room.is_none() || self.passphrase.is_some(),
+            "room: a named room needs a passphrase".to_string(),
+        );
+        check(
+            !matches!(self.ip_mode, IpMode::V4) || self.bind_v6.is_none(),
+            "bind_v6: not used with ip_mode = \"v4\"".to_string(),
+        );
+        check(
+            !matches!(self.ip_mode, IpMode::V6) || self.bind_v4.is_none(),
+            "bind_v4: not used with ip_mode = \"v6\"".to_string(),
+        );
+        check(
+            !matches!(self.relay.mode, RelayModeConfig::Custom) || !self.relay.urls.is_empty(),
+            "relay.urls: the custom relay mode needs at least one relay URL".to_string(),
+        );
+        check(
+            !matches!(
+                self.relay.mode,
+                RelayModeConfig::Staging | RelayModeConfig::Disabled
+            ) || self.relay.urls.is_empty(),
+            format!(
+                "relay.urls: not used in the {:?} relay mode",
+                self.relay.mode
+            )
+            .to_lowercase(),
+        );
+        for node_id in &self.access.allow {
+            check(
+                !self.access.block.contains(node_id),
+                format!(
+                    "access: {} is both allowed and blocked",
+                    node_id.fmt_short()
+                ),
+            );
+        }
+        check(
+            self.openhab.password.is_none() || self.openhab.username.is_some(),
+            "openhab.password: set without openhab.username".to_string(),
+        );
+        check(
+            self.home != HomeBackend::HomeAssistant || self.openhab_servers.is_empty(),
+            "openhab_servers: not used with home = \"home_assistant\"".to_string(),
+        );
+        check(
+            self.openhab_servers.is_empty() || self.openhab.is_configured(),
+            "openhab_servers: needs the main server in openhab.url".to_string(),
+        );
+        check(
+            self.home != HomeBackend::HomeAssistant || self.home_assistant.token.is_some(),
+            "home_assistant.token: Home Assistant needs a long-lived access token".to_string(),
+        );
+        check(
+            self.matrix.homeserver.is_none()
+                || (self.matrix.access_token.is_some() && self.matrix.room.is_some()),
+            "matrix: the bridge needs access_token and room besides homeserver".to_string(),
+        );
+        check(
+            self.irc.server.is_none() || self.irc.channel.is_some(),
+            "irc.channel: the bridge needs a channel to join".to_string(),
+        );
+        check(
+            !self.inbound_webhook.is_enabled() || self.http_listen.is_some(),
+            "inbound_webhook: needs the HTTP API, set http_listen".to_string(),
+        );
+        for rule in &self.rules {
+            if let Err(err) = Rules::new(std::slice::from_ref(rule)) {
+                check(false, format!("rules: {err:#}"));
+            }
+        }
+        for trigger in &self.triggers {
+            if let Err(err) = Bot::new(std::slice::from_ref(trigger)) {
+                check(false, format!("triggers: {err:#}"));
+            }
+        }
+
+        match problems.len() {
+            0 => Ok(()),
+            1 => bail!("invalid configuration: {}", problems[0]),
+            n => bail!(
+                "invalid configuration, {n} problems:\n  - {}",
+                problems.join("\n  - ")
+            ),
+        }
+    }
+
+    /// The IPv4 and IPv6 socket addresses to bind.
+    ///
+    /// iroh always opens an IPv4 socket, so the single-stack modes bind the
+    /// other address family to loopback, where no peer can reach it.
+    pub fn bind_addrs(&self) -> (SocketAddrV4, Option<SocketAddrV6>) {
+        let port = self.bind_port.unwrap_or(0);
+        let v4 = SocketAddrV4::new(self.bind_v4.unwrap_or(Ipv4Addr::UNSPECIFIED), port);
+        // Without an explicit address iroh picks the port after the IPv4 one.
+        let v6 = self.bind_v6.map(|ip| SocketAddrV6::new(ip, port, 0, 0));
+        match self.ip_mode {
+            IpMode::Dual => (v4, v6),
+            IpMode::V4 => (v4, Some(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 0, 0, 0))),
+            IpMode::V6 => (
+                SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+                Some(v6.unwrap_or(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0))),
+            ),
+        }
+    }
+}
+
+/// Prefix of the environment variables overriding settings.
+const ENV_PREFIX: &str = "IROH_P2P_";
+
+/// Sets the setting at `path`, the name of an environment variable without
+/// the prefix, in `table`, creating the tables on the way.
+fn set_env_override(table: &mut toml::Table, path: &str, value: toml::Value) -> Result<()> {
+    let mut keys = path.split("__").map(str::to_lowercase).peekable();
+    let mut table = table;
+    while let Some(key) = keys.next() {
+        ensure!(!key.is_empty(), "empty setting name");
+        if keys.peek().is_none() {
+            table.insert(key, value);
+            return Ok(());
+        }
+        let entry = table
+            .entry(key.clone())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        table = entry
+            .as_table_mut()
+            .with_context(|| format!("{key} is not a table"))?;
+    }
+    Ok(())
+}
+
+/// A value of an environment variable as TOML, such as `47001`, `true` or
+/// `["a", "b"]`, or as a string if it is none. String settings fall back to
+/// the text in [`Config::with_env_overrides`].
+fn parse_env_value(value: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("value = {value}"))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()))
+}
+
+/// Checks that `url` is an absolute `http` or `https` URL with a host.
+fn check_http_url(url: &str) -> Result<(), String> {
+    let url = Url::parse(url).map_err(|err| err.to_string())?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("expected an http or https URL".to_string());
+    }
+    if url.host().is_none() {
+        return Err("the URL has no host".to_string());
+    }
+    Ok(())
+}
+
+/// Checks that only its owner can access the file at `path`.
+#[cfg(unix)]
+fn check_private_file(path: &Path) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let metadata = std::fs::metadata(path).map_err(|err| err.to_string())?;
+    if !metadata.is_file() {
+        return Err("not a file".to_string());
+    }
+    let mode = metadata.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        return Err(format!(
+            "readable by other users (mode {mode:o}), restrict it with chmod 600"
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_private_file(path: &Path) -> Result<(), String> {
+    match path.is_file() {
+        true => Ok(()),
+        false => Err("not a file".to_string()),
+    }
+}
+
+/// Parses the topic from its hex form, as printed when opening a room,
+/// rather than serde's byte array.
+fn deserialize_topic<'de, D: Deserializer<'de>>(d: D) -> Result<Option<TopicId>, D::Error> {
+    let topic = String::deserialize(d)?;
+    topic.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+/// A node given as `<nodeid>@<ip:port>`, or as a bare node id to be found
+/// through discovery.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct BootstrapPeer(pub NodeAddr);
+
+impl FromStr for BootstrapPeer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (node_id, addr) = match s.split_once('@') {
+            Some((node_id, addr)) => (node_id, Some(addr)),
+            None => (s, None),
+        };
+        let node_id: NodeId = node_id
+            .parse()
+            .with_context(|| format!("invalid node id in bootstrap peer {s}"))?;
+        let mut node = NodeAddr::new(node_id);
+        if let Some(addr) = addr {
+            let addr: SocketAddr = addr
+                .parse()
+                .with_context(|| format!("invalid address in bootstrap peer {s}"))?;
+            node = node.with_direct_addresses([addr]);
+        }
+        Ok(Self(node))
+    }
+}
+
+impl TryFrom<String> for BootstrapPeer {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// Address families used for peer connections.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpMode {
+    /// IPv4 and, where available, IPv6.
+    #[default]
+    Dual,
+    /// IPv4 only.
+    V4,
+    /// IPv6 only.
+    V6,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayModeConfig {
+    /// Use the n0 production relays.
+    #[default]
+    Default,
+    /// Use the n0 staging relays.
+    Staging,
+    /// Never use a relay, direct connections only.
+    Disabled,
+    /// Use the relays listed in `urls`, e.g. self-hosted ones.
+    Custom,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayConfig {
+    pub mode: RelayModeConfig,
+    /// Relay servers for the `custom` mode. Listing any switches the
+    /// `default` mode to `custom`.
+    pub urls: Vec<RelayUrl>,
+}
+
+impl RelayConfig {
+    pub fn relay_mode(&self) -> Result<RelayMode> {
+        let mode = match self.mode {
+            RelayModeConfig::Default if self.urls.is_empty() => RelayMode::Default,
+            RelayModeConfig::Staging => RelayMode::Staging,
+            RelayModeConfig::Disabled => RelayMode::Disabled,
+            RelayModeConfig::Default | RelayModeConfig::Custom => {
+                if self.urls.is_empty() {
+                    bail!("the custom relay mode needs at least one relay URL");
+                }
+                // `from_url` fills in the default STUN and QUIC ports.
+                let nodes = self
+                    .urls
+                    .iter()
+                    .flat_map(|url| {
+                        RelayMap::from_url(url.clone())
+                            .nodes()
+                            .cloned()
+                            .collect::<Vec<_>>()
+                    })
+                    .collect::<Vec<_>>();
+                RelayMode::Custom(RelayMap::from_nodes(nodes)?)
+            }
+        };
+        Ok(mode)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// Resolve nodes through the n0 DNS server.
+    pub dns: bool,
+    /// Find nodes on the local network via mDNS.
+    pub mdns: bool,
+    /// Publish our addresses to the pkarr relay, so peers can resolve us
+    /// by node id even from tickets without addresses.
+    pub publish: bool,
+    /// Pkarr relay used for publishing and DNS discovery, instead of the
+    /// n0 one.
+    pub pkarr_relay: Option<Url>,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            dns: true,
+            mdns: true,
+            publish: false,
+            pkarr_relay: None,
+        }
+    }
+}
+
+/// Which nodes' room messages are accepted.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessConfig {
+    /// Only accept messages from these nodes. Everyone is allowed when
+    /// empty.
+    pub allow: Vec<NodeId>,
+    /// Never accept messages from these nodes.
+    pub block: Vec<NodeId>,
+    /// Nodes whose bans we follow, adding the banned node to our blocklist.
+    pub moderators: Vec<NodeId>,
+}
+
+impl AccessConfig {
+    pub fn permits(&self, node_id: &NodeId) -> bool {
+        (self.allow.is_empty() || self.allow.contains(node_id)) && !self.block.contains(node_id)
+    }
+}
+
+/// Per-peer limit on inbound room messages, as a token bucket.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Messages per second a peer may send on average, 0 disables the limit.
+    pub messages_per_sec: f64,
+    /// Messages a peer may send in a burst.
+    pub burst: f64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            messages_per_sec: 5.0,
+            burst: 20.0,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PresenceConfig {
+    /// Seconds between heartbeats.
+    pub heartbeat_secs: Option<u64>,
+    /// Seconds without any message before a peer is marked offline.
+    pub timeout_secs: Option<u64>,
+}
+
+impl PresenceConfig {
+    pub fn heartbeat_interval(&self) -> Duration {
+        self.heartbeat_secs
+            .map_or(DEFAULT_HEARTBEAT_INTERVAL, Duration::from_secs)
+    }
+
+    pub fn peer_timeout(&self) -> Duration {
+        self.timeout_secs
+            .map_or(DEFAULT_PEER_TIMEOUT, Duration::from_secs)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpenHabConfig {
+    /// Base URL of the openHAB server, without the `/rest` suffix. Empty
+    /// unless configured, which leaves the node without openHAB.
+    pub url: String,
+    /// Items whose states are attached to chat messages.
+    pub items: Vec<String>,
+    /// API token sent as a Bearer token, required by openHAB 3+ for
+    /// non-localhost access.
+    pub token: Option<String>,
+    /// User for HTTP basic auth, as required by some reverse proxies in
+    /// front of openHAB.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// PEM file with the certificate of a CA to trust for `https://` URLs,
+    /// e.g. for a self-signed server certificate.
+    pub ca_cert: Option<PathBuf>,
+    /// Accept any server certificate, even invalid or self-signed ones.
+    /// This allows others on the network to impersonate the server.
+    pub accept_invalid_certs: bool,
+    /// Forward `/set` commands from other peers to the openHAB server. Off
+    /// by default, as anyone in the room could then control devices.
+    pub accept_commands: bool,
+    /// How often a request failing with a network error, a timeout or a
+    /// server error is retried.
+    pub retries: u32,
+    /// Delay before the first retry in milliseconds, doubled after every
+    /// further attempt and randomized by up to half to spread out retries.
+    pub retry_delay_ms: u64,
+    /// Seconds to wait for the server to answer a single attempt.
+    pub attempt_timeout_secs: u64,
+    /// Seconds to wait for a connection to the server.
+    pub connect_timeout_secs: u64,
+    /// Seconds a request may take until its response is read completely.
+    /// Event streams are exempt, as they stay open for good.
+    pub read_timeout_secs: u64,
+    /// Seconds after which the cached item states are fetched again, in
+    /// case the event bus missed a change.
+    pub state_ttl_secs: u64,
+    /// Poll the items every this many seconds instead of following the
+    /// WebSocket, for servers where it is unavailable.
+    pub poll_interval_secs: Option<u64>,
+    /// Check the status of the openHAB Things every this many seconds and
+    /// share changes with the rooms, off when unset.
+    pub things_interval_secs: Option<u64>,
+}
+
+impl OpenHabConfig {
+    /// Whether a server URL is set.
+    pub fn is_configured(&self) -> bool {
+        !self.url.is_empty()
+    }
+
+    /// The `Authorization` header for basic auth, if a user is set.
+    pub fn basic_auth(&self) -> Option<String> {
+        let username = self.username.as_ref()?;
+        let credentials = format!("{username}:{}", self.password.as_deref().unwrap_or(""));
+        Some(format!(
+            "Basic {}",
+            data_encoding::BASE64.encode(credentials.as_bytes())
+        ))
+    }
+
+    pub fn retry_delay(&self) -> Duration {
+        Duration::from_millis(self.retry_delay_ms)
+    }
+
+    pub fn attempt_timeout(&self) -> Duration {
+        Duration::from_secs(self.attempt_timeout_secs)
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        Duration::from_secs(self.read_timeout_secs)
+    }
+
+    pub fn state_ttl(&self) -> Duration {
+        Duration::from_secs(self.state_ttl_secs)
+    }
+
+    pub fn poll_interval(&self) -> Option<Duration> {
+        self.poll_interval_secs.map(Duration::from_secs)
+    }
+
+    pub fn things_interval(&self) -> Option<Duration> {
+        self.things_interval_secs.map(Duration::from_secs)
+    }
+}
+
+impl Default for OpenHabConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            items: Vec::new(),
+            token: None,
+            username: None,
+            password: None,
+            ca_cert: None,
+            accept_invalid_certs: false,
+            accept_commands: false,
+            retries: 3,
+            retry_delay_ms: 250,
+            attempt_timeout_secs: 5,
+            connect_timeout_secs: 5,
+            read_timeout_secs: 10,
+            state_ttl_secs: 300,
+            poll_interval_secs: None,
+            things_interval_secs: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HomeBackend {
+    #[default]
+    Openhab,
+    HomeAssistant,
+}
+
+impl HomeBackend {
+    /// Name of the system, shown to the user.
+    pub fn name(self) -> &'static str {
+        match self {
+            HomeBackend::Openhab => "openHAB",
+            HomeBackend::HomeAssistant => "Home Assistant",
+        }
+    }
+}
+
+/// A Home Assistant server, used instead of openHAB with
+/// `home = "home_assistant"`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HomeAssistantConfig {
+    /// Base URL of the server, without the `/api` suffix.
+    pub url: String,
+    /// Long-lived access token, created on the user's profile page.
+    pub token: Option<String>,
+    /// Entities whose states are attached to chat messages and shared
+    /// with peers, such as `sensor.living_room_temperature`.
+    pub entities: Vec<String>,
+    /// Carry out `/set` commands from other peers. Off by default, as
+    /// anyone in the room could then control devices.
+    pub accept_commands: bool,
+}
+
+impl Default for HomeAssistantConfig {
+    fn default() -> Self {
+        Self {
+            url: "http://homeassistant.local:8123".to_string(),
+            token: None,
+            entities: Vec::new(),
+            accept_commands: false,
+        }
+    }
+}
+
+/// Sends `command` to `item` whenever a peer's message matches.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    /// Shown when the rule fires, defaults to the keyword or pattern.
+    pub name: Option<String>,
+    /// Text the message has to contain, ignoring case.
+    pub keyword: Option<String>,
+    /// Regular expression the message has to match, instead of a keyword.
+    pub pattern: Option<String>,
+    pub item: String,
+    pub command: String,
+    /// Nodes that may trigger the rule. Everyone allowed in the room may
+    /// when empty.
+    #[serde(default)]
+    pub allow: Vec<NodeId>,
+}
+
+/// Something the bot answers, set by exactly one of `command`,
+/// `every_secs`, `at` and `item`.
+///
+/// The answer is the `reply` template, with `{from}`, `{args}`, `{item}`,
+/// `{old}`, `{new}`, `{time}` and `{output}` filled in, or the output of
+/// `run`, a shell command getting the event as JSON on stdin.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TriggerConfig {
+    /// Messages starting with this word, e.g. `!weather`. The rest of the
+    /// message becomes `{args}`.
+    pub command: Option<String>,
+    /// Post to every room this often.
+    pub every_secs: Option<u64>,
+    /// Post to every room daily at this local time, as `HH:MM`.
+    pub at: Option<String>,
+    /// Changes of this openHAB item, ours or shared by a peer.
+    pub item: Option<String>,
+    pub reply: Option<String>,
+    pub run: Option<String>,
+}
+
+/// A WebAssembly plugin, e.g. `path = "plugins/lights.wasm"`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PluginConfig {
+    /// The module, in binary or text format.
+    pub path: PathBuf,
+    /// openHAB items the plugin may read and send commands to.
+    #[serde(default)]
+    pub items: Vec<String>,
+}
+
+/// Shell commands run with an event as JSON on stdin, e.g.
+/// `on_message = "notify-lights.sh"`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HooksConfig {
+    /// Run for each chat message from a peer.
+    pub on_message: Option<String>,
+    /// Run for each private message.
+    pub on_direct_message: Option<String>,
+    /// Run for each item change shared by a peer.
+    pub on_item_changed: Option<String>,
+}
+
+impl HooksConfig {
+    pub fn is_empty(&self) -> bool {
+        self.on_message.is_none()
+            && self.on_direct_message.is_none()
+            && self.on_item_changed.is_none()
+    }
+}
+
+/// A URL receiving events in HTTP POSTs, in the format of the HTTP API's
+/// event stream, e.g. `url = "https://example.org/hook"` with
+/// `events = ["message", "item_changed"]`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: Url,
+    /// Events to post, all of them when empty.
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+    /// Key signing each body with HMAC-SHA256, sent hex encoded in the
+    /// `X-Signature-256` header as `sha256=<signature>`.
+    pub secret: Option<String>,
+}
+
+/// Credentials for `POST /webhook` on the HTTP API, which turns requests of
+/// other services, such as Grafana alerts, into chat messages and item
+/// commands. Off unless `token` or `secret` is set.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InboundWebhookConfig {
+    /// Expected as `Authorization: Bearer <token>` or as the `token` query
+    /// parameter. When `http_listen` is not a loopback address, every
+    /// request to the HTTP API needs it.
+    pub token: Option<String>,
+    /// Key of an HMAC-SHA256 signature of the body, expected hex encoded in
+    /// the `X-Signature-256` header as `sha256=<signature>`.
+    pub secret: Option<String>,
+}
+
+impl InboundWebhookConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.token.is_some() || self.secret.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    Message,
+    DirectMessage,
+    /// Item changes shared by peers and on our own openHAB server.
+    ItemChanged,
+    PeerJoined,
+    PeerLeft,
+    PeerOffline,
+}
+
+/// Programs recording and playing voice notes, given as shell commands
+/// with `{path}` standing for a WAV file and `{secs}` for the length of a
+/// recording.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VoiceConfig {
+    pub record: Option<String>,
+    pub play: Option<String>,
+    /// Play voice notes from peers as soon as they arrive, like an
+    /// intercom.
+    pub autoplay: bool,
+    /// Longest voice note `/voice` records, in seconds.
+    pub max_secs: u32,
+}
+
+impl Default for VoiceConfig {
+    fn default() -> Self {
+        Self {
+            record: voice::default_record_command(),
+            play: voice::default_play_command(),
+            autoplay: false,
+            max_secs: 60,
+        }
+    }
+}
+
+/// Desktop notifications for messages and openHAB alerts.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationConfig {
+    pub enabled: bool,
+    /// Rooms not to notify about unless we are mentioned, as topics or
+    /// topic prefixes in hex.
+    pub muted: Vec<String>,
+}
+
+/// A Matrix room to bridge with the room opened or joined on startup, off
+/// when `homeserver` is unset.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatrixConfig {
+    /// Base URL of the homeserver, e.g. `https://matrix.org`.
+    pub homeserver: Option<Url>,
+    /// Access token of the account the bridge posts as, e.g. copied from
+    /// Element's Help & About settings.
+    pub access_token: Option<String>,
+    /// Room ID or alias, e.g. `#family:matrix.org`.
+    pub room: Option<String>,
+}
+
+/// A Telegram chat to bridge with the room opened or joined on startup,
+/// through a bot created with @BotFather. Off when `token` is unset.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelegramConfig {
+    pub token: Option<String>,
+    /// Chat the bot posts to, and the only one it takes messages from.
+    pub chat_id: Option<i64>,
+    /// Bot API server, for a self-hosted one.
+    pub api_url: Url,
+}
+
+impl Default for TelegramConfig {
+    fn default() -> Self {
+        Self {
+            token: None,
+            chat_id: None,
+            api_url: Url::parse("https://api.telegram.org").expect("valid URL"),
+        }
+    }
+}
+
+/// An IRC channel to bridge with the room opened or joined on startup, off
+/// when `server` is unset.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IrcConfig {
+    /// Host name of the server, e.g. `irc.libera.chat`.
+    pub server: Option<String>,
+    pub port: u16,
+    pub tls: bool,
+    /// Nick of the bridge, suffixed with `_` while it is taken.
+    pub nick: String,
+    /// Server password, sent with `PASS`.
+    pub password: Option<String>,
+    /// Channel to join, e.g. `#family`.
+    pub channel: Option<String>,
+}
+
+impl Default for IrcConfig {
+    fn default() -> Self {
+        Self {
+            server: None,
+            port: 6697,
+            tls: true,
+            nick: "gossip-bridge".to_string(),
+            password: None,
+            channel: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    /// Broker to bridge with, the bridge is off when unset.
+    pub host: Option<String>,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Topic that chat messages from peers are published to.
+    pub message_topic: String,
+    /// Topic that item changes from peers are published to, `{item}` is
+    /// replaced with the item name.
+    pub item_topic: String,
+    /// MQTT topics, wildcards allowed, whose messages are shared with the
+    /// rooms as item changes named after the topic.
+    pub subscribe: Vec<String>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            host: None,
+            port: 1883,
+            client_id: "iroh-gossip-chat".to_string(),
+            username: None,
+            password: None,
+            message_topic: "iroh-gossip-chat/messages".to_string(),
+            item_topic: "iroh-gossip-chat/items/{item}".to_string(),
+            subscribe: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `text`, with the secret key somewhere that does not exist so
+    /// the user's own key file is not checked.
+    fn config(text: &str) -> Config {
+        let mut config: Config = toml::from_str(text).unwrap();
+        config
+            .secret_key_file
+            .get_or_insert_with(|| PathBuf::from("/nonexistent/secret.key"));
+        config
+    }
+
+    fn problems(text: &str) -> String {
+        config(text).validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn binds_the_sockets_of_each_ip_mode() {
+        let bind = |text: &str| toml::from_str::<Config>(text).unwrap().bind_addrs();
+        assert_eq!(
+            bind("bind_port = 47001"),
+            ("0.0.0.0:47001".parse().unwrap(), None)
+        );
+        assert_eq!(
+            bind("bind_port = 47001\nbind_v6 = \"::1\"").1,
+            Some("[::1]:47001".parse().unwrap())
+        );
+        // The family left out is bound to loopback, out of reach of peers.
+        assert_eq!(
+            bind("ip_mode = \"v4\"\nbind_v4 = \"192.168.1.2\""),
+            (
+                "192.168.1.2:0".parse().unwrap(),
+                Some("[::1]:0".parse().unwrap())
+            )
+        );
+        assert_eq!(
+            bind("ip_mode = \"v6\"\nbind_port = 47001"),
+            (
+                "127.0.0.1:0".parse().unwrap(),
+                Some("[::]:47001".parse().unwrap())
+            )
+        );
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        config("").validate().unwrap();
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        let problems = problems(
+            r#"
+            max_message_size = 0
+            room = "kitchen"
+
+            [openhab]
+            url = "ftp://openhab.local"
+            "#,
+        );
+        assert!(problems.contains("3 problems"), "{problems}");
+        assert!(problems.contains("openhab.url"));
+        assert!(problems.contains("max_message_size"));
+        assert!(problems.contains("passphrase"));
+    }
+
+    #[test]
+    fn validate_checks_server_names() {
+        let problems = problems("[openhab_servers.\"a:b\"]\nurl = \"http://oh:8080\"");
+        assert!(problems.contains("openhab_servers.a:b"), "{problems}");
+        assert!(problems.contains("needs the main server"), "{problems}");
+    }
+
+    #[test]
+    fn env_values_are_toml_or_strings() {
+        assert_eq!(parse_env_value("47001"), toml::Value::Integer(47001));
+        assert_eq!(parse_env_value("true"), toml::Value::Boolean(true));
+        assert_eq!(
+            parse_env_value(r#"["a", "b"]"#),
+            toml::Value::Array(vec!["a".into(), "b".into()])
+        );
+        assert_eq!(
+            parse_env_value("http://openhab.local:8080"),
+            toml::Value::String("http://openhab.local:8080".to_string())
+        );
+    }
+
+    #[test]
+    fn env_overrides_nested_settings() {
+        let table: toml::Table = toml::from_str("[openhab]\nitems = [\"Temp\"]\n").unwrap();
+        let overrides = [
+            ("NAME", "kitchen"),
+            ("OPENHAB__URL", "http://oh:8080"),
+            ("RATE_LIMIT__BURST", "5.0"),
+        ];
+        let overrides = overrides.map(|(name, value)| (name.to_string(), value.to_string()));
+        let config = Config::with_env_overrides(table, overrides.into()).unwrap();
+        assert_eq!(config.name.as_deref(), Some("kitchen"));
+        assert_eq!(config.openhab.url, "http://oh:8080");
+        assert_eq!(config.openhab.items, ["Temp"]);
+        assert_eq!(config.rate_limit.burst, 5.0);
+    }
+
+    #[test]
+    fn env_overrides_reject_bad_paths() {
+        let mut table: toml::Table = toml::from_str("name = \"kitchen\"").unwrap();
+        assert!(set_env_override(&mut table, "NAME__FIRST", "x".into()).is_err());
+        assert!(set_env_override(&mut table, "OPENHAB____URL", "x".into()).is_err());
+    }
+
+    #[test]
+    fn validate_requires_a_token_beyond_loopback() {
+        let problems = problems(r#"http_listen = "0.0.0.0:8787""#);
+        assert!(problems.contains("inbound_webhook.token"), "{problems}");
+        config("http_listen = \"127.0.0.1:8787\"")
+            .validate()
+            .unwrap();
+        config("http_listen = \"0.0.0.0:8787\"\n[inbound_webhook]\ntoken = \"t\"")
+            .validate()
+            .unwrap();
+    }
+
+    #[test]
+    fn env_overrides_keep_numeric_text_for_string_settings() {
+        let overrides = [
+            ("PASSPHRASE", "1234"),
+            ("ROOM", "2024"),
+            ("NAME", "true"),
+            ("BIND_PORT", "47001"),
+        ];
+        let overrides = overrides.map(|(name, value)| (name.to_string(), value.to_string()));
+        let config = Config::with_env_overrides(toml::Table::new(), overrides.into()).unwrap();
+        assert_eq!(config.passphrase.as_deref(), Some("1234"));
+        assert_eq!(config.room.as_deref(), Some("2024"));
+        assert_eq!(config.name.as_deref(), Some("true"));
+        assert_eq!(config.bind_port, Some(47001));
+    }
+
+    #[test]
+    fn openhab_is_off_without_a_url() {
+        let config = config("[openhab]\nitems = [\"Temp\"]");
+        assert!(!config.openhab.is_configured());
+        config.validate().unwrap();
+    }
+}
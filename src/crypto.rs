@@ -0,0 +1,103 @@
+use anyhow::{anyhow, ensure, Result};
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use iroh_gossip::proto::TopicId;
+
+const NONCE_LEN: usize = 24;
+const SALT_LEN: usize = 16;
+
+/// Domain separation for [`room_topic`], so the topic never equals a key.
+const ROOM_TOPIC_CONTEXT: &str = "iroh-gossip-chat room topic v1";
+
+/// Derives the topic of a named room from its name and passphrase.
+///
+/// Peers knowing both end up in the same room without exchanging a ticket,
+/// everyone else can neither guess the topic nor read its traffic. Argon2
+/// makes brute forcing the passphrase from an observed topic expensive.
+pub fn room_topic(name: &str, passphrase: &str) -> Result<TopicId> {
+    let salt = blake3::derive_key(ROOM_TOPIC_CONTEXT, name.as_bytes());
+    let mut topic = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), &salt, &mut topic)
+        .map_err(|err| anyhow!("failed to derive room topic: {err}"))?;
+    Ok(TopicId::from_bytes(topic))
+}
+
+/// Encrypts `plaintext` with a key derived from `passphrase`, for secrets
+/// stored on disk. Returns `salt || nonce || ciphertext`.
+pub fn seal_with_passphrase(passphrase: &str, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let salt: [u8; SALT_LEN] = rand::random();
+    let cipher = passphrase_cipher(passphrase, &salt)?;
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .expect("encryption should not fail");
+    let mut out = salt.to_vec();
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// Decrypts the output of [`seal_with_passphrase`].
+pub fn open_with_passphrase(passphrase: &str, bytes: &[u8]) -> Result<Vec<u8>> {
+    ensure!(bytes.len() > SALT_LEN + NONCE_LEN, "ciphertext too short");
+    let (salt, rest) = bytes.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    passphrase_cipher(passphrase, salt)?
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("decryption failed, wrong passphrase?"))
+}
+
+fn passphrase_cipher(passphrase: &str, salt: &[u8]) -> Result<XChaCha20Poly1305> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|err| anyhow!("failed to derive key: {err}"))?;
+    Ok(XChaCha20Poly1305::new(&key.into()))
+}
+
+/// Symmetric cipher shared by all members of a passphrase-protected room.
+///
+/// Payloads are encrypted after signing, so relays and nodes without the
+/// passphrase only ever see ciphertext.
+#[derive(Clone)]
+pub struct RoomCipher {
+    cipher: XChaCha20Poly1305,
+}
+
+impl RoomCipher {
+    /// Derives the room key from the passphrase with Argon2, salted with the
+    /// topic so the same passphrase yields different keys per room.
+    pub fn from_passphrase(passphrase: &str, topic: &TopicId) -> Result<Self> {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), topic.as_bytes(), &mut key)
+            .map_err(|err| anyhow!("failed to derive room key: {err}"))?;
+        Ok(Self {
+            cipher: XChaCha20Poly1305::new(&key.into()),
+        })
+    }
+
+    /// Returns `nonce || ciphertext`.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .expect("encryption should not fail");
+        let mut out = nonce.to_vec();
+        out.extend_from_slice(&ciphertext);
+        out
+    }
+
+    pub fn decrypt(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        ensure!(bytes.len() > NONCE_LEN, "ciphertext too short");
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("decryption failed, wrong room passphrase?"))
+    }
+}
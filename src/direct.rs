@@ -0,0 +1,83 @@
+//! Private messages sent over a direct QUIC connection instead of the gossip
+//! topic.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{ensure, Result};
+use futures_lite::future::Boxed as BoxedFuture;
+use iroh::{endpoint::Connecting, protocol::ProtocolHandler, Endpoint, NodeId};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tracing::{debug, instrument};
+
+use crate::{node::Access, Event};
+
+/// ALPN of the direct message protocol.
+pub const ALPN: &[u8] = b"iroh-gossip-chat/dm/0";
+
+/// Upper bound for a single direct message.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Reply sent back once a message was received.
+const ACK: &[u8] = b"ok";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DirectMessage {
+    text: String,
+}
+
+/// Sends `text` to `node_id` and waits for it to be acknowledged.
+///
+/// The QUIC handshake authenticates both ends, so the payload needs no
+/// signature of its own.
+#[instrument(skip_all, fields(to = %node_id.fmt_short()))]
+pub async fn send(endpoint: &Endpoint, node_id: NodeId, text: String) -> Result<()> {
+    let conn = endpoint.connect(node_id, ALPN).await?;
+    let (mut send, mut recv) = conn.open_bi().await?;
+    let bytes = postcard::to_stdvec(&DirectMessage { text })?;
+    send.write_all(&bytes).await?;
+    send.finish()?;
+    let ack = recv.read_to_end(ACK.len()).await?;
+    ensure!(ack == ACK, "unexpected reply from {}", node_id.fmt_short());
+    conn.close(0u32.into(), b"bye");
+    Ok(())
+}
+
+/// Accepts direct messages and publishes them as
+/// [`Event::DirectMessage`](crate::Event::DirectMessage).
+#[derive(Debug, Clone)]
+pub(crate) struct DirectMessages {
+    pub(crate) names: Arc<Mutex<HashMap<NodeId, String>>>,
+    pub(crate) events: broadcast::Sender<Event>,
+    pub(crate) access: Arc<Access>,
+}
+
+impl ProtocolHandler for DirectMessages {
+    fn accept(&self, connecting: Connecting) -> BoxedFuture<Result<()>> {
+        let this = self.clone();
+        Box::pin(async move {
+            let conn = connecting.await?;
+            let from = conn.remote_node_id()?;
+            if !this.access.permits(&from) {
+                debug!(from = %from.fmt_short(), "refused direct message from blocked node");
+                conn.close(1u32.into(), b"blocked");
+                return Ok(());
+            }
+            let (mut send, mut recv) = conn.accept_bi().await?;
+            let bytes = recv.read_to_end(MAX_MESSAGE_SIZE).await?;
+            let DirectMessage { text } = postcard::from_bytes(&bytes)?;
+            debug!(from = %from.fmt_short(), "received direct message");
+            send.write_all(ACK).await?;
+            send.finish()?;
+            let name = this.names.lock().unwrap().get(&from).cloned();
+            this.events
+                .send(Event::DirectMessage { from, name, text })
+                .ok();
+            conn.closed().await;
+            Ok(())
+        })
+    }
+}
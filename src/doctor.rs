@@ -0,0 +1,203 @@
+//! The `doctor` subcommand, checking what the node needs to reach peers.
+
+use std::{collections::BTreeSet, time::Duration};
+
+use futures_lite::StreamExt;
+use iroh::{dns::ResolverExt, endpoint::DirectAddrType};
+use iroh_gossip_chat::{
+    config::{Config, DiscoveryConfig, RelayModeConfig},
+    home::HomeProvider,
+    ChatNode,
+};
+use serde::Serialize;
+
+/// How long to wait for the first direct addresses.
+const DIRECT_ADDRS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to listen for other nodes on the local network.
+const MDNS_WAIT: Duration = Duration::from_secs(3);
+
+/// How long to wait for DNS answers.
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Host looked up to check DNS when no pkarr relay is configured.
+const N0_DNS_HOST: &str = "dns.iroh.link.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Ok,
+    /// Works, but peers may have trouble reaching us.
+    Warn,
+    Fail,
+    /// Not checked as it is disabled.
+    Skip,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub check: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(check: &'static str, outcome: Outcome, detail: impl Into<String>) -> Self {
+        Self {
+            check,
+            outcome,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let outcome = match self.outcome {
+            Outcome::Ok => "ok",
+            Outcome::Warn => "warn",
+            Outcome::Fail => "FAIL",
+            Outcome::Skip => "skip",
+        };
+        write!(f, "{outcome:<5} {:<9} {}", self.check, self.detail)
+    }
+}
+
+/// Runs every check against the freshly spawned `node`.
+pub async fn run(node: &ChatNode, config: &Config) -> Vec<Check> {
+    vec![
+        check_sockets(node),
+        check_relay(node, config.relay.mode).await,
+        check_nat(node).await,
+        check_dns(node, &config.discovery).await,
+        check_mdns(node, &config.discovery).await,
+        check_home(node.home().map(|home| &**home)).await,
+    ]
+}
+
+fn check_sockets(node: &ChatNode) -> Check {
+    match node.endpoint().bound_sockets() {
+        (v4, Some(v6)) => Check::new("sockets", Outcome::Ok, format!("{v4}, {v6}")),
+        (v4, None) => Check::new("sockets", Outcome::Warn, format!("{v4}, no IPv6")),
+    }
+}
+
+async fn check_relay(node: &ChatNode, mode: RelayModeConfig) -> Check {
+    if let RelayModeConfig::Disabled = mode {
+        return Check::new("relay", Outcome::Skip, "relays are disabled");
+    }
+    match node.home_relay().await {
+        Some(relay) => Check::new("relay", Outcome::Ok, format!("connected to {relay}")),
+        None => Check::new(
+            "relay",
+            Outcome::Fail,
+            "no relay reachable, peers behind NATs cannot reach us",
+        ),
+    }
+}
+
+async fn check_nat(node: &ChatNode) -> Check {
+    let mut addrs = node.endpoint().direct_addresses();
+    let addrs = match tokio::time::timeout(DIRECT_ADDRS_TIMEOUT, addrs.initialized()).await {
+        Ok(Ok(addrs)) => addrs,
+        _ => return Check::new("NAT", Outcome::Fail, "no direct addresses found"),
+    };
+    let public: Vec<String> = addrs
+        .iter()
+        .filter(|addr| {
+            matches!(
+                addr.typ,
+                DirectAddrType::Stun | DirectAddrType::Portmapped | DirectAddrType::Stun4LocalPort
+            )
+        })
+        .map(|addr| format!("{} ({})", addr.addr, addr.typ))
+        .collect();
+    match public.is_empty() {
+        false => Check::new(
+            "NAT",
+            Outcome::Ok,
+            format!("public addresses {}", public.join(", ")),
+        ),
+        true => Check::new(
+            "NAT",
+            Outcome::Warn,
+            format!(
+                "only local addresses ({}), peers on other networks need the relay",
+                addrs.len()
+            ),
+        ),
+    }
+}
+
+async fn check_dns(node: &ChatNode, discovery: &DiscoveryConfig) -> Check {
+    let host = match &discovery.pkarr_relay {
+        Some(url) => match url.host_str() {
+            Some(host) => host.to_string(),
+            None => return Check::new("DNS", Outcome::Fail, format!("no host in {url}")),
+        },
+        None if discovery.dns || discovery.publish => N0_DNS_HOST.to_string(),
+        None => return Check::new("DNS", Outcome::Skip, "DNS discovery is disabled"),
+    };
+    let resolver = node.endpoint().dns_resolver();
+    match resolver.lookup_ipv4_ipv6(host.clone(), DNS_TIMEOUT).await {
+        Ok(ips) => {
+            let ips: Vec<String> = ips.map(|ip| ip.to_string()).collect();
+            Check::new(
+                "DNS",
+                Outcome::Ok,
+                format!("{host} resolves to {}", ips.join(", ")),
+            )
+        }
+        Err(err) => Check::new(
+            "DNS",
+            Outcome::Fail,
+            format!("failed to resolve {host}: {err:#}"),
+        ),
+    }
+}
+
+async fn check_mdns(node: &ChatNode, discovery: &DiscoveryConfig) -> Check {
+    if !discovery.mdns {
+        return Check::new("mDNS", Outcome::Skip, "mDNS discovery is disabled");
+    }
+    let Some(mut items) = node.endpoint().discovery().and_then(|d| d.subscribe()) else {
+        return Check::new("mDNS", Outcome::Fail, "mDNS discovery is not running");
+    };
+    let mut found = BTreeSet::new();
+    let _ = tokio::time::timeout(MDNS_WAIT, async {
+        while let Some(item) = items.next().await {
+            found.insert(item.node_addr.node_id);
+        }
+    })
+    .await;
+    match found.len() {
+        0 => Check::new(
+            "mDNS",
+            Outcome::Warn,
+            "no other nodes on the local network, none may be running or multicast is blocked",
+        ),
+        n => Check::new(
+            "mDNS",
+            Outcome::Ok,
+            format!("found {n} nodes on the local network"),
+        ),
+    }
+}
+
+async fn check_home(home: Option<&dyn HomeProvider>) -> Check {
+    let Some(home) = home else {
+        return Check::new("home", Outcome::Skip, "no smart home server");
+    };
+    match home.check().await {
+        Ok(()) => Check::new(
+            "home",
+            Outcome::Ok,
+            format!("{} at {} is reachable", home.name(), home.url()),
+        ),
+        Err(err) => Check::new(
+            "home",
+            Outcome::Fail,
+            format!("{} at {} is unreachable: {err:#}", home.name(), home.url()),
+        ),
+    }
+}
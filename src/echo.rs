@@ -0,0 +1,53 @@
+//! Echo mode, answering every chat message with the time it arrived, to
+//! measure propagation delays and check that two deployments can talk.
+
+use anyhow::Result;
+use chrono::{SecondsFormat, Utc};
+use futures_lite::future::Boxed as BoxedFuture;
+
+use crate::{
+    handler::{ChatMessage, MessageHandler},
+    message::MessageRef,
+    ChatNode,
+};
+
+/// Starts the text of echoes, which are never echoed themselves so two
+/// echoing nodes do not answer each other forever.
+pub const ECHO_PREFIX: &str = "echo: ";
+
+/// Replies to each chat message with an echo, registered as a
+/// [`MessageHandler`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Echo;
+
+impl MessageHandler for Echo {
+    fn on_message(&self, node: ChatNode, message: ChatMessage) -> BoxedFuture<Result<()>> {
+        let received = Utc::now();
+        Box::pin(async move {
+            if message.text.starts_with(ECHO_PREFIX) {
+                return Ok(());
+            }
+            let target = MessageRef {
+                from: message.from,
+                clock: message.clock,
+            };
+            let text = format!(
+                "{ECHO_PREFIX}received at {}",
+                received.to_rfc3339_opts(SecondsFormat::Millis, true)
+            );
+            node.send_reply(message.topic, text, Vec::new(), target)
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+/// How long the echo `text` answering `target` took to come back, if
+/// `target` is one of our messages.
+pub fn round_trip(node: &ChatNode, text: &str, target: &MessageRef) -> Option<chrono::Duration> {
+    if !text.starts_with(ECHO_PREFIX) || target.from != node.node_id() {
+        return None;
+    }
+    let sent = node.history()?.timestamp(target).ok()??;
+    Some(Utc::now() - sent)
+}
@@ -0,0 +1,87 @@
+//! The `export` subcommand, writing the history out for archiving or
+//! analysis in other tools.
+
+use std::io::Write;
+
+use anyhow::Result;
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use clap::ValueEnum;
+use iroh_gossip_chat::{history::HistoryEntry, http};
+
+use crate::entry_text;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// One JSON object per line, as served by the HTTP API.
+    Jsonl,
+    Csv,
+    /// Lines like those of the `history` subcommand.
+    Text,
+}
+
+/// Columns of the CSV export.
+const CSV_HEADER: &str = "timestamp,room,from,name,clock,text,readings,edited,deleted";
+
+/// Writes `entries` to `out` in `format`, naming readings in text after the
+/// `home` system.
+pub fn write(
+    entries: &[HistoryEntry],
+    format: ExportFormat,
+    home: &str,
+    mut out: impl Write,
+) -> Result<()> {
+    if format == ExportFormat::Csv {
+        writeln!(out, "{CSV_HEADER}")?;
+    }
+    for entry in entries {
+        match format {
+            ExportFormat::Jsonl => writeln!(out, "{}", http::entry_json(entry))?,
+            ExportFormat::Csv => writeln!(
+                out,
+                "{},{},{},{},{},{},{},{},{}",
+                entry.timestamp.to_rfc3339(),
+                entry.topic,
+                entry.from,
+                csv_field(entry.name.as_deref().unwrap_or_default()),
+                entry.clock,
+                csv_field(&entry.text),
+                csv_field(&serde_json::to_string(&entry.readings)?),
+                entry.edited,
+                entry.deleted,
+            )?,
+            ExportFormat::Text => {
+                let name = entry.name.clone().unwrap_or_else(|| entry.from.fmt_short());
+                writeln!(
+                    out,
+                    "{} [{}] {name}: {}",
+                    entry.timestamp.with_timezone(&Local).to_rfc3339(),
+                    entry.topic,
+                    entry_text(entry, home)
+                )?
+            }
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Quotes a CSV field if it contains separators, quotes or line breaks.
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+/// Parses a time given as RFC 3339, or as a date meaning local midnight.
+pub fn parse_time(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.to_utc());
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map_err(|_| format!("expected a date like 2025-01-31 or an RFC 3339 time, got {s}"))?;
+    date.and_hms_opt(0, 0, 0)
+        .and_then(|midnight| midnight.and_local_timezone(Local).earliest())
+        .map(|midnight| midnight.to_utc())
+        .ok_or_else(|| format!("no local midnight on {date}"))
+}
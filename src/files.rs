@@ -0,0 +1,197 @@
+//! File sharing through an iroh-blobs store on disk.
+//!
+//! Shared files are announced in the room with a [`BlobTicket`], receivers
+//! fetch the content from the sharing node and copy it to disk.
+
+use std::{
+    fs::OpenOptions,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use futures_lite::StreamExt;
+use iroh_blobs::{
+    get::db::DownloadProgress,
+    net_protocol::{Blobs, DownloadMode},
+    rpc::client::blobs::{DownloadOptions, WrapOption},
+    store::{fs::Store, ExportFormat, ExportMode},
+    ticket::BlobTicket,
+    util::SetTagOption,
+    BlobFormat, Hash,
+};
+
+/// The blobs protocol handler holding the files we share or downloaded.
+pub(crate) type BlobStore = Blobs<Store>;
+
+/// A file announced in one of our rooms.
+#[derive(Debug, Clone)]
+pub struct FileOffer {
+    pub name: String,
+    pub size: u64,
+    pub ticket: BlobTicket,
+}
+
+impl FileOffer {
+    pub fn hash(&self) -> Hash {
+        self.ticket.hash()
+    }
+}
+
+/// An image shared in one of our rooms, fetched like any other file.
+#[derive(Debug, Clone)]
+pub struct ImageOffer {
+    pub file: FileOffer,
+    pub mime: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A recorded voice note shared in one of our rooms, as a WAV file.
+#[derive(Debug, Clone)]
+pub struct VoiceNote {
+    pub file: FileOffer,
+    /// Length of the recording in seconds.
+    pub secs: u32,
+}
+
+/// File name for a voice note received as `hash`.
+pub(crate) fn voice_name(hash: &Hash) -> String {
+    format!("{}.wav", &hash.to_string()[..16])
+}
+
+/// MIME types of the images we share, with their file extension.
+const IMAGE_TYPES: &[(&str, &str)] = &[
+    ("image/png", "png"),
+    ("image/jpeg", "jpg"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+];
+
+/// Reads the MIME type and dimensions of the image at `path` from its
+/// header.
+pub(crate) fn image_info(path: &Path) -> Result<(String, u32, u32)> {
+    let data = std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let mime = match imagesize::image_type(&data) {
+        Ok(imagesize::ImageType::Png) => "image/png",
+        Ok(imagesize::ImageType::Jpeg) => "image/jpeg",
+        Ok(imagesize::ImageType::Gif) => "image/gif",
+        Ok(imagesize::ImageType::Webp) => "image/webp",
+        _ => bail!("{} is not a PNG, JPEG, GIF or WebP image", path.display()),
+    };
+    let size = imagesize::blob_size(&data).context("failed to read the image size")?;
+    Ok((mime.to_string(), size.width as u32, size.height as u32))
+}
+
+/// File name for an image received as `hash`, with the extension of its
+/// MIME type.
+pub(crate) fn image_name(hash: &Hash, mime: &str) -> Result<String> {
+    let (_, extension) = IMAGE_TYPES
+        .iter()
+        .find(|(known, _)| *known == mime)
+        .with_context(|| format!("unsupported image type {mime}"))?;
+    Ok(format!("{}.{extension}", &hash.to_string()[..16]))
+}
+
+/// Adds the file at `path` to the store and returns its hash and size.
+pub(crate) async fn import(blobs: &BlobStore, path: &Path) -> Result<(Hash, u64)> {
+    let path = std::path::absolute(path)?;
+    let outcome = blobs
+        .client()
+        .add_from_path(path.clone(), false, SetTagOption::Auto, WrapOption::NoWrap)
+        .await?
+        .finish()
+        .await
+        .with_context(|| format!("failed to import {}", path.display()))?;
+    Ok((outcome.hash, outcome.size))
+}
+
+/// Downloads an offered file and writes it to `dir`, returning its path.
+///
+/// The download is aborted once the sender turns out to hold more than
+/// `max_size` bytes, whatever size the offer announced. An existing file is
+/// never overwritten, the new one gets a numbered name instead.
+pub(crate) async fn download(
+    blobs: &BlobStore,
+    offer: &FileOffer,
+    dir: &Path,
+    max_size: Option<u64>,
+) -> Result<PathBuf> {
+    let client = blobs.client();
+    // Direct downloads stop as soon as we drop the progress stream.
+    let options = DownloadOptions {
+        format: BlobFormat::Raw,
+        nodes: vec![offer.ticket.node_addr().clone()],
+        tag: SetTagOption::Auto,
+        mode: DownloadMode::Direct,
+    };
+    let mut progress = client.download_with_opts(offer.hash(), options).await?;
+    while let Some(event) = progress.next().await {
+        // The size is part of the verified stream, so the sender cannot
+        // send more than it reports here.
+        let size = match event.context("download failed")? {
+            DownloadProgress::Found { size, .. } => size,
+            DownloadProgress::FoundLocal { size, .. } => size.value(),
+            DownloadProgress::AllDone(_) => break,
+            DownloadProgress::Abort(err) => {
+                return Err(anyhow::Error::from(err).context("download failed"))
+            }
+            _ => continue,
+        };
+        if let Some(max_size) = max_size.filter(|max_size| size > *max_size) {
+            bail!(
+                "{} is {size} bytes, more than the {max_size} allowed",
+                offer.name
+            );
+        }
+    }
+
+    // Never trust the announced name to stay inside `dir`.
+    let name = Path::new(&offer.name)
+        .file_name()
+        .context("offer has no file name")?;
+    let target = std::path::absolute(unused_path(dir, Path::new(name))?)?;
+    client
+        .export(
+            offer.hash(),
+            target.clone(),
+            ExportFormat::Blob,
+            ExportMode::Copy,
+        )
+        .await?
+        .finish()
+        .await?;
+    Ok(target)
+}
+
+/// Claims a new file for `name` in `dir`, numbering it as `name (1).ext`,
+/// `name (2).ext` and so on if it is taken.
+fn unused_path(dir: &Path, name: &Path) -> Result<PathBuf> {
+    let stem = name
+        .file_stem()
+        .unwrap_or(name.as_os_str())
+        .to_string_lossy();
+    let extension = name
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    let mut path = dir.join(name);
+    for n in 1.. {
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => break,
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                path = dir.join(format!("{stem} ({n}){extension}"));
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to create {}", path.display()))
+            }
+        }
+    }
+    Ok(path)
+}
+
+/// A ticket for `hash` that points at our node.
+pub(crate) async fn ticket(blobs: &BlobStore, hash: Hash) -> Result<BlobTicket> {
+    let me = blobs.endpoint().node_addr().await?;
+    BlobTicket::new(me, hash, BlobFormat::Raw)
+}
@@ -0,0 +1,64 @@
+//! Inline images in terminals that support a graphics protocol.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+/// Size of the base64 pieces an image is sent to kitty in.
+const KITTY_CHUNK: usize = 4096;
+
+/// Terminal graphics protocols we can draw images with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// kitty's graphics protocol, also spoken by Ghostty and Konsole. Only
+    /// PNG images are sent, others would need decoding first.
+    Kitty,
+    /// iTerm2's inline images, also shown by WezTerm.
+    ITerm,
+}
+
+impl Protocol {
+    /// The protocol the terminal we run in speaks, guessed from its
+    /// environment variables.
+    pub fn detect() -> Option<Self> {
+        let term = std::env::var("TERM").unwrap_or_default();
+        let program = std::env::var("TERM_PROGRAM").unwrap_or_default();
+        if std::env::var_os("KITTY_WINDOW_ID").is_some()
+            || term.contains("kitty")
+            || term.contains("ghostty")
+        {
+            return Some(Protocol::Kitty);
+        }
+        match program.as_str() {
+            "iTerm.app" | "WezTerm" => Some(Protocol::ITerm),
+            _ => None,
+        }
+    }
+
+    /// Escape sequence drawing the image at `path`, or `None` if the
+    /// protocol cannot show its format.
+    pub fn render(self, path: &Path) -> Result<Option<String>> {
+        let png = path.extension().is_some_and(|ext| ext == "png");
+        if self == Protocol::Kitty && !png {
+            return Ok(None);
+        }
+        let data = data_encoding::BASE64.encode(&std::fs::read(path)?);
+        let sequence = match self {
+            Protocol::Kitty => {
+                let chunks: Vec<&[u8]> = data.as_bytes().chunks(KITTY_CHUNK).collect();
+                let mut sequence = String::new();
+                for (i, chunk) in chunks.iter().enumerate() {
+                    let more = u8::from(i + 1 < chunks.len());
+                    let chunk = std::str::from_utf8(chunk)?;
+                    match i {
+                        0 => sequence.push_str(&format!("\x1b_Ga=T,f=100,m={more};{chunk}\x1b\\")),
+                        _ => sequence.push_str(&format!("\x1b_Gm={more};{chunk}\x1b\\")),
+                    }
+                }
+                sequence
+            }
+            Protocol::ITerm => format!("\x1b]1337;File=inline=1;preserveAspectRatio=1:{data}\x07"),
+        };
+        Ok(Some(sequence))
+    }
+}
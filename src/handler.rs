@@ -0,0 +1,277 @@
+//! Plugins reacting to what happens in the rooms, such as bridges, loggers
+//! or bots, registered with [`NodeBuilder::handler`](crate::NodeBuilder::handler)
+//! or [`ChatNode::add_handler`].
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures_lite::{future::Boxed as BoxedFuture, StreamExt};
+use iroh::NodeId;
+use iroh_gossip::proto::TopicId;
+use tracing::warn;
+
+use crate::{
+    message::MessageRef,
+    openhab::{OpenHabEvent, SensorReading},
+    ChatNode, Event,
+};
+
+/// Reacts to events of a [`ChatNode`].
+///
+/// Handlers are called one after another, in the order they were
+/// registered, and for one event at a time, so slow work should be spawned
+/// rather than awaited. Errors are logged. Every method does nothing by
+/// default.
+pub trait MessageHandler: Send + Sync + 'static {
+    /// A chat message from a peer.
+    fn on_message(&self, _node: ChatNode, _message: ChatMessage) -> BoxedFuture<Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// A private message from a peer.
+    fn on_direct_message(
+        &self,
+        _node: ChatNode,
+        _message: DirectMessage,
+    ) -> BoxedFuture<Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// A peer joined, left or went offline.
+    fn on_presence(&self, _node: ChatNode, _presence: Presence) -> BoxedFuture<Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// An item changed on a peer's openHAB server or on our own.
+    fn on_item_change(&self, _node: ChatNode, _change: ItemChange) -> BoxedFuture<Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// A chat message, as passed to [`MessageHandler::on_message`].
+#[derive(Debug, Clone)]
+pub struct ChatMessage {
+    pub topic: TopicId,
+    pub from: NodeId,
+    pub name: Option<String>,
+    pub clock: u64,
+    pub text: String,
+    pub readings: Vec<SensorReading>,
+    pub in_reply_to: Option<MessageRef>,
+}
+
+impl From<ChatMessage> for Event {
+    fn from(message: ChatMessage) -> Self {
+        Event::Message {
+            topic: message.topic,
+            from: message.from,
+            name: message.name,
+            clock: message.clock,
+            text: message.text,
+            readings: message.readings,
+            in_reply_to: message.in_reply_to,
+        }
+    }
+}
+
+/// A private message, as passed to [`MessageHandler::on_direct_message`].
+#[derive(Debug, Clone)]
+pub struct DirectMessage {
+    pub from: NodeId,
+    pub name: Option<String>,
+    pub text: String,
+}
+
+impl From<DirectMessage> for Event {
+    fn from(message: DirectMessage) -> Self {
+        Event::DirectMessage {
+            from: message.from,
+            name: message.name,
+            text: message.text,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PresenceChange {
+    Joined,
+    Left,
+    /// Stopped sending heartbeats without saying goodbye.
+    Offline,
+}
+
+/// A peer coming or going, as passed to [`MessageHandler::on_presence`].
+#[derive(Debug, Clone)]
+pub struct Presence {
+    pub topic: TopicId,
+    pub from: NodeId,
+    pub name: Option<String>,
+    pub change: PresenceChange,
+}
+
+impl From<Presence> for Event {
+    fn from(presence: Presence) -> Self {
+        let Presence {
+            topic, from, name, ..
+        } = presence;
+        match presence.change {
+            PresenceChange::Joined => Event::PeerJoined { topic, from, name },
+            PresenceChange::Left => Event::PeerLeft { topic, from, name },
+            PresenceChange::Offline => Event::PeerOffline { topic, from, name },
+        }
+    }
+}
+
+/// An item state change, as passed to [`MessageHandler::on_item_change`].
+#[derive(Debug, Clone)]
+pub struct ItemChange {
+    /// The room and peer that shared the change, unset for changes on our
+    /// own openHAB server.
+    pub source: Option<(TopicId, NodeId)>,
+    pub name: Option<String>,
+    pub item: String,
+    pub old: String,
+    pub new: String,
+    pub ts: DateTime<Utc>,
+}
+
+impl From<ItemChange> for Event {
+    fn from(change: ItemChange) -> Self {
+        match change.source {
+            Some((topic, from)) => Event::ItemChanged {
+                topic,
+                from,
+                name: change.name,
+                item: change.item,
+                old: change.old,
+                new: change.new,
+                ts: change.ts,
+            },
+            None => Event::OpenHab(OpenHabEvent::ItemStateChanged {
+                item: change.item,
+                old_state: change.old,
+                state: change.new,
+            }),
+        }
+    }
+}
+
+/// Calls the registered handlers for every event of `node`, until it shuts
+/// down.
+pub(crate) async fn dispatch(node: ChatNode) {
+    let mut events = node.events();
+    while let Some(event) = events.next().await {
+        let handlers = node.handlers();
+        if handlers.is_empty() {
+            continue;
+        }
+        for handler in handlers {
+            let node = node.clone();
+            let result = match event.clone() {
+                Event::Message {
+                    topic,
+                    from,
+                    name,
+                    clock,
+                    text,
+                    readings,
+                    in_reply_to,
+                } => {
+                    let message = ChatMessage {
+                        topic,
+                        from,
+                        name,
+                        clock,
+                        text,
+                        readings,
+                        in_reply_to,
+                    };
+                    handler.on_message(node, message).await
+                }
+                Event::DirectMessage { from, name, text } => {
+                    let message = DirectMessage { from, name, text };
+                    handler.on_direct_message(node, message).await
+                }
+                Event::PeerJoined { topic, from, name } => {
+                    let change = PresenceChange::Joined;
+                    handler
+                        .on_presence(
+                            node,
+                            Presence {
+                                topic,
+                                from,
+                                name,
+                                change,
+                            },
+                        )
+                        .await
+                }
+                Event::PeerLeft { topic, from, name } => {
+                    let change = PresenceChange::Left;
+                    handler
+                        .on_presence(
+                            node,
+                            Presence {
+                                topic,
+                                from,
+                                name,
+                                change,
+                            },
+                        )
+                        .await
+                }
+                Event::PeerOffline { topic, from, name } => {
+                    let change = PresenceChange::Offline;
+                    handler
+                        .on_presence(
+                            node,
+                            Presence {
+                                topic,
+                                from,
+                                name,
+                                change,
+                            },
+                        )
+                        .await
+                }
+                Event::ItemChanged {
+                    topic,
+                    from,
+                    name,
+                    item,
+                    old,
+                    new,
+                    ts,
+                } => {
+                    let change = ItemChange {
+                        source: Some((topic, from)),
+                        name,
+                        item,
+                        old,
+                        new,
+                        ts,
+                    };
+                    handler.on_item_change(node, change).await
+                }
+                Event::OpenHab(OpenHabEvent::ItemStateChanged {
+                    item,
+                    old_state,
+                    state,
+                }) => {
+                    let change = ItemChange {
+                        source: None,
+                        name: None,
+                        item,
+                        old: old_state,
+                        new: state,
+                        ts: Utc::now(),
+                    };
+                    handler.on_item_change(node, change).await
+                }
+                _ => Ok(()),
+            };
+            if let Err(err) = result {
+                warn!("message handler failed: {err:#}");
+            }
+        }
+    }
+}
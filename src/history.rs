@@ -0,0 +1,385 @@
+//! Local message history in a SQLite database.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use iroh::NodeId;
+use iroh_gossip::proto::TopicId;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::{message::MessageRef, openhab::SensorReading};
+
+/// Default location of the history database, under the XDG data dir.
+pub fn default_history_path() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("iroh-gossip-chat").join("history.db"))
+}
+
+/// A chat message we sent or received.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub topic: TopicId,
+    pub from: NodeId,
+    pub name: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    /// Lamport clock of the message, see [`Stamped`](crate::message::Stamped).
+    pub clock: u64,
+    pub text: String,
+    /// openHAB item states attached to the message.
+    pub readings: Vec<SensorReading>,
+    /// Number of peers that reacted with each emoji.
+    pub reactions: BTreeMap<String, usize>,
+    /// Whether the sender changed the text since sending it.
+    pub edited: bool,
+    /// Whether the sender retracted the message, leaving its text empty.
+    pub deleted: bool,
+    /// The message this one answers.
+    pub in_reply_to: Option<MessageRef>,
+}
+
+/// Schema changes applied in order to databases created by older versions,
+/// tracked with SQLite's `user_version`.
+const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE messages ADD COLUMN clock INTEGER NOT NULL DEFAULT 0",
+    "ALTER TABLE messages ADD COLUMN readings TEXT NOT NULL DEFAULT '[]'",
+    "CREATE TABLE names (node_id TEXT PRIMARY KEY, name TEXT NOT NULL)",
+    "CREATE TABLE reactions (
+        target_sender TEXT NOT NULL,
+        target_clock INTEGER NOT NULL,
+        sender TEXT NOT NULL,
+        emoji TEXT NOT NULL,
+        PRIMARY KEY (target_sender, target_clock, sender, emoji)
+    )",
+    "ALTER TABLE messages ADD COLUMN edited INTEGER NOT NULL DEFAULT 0;
+     ALTER TABLE messages ADD COLUMN deleted INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE messages ADD COLUMN reply_sender TEXT;
+     ALTER TABLE messages ADD COLUMN reply_clock INTEGER;",
+    // Full-text index of the message texts, kept up to date by triggers.
+    "CREATE VIRTUAL TABLE messages_fts USING fts5(text, content = 'messages', content_rowid = 'id');
+     INSERT INTO messages_fts (messages_fts) VALUES ('rebuild');
+     CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages BEGIN
+         INSERT INTO messages_fts (rowid, text) VALUES (new.id, new.text);
+     END;
+     CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+         INSERT INTO messages_fts (messages_fts, rowid, text) VALUES ('delete', old.id, old.text);
+     END;
+     CREATE TRIGGER messages_fts_update AFTER UPDATE OF text ON messages BEGIN
+         INSERT INTO messages_fts (messages_fts, rowid, text) VALUES ('delete', old.id, old.text);
+         INSERT INTO messages_fts (rowid, text) VALUES (new.id, new.text);
+     END;",
+];
+
+/// Handle to the history database.
+///
+/// Cloning is cheap and all clones share the same connection.
+#[derive(Debug, Clone)]
+pub struct History {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl History {
+    /// Opens the database at `path`, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("failed to open history {}", path.display()))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY,
+                topic TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                name TEXT,
+                timestamp TEXT NOT NULL,
+                text TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS messages_by_topic ON messages (topic, timestamp);",
+        )?;
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            conn.execute_batch(migration)?;
+            conn.pragma_update(None, "user_version", i + 1)?;
+        }
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    pub fn insert(&self, entry: &HistoryEntry) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO messages (
+                topic, sender, name, timestamp, clock, text, readings, reply_sender, reply_clock
+             ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                entry.topic.to_string(),
+                entry.from.to_string(),
+                entry.name,
+                entry.timestamp,
+                entry.clock,
+                entry.text,
+                serde_json::to_string(&entry.readings)?,
+                entry.in_reply_to.map(|target| target.from.to_string()),
+                entry.in_reply_to.map(|target| target.clock),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The text of a message, unless it was deleted or is unknown.
+    pub fn text(&self, target: &MessageRef) -> Result<Option<String>> {
+        let text = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT text FROM messages WHERE sender = ?1 AND clock = ?2 AND NOT deleted",
+                params![target.from.to_string(), target.clock],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(text)
+    }
+
+    /// When a message was sent, for ours, or received.
+    pub fn timestamp(&self, target: &MessageRef) -> Result<Option<DateTime<Utc>>> {
+        let timestamp = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT timestamp FROM messages WHERE sender = ?1 AND clock = ?2",
+                params![target.from.to_string(), target.clock],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(timestamp)
+    }
+
+    /// Replaces the text of a message, unless it was deleted.
+    pub fn edit(&self, target: &MessageRef, text: &str) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE messages SET text = ?3, edited = 1
+             WHERE sender = ?1 AND clock = ?2 AND NOT deleted",
+            params![target.from.to_string(), target.clock, text],
+        )?;
+        Ok(())
+    }
+
+    /// Leaves a tombstone in place of a message, dropping its text and
+    /// readings.
+    pub fn delete(&self, target: &MessageRef) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE messages SET text = '', readings = '[]', deleted = 1
+             WHERE sender = ?1 AND clock = ?2",
+            params![target.from.to_string(), target.clock],
+        )?;
+        Ok(())
+    }
+
+    /// Records that `from` reacted to a message with `emoji`.
+    pub fn insert_reaction(&self, target: &MessageRef, from: NodeId, emoji: &str) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO reactions (target_sender, target_clock, sender, emoji)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                target.from.to_string(),
+                target.clock,
+                from.to_string(),
+                emoji
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Number of peers that reacted to a message with each emoji.
+    fn reactions(conn: &Connection, target: &MessageRef) -> Result<BTreeMap<String, usize>> {
+        let mut stmt = conn.prepare_cached(
+            "SELECT emoji, COUNT(*) FROM reactions
+             WHERE target_sender = ?1 AND target_clock = ?2
+             GROUP BY emoji",
+        )?;
+        let rows = stmt.query_map(params![target.from.to_string(), target.clock], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+        rows.map(|row| Ok(row?)).collect()
+    }
+
+    /// Remembers the display name announced by `node_id`.
+    pub fn save_name(&self, node_id: NodeId, name: &str) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO names (node_id, name) VALUES (?1, ?2)
+             ON CONFLICT (node_id) DO UPDATE SET name = excluded.name",
+            params![node_id.to_string(), name],
+        )?;
+        Ok(())
+    }
+
+    /// Display names announced by peers in earlier sessions.
+    pub fn names(&self) -> Result<HashMap<NodeId, String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT node_id, name FROM names")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        rows.map(|row| {
+            let (node_id, name) = row?;
+            Ok((node_id.parse().context("invalid node id in names")?, name))
+        })
+        .collect()
+    }
+
+    /// The highest Lamport clock seen so far, to resume counting after a
+    /// restart.
+    pub fn max_clock(&self) -> Result<u64> {
+        let clock = self.conn.lock().unwrap().query_row(
+            "SELECT COALESCE(MAX(clock), 0) FROM messages",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(clock)
+    }
+
+    /// The latest `limit` messages in clock order, optionally only from rooms
+    /// whose topic starts with `topic_prefix`.
+    pub fn recent(&self, topic_prefix: Option<&str>, limit: usize) -> Result<Vec<HistoryEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {ENTRY_COLUMNS} FROM (
+                SELECT * FROM messages
+                WHERE topic LIKE ?1 || '%'
+                ORDER BY clock DESC, sender DESC, id DESC
+                LIMIT ?2
+            ) ORDER BY clock, sender, id"
+        ))?;
+        let rows = stmt.query_map(params![topic_prefix.unwrap_or(""), limit], EntryRow::read)?;
+        rows.map(|row| row?.into_entry(&conn)).collect()
+    }
+
+    /// All messages in clock order sent between `since` and `until`,
+    /// optionally only from rooms whose topic starts with `topic_prefix`.
+    pub fn range(
+        &self,
+        topic_prefix: Option<&str>,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<Vec<HistoryEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {ENTRY_COLUMNS} FROM messages
+             WHERE topic LIKE ?1 || '%'
+                AND (?2 IS NULL OR timestamp >= ?2)
+                AND (?3 IS NULL OR timestamp < ?3)
+             ORDER BY clock, sender, id"
+        ))?;
+        let rows = stmt.query_map(
+            params![topic_prefix.unwrap_or(""), since, until],
+            EntryRow::read,
+        )?;
+        rows.map(|row| row?.into_entry(&conn)).collect()
+    }
+
+    /// The `limit` best matches for `query` in clock order, optionally only
+    /// from rooms whose topic starts with `topic_prefix`.
+    ///
+    /// Messages match if they contain every word of the query, in any order
+    /// and ignoring case.
+    pub fn search(
+        &self,
+        query: &str,
+        topic_prefix: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<HistoryEntry>> {
+        // Quote each word so punctuation is not taken as FTS5 syntax.
+        let query = query
+            .split_whitespace()
+            .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {ENTRY_COLUMNS} FROM (
+                SELECT messages.* FROM messages_fts
+                JOIN messages ON messages.id = messages_fts.rowid
+                WHERE messages_fts MATCH ?1 AND topic LIKE ?2 || '%'
+                ORDER BY rank
+                LIMIT ?3
+            ) ORDER BY clock, sender, id"
+        ))?;
+        let rows = stmt.query_map(
+            params![query, topic_prefix.unwrap_or(""), limit],
+            EntryRow::read,
+        )?;
+        rows.map(|row| row?.into_entry(&conn)).collect()
+    }
+}
+
+/// Columns of the `messages` table read into a [`HistoryEntry`], in the
+/// order [`EntryRow::read`] expects them.
+const ENTRY_COLUMNS: &str = "topic, sender, name, timestamp, clock, text, readings, edited,
+    deleted, reply_sender, reply_clock";
+
+/// A row of [`ENTRY_COLUMNS`], before parsing ids and readings.
+struct EntryRow {
+    topic: String,
+    from: String,
+    name: Option<String>,
+    timestamp: DateTime<Utc>,
+    clock: u64,
+    text: String,
+    readings: String,
+    edited: bool,
+    deleted: bool,
+    reply_sender: Option<String>,
+    reply_clock: Option<u64>,
+}
+
+impl EntryRow {
+    fn read(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            topic: row.get(0)?,
+            from: row.get(1)?,
+            name: row.get(2)?,
+            timestamp: row.get(3)?,
+            clock: row.get(4)?,
+            text: row.get(5)?,
+            readings: row.get(6)?,
+            edited: row.get(7)?,
+            deleted: row.get(8)?,
+            reply_sender: row.get(9)?,
+            reply_clock: row.get(10)?,
+        })
+    }
+
+    fn into_entry(self, conn: &Connection) -> Result<HistoryEntry> {
+        let from = self.from.parse().context("invalid sender in history")?;
+        let in_reply_to = match (self.reply_sender, self.reply_clock) {
+            (Some(sender), Some(clock)) => Some(MessageRef {
+                from: sender.parse().context("invalid reply sender in history")?,
+                clock,
+            }),
+            _ => None,
+        };
+        let clock = self.clock;
+        Ok(HistoryEntry {
+            topic: self.topic.parse().context("invalid topic in history")?,
+            from,
+            name: self.name,
+            timestamp: self.timestamp,
+            clock,
+            text: self.text,
+            readings: serde_json::from_str(&self.readings)
+                .context("invalid readings in history")?,
+            reactions: History::reactions(conn, &MessageRef { from, clock })?,
+            edited: self.edited,
+            deleted: self.deleted,
+            in_reply_to,
+        })
+    }
+}
@@ -0,0 +1,54 @@
+//! The smart home server of a node, behind the [`HomeProvider`] trait so
+//! the chat does not depend on which system controls the devices.
+//!
+//! [`OpenHab`](crate::openhab::OpenHab) and
+//! [`HomeAssistant`](crate::homeassistant::HomeAssistant) are built in;
+//! other backends, such as an MQTT broker or a simulation for tests, can
+//! be passed to [`NodeBuilder::home`](crate::NodeBuilder::home).
+
+use anyhow::Result;
+use futures_lite::future::Boxed as BoxedFuture;
+use tokio::sync::watch;
+
+use crate::openhab::{ItemStates, OpenHabEvent};
+
+/// Called with every event of the server.
+pub type EventCallback = Box<dyn FnMut(OpenHabEvent) + Send>;
+
+/// A smart home server whose items the node shares with its peers.
+///
+/// Items are named the way the server names them, and their states and
+/// commands take openHAB's form, such as `21.5 °C`, `ON` or `NULL`, which
+/// implementations for other systems convert to.
+pub trait HomeProvider: Send + Sync + 'static {
+    /// Name of the system, shown to the user.
+    fn name(&self) -> &'static str;
+
+    /// Where the server is, shown to the user.
+    fn url(&self) -> &str;
+
+    /// Items whose states are attached to chat messages and shared with
+    /// peers.
+    fn items(&self) -> &[String];
+
+    /// Whether peers may send commands to the items.
+    fn accepts_commands(&self) -> bool;
+
+    /// Checks that the server can be reached.
+    fn check(&self) -> BoxedFuture<Result<()>>;
+
+    fn get_state(&self, item: &str) -> BoxedFuture<Result<String>>;
+
+    fn send_command(&self, item: &str, command: &str) -> BoxedFuture<Result<()>>;
+
+    /// Publishes the current states of the [`items`](Self::items) to
+    /// `states`, then keeps them up to date and passes every event of the
+    /// server to `on_event`.
+    ///
+    /// Only returns when the connection fails.
+    fn subscribe_events(
+        &self,
+        states: watch::Sender<ItemStates>,
+        on_event: EventCallback,
+    ) -> BoxedFuture<Result<()>>;
+}
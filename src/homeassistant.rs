@@ -0,0 +1,337 @@
+//! Home Assistant as the smart home server of a node, in place of openHAB.
+//!
+//! Entities play the part of openHAB items: their states, with the unit
+//! appended like openHAB does, are attached to chat messages and shared
+//! with peers, and `/set` commands are turned into service calls. States
+//! are read over the REST API and followed over the WebSocket API, both
+//! authenticated with a long-lived access token.
+
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use futures_lite::future::Boxed as BoxedFuture;
+use futures_util::{SinkExt, StreamExt};
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tracing::{debug, instrument};
+
+use crate::{
+    config::HomeAssistantConfig,
+    home::{EventCallback, HomeProvider},
+    openhab::{path_segment, ItemStates, OpenHabEvent},
+};
+
+/// How long a REST request may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often to ping the WebSocket, so a dead connection is noticed.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The state of an entity, as returned by the REST API and in
+/// `state_changed` events.
+#[derive(Debug, Deserialize)]
+struct EntityState {
+    entity_id: String,
+    state: String,
+    #[serde(default)]
+    attributes: Attributes,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Attributes {
+    unit_of_measurement: Option<String>,
+}
+
+impl EntityState {
+    /// The state in openHAB's form, so peers handle both alike: `21.5 °C`
+    /// with the unit, and `NULL` and `UNDEF` for unknown and unavailable
+    /// entities.
+    fn item_state(&self) -> String {
+        match (self.state.as_str(), &self.attributes.unit_of_measurement) {
+            ("unknown", _) => "NULL".to_string(),
+            ("unavailable", _) => "UNDEF".to_string(),
+            (state, Some(unit)) if !unit.is_empty() => format!("{state} {unit}"),
+            (state, _) => state.to_string(),
+        }
+    }
+}
+
+/// A Home Assistant server as the [`HomeProvider`] of a node.
+#[derive(Debug, Clone)]
+pub struct HomeAssistant {
+    pub config: HomeAssistantConfig,
+    /// Shared by all requests to the server.
+    client: Client,
+}
+
+impl HomeAssistant {
+    pub fn new(config: HomeAssistantConfig) -> Self {
+        Self {
+            config,
+            client: Client::new(),
+        }
+    }
+}
+
+impl HomeProvider for HomeAssistant {
+    fn name(&self) -> &'static str {
+        "Home Assistant"
+    }
+
+    fn url(&self) -> &str {
+        &self.config.url
+    }
+
+    fn items(&self) -> &[String] {
+        &self.config.entities
+    }
+
+    fn accepts_commands(&self) -> bool {
+        self.config.accept_commands
+    }
+
+    fn check(&self) -> BoxedFuture<Result<()>> {
+        let server = self.clone();
+        Box::pin(async move { check(&server).await })
+    }
+
+    fn get_state(&self, entity: &str) -> BoxedFuture<Result<String>> {
+        let (server, entity) = (self.clone(), entity.to_string());
+        Box::pin(async move { get_item_state(&server, &entity).await })
+    }
+
+    fn send_command(&self, entity: &str, command: &str) -> BoxedFuture<Result<()>> {
+        let (server, entity, command) = (self.clone(), entity.to_string(), command.to_string());
+        Box::pin(async move { send_command(&server, &entity, &command).await })
+    }
+
+    fn subscribe_events(
+        &self,
+        states: watch::Sender<ItemStates>,
+        on_event: EventCallback,
+    ) -> BoxedFuture<Result<()>> {
+        let server = self.clone();
+        Box::pin(async move { follow_item_states(&server, &states, on_event).await })
+    }
+}
+
+/// Fetches the state of an entity.
+#[instrument(skip(server), fields(url = %server.config.url))]
+pub async fn get_item_state(server: &HomeAssistant, entity: &str) -> Result<String> {
+    let path = format!("states/{}", path_segment(entity));
+    let response = send(request(server, Method::GET, &path)?).await?;
+    let state: EntityState = response.json().await.context("unexpected state JSON")?;
+    Ok(state.item_state())
+}
+
+/// Fetches the state of every configured entity concurrently.
+pub async fn get_item_states(server: &HomeAssistant) -> Result<ItemStates> {
+    let entities = &server.config.entities;
+    let states = futures_util::future::try_join_all(
+        entities.iter().map(|entity| get_item_state(server, entity)),
+    )
+    .await?;
+    Ok(entities.iter().cloned().zip(states).collect())
+}
+
+/// Sends an openHAB style `command`, such as `ON`, `DOWN` or `21`, to
+/// `entity` as the matching service call.
+#[instrument(skip(server), fields(url = %server.config.url))]
+pub async fn send_command(server: &HomeAssistant, entity: &str, command: &str) -> Result<()> {
+    let (domain, service, data) = service_call(entity, command)?;
+    let request = request(
+        server,
+        Method::POST,
+        &format!("services/{}/{service}", path_segment(&domain)),
+    )?;
+    send(request.json(&data)).await?;
+    Ok(())
+}
+
+/// Checks that the server answers and accepts the token.
+#[instrument(skip_all, fields(url = %server.config.url))]
+pub async fn check(server: &HomeAssistant) -> Result<()> {
+    send(request(server, Method::GET, "")?).await?;
+    Ok(())
+}
+
+/// Publishes the current state of the configured entities to `states`,
+/// then follows their changes over the WebSocket API, publishing each one
+/// and passing it to `on_event`.
+///
+/// Only returns when the connection fails or the server closes it.
+#[instrument(skip_all, fields(url = %server.config.url))]
+pub async fn follow_item_states(
+    server: &HomeAssistant,
+    states: &watch::Sender<ItemStates>,
+    mut on_event: impl FnMut(OpenHabEvent),
+) -> Result<()> {
+    let config = &server.config;
+    let mut url = url::Url::parse(&config.url).context("invalid Home Assistant URL")?;
+    let scheme = match url.scheme() {
+        "https" => "wss",
+        _ => "ws",
+    };
+    url.set_scheme(scheme)
+        .map_err(|()| anyhow::anyhow!("invalid Home Assistant URL"))?;
+    url.set_path("/api/websocket");
+    let (mut socket, _) = tokio::time::timeout(
+        REQUEST_TIMEOUT,
+        tokio_tungstenite::connect_async(url.as_str()),
+    )
+    .await
+    .context("connecting timed out")?
+    .context("failed to connect to the Home Assistant WebSocket")?;
+
+    let greeting = receive(&mut socket).await?;
+    ensure!(
+        greeting["type"] == "auth_required",
+        "unexpected greeting {greeting}"
+    );
+    let token = config.token.as_deref().unwrap_or_default();
+    let auth = json!({ "type": "auth", "access_token": token });
+    socket.send(WsMessage::Text(auth.to_string())).await?;
+    let answer = receive(&mut socket).await?;
+    if answer["type"] != "auth_ok" {
+        bail!(
+            "Home Assistant rejected the access token: {}",
+            answer["message"]
+        );
+    }
+    let subscribe = json!({ "id": 1, "type": "subscribe_events", "event_type": "state_changed" });
+    socket.send(WsMessage::Text(subscribe.to_string())).await?;
+
+    // Subscribe first, so no change is missed between the two.
+    states.send_replace(get_item_states(server).await?);
+
+    let mut id = 1;
+    let mut interval = tokio::time::interval(PING_INTERVAL);
+    interval.tick().await;
+    loop {
+        let message = tokio::select! {
+            _ = interval.tick() => {
+                id += 1;
+                let ping = json!({ "id": id, "type": "ping" });
+                socket.send(WsMessage::Text(ping.to_string())).await?;
+                continue;
+            }
+            message = receive(&mut socket) => message?,
+        };
+        match message["type"].as_str() {
+            Some("result") if message["success"] == false => {
+                bail!(
+                    "Home Assistant refused the subscription: {}",
+                    message["error"]
+                )
+            }
+            Some("event") => {}
+            _ => continue,
+        }
+        let data = &message["event"]["data"];
+        let Ok(new) = EntityState::deserialize(&data["new_state"]) else {
+            // Removed entities have no new state.
+            continue;
+        };
+        if !config.entities.contains(&new.entity_id) {
+            continue;
+        }
+        let old = EntityState::deserialize(&data["old_state"])
+            .map(|old| old.item_state())
+            .unwrap_or_default();
+        let state = new.item_state();
+        // Changes of the attributes only.
+        if old == state {
+            continue;
+        }
+        debug!(entity = %new.entity_id, %state, "entity state changed");
+        states.send_modify(|states| {
+            states.insert(new.entity_id.clone(), state.clone());
+        });
+        on_event(OpenHabEvent::ItemStateChanged {
+            item: new.entity_id,
+            old_state: old,
+            state,
+        });
+    }
+}
+
+/// The next JSON message from the WebSocket.
+async fn receive<S>(socket: &mut S) -> Result<Value>
+where
+    S: futures_util::Stream<Item = tokio_tungstenite::tungstenite::Result<WsMessage>> + Unpin,
+{
+    loop {
+        match socket.next().await.transpose()? {
+            Some(WsMessage::Text(text)) => {
+                return serde_json::from_str(&text).context("unexpected message JSON")
+            }
+            Some(WsMessage::Close(_)) | None => bail!("Home Assistant closed the WebSocket"),
+            Some(_) => continue,
+        }
+    }
+}
+
+/// The domain, service and data of the service call carrying out an
+/// openHAB style command.
+fn service_call(entity: &str, command: &str) -> Result<(String, &'static str, Value)> {
+    let (domain, _) = entity
+        .split_once('.')
+        .with_context(|| format!("invalid entity id {entity}, expected domain.name"))?;
+    let number = command.parse::<f64>().ok();
+    let keyword = command.to_ascii_uppercase();
+    let service = match (domain, keyword.as_str(), number) {
+        ("cover", "UP" | "OPEN", _) => "open_cover",
+        ("cover", "DOWN" | "CLOSE" | "CLOSED", _) => "close_cover",
+        ("cover", "STOP", _) => "stop_cover",
+        ("lock", "ON" | "LOCK", _) => "lock",
+        ("lock", "OFF" | "UNLOCK", _) => "unlock",
+        (_, "ON", _) => "turn_on",
+        (_, "OFF", _) => "turn_off",
+        (_, "TOGGLE", _) => "toggle",
+        ("light", _, Some(brightness)) => {
+            let data = json!({ "entity_id": entity, "brightness_pct": brightness });
+            return Ok((domain.to_string(), "turn_on", data));
+        }
+        ("climate", _, Some(temperature)) => {
+            let data = json!({ "entity_id": entity, "temperature": temperature });
+            return Ok((domain.to_string(), "set_temperature", data));
+        }
+        ("input_number" | "number", _, Some(value)) => {
+            let data = json!({ "entity_id": entity, "value": value });
+            return Ok((domain.to_string(), "set_value", data));
+        }
+        ("input_select" | "select", _, _) => {
+            let data = json!({ "entity_id": entity, "option": command });
+            return Ok((domain.to_string(), "select_option", data));
+        }
+        ("input_text" | "text", _, _) => {
+            let data = json!({ "entity_id": entity, "value": command });
+            return Ok((domain.to_string(), "set_value", data));
+        }
+        _ => bail!("cannot send {command} to {entity}"),
+    };
+    Ok((domain.to_string(), service, json!({ "entity_id": entity })))
+}
+
+/// A request to `path` under the REST API.
+fn request(server: &HomeAssistant, method: Method, path: &str) -> Result<RequestBuilder> {
+    let url = format!("{}/api/{path}", server.config.url.trim_end_matches('/'));
+    let mut request = server.client.request(method, url).timeout(REQUEST_TIMEOUT);
+    if let Some(token) = &server.config.token {
+        request = request.bearer_auth(token);
+    }
+    Ok(request)
+}
+
+async fn send(request: RequestBuilder) -> Result<Response> {
+    let response = request.send().await?;
+    match response.status() {
+        StatusCode::UNAUTHORIZED => {
+            bail!("Home Assistant rejected the access token (401 Unauthorized)")
+        }
+        StatusCode::NOT_FOUND => bail!("Home Assistant has no such entity or service (404)"),
+        _ => Ok(response.error_for_status()?),
+    }
+}
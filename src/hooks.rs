@@ -0,0 +1,99 @@
+//! Shell commands run on room events, such as switching on a light or
+//! reading a message aloud, without changing the chat itself.
+//!
+//! Each hook gets the event as a line of JSON on stdin, in the format of
+//! the HTTP API's event stream.
+
+use std::{process::Stdio, time::Duration};
+
+use anyhow::{ensure, Context, Result};
+use futures_lite::future::Boxed as BoxedFuture;
+use tokio::{io::AsyncWriteExt, process::Command};
+use tracing::{debug, instrument, warn};
+
+use crate::{
+    config::HooksConfig,
+    handler::{ChatMessage, DirectMessage, ItemChange, MessageHandler},
+    http, ChatNode, Event,
+};
+
+/// How long a hook may run before it is killed.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs the configured hooks, registered as a [`MessageHandler`].
+///
+/// Hooks run concurrently, so a slow one does not hold up the others.
+#[derive(Debug, Clone)]
+pub struct Hooks(pub HooksConfig);
+
+impl MessageHandler for Hooks {
+    fn on_message(&self, _node: ChatNode, message: ChatMessage) -> BoxedFuture<Result<()>> {
+        spawn_hook(&self.0.on_message, message.into());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn on_direct_message(
+        &self,
+        _node: ChatNode,
+        message: DirectMessage,
+    ) -> BoxedFuture<Result<()>> {
+        spawn_hook(&self.0.on_direct_message, message.into());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn on_item_change(&self, _node: ChatNode, change: ItemChange) -> BoxedFuture<Result<()>> {
+        // Only changes shared by peers, as before handlers existed.
+        if change.source.is_some() {
+            spawn_hook(&self.0.on_item_changed, change.into());
+        }
+        Box::pin(async { Ok(()) })
+    }
+}
+
+fn spawn_hook(command: &Option<String>, event: Event) {
+    let Some(command) = command.clone() else {
+        return;
+    };
+    let json = http::event_json(&event).to_string();
+    tokio::spawn(async move {
+        if let Err(err) = run_hook(&command, json).await {
+            warn!("hook {command:?} failed: {err:#}");
+        }
+    });
+}
+
+/// Runs `command` with the shell, writing `json` to its stdin.
+#[instrument(skip(json))]
+async fn run_hook(command: &str, json: String) -> Result<()> {
+    let mut child = shell(command)
+        .stdin(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("failed to start")?;
+    let mut stdin = child.stdin.take().context("no stdin")?;
+    // A hook may exit without reading its input.
+    if let Err(err) = stdin.write_all(format!("{json}\n").as_bytes()).await {
+        debug!("hook did not read the event: {err}");
+    }
+    drop(stdin);
+    let status = tokio::time::timeout(HOOK_TIMEOUT, child.wait())
+        .await
+        .with_context(|| format!("timed out after {}s", HOOK_TIMEOUT.as_secs()))??;
+    ensure!(status.success(), "exited with {status}");
+    Ok(())
+}
+
+/// A command run by the platform's shell.
+#[cfg(unix)]
+pub(crate) fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+pub(crate) fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
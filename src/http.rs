@@ -0,0 +1,743 @@
+//! Local HTTP API, so other programs can drive the node.
+//!
+//! - `POST /messages` with `{"text": ..., "room": ...}` sends a chat message,
+//!   `room` is a topic prefix and may be left out when in a single room.
+//!   With `"reliable": true` it answers once most peers acknowledged the
+//!   message, listing them in `acked`.
+//! - `GET /roster` lists the peers present in our rooms.
+//! - `GET /stats` returns the messages and bytes exchanged with each peer.
+//! - `GET /history?topic=<prefix>&limit=<n>` returns recent messages.
+//! - `GET /items` returns the cached openHAB states, ours and our peers'.
+//! - `GET /metrics` returns Prometheus metrics.
+//! - `GET /ws` upgrades to a WebSocket that streams every [`Event`] as a
+//!   JSON object tagged with `type`, see [`event_json`], and accepts
+//!   messages to send in the same form as `POST /messages`.
+//! - `POST /webhook` takes requests of other services, authenticated as set
+//!   in [`InboundWebhookConfig`]: `{"text": ...}`, Grafana's `title` and
+//!   `message`, or `{"item": ..., "command": ...}` for an item command,
+//!   each with an optional `room`.
+//!
+//! On loopback addresses only `/webhook` is authenticated. Listening on any
+//! other address needs the token of [`InboundWebhookConfig`], which every
+//! request then has to carry as `Authorization: Bearer <token>` or as the
+//! `token` query parameter, e.g. for WebSockets from browsers.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::{Context, Result};
+use axum::{
+    body::Bytes,
+    extract::{
+        ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+        Query, Request, State,
+    },
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use futures_lite::StreamExt;
+use iroh::NodeId;
+use iroh_gossip::proto::TopicId;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::watch;
+use tracing::{debug, instrument};
+
+use crate::{
+    config::InboundWebhookConfig,
+    history::HistoryEntry,
+    message::MessageRef,
+    metrics,
+    openhab::{ItemStates, OpenHabEvent},
+    webhooks, ChatNode, Event,
+};
+
+/// Number of messages returned by `/history` unless asked otherwise.
+const DEFAULT_HISTORY_LIMIT: usize = 50;
+
+#[derive(Clone)]
+struct ApiState {
+    node: ChatNode,
+    item_states: watch::Receiver<ItemStates>,
+    inbound_webhook: Arc<InboundWebhookConfig>,
+}
+
+/// Serves the API on `addr` until the listener fails.
+///
+/// `item_states` provides the states of our own openHAB items.
+#[instrument(skip(node, item_states, inbound_webhook))]
+pub async fn serve(
+    addr: SocketAddr,
+    node: ChatNode,
+    item_states: watch::Receiver<ItemStates>,
+    inbound_webhook: InboundWebhookConfig,
+) -> Result<()> {
+    let state = ApiState {
+        node,
+        item_states,
+        inbound_webhook: Arc::new(inbound_webhook),
+    };
+    let mut app = Router::new()
+        .route("/messages", post(post_message))
+        .route("/roster", get(roster))
+        .route("/stats", get(stats))
+        .route("/history", get(history))
+        .route("/items", get(items))
+        .route("/metrics", get(prometheus))
+        .route("/ws", get(websocket))
+        .route("/webhook", post(webhook));
+    if !addr.ip().is_loopback() {
+        app = app.layer(middleware::from_fn_with_state(state.clone(), require_token));
+    }
+    let app = app.with_state(state);
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to listen on {addr}"))?;
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+/// An error answered with its status and a JSON `{"error": ...}` body.
+struct ApiError(StatusCode, anyhow::Error);
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = Json(json!({ "error": format!("{:#}", self.1) }));
+        (self.0, body).into_response()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PostMessage {
+    text: String,
+    room: Option<String>,
+    #[serde(default)]
+    reliable: bool,
+}
+
+async fn post_message(
+    State(state): State<ApiState>,
+    Json(request): Json<PostMessage>,
+) -> Result<Json<Value>, ApiError> {
+    let topic = find_room(&state.node, request.room.as_deref())?;
+    if request.reliable {
+        let delivery = state.node.send_reliable(topic, request.text).await?;
+        let acked: Vec<String> = delivery.acked.iter().map(|id| id.to_string()).collect();
+        return Ok(Json(json!({
+            "room": topic.to_string(),
+            "clock": delivery.clock,
+            "attempts": delivery.attempts,
+            "acked": acked,
+        })));
+    }
+    let clock = state
+        .node
+        .send_text(topic, request.text, Vec::new())
+        .await?;
+    Ok(Json(json!({ "room": topic.to_string(), "clock": clock })))
+}
+
+#[derive(Debug, Deserialize)]
+struct WebhookQuery {
+    token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WebhookRequest {
+    room: Option<String>,
+    text: Option<String>,
+    /// Grafana's alert notifications.
+    title: Option<String>,
+    message: Option<String>,
+    item: Option<String>,
+    command: Option<String>,
+}
+
+async fn webhook(
+    State(state): State<ApiState>,
+    Query(query): Query<WebhookQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let config = &state.inbound_webhook;
+    if !config.is_enabled() {
+        return Err(ApiError(
+            StatusCode::NOT_FOUND,
+            anyhow::anyhow!("the inbound webhook is not configured"),
+        ));
+    }
+    if !webhook_authorized(config, &query, &headers, &body) {
+        return Err(ApiError(
+            StatusCode::UNAUTHORIZED,
+            anyhow::anyhow!("missing or wrong webhook credentials"),
+        ));
+    }
+    let request: WebhookRequest = serde_json::from_slice(&body)
+        .map_err(|err| ApiError(StatusCode::BAD_REQUEST, err.into()))?;
+    let topic = find_room(&state.node, request.room.as_deref())?;
+    if let (Some(item), Some(command)) = (request.item, request.command) {
+        debug!(%item, %command, "webhook item command");
+        state
+            .node
+            .send_command(topic, item.clone(), command.clone())
+            .await?;
+        return Ok(Json(json!({
+            "room": topic.to_string(),
+            "item": item,
+            "command": command,
+        })));
+    }
+    let text = match (request.text, request.title, request.message) {
+        (Some(text), ..) => text,
+        (None, Some(title), Some(message)) => format!("{title}\n{message}"),
+        (None, Some(text), None) | (None, None, Some(text)) => text,
+        (None, None, None) => {
+            return Err(ApiError(
+                StatusCode::BAD_REQUEST,
+                anyhow::anyhow!("expected text, title and message, or item and command"),
+            ))
+        }
+    };
+    let clock = state.node.send_text(topic, text, Vec::new()).await?;
+    Ok(Json(json!({ "room": topic.to_string(), "clock": clock })))
+}
+
+/// Rejects requests without the token, for listeners other hosts can
+/// reach. `/webhook` checks its credentials itself, as it also accepts
+/// signed bodies.
+async fn require_token(
+    State(state): State<ApiState>,
+    Query(query): Query<WebhookQuery>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Response {
+    let token = state.inbound_webhook.token.as_deref();
+    if request.uri().path() == "/webhook"
+        || token.is_some_and(|token| has_token(token, &query, &headers))
+    {
+        return next.run(request).await;
+    }
+    ApiError(
+        StatusCode::UNAUTHORIZED,
+        anyhow::anyhow!("missing or wrong token"),
+    )
+    .into_response()
+}
+
+/// Whether the request carries `token` as a bearer token or in the query.
+fn has_token(token: &str, query: &WebhookQuery, headers: &HeaderMap) -> bool {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let given = bearer.or(query.token.as_deref());
+    given.is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()))
+}
+
+/// Whether the request carries the configured token or a valid signature.
+fn webhook_authorized(
+    config: &InboundWebhookConfig,
+    query: &WebhookQuery,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> bool {
+    if config
+        .token
+        .as_deref()
+        .is_some_and(|token| has_token(token, query, headers))
+    {
+        return true;
+    }
+    if let Some(secret) = &config.secret {
+        let signature = headers
+            .get("X-Signature-256")
+            .and_then(|value| value.to_str().ok());
+        if signature.is_some_and(|signature| webhooks::verify(secret, body, signature)) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Compares secrets without revealing through timing how much matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+async fn roster(State(state): State<ApiState>) -> Json<Value> {
+    let peers: Vec<Value> = state
+        .node
+        .peers()
+        .into_iter()
+        .map(|peer| {
+            json!({
+                "node_id": peer.node_id.to_string(),
+                "name": peer.name,
+                "neighbor": peer.neighbor,
+                "last_seen_secs": peer.last_seen.map(|ago| ago.as_secs()),
+            })
+        })
+        .collect();
+    Json(Value::Array(peers))
+}
+
+async fn stats(State(state): State<ApiState>) -> Json<Value> {
+    let peers: Vec<Value> = state
+        .node
+        .traffic()
+        .into_iter()
+        .map(|(node_id, traffic)| {
+            json!({
+                "node_id": node_id.to_string(),
+                "name": state.node.name_of(&node_id),
+                "messages_sent": traffic.messages_sent,
+                "bytes_sent": traffic.bytes_sent,
+                "messages_received": traffic.messages_received,
+                "bytes_received": traffic.bytes_received,
+            })
+        })
+        .collect();
+    Json(Value::Array(peers))
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    topic: Option<String>,
+    limit: Option<usize>,
+}
+
+async fn history(
+    State(state): State<ApiState>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<Value>, ApiError> {
+    let history = state.node.history().ok_or_else(|| {
+        ApiError(
+            StatusCode::NOT_FOUND,
+            anyhow::anyhow!("history is disabled"),
+        )
+    })?;
+    let limit = query.limit.unwrap_or(DEFAULT_HISTORY_LIMIT);
+    let entries: Vec<Value> = history
+        .recent(query.topic.as_deref(), limit)?
+        .iter()
+        .map(entry_json)
+        .collect();
+    Ok(Json(Value::Array(entries)))
+}
+
+async fn items(State(state): State<ApiState>) -> Json<Value> {
+    let peers: serde_json::Map<String, Value> = state
+        .node
+        .peer_item_states()
+        .into_iter()
+        .map(|(node_id, states)| (node_id.to_string(), json!(states)))
+        .collect();
+    let local = state.item_states.borrow().clone();
+    Json(json!({ "local": local, "peers": peers }))
+}
+
+async fn prometheus() -> Result<Response, ApiError> {
+    let text = metrics::metrics().encode()?;
+    Ok(([(header::CONTENT_TYPE, metrics::CONTENT_TYPE)], text).into_response())
+}
+
+async fn websocket(State(state): State<ApiState>, upgrade: WebSocketUpgrade) -> Response {
+    upgrade.on_upgrade(move |socket| stream_events(state.node, socket))
+}
+
+/// Forwards events to the socket and sends the messages it receives, until
+/// either side goes away.
+async fn stream_events(node: ChatNode, mut socket: WebSocket) {
+    debug!("WebSocket client connected");
+    let mut events = node.events();
+    loop {
+        let reply = tokio::select! {
+            event = events.next() => match event {
+                Some(event) => event_json(&event),
+                None => break,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(WsMessage::Text(text))) => send_from_socket(&node, &text).await,
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+        };
+        if socket
+            .send(WsMessage::Text(reply.to_string()))
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+/// Sends a chat message requested over the WebSocket, answering with the
+/// outcome.
+async fn send_from_socket(node: &ChatNode, text: &str) -> Value {
+    let result = async {
+        let request: PostMessage = serde_json::from_str(text).context("invalid message JSON")?;
+        let topic = find_room(node, request.room.as_deref()).map_err(|err| err.1)?;
+        let clock = node.send_text(topic, request.text, Vec::new()).await?;
+        anyhow::Ok((topic, clock))
+    }
+    .await;
+    match result {
+        Ok((topic, clock)) => json!({ "type": "sent", "room": topic.to_string(), "clock": clock }),
+        Err(err) => json!({ "type": "error", "error": format!("{err:#}") }),
+    }
+}
+
+/// The JSON form of an event, as streamed on `/ws` and printed by
+/// `--output json`.
+pub fn event_json(event: &Event) -> Value {
+    match event {
+        Event::Message {
+            topic,
+            from,
+            name,
+            clock,
+            text,
+            readings,
+            in_reply_to,
+        } => json!({
+            "type": "message",
+            "room": topic.to_string(),
+            "from": from.to_string(),
+            "name": name,
+            "clock": clock,
+            "text": text,
+            "readings": readings,
+            "in_reply_to": in_reply_to.as_ref().map(message_ref_json),
+        }),
+        Event::NameChanged { topic, from, name } => json!({
+            "type": "name_changed",
+            "room": topic.to_string(),
+            "from": from.to_string(),
+            "name": name,
+        }),
+        Event::Seen {
+            topic,
+            from,
+            name,
+            clock,
+        } => json!({
+            "type": "seen",
+            "room": topic.to_string(),
+            "from": from.to_string(),
+            "name": name,
+            "clock": clock,
+        }),
+        Event::Reaction {
+            topic,
+            from,
+            name,
+            target,
+            emoji,
+        } => json!({
+            "type": "reaction",
+            "room": topic.to_string(),
+            "from": from.to_string(),
+            "name": name,
+            "target": message_ref_json(target),
+            "emoji": emoji,
+        }),
+        Event::Edited {
+            topic,
+            from,
+            name,
+            target,
+            text,
+        } => json!({
+            "type": "edited",
+            "room": topic.to_string(),
+            "from": from.to_string(),
+            "name": name,
+            "clock": target.clock,
+            "text": text,
+        }),
+        Event::Deleted {
+            topic,
+            from,
+            name,
+            target,
+        } => json!({
+            "type": "deleted",
+            "room": topic.to_string(),
+            "from": from.to_string(),
+            "name": name,
+            "clock": target.clock,
+        }),
+        Event::Typing { topic, from, name } => presence_json("typing", topic, from, name),
+        Event::PeerJoined { topic, from, name } => presence_json("joined", topic, from, name),
+        Event::PeerLeft { topic, from, name } => presence_json("left", topic, from, name),
+        Event::PeerOffline { topic, from, name } => presence_json("offline", topic, from, name),
+        Event::ItemChanged {
+            topic,
+            from,
+            name,
+            item,
+            old,
+            new,
+            ts,
+        } => json!({
+            "type": "item_changed",
+            "room": topic.to_string(),
+            "from": from.to_string(),
+            "name": name,
+            "item": item,
+            "old": old,
+            "new": new,
+            "timestamp": ts,
+        }),
+        Event::ThingStatusChanged {
+            topic,
+            from,
+            name,
+            thing,
+            label,
+            status,
+            detail,
+            ts,
+        } => json!({
+            "type": "thing_status",
+            "room": topic.to_string(),
+            "from": from.to_string(),
+            "name": name,
+            "thing": thing,
+            "label": label,
+            "status": status,
+            "detail": detail,
+            "timestamp": ts,
+        }),
+        Event::ItemCommand {
+            topic,
+            from,
+            name,
+            item,
+            value,
+            result,
+        } => json!({
+            "type": "item_command",
+            "room": topic.to_string(),
+            "from": from.to_string(),
+            "name": name,
+            "item": item,
+            "value": value,
+            "forwarded": result.is_some(),
+            "error": result.as_ref().and_then(|result| result.clone().err()),
+        }),
+        Event::RuleFired {
+            topic,
+            from,
+            name,
+            rule,
+            item,
+            command,
+            result,
+        } => json!({
+            "type": "rule_fired",
+            "room": topic.to_string(),
+            "from": from.to_string(),
+            "name": name,
+            "rule": rule,
+            "item": item,
+            "command": command,
+            "error": result.as_ref().err(),
+        }),
+        Event::ImageShared {
+            topic,
+            from,
+            name,
+            image,
+        } => json!({
+            "type": "image_shared",
+            "room": topic.to_string(),
+            "from": from.to_string(),
+            "name": name,
+            "mime": image.mime,
+            "width": image.width,
+            "height": image.height,
+            "size": image.file.size,
+            "hash": image.file.hash().to_string(),
+        }),
+        Event::VoiceNote {
+            topic,
+            from,
+            name,
+            note,
+        } => json!({
+            "type": "voice_note",
+            "room": topic.to_string(),
+            "from": from.to_string(),
+            "name": name,
+            "secs": note.secs,
+            "size": note.file.size,
+            "hash": note.file.hash().to_string(),
+        }),
+        Event::FileOffered {
+            topic,
+            from,
+            name,
+            offer,
+        } => json!({
+            "type": "file_offered",
+            "room": topic.to_string(),
+            "from": from.to_string(),
+            "name": name,
+            "file": offer.name,
+            "size": offer.size,
+            "hash": offer.hash().to_string(),
+        }),
+        Event::Backfilled {
+            topic,
+            from,
+            entries,
+        } => json!({
+            "type": "backfilled",
+            "room": topic.to_string(),
+            "from": from.to_string(),
+            "entries": entries.iter().map(entry_json).collect::<Vec<_>>(),
+        }),
+        Event::Banned {
+            topic,
+            from,
+            name,
+            node_id,
+        } => json!({
+            "type": "banned",
+            "room": topic.to_string(),
+            "from": from.to_string(),
+            "name": name,
+            "node_id": node_id.to_string(),
+        }),
+        Event::ProtocolMismatch {
+            topic,
+            from,
+            name,
+            protocol,
+            agent,
+        } => json!({
+            "type": "protocol_mismatch",
+            "room": topic.to_string(),
+            "from": from.to_string(),
+            "name": name,
+            "protocol": protocol,
+            "agent": agent,
+        }),
+        Event::InviteReused { topic, from, name } => json!({
+            "type": "invite_reused",
+            "room": topic.to_string(),
+            "from": from.to_string(),
+            "name": name,
+        }),
+        Event::DirectMessage { from, name, text } => json!({
+            "type": "direct_message",
+            "from": from.to_string(),
+            "name": name,
+            "text": text,
+        }),
+        Event::Rejoining { topic, delay } => json!({
+            "type": "rejoining",
+            "room": topic.to_string(),
+            "delay_secs": delay.as_secs(),
+        }),
+        Event::NeighborUp { topic, node_id } => json!({
+            "type": "neighbor_up",
+            "room": topic.to_string(),
+            "node_id": node_id.to_string(),
+        }),
+        Event::NeighborDown { topic, node_id } => json!({
+            "type": "neighbor_down",
+            "room": topic.to_string(),
+            "node_id": node_id.to_string(),
+        }),
+        Event::OpenHab(OpenHabEvent::ItemStateChanged {
+            item,
+            old_state,
+            state,
+        }) => json!({
+            "type": "openhab_state_changed",
+            "item": item,
+            "old": old_state,
+            "new": state,
+        }),
+        Event::OpenHab(OpenHabEvent::ItemCommand { item, command }) => json!({
+            "type": "openhab_command",
+            "item": item,
+            "command": command,
+        }),
+        Event::OpenHab(OpenHabEvent::Other { kind, topic }) => json!({
+            "type": "openhab_event",
+            "kind": kind,
+            "topic": topic,
+        }),
+        Event::Dropped {
+            topic,
+            delivered_from,
+            reason,
+        } => json!({
+            "type": "dropped",
+            "room": topic.to_string(),
+            "delivered_from": delivered_from.to_string(),
+            "reason": reason,
+        }),
+    }
+}
+
+pub fn entry_json(entry: &HistoryEntry) -> Value {
+    json!({
+        "room": entry.topic.to_string(),
+        "from": entry.from.to_string(),
+        "name": entry.name,
+        "timestamp": entry.timestamp,
+        "clock": entry.clock,
+        "text": entry.text,
+        "readings": entry.readings,
+        "reactions": entry.reactions,
+        "edited": entry.edited,
+        "deleted": entry.deleted,
+        "in_reply_to": entry.in_reply_to.as_ref().map(message_ref_json),
+    })
+}
+
+/// A message reference as `{"from": ..., "clock": ...}`.
+pub(crate) fn message_ref_json(target: &MessageRef) -> Value {
+    json!({ "from": target.from.to_string(), "clock": target.clock })
+}
+
+fn presence_json(kind: &str, topic: &TopicId, from: &NodeId, name: &Option<String>) -> Value {
+    json!({
+        "type": kind,
+        "room": topic.to_string(),
+        "from": from.to_string(),
+        "name": name,
+    })
+}
+
+/// The room whose topic starts with `prefix`, or our only room.
+fn find_room(node: &ChatNode, prefix: Option<&str>) -> Result<TopicId, ApiError> {
+    let bad_request =
+        |message: &str| ApiError(StatusCode::BAD_REQUEST, anyhow::anyhow!("{message}"));
+    let matches: Vec<TopicId> = node
+        .rooms()
+        .into_iter()
+        .filter(|topic| topic.to_string().starts_with(prefix.unwrap_or("")))
+        .collect();
+    match matches[..] {
+        [topic] => Ok(topic),
+        [] => Err(bad_request("no room matches")),
+        _ => Err(bad_request(
+            "several rooms match, pass a longer room prefix",
+        )),
+    }
+}
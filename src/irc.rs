@@ -0,0 +1,220 @@
+//! Bridges a room with an IRC channel through a single IRC connection.
+//!
+//! Chat messages from peers are posted as `<name> text`, and messages in
+//! the channel are sent to the room the same way. Name announcements of
+//! peers and nick changes in the channel are relayed as `* old is now known
+//! as new`.
+
+use std::pin::Pin;
+
+use anyhow::{bail, Context, Result};
+use futures_lite::StreamExt;
+use iroh_gossip::proto::TopicId;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::mpsc,
+};
+use tracing::{debug, info, instrument};
+
+use crate::{config::IrcConfig, ChatNode, Event};
+
+/// Longest text sent in one `PRIVMSG`, leaving room for the prefix the
+/// server adds within the 512 byte line limit.
+const MAX_TEXT: usize = 400;
+
+trait Stream: AsyncRead + AsyncWrite + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Send> Stream for T {}
+
+/// A line received from the server.
+#[derive(Debug, PartialEq, Eq)]
+struct Line<'a> {
+    /// Nick of the sender, unset for messages from the server.
+    nick: Option<&'a str>,
+    command: &'a str,
+    params: Vec<&'a str>,
+}
+
+impl<'a> Line<'a> {
+    fn parse(line: &'a str) -> Option<Self> {
+        let mut rest = line.trim_end_matches(['\r', '\n']);
+        let mut nick = None;
+        if let Some(prefixed) = rest.strip_prefix(':') {
+            let (prefix, after) = prefixed.split_once(' ')?;
+            nick = prefix.split('!').next();
+            rest = after;
+        }
+        let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        let mut params = Vec::new();
+        while !rest.is_empty() {
+            if let Some(trailing) = rest.strip_prefix(':') {
+                params.push(trailing);
+                break;
+            }
+            let (param, after) = rest.split_once(' ').unwrap_or((rest, ""));
+            params.push(param);
+            rest = after.trim_start_matches(' ');
+        }
+        Some(Self {
+            nick,
+            command,
+            params,
+        })
+    }
+}
+
+/// Connects to the IRC server and bridges its channel with `topic`.
+///
+/// Only returns when the connection fails.
+#[instrument(skip_all, fields(server = ?config.server, channel = ?config.channel))]
+pub async fn run_bridge(config: &IrcConfig, node: ChatNode, topic: TopicId) -> Result<()> {
+    let server = config.server.clone().context("no IRC server configured")?;
+    let channel = config
+        .channel
+        .clone()
+        .context("no IRC channel configured")?;
+    let tcp = TcpStream::connect((server.as_str(), config.port))
+        .await
+        .with_context(|| format!("failed to connect to {server}:{}", config.port))?;
+    let stream: Pin<Box<dyn Stream>> = match config.tls {
+        true => {
+            let connector = tokio_native_tls::TlsConnector::from(native_tls::TlsConnector::new()?);
+            Box::pin(connector.connect(&server, tcp).await?)
+        }
+        false => Box::pin(tcp),
+    };
+    let (reader, mut writer) = tokio::io::split(stream);
+    let (line_tx, mut line_rx) = mpsc::unbounded_channel::<String>();
+    let sender = tokio::spawn(async move {
+        while let Some(line) = line_rx.recv().await {
+            debug!(%line, "IRC send");
+            writer.write_all(format!("{line}\r\n").as_bytes()).await?;
+        }
+        anyhow::Ok(())
+    });
+
+    if let Some(password) = &config.password {
+        line_tx.send(format!("PASS {password}"))?;
+    }
+    let mut nick = config.nick.clone();
+    line_tx.send(format!("NICK {nick}"))?;
+    line_tx.send(format!("USER {nick} 0 * :iroh-gossip-chat bridge"))?;
+
+    let mut forwarder = None;
+    let mut lines = BufReader::new(reader).lines();
+    let result = async {
+        while let Some(text) = lines.next_line().await? {
+            let Some(line) = Line::parse(&text) else {
+                continue;
+            };
+            match (line.command, line.params.as_slice()) {
+                ("PING", [token, ..]) => line_tx.send(format!("PONG :{token}"))?,
+                // Welcome, registration is done.
+                ("001", _) => {
+                    info!(%nick, "connected to IRC server");
+                    line_tx.send(format!("JOIN {channel}"))?;
+                    forwarder.get_or_insert_with(|| {
+                        tokio::spawn(forward_events(
+                            node.clone(),
+                            topic,
+                            channel.clone(),
+                            line_tx.clone(),
+                        ))
+                    });
+                }
+                // Nick in use.
+                ("433", _) => {
+                    nick.push('_');
+                    line_tx.send(format!("NICK {nick}"))?;
+                }
+                ("ERROR", params) => bail!("IRC server closed the connection: {params:?}"),
+                ("PRIVMSG", [target, text]) if target.eq_ignore_ascii_case(&channel) => {
+                    let Some(from) = line.nick else {
+                        continue;
+                    };
+                    let text = match text.strip_prefix("\x01ACTION ") {
+                        Some(action) => format!("* {from} {}", action.trim_end_matches('\x01')),
+                        // Other CTCP requests.
+                        None if text.starts_with('\x01') => continue,
+                        None => format!("<{from}> {text}"),
+                    };
+                    node.send_text(topic, text, Vec::new()).await?;
+                }
+                ("NICK", [new, ..]) => match line.nick {
+                    Some(old) if old == nick => nick = new.to_string(),
+                    Some(old) => {
+                        let text = format!("* {old} is now known as {new}");
+                        node.send_text(topic, text, Vec::new()).await?;
+                    }
+                    None => {}
+                },
+                _ => {}
+            }
+        }
+        bail!("IRC server closed the connection")
+    }
+    .await;
+    if let Some(forwarder) = forwarder {
+        forwarder.abort();
+    }
+    sender.abort();
+    result
+}
+
+/// Posts chat messages and name announcements of peers in `topic` to
+/// `channel`.
+async fn forward_events(
+    node: ChatNode,
+    topic: TopicId,
+    channel: String,
+    line_tx: mpsc::UnboundedSender<String>,
+) -> Result<()> {
+    let mut events = node.events();
+    while let Some(event) = events.next().await {
+        let text = match event {
+            Event::Message {
+                topic: room,
+                from,
+                name,
+                text,
+                ..
+            } if room == topic => {
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                text.lines()
+                    .flat_map(|line| split(line, MAX_TEXT))
+                    .map(|line| format!("<{name}> {line}"))
+                    .collect()
+            }
+            Event::NameChanged {
+                topic: room,
+                from,
+                name,
+            } if room == topic => vec![format!("* {} is now known as {name}", from.fmt_short())],
+            _ => continue,
+        };
+        for text in text {
+            line_tx.send(format!("PRIVMSG {channel} :{text}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Splits `text` into pieces of at most `max` bytes, on char boundaries.
+fn split(text: &str, max: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while rest.len() > max {
+        let mut end = max;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (piece, after) = rest.split_at(end);
+        pieces.push(piece);
+        rest = after;
+    }
+    if !rest.is_empty() {
+        pieces.push(rest);
+    }
+    pieces
+}
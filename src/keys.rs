@@ -0,0 +1,206 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use iroh::SecretKey;
+use tracing::{info, warn};
+
+use crate::{crypto, secrets};
+
+/// Starts the contents of secret key files encrypted with a passphrase,
+/// followed by the sealed key in base64.
+const ENCRYPTED_PREFIX: &str = "encrypted:";
+
+/// How the secret key file is protected on disk.
+pub struct KeyProtection<F> {
+    /// Encrypt new key files, and existing plaintext ones in place.
+    pub encrypt: bool,
+    /// Asks for the passphrase, called only when one is needed.
+    pub passphrase: F,
+}
+
+impl KeyProtection<fn() -> Result<String>> {
+    /// Plaintext key files, failing on encrypted ones.
+    pub fn none() -> Self {
+        Self {
+            encrypt: false,
+            passphrase: || anyhow::bail!("no passphrase given"),
+        }
+    }
+}
+
+/// Default location of the node secret key, under the XDG data dir.
+pub fn default_secret_key_path() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("iroh-gossip-chat").join("secret.key"))
+}
+
+/// Loads the secret key stored at `path`, creating and saving a new one if
+/// the file does not exist yet, so the node keeps the same NodeId.
+///
+/// Encrypted files are decrypted with the passphrase of `protection`,
+/// whether or not it asks for encryption.
+pub fn load_or_create_secret_key<F>(path: &Path, protection: KeyProtection<F>) -> Result<SecretKey>
+where
+    F: FnOnce() -> Result<String>,
+{
+    if path.exists() {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read secret key {}", path.display()))?;
+        if let Some(sealed) = text.trim().strip_prefix(ENCRYPTED_PREFIX) {
+            let sealed = data_encoding::BASE64
+                .decode(sealed.as_bytes())
+                .with_context(|| format!("corrupt encrypted secret key {}", path.display()))?;
+            let text = crypto::open_with_passphrase(&(protection.passphrase)()?, &sealed)
+                .with_context(|| format!("failed to decrypt secret key {}", path.display()))?;
+            return String::from_utf8_lossy(&text)
+                .parse()
+                .with_context(|| format!("invalid secret key in {}", path.display()));
+        }
+        let key: SecretKey = text
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid secret key in {}", path.display()))?;
+        if protection.encrypt {
+            let contents = encrypted_contents(&key, &(protection.passphrase)()?)?;
+            // Replace the file only once the encrypted one is complete.
+            let tmp = path.with_extension("tmp");
+            let _ = std::fs::remove_file(&tmp);
+            write_private(&tmp, contents.as_bytes())
+                .and_then(|()| std::fs::rename(&tmp, path))
+                .with_context(|| format!("failed to encrypt secret key {}", path.display()))?;
+            info!("encrypted the secret key {}", path.display());
+        }
+        return Ok(key);
+    }
+
+    let key = SecretKey::generate(rand::rngs::OsRng);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    let contents = match protection.encrypt {
+        true => encrypted_contents(&key, &(protection.passphrase)()?)?,
+        false => key.to_string(),
+    };
+    write_private(path, contents.as_bytes())
+        .with_context(|| format!("failed to write secret key {}", path.display()))?;
+    Ok(key)
+}
+
+/// The contents of a secret key file encrypted with `passphrase`.
+fn encrypted_contents(key: &SecretKey, passphrase: &str) -> Result<String> {
+    let sealed = crypto::seal_with_passphrase(passphrase, key.to_string().as_bytes())?;
+    Ok(format!(
+        "{ENCRYPTED_PREFIX}{}\n",
+        data_encoding::BASE64.encode(&sealed)
+    ))
+}
+
+/// Like [`load_or_create_secret_key`], but keeps the key in the platform
+/// keyring, in an entry named after `path`.
+///
+/// A key already stored at `path` is imported into the keyring, and the
+/// file is used as before when the keyring is unavailable.
+pub async fn load_or_create_secret_key_in_keyring<F>(
+    path: &Path,
+    protection: KeyProtection<F>,
+) -> Result<SecretKey>
+where
+    F: FnOnce() -> Result<String>,
+{
+    let entry = secrets::secret_key_entry(path);
+    match secrets::get(&entry).await {
+        Ok(Some(text)) => {
+            return text
+                .trim()
+                .parse()
+                .context("invalid secret key in the keyring")
+        }
+        Ok(None) => {}
+        Err(err) => {
+            warn!("using the secret key file {}: {err:#}", path.display());
+            return load_or_create_secret_key(path, protection);
+        }
+    }
+    if path.exists() {
+        let key = load_or_create_secret_key(path, protection)?;
+        match secrets::set(&entry, &key.to_string()).await {
+            Ok(()) => info!(
+                "imported the secret key into the keyring, {} can be deleted",
+                path.display()
+            ),
+            Err(err) => warn!("using the secret key file {}: {err:#}", path.display()),
+        }
+        return Ok(key);
+    }
+    let key = SecretKey::generate(rand::rngs::OsRng);
+    if let Err(err) = secrets::set(&entry, &key.to_string()).await {
+        warn!("saving the secret key to {}: {err:#}", path.display());
+        return load_or_create_secret_key(path, protection);
+    }
+    Ok(key)
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_key_path() -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("iroh-gossip-chat-test-{}", rand::random::<u64>()))
+            .join("secret.key")
+    }
+
+    fn protection(passphrase: &str) -> KeyProtection<impl FnOnce() -> Result<String> + '_> {
+        KeyProtection {
+            encrypt: true,
+            passphrase: move || Ok(passphrase.to_string()),
+        }
+    }
+
+    #[test]
+    fn encrypted_key_file_round_trip() {
+        let path = temp_key_path();
+        let key = load_or_create_secret_key(&path, protection("hunter2")).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with(ENCRYPTED_PREFIX));
+        assert!(!contents.contains(&key.to_string()));
+
+        let loaded = load_or_create_secret_key(&path, protection("hunter2")).unwrap();
+        assert_eq!(loaded.public(), key.public());
+        assert!(load_or_create_secret_key(&path, protection("wrong")).is_err());
+        assert!(load_or_create_secret_key(&path, KeyProtection::none()).is_err());
+        std::fs::remove_dir_all(path.parent().unwrap()).ok();
+    }
+
+    #[test]
+    fn encrypts_plaintext_key_files_in_place() {
+        let path = temp_key_path();
+        let key = load_or_create_secret_key(&path, KeyProtection::none()).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), key.to_string());
+
+        let loaded = load_or_create_secret_key(&path, protection("hunter2")).unwrap();
+        assert_eq!(loaded.public(), key.public());
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with(ENCRYPTED_PREFIX));
+        let loaded = load_or_create_secret_key(&path, protection("hunter2")).unwrap();
+        assert_eq!(loaded.public(), key.public());
+        std::fs::remove_dir_all(path.parent().unwrap()).ok();
+    }
+}
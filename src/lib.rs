@@ -0,0 +1,43 @@
+//! Gossip chat over iroh with openHAB state sharing.
+//!
+//! [`ChatNode`] owns the iroh endpoint, the gossip protocol and the router,
+//! and exposes received messages as a stream of [`Event`]s so the chat can be
+//! embedded in other programs.
+
+pub mod backfill;
+pub mod bot;
+pub mod command;
+pub mod config;
+pub mod crypto;
+pub mod direct;
+pub mod echo;
+pub mod files;
+pub mod handler;
+pub mod history;
+pub mod home;
+pub mod homeassistant;
+pub mod hooks;
+pub mod http;
+pub mod irc;
+pub mod keys;
+pub mod matrix;
+pub mod message;
+pub mod metrics;
+pub mod mqtt;
+mod node;
+pub mod openhab;
+pub mod ping;
+pub mod plugins;
+pub mod profile;
+pub mod rules;
+pub mod scripts;
+pub mod secrets;
+pub mod telegram;
+pub mod ticket;
+pub mod voice;
+pub mod webhooks;
+
+pub use node::{
+    ChatNode, Delivery, Event, NodeBuilder, PeerInfo, PeerTraffic, DEFAULT_HEARTBEAT_INTERVAL,
+    DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_PEER_TIMEOUT,
+};
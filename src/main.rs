@@ -1,210 +1,2410 @@
-use std::{collections::HashMap, fmt, str::FromStr};
-use anyhow::Result;
-use clap::Parser;
-use futures_lite::StreamExt;
-use reqwest::Client; 
-use iroh::{
-    discovery::{dns::DnsDiscovery, local_swarm_discovery::LocalSwarmDiscovery, ConcurrentDiscovery},
-    protocol::Router, Endpoint, NodeAddr, NodeId, SecretKey,
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    io::IsTerminal,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
 };
-use iroh_gossip::{
-    net::{Event, Gossip, GossipEvent, GossipReceiver},
-    proto::TopicId,
+
+use anyhow::{bail, ensure, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::{Parser, ValueEnum};
+use futures_lite::StreamExt;
+use iroh::{NodeId, RelayUrl};
+use iroh_gossip::proto::TopicId;
+use tokio::sync::{mpsc, watch};
+use tracing::{error, warn};
+use tracing_subscriber::EnvFilter;
+use url::Url;
+
+mod doctor;
+mod export;
+mod graphics;
+mod notify;
+mod tui;
+
+use export::ExportFormat;
+use notify::Notifier;
+
+use iroh_gossip_chat::{
+    bot::Bot,
+    command::Input,
+    config::{
+        BootstrapPeer, Config, DiscoveryConfig, HomeBackend, IpMode, IrcConfig, MatrixConfig,
+        MqttConfig, RelayModeConfig, TelegramConfig, VoiceConfig,
+    },
+    crypto::{self, RoomCipher},
+    echo::{self, Echo},
+    files::FileOffer,
+    history::{self, History, HistoryEntry},
+    home::HomeProvider,
+    homeassistant::HomeAssistant,
+    hooks::Hooks,
+    http, irc,
+    keys::{self, KeyProtection},
+    matrix,
+    message::{MessageRef, PROTOCOL_VERSION},
+    mqtt,
+    openhab::{
+        self, ItemInfo, ItemStates, OpenHab, OpenHabEvent, OpenHabServers, SensorReading,
+        STATE_ERROR,
+    },
+    plugins::Plugin,
+    profile::Profile,
+    rules::Rules,
+    scripts::Scripts,
+    secrets, telegram,
+    ticket::{Ticket, Tickets},
+    voice,
+    webhooks::Webhooks,
+    ChatNode, Event, PeerInfo, DEFAULT_MAX_MESSAGE_SIZE,
 };
-use serde::{Deserialize, Serialize};
-
-// Function to retrieve OpenHAB item state
-pub async fn get_item_state() -> Result<String> {
-    let client = Client::new();
-    let url = "http://192.168.38.59:8080/rest/items/TestItem"; 
-    let response = client
-        .get(url)
-        .header("Accept", "application/json")
-        .send()
-        .await?
-        .text()
-        .await?;
 
-    Ok(response)
-}
+/// Delay before reconnecting to the openHAB event bus.
+const OPENHAB_RETRY: Duration = Duration::from_secs(10);
+
+/// Largest image fetched as soon as it is shared, larger ones are fetched
+/// with `/get`.
+const AUTO_FETCH_IMAGE_SIZE: u64 = 1024 * 1024;
+
+/// Length of a voice note recorded with `/voice` without a length.
+const DEFAULT_VOICE_SECS: u32 = 5;
+
+/// Number of matches `/search` shows.
+const SEARCH_LIMIT: usize = 20;
+
+/// Number of characters of an answered message quoted before a reply.
+const QUOTE_LEN: usize = 30;
+
+/// Delay before reconnecting a bridge, e.g. to the MQTT broker.
+const BRIDGE_RETRY: Duration = Duration::from_secs(10);
 
 #[derive(Parser, Debug)]
 struct Args {
+    /// Path to a TOML config file. `IROH_P2P_*` environment variables
+    /// override its values, and command line flags override both.
+    #[clap(short, long)]
+    config: Option<PathBuf>,
+
+    /// Run as a separate identity, with its own secret key, history and
+    /// `config.toml` in `iroh-gossip-chat/profiles/<name>` in the user
+    /// data directory. Its nickname defaults to the profile name. The rooms
+    /// it joins with tickets are rejoined on the next start until it leaves
+    /// them with `/leave`.
+    #[clap(long, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Relay server to use instead of the n0 ones. Repeat the flag for
+    /// several relays.
+    #[clap(long = "relay", value_name = "URL")]
+    relays: Vec<RelayUrl>,
+
+    /// Never use a relay, only connect directly.
+    #[clap(long, conflicts_with = "relays")]
+    no_relay: bool,
+
     #[clap(short, long)]
     name: Option<String>,
 
-    #[clap(short, long, default_value = "0")]
-    bind_port: u16,
+    /// Port to bind to, defaults to 0 (random).
+    #[clap(short, long)]
+    bind_port: Option<u16>,
+
+    /// Address to bind instead of all interfaces. Pass it twice to set both
+    /// an IPv4 and an IPv6 address.
+    #[clap(long = "bind-addr", value_name = "IP")]
+    bind_addrs: Vec<IpAddr>,
+
+    /// How to find peers from their node id: through the n0 DNS server, via
+    /// mDNS on the local network, both, or none.
+    #[clap(long, value_enum)]
+    discovery: Option<DiscoveryMode>,
+
+    /// Publish our addresses via pkarr, so peers can find us by node id.
+    #[clap(long)]
+    publish: bool,
+
+    /// Pkarr relay to publish to and resolve from, instead of the n0 one.
+    #[clap(long, value_name = "URL")]
+    pkarr_relay: Option<Url>,
+
+    /// Node to join the room through, as `<nodeid>@<ip:port>` or a bare node
+    /// id. Repeat the flag for several nodes.
+    #[clap(long = "bootstrap", value_name = "NODE")]
+    bootstrap: Vec<BootstrapPeer>,
+
+    /// Only accept room messages from this node. Repeat the flag for several
+    /// nodes.
+    #[clap(long = "allow", value_name = "NODEID")]
+    allow: Vec<NodeId>,
+
+    /// Ignore room messages from this node. Repeat the flag for several
+    /// nodes.
+    #[clap(long = "block", value_name = "NODEID")]
+    block: Vec<NodeId>,
+
+    /// Follow bans issued by this node. Repeat the flag for several
+    /// moderators.
+    #[clap(long = "moderator", value_name = "NODEID")]
+    moderators: Vec<NodeId>,
+
+    /// Messages per second each peer may send us on average, 0 disables
+    /// the limit.
+    #[clap(long, value_name = "MSGS_PER_SEC")]
+    rate_limit: Option<f64>,
+
+    /// Largest room message to send or accept, in bytes.
+    #[clap(long, value_name = "BYTES")]
+    max_message_size: Option<usize>,
+
+    /// Tell peers which of their messages we displayed, and show when
+    /// ours were seen.
+    #[clap(long)]
+    read_receipts: bool,
+
+    /// Show desktop notifications for messages and openHAB alerts while
+    /// the chat is in the background.
+    #[clap(long)]
+    notify: bool,
+
+    /// Only connect to peers over IPv4.
+    #[clap(long, conflicts_with = "ipv6_only")]
+    ipv4_only: bool,
+
+    /// Only connect to peers over IPv6.
+    #[clap(long)]
+    ipv6_only: bool,
+
+    /// File holding the node secret key, created on first run. Defaults to
+    /// `iroh-gossip-chat/secret.key` in the user data directory.
+    #[clap(long)]
+    secret_key_file: Option<PathBuf>,
+
+    /// Encrypt the secret key file with a passphrase. Existing plaintext
+    /// files are encrypted in place; encrypted files are always read.
+    #[clap(long)]
+    encrypt_secret_key: bool,
+
+    /// Passphrase of an encrypted secret key file, asked for on the
+    /// terminal when unset.
+    #[clap(long, env = "SECRET_KEY_PASSPHRASE", hide_env_values = true)]
+    secret_key_passphrase: Option<String>,
+
+    /// Keep the node secret key in the platform keyring, and look up the
+    /// openHAB token there when it is not configured.
+    #[clap(long)]
+    keyring: bool,
+
+    /// SQLite database holding the message history. Defaults to
+    /// `iroh-gossip-chat/history.db` in the user data directory. Shared and
+    /// fetched files are stored next to it, with a `.blobs` extension.
+    #[clap(long)]
+    history_file: Option<PathBuf>,
+
+    /// Serve the local HTTP API on this address, e.g. `127.0.0.1:8787`.
+    #[clap(long)]
+    http_listen: Option<SocketAddr>,
+
+    /// Base URL of the openHAB server, e.g. `http://openhab.local:8080`.
+    #[clap(long, env = "OPENHAB_URL")]
+    openhab_url: Option<String>,
+
+    /// openHAB items whose states are attached to chat messages. Repeat the
+    /// flag or separate items with commas.
+    #[clap(long = "openhab-item", env = "OPENHAB_ITEM", value_delimiter = ',')]
+    openhab_items: Vec<String>,
+
+    /// openHAB API token, created under the user profile in the main UI.
+    #[clap(long, env = "OPENHAB_TOKEN", hide_env_values = true)]
+    openhab_token: Option<String>,
+
+    /// User for HTTP basic auth in front of openHAB, e.g. by a reverse proxy.
+    #[clap(long, env = "OPENHAB_USER")]
+    openhab_user: Option<String>,
+
+    /// Password for `--openhab-user`.
+    #[clap(long, env = "OPENHAB_PASSWORD", hide_env_values = true)]
+    openhab_password: Option<String>,
+
+    /// PEM file with a CA certificate to trust for an `https://` openHAB URL.
+    #[clap(long)]
+    openhab_ca_cert: Option<PathBuf>,
+
+    /// Accept any TLS certificate from openHAB, even invalid ones. Anyone on
+    /// the network could then impersonate the server.
+    #[clap(long)]
+    openhab_accept_invalid_certs: bool,
+
+    /// Let other peers control openHAB items with `/set`.
+    #[clap(long)]
+    openhab_accept_commands: bool,
+
+    /// Whether to use openHAB at all. `auto` checks at startup that the
+    /// server is reachable and chats without it otherwise.
+    #[clap(long = "openhab", value_enum, default_value_t = OpenHabMode::Auto)]
+    openhab_mode: OpenHabMode,
+
+    /// MQTT broker to bridge the rooms with.
+    #[clap(long, env = "MQTT_HOST")]
+    mqtt_host: Option<String>,
+
+    /// MQTT topics to share with the rooms as item changes. Repeat the flag
+    /// or separate topics with commas.
+    #[clap(long, value_delimiter = ',')]
+    mqtt_subscribe: Vec<String>,
+
+    /// Encrypt all room traffic with a key derived from this passphrase.
+    /// Every member of the room must use the same passphrase.
+    #[clap(long, env = "ROOM_PASSPHRASE", hide_env_values = true)]
+    passphrase: Option<String>,
+
+    /// Open the room with this name instead of a random one. Its topic is
+    /// derived from the name and the passphrase, which is then required.
+    #[clap(long, requires = "passphrase")]
+    room: Option<String>,
+
+    /// Print a single-use invite that expires after this many seconds
+    /// instead of a reusable ticket.
+    #[clap(long, value_name = "SECS")]
+    invite_ttl: Option<u64>,
+
+    /// Only put our node id in the ticket, leaving our relay and addresses
+    /// to discovery. Shorter, but needs DNS or mDNS discovery to join.
+    #[clap(long)]
+    minimal_ticket: bool,
+
+    /// Also render the join ticket as a QR code in the terminal.
+    #[clap(long)]
+    qr: bool,
+
+    /// Use a full-screen terminal UI instead of line-based output.
+    #[clap(long, conflicts_with = "output")]
+    tui: bool,
+
+    /// Run without reading stdin, only relaying and bridging, until
+    /// stopped with SIGINT or SIGTERM.
+    #[clap(long, conflicts_with = "tui")]
+    daemon: bool,
+
+    /// Answer every chat message with an echo stating when it arrived, to
+    /// measure delays and check connectivity between deployments.
+    #[clap(long)]
+    echo: bool,
+
+    /// Print events as human-readable text or as one JSON object per line.
+    #[clap(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Log filter for diagnostics on stderr, a level like `debug` or
+    /// directives like `iroh_gossip_chat=debug,iroh=warn`. Off by default.
+    #[clap(long, env = "RUST_LOG")]
+    log_level: Option<String>,
+
+    /// Log as JSON lines, for log aggregation.
+    #[clap(long)]
+    log_json: bool,
 
     #[clap(subcommand)]
     command: Command,
 }
 
-#[derive(Parser, Debug)]
-enum Command {
-    Open,
-    Join { ticket: String },
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Ticket {
-    topic: TopicId,
-    nodes: Vec<NodeAddr>,
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum DiscoveryMode {
+    Dns,
+    Mdns,
+    Both,
+    None,
 }
 
-impl FromStr for Ticket {
-    type Err = anyhow::Error;
-
-    fn from_str(s: &str) -> Result<Self> {
-        serde_json::from_str(s).map_err(Into::into)
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OpenHabMode {
+    On,
+    Off,
+    Auto,
 }
 
-impl fmt::Display for Ticket {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Node ID: {}", self.nodes[0].node_id)
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
 }
 
-fn simplify_ticket(ticket: &Ticket) -> String {
-    ticket.nodes[0].node_id.to_string()
+#[derive(Parser, Debug)]
+enum Command {
+    Open,
+    /// Join a room through the nodes of one or more tickets to it, so the
+    /// join succeeds as long as any of the inviters is online.
+    Join {
+        #[clap(required = true)]
+        tickets: Vec<String>,
+    },
+    /// Run as a bot answering the `[[triggers]]` of the config, without a
+    /// terminal. Joins the room of the tickets, or opens one like `open`.
+    Bot {
+        tickets: Vec<String>,
+    },
+    /// List the items of the openHAB server with their types and states.
+    Items,
+    /// Save the openHAB API token, read from stdin, in the platform keyring,
+    /// so it needs not be written in the config. Used with `--keyring`.
+    StoreOpenhabToken {
+        /// Store the token of this server from `[openhab_servers]` instead
+        /// of the main one.
+        #[clap(long)]
+        server: Option<String>,
+    },
+    /// Print our node id, sockets, relay, discovery services and whether
+    /// openHAB is reachable, then exit.
+    Status,
+    /// Check relay, NAT, DNS and mDNS discovery and openHAB, and print a
+    /// report of what works.
+    Doctor,
+    /// Print messages from past sessions.
+    History {
+        /// Only show rooms whose topic starts with this prefix.
+        #[clap(long)]
+        topic: Option<String>,
+        /// Number of messages to show.
+        #[clap(short, long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// Write the history to a file or stdout.
+    Export {
+        #[clap(long, value_enum, default_value_t = ExportFormat::Jsonl)]
+        format: ExportFormat,
+        /// Only export rooms whose topic starts with this prefix.
+        #[clap(long)]
+        topic: Option<String>,
+        /// Only export messages sent at or after this date or RFC 3339 time.
+        #[clap(long, value_parser = export::parse_time)]
+        since: Option<DateTime<Utc>>,
+        /// Only export messages sent before this date or RFC 3339 time.
+        #[clap(long, value_parser = export::parse_time)]
+        until: Option<DateTime<Utc>>,
+        /// File to write, stdout when unset.
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Find past messages containing all words of a query.
+    Search {
+        #[clap(required = true)]
+        query: Vec<String>,
+        /// Only search rooms whose topic starts with this prefix.
+        #[clap(long)]
+        topic: Option<String>,
+        /// Number of messages to show.
+        #[clap(short, long, default_value_t = 50)]
+        limit: usize,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
-    let (topic, nodes) = match &args.command {
-        Command::Open => {
-            let topic = TopicId::from_bytes(rand::random());
-            println!("> opening chat room for topic {topic}");
-            (topic, vec![])
-        }
-        Command::Join { ticket } => {
-            let Ticket { topic, nodes } = Ticket::from_str(ticket)?;
-            println!("> joining chat room for topic {topic}");
-            (topic, nodes)
-        }
-    };
-
-    let secret_key = SecretKey::generate(rand::rngs::OsRng);
-    
-    let discovery = ConcurrentDiscovery::from_services(vec![
-        Box::new(DnsDiscovery::n0_dns()),
-        Box::new(LocalSwarmDiscovery::new(secret_key.public())?),
-    ]);
-    
-    let endpoint = Endpoint::builder()
-        .discovery(Box::new(discovery))
-        .bind()
-        .await?;
-    println!("> our node id: {}", endpoint.node_id());
+    let mut args = Args::parse();
+    if let Command::Bot { .. } = args.command {
+        ensure!(!args.tui, "the bot runs without a terminal UI");
+        args.daemon = true;
+    }
+    init_logging(&args)?;
+    let profile = args.profile.as_deref().map(Profile::new).transpose()?;
+    let config_path = args.config.clone().or_else(|| {
+        let path = profile.as_ref()?.config_path();
+        path.exists().then_some(path)
+    });
+    let mut config = Config::load(config_path.as_deref())?;
+    if let Some(profile) = &profile {
+        config
+            .secret_key_file
+            .get_or_insert_with(|| profile.secret_key_path());
+        config
+            .history_file
+            .get_or_insert_with(|| profile.history_path());
+        config.name.get_or_insert_with(|| profile.name.clone());
+    }
+    if let Some(url) = args.openhab_url.clone() {
+        config.openhab.url = url;
+    }
+    if !args.openhab_items.is_empty() {
+        config.openhab.items = args.openhab_items.clone();
+    }
+    if let Some(token) = args.openhab_token.clone() {
+        config.openhab.token = Some(token);
+    }
+    if let Some(user) = args.openhab_user.clone() {
+        config.openhab.username = Some(user);
+    }
+    if let Some(password) = args.openhab_password.clone() {
+        config.openhab.password = Some(password);
+    }
+    if let Some(path) = args.openhab_ca_cert.clone() {
+        config.openhab.ca_cert = Some(path);
+    }
+    if args.openhab_accept_invalid_certs {
+        config.openhab.accept_invalid_certs = true;
+    }
+    if args.openhab_accept_commands {
+        config.openhab.accept_commands = true;
+    }
+    if let Some(addr) = args.http_listen {
+        config.http_listen = Some(addr);
+    }
+    if let Some(host) = args.mqtt_host.clone() {
+        config.mqtt.host = Some(host);
+    }
+    if !args.mqtt_subscribe.is_empty() {
+        config.mqtt.subscribe = args.mqtt_subscribe.clone();
+    }
+    if !args.relays.is_empty() {
+        config.relay.mode = RelayModeConfig::Custom;
+        config.relay.urls = args.relays.clone();
+    }
+    if args.no_relay {
+        config.relay.mode = RelayModeConfig::Disabled;
+    }
+    if let Some(bind_port) = args.bind_port {
+        config.bind_port = Some(bind_port);
+    }
+    for addr in &args.bind_addrs {
+        match addr {
+            IpAddr::V4(ip) => config.bind_v4 = Some(*ip),
+            IpAddr::V6(ip) => config.bind_v6 = Some(*ip),
+        }
+    }
+    if let Some(mode) = args.discovery {
+        config.discovery.dns = matches!(mode, DiscoveryMode::Dns | DiscoveryMode::Both);
+        config.discovery.mdns = matches!(mode, DiscoveryMode::Mdns | DiscoveryMode::Both);
+    }
+    if args.publish {
+        config.discovery.publish = true;
+    }
+    if let Some(url) = args.pkarr_relay.clone() {
+        config.discovery.pkarr_relay = Some(url);
+    }
+    config.bootstrap.extend(args.bootstrap.iter().cloned());
+    config.access.allow.extend(args.allow.iter().copied());
+    config.access.block.extend(args.block.iter().copied());
+    config
+        .access
+        .moderators
+        .extend(args.moderators.iter().copied());
+    if args.notify {
+        config.notifications.enabled = true;
+    }
+    if args.read_receipts {
+        config.read_receipts = true;
+    }
+    if let Some(size) = args.max_message_size {
+        config.max_message_size = Some(size);
+    }
+    if let Some(rate) = args.rate_limit {
+        config.rate_limit.messages_per_sec = rate;
+    }
+    if args.ipv4_only {
+        config.ip_mode = IpMode::V4;
+    }
+    if args.ipv6_only {
+        config.ip_mode = IpMode::V6;
+    }
+    if let Some(room) = args.room.clone() {
+        config.room = Some(room);
+    }
+    if let Some(passphrase) = args.passphrase.clone() {
+        config.passphrase = Some(passphrase);
+    }
+    if let Some(path) = args.secret_key_file.clone() {
+        config.secret_key_file = Some(path);
+    }
+    if args.keyring {
+        config.keyring = true;
+    }
+    if args.encrypt_secret_key {
+        config.encrypt_secret_key = true;
+    }
+    config.validate()?;
+    if config.keyring {
+        let servers =
+            std::iter::once(&mut config.openhab).chain(config.openhab_servers.values_mut());
+        for server in servers.filter(|server| server.token.is_none()) {
+            match secrets::get(&secrets::openhab_token_entry(&server.url)).await {
+                Ok(token) => server.token = token,
+                Err(err) => warn!("no openHAB token for {}: {err:#}", server.url),
+            }
+        }
+    }
+    let name = args.name.clone().or(config.name.clone());
+    let passphrase = config.passphrase.clone();
 
-    let gossip = Gossip::builder().spawn(endpoint.clone()).await?;
+    let history_path = args
+        .history_file
+        .clone()
+        .or(config.history_file.clone())
+        .or_else(history::default_history_path)
+        .context("no data directory found, pass --history-file")?;
+    let history = History::open(&history_path)?;
+    let json = args.output == OutputFormat::Json;
+    // Startup progress, only for humans.
+    let status = |line: String| {
+        if !json {
+            println!("{line}");
+        }
+    };
 
-    let router = Router::builder(endpoint.clone())
-        .accept(iroh_gossip::ALPN, gossip.clone())
+    // The rooms the profile was in last time.
+    let saved_rooms = match &profile {
+        Some(profile) => profile.saved_rooms()?,
+        None => Vec::new(),
+    };
+    let (topic, mut nodes, nonces) = match &args.command {
+        Command::Join { tickets } | Command::Bot { tickets } if !tickets.is_empty() => {
+            let tickets = tickets
+                .iter()
+                .map(|ticket| Ticket::from_str(ticket))
+                .collect::<Result<_>>()?;
+            let tickets = Tickets::merge(tickets)?;
+            status(format!("> joining chat room for topic {}", tickets.topic));
+            (tickets.topic, tickets.nodes, tickets.nonces)
+        }
+        // Clap makes `join` require tickets.
+        Command::Open | Command::Join { .. } | Command::Bot { .. } => {
+            let (topic, nodes) = match (&config.room, config.topic) {
+                (Some(room), _) => {
+                    let passphrase = passphrase
+                        .as_deref()
+                        .context("a named room needs a passphrase")?;
+                    (crypto::room_topic(room, passphrase)?, vec![])
+                }
+                (None, Some(topic)) => (topic, vec![]),
+                // Back in the room the profile joined first.
+                (None, None) => match saved_rooms.first() {
+                    Some(room) => (room.topic, room.nodes.clone()),
+                    None => (TopicId::from_bytes(rand::random()), vec![]),
+                },
+            };
+            status(format!("> opening chat room for topic {topic}"));
+            (topic, nodes, vec![])
+        }
+        // Only the node is needed, not a room.
+        Command::Status | Command::Doctor => (TopicId::from_bytes([0; 32]), vec![], vec![]),
+        Command::History { topic, limit } => {
+            let entries = history.recent(topic.as_deref(), *limit)?;
+            return print_history(&history, entries, config.home.name());
+        }
+        Command::Export {
+            format,
+            topic,
+            since,
+            until,
+            output,
+        } => {
+            let entries = history.range(topic.as_deref(), *since, *until)?;
+            match output {
+                Some(path) => {
+                    let file = std::fs::File::create(path)
+                        .with_context(|| format!("failed to create {}", path.display()))?;
+                    export::write(
+                        &entries,
+                        *format,
+                        config.home.name(),
+                        std::io::BufWriter::new(file),
+                    )?;
+                    eprintln!(
+                        "> exported {} messages to {}",
+                        entries.len(),
+                        path.display()
+                    );
+                }
+                None => export::write(
+                    &entries,
+                    *format,
+                    config.home.name(),
+                    std::io::stdout().lock(),
+                )?,
+            }
+            return Ok(());
+        }
+        Command::Search {
+            query,
+            topic,
+            limit,
+        } => {
+            let entries = history.search(&query.join(" "), topic.as_deref(), *limit)?;
+            if entries.is_empty() {
+                bail!("no messages match");
+            }
+            return print_history(&history, entries, config.home.name());
+        }
+        Command::StoreOpenhabToken { server } => {
+            let url = match server {
+                Some(name) => match config.openhab_servers.get(name) {
+                    Some(server) => &server.url,
+                    None => bail!("no openHAB server {name} in [openhab_servers]"),
+                },
+                None if config.openhab.is_configured() => &config.openhab.url,
+                None => bail!("no openHAB server configured, set openhab.url or --openhab-url"),
+            };
+            let mut token = String::new();
+            std::io::stdin()
+                .read_line(&mut token)
+                .context("failed to read the token")?;
+            let token = token.trim();
+            ensure!(!token.is_empty(), "no token given on stdin");
+            let entry = secrets::openhab_token_entry(url);
+            secrets::set(&entry, token).await?;
+            eprintln!("> stored the token for {url} in the keyring");
+            return Ok(());
+        }
+        Command::Items => {
+            ensure!(
+                config.openhab.is_configured(),
+                "no openHAB server configured, set openhab.url or --openhab-url"
+            );
+            let openhab = OpenHab::new(config.openhab.clone())?;
+            for item in openhab::list_items(&openhab).await? {
+                println!("{}", format_item(&item));
+            }
+            return Ok(());
+        }
+    };
+    // Check the server while the node starts, `status` reports on it itself.
+    let checks_openhab = config.home == HomeBackend::Openhab
+        && config.openhab.is_configured()
+        && args.openhab_mode == OpenHabMode::Auto
+        && !matches!(args.command, Command::Status | Command::Doctor);
+    let openhab_check = checks_openhab.then(|| {
+        let config = config.openhab.clone();
+        tokio::spawn(async move {
+            let openhab = OpenHab::new(config)?;
+            openhab::check(&openhab).await?;
+            anyhow::Ok(openhab)
+        })
+    });
+
+    let cipher = room_cipher(passphrase.as_deref(), &topic)?;
+    if cipher.is_some() {
+        status("> room traffic is end-to-end encrypted".to_string());
+    }
+
+    let secret_key_path = args
+        .secret_key_file
+        .clone()
+        .or(config.secret_key_file.clone())
+        .or_else(keys::default_secret_key_path)
+        .context("no data directory found, pass --secret-key-file")?;
+    let protection = KeyProtection {
+        encrypt: config.encrypt_secret_key,
+        passphrase: || match args.secret_key_passphrase.clone() {
+            Some(passphrase) => Ok(passphrase),
+            None => read_key_passphrase(&secret_key_path),
+        },
+    };
+    let secret_key = match config.keyring {
+        true => keys::load_or_create_secret_key_in_keyring(&secret_key_path, protection).await?,
+        false => keys::load_or_create_secret_key(&secret_key_path, protection)?,
+    };
+
+    let (bind_v4, bind_v6) = config.bind_addrs();
+    let mut builder = ChatNode::builder();
+    if let Some(addr) = bind_v6 {
+        builder = builder.bind_addr_v6(addr);
+    }
+    if let Some(url) = config.discovery.pkarr_relay.clone() {
+        builder = builder.pkarr_relay(url);
+    }
+    let openhab = match (args.openhab_mode, openhab_check) {
+        _ if config.home != HomeBackend::Openhab || !config.openhab.is_configured() => None,
+        (OpenHabMode::Off, _) => None,
+        (_, Some(check)) => match check.await? {
+            Ok(openhab) => Some(openhab),
+            Err(err) => {
+                status(format!(
+                    "> openHAB is unreachable, chatting without it: {err:#}"
+                ));
+                None
+            }
+        },
+        (_, None) => Some(OpenHab::new(config.openhab.clone())?),
+    };
+    let home: Option<Arc<dyn HomeProvider>> = match config.home {
+        HomeBackend::Openhab => match openhab.clone() {
+            Some(openhab) if config.openhab_servers.is_empty() => Some(Arc::new(openhab) as _),
+            Some(openhab) => Some(Arc::new(OpenHabServers::new(
+                openhab,
+                config.openhab_servers.clone(),
+            )?) as _),
+            None => None,
+        },
+        HomeBackend::HomeAssistant => {
+            Some(Arc::new(HomeAssistant::new(config.home_assistant.clone())))
+        }
+    };
+    let node = builder
+        .home(home)
+        .rules(Rules::new(&config.rules)?)
+        .read_receipts(config.read_receipts)
+        .secret_key(secret_key)
+        .relay_mode(config.relay.relay_mode()?)
+        .bind_addr_v4(bind_v4)
+        .dns_discovery(config.discovery.dns)
+        .mdns_discovery(config.discovery.mdns)
+        .publish_pkarr(config.discovery.publish)
+        .access(config.access.clone())
+        .rate_limit(config.rate_limit)
+        .max_message_size(config.max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE))
+        .history(Some(history))
+        .blobs_dir(history_path.with_extension("blobs"))
+        .heartbeat_interval(config.presence.heartbeat_interval())
+        .peer_timeout(config.presence.peer_timeout())
         .spawn()
         .await?;
+    if let Command::Status = args.command {
+        for line in status_report(&node, &config.discovery).await {
+            println!("{line}");
+        }
+        return node.shutdown().await;
+    }
+    if let Command::Doctor = args.command {
+        let checks = doctor::run(&node, &config).await;
+        match json {
+            true => println!("{}", serde_json::json!({ "checks": checks })),
+            false => checks.iter().for_each(|check| println!("{check}")),
+        }
+        node.shutdown().await?;
+        let failed = checks
+            .iter()
+            .filter(|check| check.outcome == doctor::Outcome::Fail)
+            .count();
+        if failed > 0 {
+            bail!("{failed} checks failed");
+        }
+        return Ok(());
+    }
+    status(format!("> our node id: {}", node.node_id()));
 
-    let ticket = {
-        let me = endpoint.node_addr().await?;
-        let nodes = vec![me];
-        Ticket { topic, nodes }
+    // A fleet shares one config, so skip ourselves and peers already in the
+    // ticket.
+    for BootstrapPeer(peer) in config.bootstrap.iter().cloned() {
+        if peer.node_id != node.node_id() && !nodes.iter().any(|n| n.node_id == peer.node_id) {
+            nodes.push(peer);
+        }
+    }
+
+    let ticket = match args.invite_ttl {
+        Some(ttl) => node.invite(topic, Duration::from_secs(ttl)).await?,
+        None => node.ticket(topic).await?,
     };
-    let ticket_str = serde_json::to_string(&ticket)?;
-    println!("> ticket to join us: {}", ticket_str);
-    
-    let node_ids = nodes.iter().map(|p| p.node_id).collect();
+    let ticket = match args.minimal_ticket {
+        true => ticket.minimal(),
+        false => ticket,
+    };
+    status(format!("> ticket to join us: {ticket}"));
+    if let Some(expires) = ticket.expires_at() {
+        let expires = expires.with_timezone(&chrono::Local);
+        status(format!(
+            "> single-use invite, valid until {}",
+            expires.format("%Y-%m-%d %H:%M:%S")
+        ));
+    }
+    if json {
+        let ready = serde_json::json!({
+            "type": "ready",
+            "node_id": node.node_id().to_string(),
+            "room": topic.to_string(),
+            "ticket": ticket.to_string(),
+        });
+        println!("{ready}");
+    }
+    if args.qr && !json {
+        println!("{}", ticket.to_qr()?);
+    }
+
     if nodes.is_empty() {
-        println!("> waiting for nodes to join us...");
+        status("> waiting for nodes to join us...".to_string());
     } else {
-        println!("> trying to connect to {} nodes...", nodes.len());
-        for node in nodes.into_iter() {
-            endpoint.add_node_addr(node)?;
+        status(format!("> trying to connect to {} nodes...", nodes.len()));
+    }
+
+    let (output_tx, mut output_rx) = mpsc::unbounded_channel();
+    let output = Output(output_tx);
+
+    // The other rooms the profile was in, rejoined in the background so the
+    // first room does not wait for them.
+    for room in saved_rooms.into_iter().filter(|room| room.topic != topic) {
+        let cipher = room_cipher(passphrase.as_deref(), &room.topic)?;
+        let (node, name, output) = (node.clone(), name.clone(), output.clone());
+        tokio::spawn(async move {
+            let short = short_topic(&room.topic);
+            if let Err(err) = node.join(room.topic, room.nodes, cipher).await {
+                output.say(format!("> failed to rejoin room {short}: {err:#}"));
+                return;
+            }
+            output.say(format!("> rejoined room {short}"));
+            if let Some(name) = name {
+                node.announce_name(room.topic, name).await.ok();
+            }
+        });
+    }
+
+    let events = node.events();
+    let room_ticket = Ticket::new(topic, nodes.clone());
+    node.join(topic, nodes, cipher).await?;
+    status("> connected!".to_string());
+    for nonce in nonces {
+        node.redeem(topic, nonce).await?;
+    }
+    let joined_with_ticket = match &args.command {
+        Command::Join { .. } => true,
+        Command::Bot { tickets } => !tickets.is_empty(),
+        _ => false,
+    };
+    if let (Some(profile), true) = (&profile, joined_with_ticket) {
+        profile.save_room(room_ticket)?;
+    }
+
+    if let Some(name) = name.clone() {
+        node.announce_name(topic, name).await?;
+    }
+
+    // Latest states of the smart home items, pushed by the server.
+    let (item_state_tx, item_state) = watch::channel(ItemStates::new());
+    let mut bridge_tasks = Vec::new();
+    if let Some(home) = node.home().cloned() {
+        bridge_tasks.push(tokio::spawn(follow_home(
+            home,
+            item_state_tx,
+            node.clone(),
+            output.clone(),
+        )));
+    }
+    // Things are openHAB's alone.
+    if let Some(openhab) = &openhab {
+        if let Some(interval) = openhab.config.things_interval() {
+            bridge_tasks.push(tokio::spawn(watch_things(
+                openhab.clone(),
+                interval,
+                node.clone(),
+                output.clone(),
+            )));
         }
     }
+    if config.mqtt.host.is_some() {
+        bridge_tasks.push(tokio::spawn(bridge_mqtt(
+            config.mqtt.clone(),
+            node.clone(),
+            output.clone(),
+        )));
+    }
+    if config.matrix.homeserver.is_some() {
+        bridge_tasks.push(tokio::spawn(bridge_matrix(
+            config.matrix.clone(),
+            node.clone(),
+            topic,
+            output.clone(),
+        )));
+    }
+    if config.irc.server.is_some() {
+        bridge_tasks.push(tokio::spawn(bridge_irc(
+            config.irc.clone(),
+            node.clone(),
+            topic,
+            output.clone(),
+        )));
+    }
+    if config.telegram.token.is_some() {
+        bridge_tasks.push(tokio::spawn(bridge_telegram(
+            config.telegram.clone(),
+            node.clone(),
+            topic,
+            output.clone(),
+        )));
+    }
+    if !config.hooks.is_empty() {
+        node.add_handler(Hooks(config.hooks.clone()));
+    }
+    if let Some(dir) = &config.scripts_dir {
+        let scripts = Scripts::load(dir)?;
+        output.say(format!(
+            "> loaded {} scripts from {}",
+            scripts.len(),
+            dir.display()
+        ));
+        node.add_handler(scripts);
+    }
+    if args.echo {
+        output.say("> echoing every message");
+        node.add_handler(Echo);
+    }
+    if let Command::Bot { .. } = args.command {
+        let bot = Bot::new(&config.triggers)?;
+        ensure!(!bot.is_empty(), "the bot needs [[triggers]] in the config");
+        output.say(format!("> answering {} triggers", bot.len()));
+        bridge_tasks.extend(bot.schedule(node.clone()));
+        node.add_handler(bot);
+    }
+    if !config.webhooks.is_empty() {
+        let webhooks = Webhooks::new(&config.webhooks);
+        output.say(format!("> posting events to {} webhooks", webhooks.len()));
+        node.add_handler(webhooks);
+    }
+    for config in &config.plugins {
+        let plugin = Plugin::load(config)?;
+        output.say(format!("> loaded plugin {}", plugin.name()));
+        node.add_handler(plugin);
+    }
+    if let Some(addr) = config.http_listen {
+        output.say(format!("> HTTP API listening on {addr}"));
+        let node = node.clone();
+        let item_state = item_state.clone();
+        let inbound_webhook = config.inbound_webhook.clone();
+        let output = output.clone();
+        bridge_tasks.push(tokio::spawn(async move {
+            if let Err(err) = http::serve(addr, node, item_state, inbound_webhook).await {
+                error!("HTTP API failed: {err:#}");
+                output.say(format!("> HTTP API failed: {err:#}"));
+            }
+        }));
+    }
+    let notifier = Notifier::new(&config.notifications);
+    let downloads = config
+        .downloads_dir
+        .clone()
+        .or_else(|| dirs::download_dir().map(|dir| dir.join("iroh-gossip-chat")))
+        .unwrap_or_else(|| PathBuf::from("."));
+    if json {
+        tokio::spawn(print_json_events(events));
+    } else {
+        tokio::spawn(print_events(
+            events,
+            node.clone(),
+            output.clone(),
+            notifier.clone(),
+            downloads.clone(),
+            config.voice.clone(),
+        ));
+    }
 
-    let (sender, receiver) = gossip.subscribe_and_join(topic, node_ids).await?.split();
-    println!("> connected!");
+    let (line_tx, mut line_rx) = mpsc::channel(1);
+    // The room messages go to, for the TUI to send typing notifications.
+    let (current_tx, current_rx) = watch::channel(topic);
+    let tui = if args.tui {
+        Some(tokio::spawn(tui::run(
+            node.clone(),
+            line_tx,
+            output_rx,
+            item_state.clone(),
+            current_rx,
+            notifier.clone(),
+        )))
+    } else {
+        if !args.daemon {
+            std::thread::spawn(move || input_loop(line_tx));
+        }
+        let graphics = std::io::stdout()
+            .is_terminal()
+            .then(graphics::Protocol::detect)
+            .flatten();
+        tokio::spawn(async move {
+            while let Some(line) = output_rx.recv().await {
+                if json {
+                    let text = line.text.trim_start_matches("> ");
+                    println!("{}", serde_json::json!({ "type": "info", "text": text }));
+                } else if line.mention {
+                    // Ring the terminal bell and mark the line.
+                    println!("\x07* {}", line.text);
+                } else {
+                    println!("{}", line.text);
+                }
+                let image = line.image.zip(graphics);
+                if let Some((path, graphics)) = image {
+                    match graphics.render(&path) {
+                        Ok(Some(sequence)) => println!("{sequence}"),
+                        Ok(None) => {}
+                        Err(err) => warn!("failed to show {}: {err:#}", path.display()),
+                    }
+                }
+            }
+        });
+        None
+    };
 
-    if let Some(name) = args.name.clone() {
-        let message = Message::AboutMe {
-            from: endpoint.node_id(),
-            name,
+    // Rooms joined with `/join` at runtime, which become the current room.
+    let (joined_tx, mut joined_rx) = mpsc::unbounded_channel();
+    let mut current = topic;
+    let home = node.home().map_or("openHAB", |home| home.name());
+
+    if args.daemon {
+        output.say("> running as a daemon, stop with SIGINT or SIGTERM");
+    } else {
+        output.say("> type a message and hit enter to broadcast...");
+    }
+    // Scripts piping messages in want to know if any of them failed.
+    let piped = !args.daemon && !args.tui && !std::io::stdin().is_terminal();
+    let mut failures = 0;
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        let line = tokio::select! {
+            // A piped `/join` switches rooms before the lines after it.
+            biased;
+            _ = &mut shutdown => {
+                output.say("> shutting down...");
+                break;
+            }
+            Some(topic) = joined_rx.recv() => {
+                current = topic;
+                current_tx.send_replace(topic);
+                output.say(format!("> connected to room {}, messages now go there", short_topic(&topic)));
+                continue;
+            }
+            line = line_rx.recv(), if !args.daemon => match line {
+                Some(line) => line,
+                None => break,
+            },
+        };
+        let input = match piped {
+            true => Input::from_piped_line(&line),
+            false => Input::from_str(&line),
         };
-        sender.broadcast(message.to_vec().into()).await?;
+        let input = match input {
+            Ok(input) => input,
+            Err(err) => {
+                output.say(format!("> {err:#}"));
+                failures += 1;
+                continue;
+            }
+        };
+        match input {
+            Input::Text(text) => {
+                // Send message with our smart home state
+                let readings = SensorReading::from_states(&item_state.borrow());
+                let line = with_readings(&text, &readings, home);
+                match node.send_text(current, text, readings).await {
+                    Ok(clock) => output.say_at((clock, node.node_id()), format!("> sent: {line}")),
+                    Err(err) => {
+                        output.say(format!("> failed to send: {err:#}"));
+                        failures += 1;
+                    }
+                }
+            }
+            Input::Join(Tickets {
+                topic,
+                nodes,
+                nonces,
+            }) => {
+                let cipher = match room_cipher(passphrase.as_deref(), &topic) {
+                    Ok(cipher) => cipher,
+                    Err(err) => {
+                        output.say(format!(
+                            "> failed to join room {}: {err:#}",
+                            short_topic(&topic)
+                        ));
+                        failures += 1;
+                        continue;
+                    }
+                };
+                output.say(format!("> joining chat room for topic {topic}"));
+                let node = node.clone();
+                let name = name.clone();
+                let joined_tx = joined_tx.clone();
+                let (profile, say) = (profile.clone(), output.clone());
+                run_command(piped, &output, &mut failures, async move {
+                    let ticket = Ticket::new(topic, nodes.clone());
+                    node.join(topic, nodes, cipher)
+                        .await
+                        .with_context(|| format!("failed to join room {}", short_topic(&topic)))?;
+                    if let Some(profile) = profile {
+                        if let Err(err) = profile.save_room(ticket) {
+                            say.say(format!("> failed to remember the room: {err:#}"));
+                        }
+                    }
+                    for nonce in nonces {
+                        node.redeem(topic, nonce).await.ok();
+                    }
+                    if let Some(name) = name {
+                        node.announce_name(topic, name).await.ok();
+                    }
+                    joined_tx.send(topic).ok();
+                    Ok(())
+                })
+                .await;
+            }
+            Input::Rooms => {
+                for topic in node.rooms() {
+                    let marker = if topic == current { "*" } else { " " };
+                    output.say(format!("> {marker} {topic}"));
+                }
+            }
+            Input::Leave => {
+                let left = current;
+                if let Err(err) = node.leave(left).await {
+                    output.say(format!("> failed to say goodbye to the room: {err:#}"));
+                }
+                if let Some(profile) = &profile {
+                    if let Err(err) = profile.forget_room(left) {
+                        output.say(format!("> failed to forget the room: {err:#}"));
+                        failures += 1;
+                    }
+                }
+                let left = short_topic(&left);
+                match node.rooms().first() {
+                    Some(&topic) => {
+                        current = topic;
+                        current_tx.send_replace(topic);
+                        output.say(format!(
+                            "> left room {left}, messages now go to room {}",
+                            short_topic(&topic)
+                        ));
+                    }
+                    None => output.say(format!("> left room {left}, /join another to chat")),
+                }
+            }
+            Input::Switch(prefix) => {
+                let matches: Vec<_> = node
+                    .rooms()
+                    .into_iter()
+                    .filter(|topic| topic.to_string().starts_with(&prefix))
+                    .collect();
+                match matches[..] {
+                    [topic] => {
+                        current = topic;
+                        current_tx.send_replace(topic);
+                        output.say(format!("> messages now go to room {}", short_topic(&topic)));
+                    }
+                    [] => output.say(format!("> no room matches {prefix}")),
+                    _ => output.say(format!("> {prefix} matches several rooms")),
+                }
+            }
+            Input::Send(path) => {
+                let (node, say) = (node.clone(), output.clone());
+                let command = async move {
+                    let offer = node
+                        .share_file(current, &path)
+                        .await
+                        .with_context(|| format!("failed to share {}", path.display()))?;
+                    say.say(format!(
+                        "> shared {} ({} bytes) as {}",
+                        offer.name,
+                        offer.size,
+                        offer.hash()
+                    ));
+                    Ok(())
+                };
+                run_command(piped, &output, &mut failures, command).await;
+            }
+            Input::Image(path) => {
+                let (node, say) = (node.clone(), output.clone());
+                let command = async move {
+                    let image = node
+                        .share_image(current, &path)
+                        .await
+                        .with_context(|| format!("failed to share {}", path.display()))?;
+                    say.say(format!(
+                        "> shared {}x{} image ({} bytes) as {}",
+                        image.width,
+                        image.height,
+                        image.file.size,
+                        image.file.hash()
+                    ));
+                    Ok(())
+                };
+                run_command(piped, &output, &mut failures, command).await;
+            }
+            Input::Voice(secs) => {
+                let secs = secs
+                    .unwrap_or(DEFAULT_VOICE_SECS)
+                    .min(config.voice.max_secs);
+                output.say(format!("> recording {secs}s..."));
+                let (node, say) = (node.clone(), output.clone());
+                let voice = config.voice.clone();
+                let command = async move {
+                    let shared = match voice::record(&voice, secs).await {
+                        Ok(path) => {
+                            let shared = node.share_voice(current, &path, secs).await;
+                            std::fs::remove_file(&path).ok();
+                            shared
+                        }
+                        Err(err) => Err(err),
+                    };
+                    let note = shared.context("failed to share voice note")?;
+                    say.say(format!(
+                        "> shared {}s voice note as {}",
+                        note.secs,
+                        note.file.hash()
+                    ));
+                    Ok(())
+                };
+                run_command(piped, &output, &mut failures, command).await;
+            }
+            Input::Play(prefix) => match node.file_offer(&prefix) {
+                Ok(file) => {
+                    tokio::spawn(play_voice_note(
+                        node.clone(),
+                        file,
+                        downloads.clone(),
+                        config.voice.clone(),
+                        output.clone(),
+                    ));
+                }
+                Err(err) => {
+                    output.say(format!("> {err:#}"));
+                    failures += 1;
+                }
+            },
+            Input::Get(prefix) => {
+                let offer = match node.file_offer(&prefix) {
+                    Ok(offer) => offer,
+                    Err(err) => {
+                        output.say(format!("> {err:#}"));
+                        failures += 1;
+                        continue;
+                    }
+                };
+                output.say(format!("> downloading {}...", offer.name));
+                let (node, say) = (node.clone(), output.clone());
+                let command = async move {
+                    let path = node
+                        .fetch_file(&offer, Path::new("."), None)
+                        .await
+                        .with_context(|| format!("failed to fetch {}", offer.name))?;
+                    say.say(format!("> saved {}", path.display()));
+                    Ok(())
+                };
+                run_command(piped, &output, &mut failures, command).await;
+            }
+            Input::Who => {
+                let peers = node.peers();
+                if peers.is_empty() {
+                    output.say("> no peers yet");
+                }
+                for peer in peers {
+                    output.say(format!("> {}", format_peer(&peer)));
+                }
+            }
+            Input::Mute => {
+                notifier.mute(&current);
+                output.say(format!("> muted {}", short_topic(&current)));
+            }
+            Input::Unmute => {
+                notifier.unmute(&current);
+                output.say(format!("> unmuted {}", short_topic(&current)));
+            }
+            Input::Search(query) => {
+                let Some(history) = node.history() else {
+                    output.say("> no history to search");
+                    continue;
+                };
+                match history.search(&query, None, SEARCH_LIMIT) {
+                    Ok(entries) if entries.is_empty() => output.say("> no messages match"),
+                    Ok(entries) => {
+                        for entry in entries {
+                            let time = entry.timestamp.with_timezone(&chrono::Local);
+                            let name = entry.name.clone().unwrap_or_else(|| entry.from.fmt_short());
+                            output.say(format!(
+                                "> {} [{}] {name}: {}",
+                                time.format("%Y-%m-%d %H:%M"),
+                                short_topic(&entry.topic),
+                                entry_text(&entry, home)
+                            ));
+                        }
+                    }
+                    Err(err) => output.say(format!("> search failed: {err:#}")),
+                }
+            }
+            Input::Stats => {
+                let traffic = node.traffic();
+                if traffic.is_empty() {
+                    output.say("> no traffic yet");
+                }
+                for (node_id, traffic) in traffic {
+                    let name = node.name_of(&node_id).unwrap_or_else(|| "(unnamed)".into());
+                    output.say(format!(
+                        "> {name} {node_id}: sent {} messages ({} bytes), received {} ({} bytes)",
+                        traffic.messages_sent,
+                        traffic.bytes_sent,
+                        traffic.messages_received,
+                        traffic.bytes_received
+                    ));
+                }
+            }
+            Input::React { peer, emoji } => {
+                let from = match peer.map(|peer| node.resolve_peer(&peer)).transpose() {
+                    Ok(from) => from,
+                    Err(err) => {
+                        output.say(format!("> {err:#}"));
+                        failures += 1;
+                        continue;
+                    }
+                };
+                let Some(target) = node.latest_message(&current, from) else {
+                    output.say("> no message to react to");
+                    continue;
+                };
+                match node.react(current, target, emoji.clone()).await {
+                    Ok(()) => output.say(format!(
+                        "> reacted {emoji} ({})",
+                        format_reactions(&node.reactions(&target))
+                    )),
+                    Err(err) => {
+                        output.say(format!("> failed to react: {err:#}"));
+                        failures += 1;
+                    }
+                }
+            }
+            Input::Reply { to, text } => {
+                let target = match node.resolve_peer(&to) {
+                    Ok(from) => node.latest_message(&current, Some(from)),
+                    Err(err) => {
+                        output.say(format!("> {err:#}"));
+                        failures += 1;
+                        continue;
+                    }
+                };
+                let Some(target) = target else {
+                    output.say(format!("> no message from {to} to reply to"));
+                    continue;
+                };
+                let readings = SensorReading::from_states(&item_state.borrow());
+                let line = format!(
+                    "{}{}",
+                    with_readings(&text, &readings, home),
+                    reply_context(&node, &target)
+                );
+                match node.send_reply(current, text, readings, target).await {
+                    Ok(clock) => output.say_at((clock, node.node_id()), format!("> sent: {line}")),
+                    Err(err) => {
+                        output.say(format!("> failed to send: {err:#}"));
+                        failures += 1;
+                    }
+                }
+            }
+            Input::Edit(text) => {
+                let Some(target) = node.latest_message(&current, Some(node.node_id())) else {
+                    output.say("> no message to edit");
+                    continue;
+                };
+                match node.edit(current, target, text.clone()).await {
+                    Ok(()) => output.say(format!("> edited: {text}")),
+                    Err(err) => {
+                        output.say(format!("> failed to edit: {err:#}"));
+                        failures += 1;
+                    }
+                }
+            }
+            Input::Delete => {
+                let Some(target) = node.latest_message(&current, Some(node.node_id())) else {
+                    output.say("> no message to delete");
+                    continue;
+                };
+                match node.delete(current, target).await {
+                    Ok(()) => output.say("> deleted your latest message"),
+                    Err(err) => {
+                        output.say(format!("> failed to delete: {err:#}"));
+                        failures += 1;
+                    }
+                }
+            }
+            Input::Reliable(text) => {
+                let (node, say) = (node.clone(), output.clone());
+                let command = async move {
+                    let delivery = node
+                        .send_reliable(current, text.clone())
+                        .await
+                        .with_context(|| format!("failed to deliver {text}"))?;
+                    say.say_at(
+                        (delivery.clock, node.node_id()),
+                        format!(
+                            "> sent: {text} (acked by {} of {} peers after {} attempts)",
+                            delivery.acked.len(),
+                            delivery.peers,
+                            delivery.attempts
+                        ),
+                    );
+                    Ok(())
+                };
+                run_command(piped, &output, &mut failures, command).await;
+            }
+            Input::Ping(peer) => {
+                let node_id = match node.resolve_peer(&peer) {
+                    Ok(node_id) => node_id,
+                    Err(err) => {
+                        output.say(format!("> {err:#}"));
+                        failures += 1;
+                        continue;
+                    }
+                };
+                let (node, say) = (node.clone(), output.clone());
+                let command = async move {
+                    let name = node
+                        .name_of(&node_id)
+                        .unwrap_or_else(|| node_id.fmt_short());
+                    let pong = node
+                        .ping(node_id)
+                        .await
+                        .with_context(|| format!("failed to ping {name}"))?;
+                    say.say(format!(
+                        "> pong from {name}: {} ms over {}, connected in {} ms",
+                        pong.rtt.as_millis(),
+                        pong.path,
+                        pong.connect.as_millis()
+                    ));
+                    Ok(())
+                };
+                run_command(piped, &output, &mut failures, command).await;
+            }
+            Input::Status => {
+                let (node, say) = (node.clone(), output.clone());
+                let discovery = config.discovery.clone();
+                let command = async move {
+                    for line in status_report(&node, &discovery).await {
+                        say.say(format!("> {line}"));
+                    }
+                    Ok(())
+                };
+                run_command(piped, &output, &mut failures, command).await;
+            }
+            Input::Items => {
+                let Some(openhab) = openhab.clone() else {
+                    output.say("> listing items needs openHAB");
+                    continue;
+                };
+                let say = output.clone();
+                let command = async move {
+                    let items = openhab::list_items(&openhab)
+                        .await
+                        .context("failed to list items")?;
+                    for item in items {
+                        say.say(format!("> {}", format_item(&item)));
+                    }
+                    Ok(())
+                };
+                run_command(piped, &output, &mut failures, command).await;
+            }
+            Input::Set { item, value } => {
+                let (node, say) = (node.clone(), output.clone());
+                let command = async move {
+                    node.send_command(current, item.clone(), value.clone())
+                        .await
+                        .with_context(|| format!("failed to set {item}"))?;
+                    say.say(format!("> sent {value} to {item}"));
+                    Ok(())
+                };
+                run_command(piped, &output, &mut failures, command).await;
+            }
+            Input::Ban(peer) => {
+                let result = match node.resolve_peer(&peer) {
+                    Ok(node_id) => node.ban(current, node_id).await,
+                    Err(err) => Err(err),
+                };
+                match result {
+                    Ok(()) => output.say(format!("> banned {peer}")),
+                    Err(err) => {
+                        output.say(format!("> failed to ban {peer}: {err:#}"));
+                        failures += 1;
+                    }
+                }
+            }
+            Input::Msg { to, text } => {
+                let node_id = match node.resolve_peer(&to) {
+                    Ok(node_id) => node_id,
+                    Err(err) => {
+                        output.say(format!("> {err:#}"));
+                        failures += 1;
+                        continue;
+                    }
+                };
+                let (node, say) = (node.clone(), output.clone());
+                let command = async move {
+                    node.send_direct(node_id, text.clone())
+                        .await
+                        .with_context(|| format!("failed to message {to}"))?;
+                    say.say(format!("> [dm to {to}] {text}"));
+                    Ok(())
+                };
+                run_command(piped, &output, &mut failures, command).await;
+            }
+        }
+    }
+
+    // Closing the input makes the TUI restore the terminal.
+    drop(line_rx);
+    if let Some(tui) = tui {
+        tui.await??;
+    }
+    for task in bridge_tasks {
+        task.abort();
+    }
+    node.shutdown().await?;
+    if piped && failures > 0 {
+        bail!("failed to process {failures} input lines");
+    }
+    Ok(())
+}
+
+/// Runs a command that may take a while, such as connecting to a peer.
+///
+/// Piped input awaits it, so commands run in order and its failure counts
+/// towards the exit status. Otherwise it runs in the background, so the
+/// prompt stays responsive.
+async fn run_command<F>(piped: bool, output: &Output, failures: &mut usize, command: F)
+where
+    F: Future<Output = Result<()>> + Send + 'static,
+{
+    let output = output.clone();
+    let command = async move {
+        let result = command.await;
+        if let Err(err) = &result {
+            output.say(format!("> {err:#}"));
+        }
+        result.is_ok()
+    };
+    match piped {
+        true => {
+            if !command.await {
+                *failures += 1;
+            }
+        }
+        false => {
+            tokio::spawn(command);
+        }
     }
+}
+
+/// Where runtime output goes: stdout, or the message pane of the TUI.
+#[derive(Clone)]
+struct Output(mpsc::UnboundedSender<OutputLine>);
+
+/// A line of output. Chat messages carry their Lamport clock and sender so
+/// the TUI can show them in conversation order.
+struct OutputLine {
+    order: Option<(u64, NodeId)>,
+    /// Whether the line is a chat message, under which the TUI shows its
+    /// reactions.
+    chat: bool,
+    /// Whether the line is a chat message mentioning us, which is
+    /// highlighted.
+    mention: bool,
+    /// Image to show below the text, where the terminal can.
+    image: Option<PathBuf>,
+    text: String,
+}
 
-    tokio::spawn(subscribe_loop(receiver));
+impl Output {
+    fn say(&self, line: impl Into<String>) {
+        let text = line.into();
+        self.0
+            .send(OutputLine {
+                order: None,
+                chat: false,
+                mention: false,
+                image: None,
+                text,
+            })
+            .ok();
+    }
 
-    let (line_tx, mut line_rx) = tokio::sync::mpsc::channel(1);
-    std::thread::spawn(move || input_loop(line_tx));
+    fn say_at(&self, order: (u64, NodeId), line: impl Into<String>) {
+        let text = line.into();
+        let order = Some(order);
+        self.0
+            .send(OutputLine {
+                order,
+                chat: true,
+                mention: false,
+                image: None,
+                text,
+            })
+            .ok();
+    }
 
-    println!("> type a message and hit enter to broadcast...");
-    while let Some(text) = line_rx.recv().await {
-        // Fetch the state of the OpenHAB item
-        let openhab_state = get_item_state().await.unwrap_or_else(|_| "Error fetching state".to_string());
+    /// Like [`Output::say_at`], for a chat message that mentions us.
+    fn mention(&self, order: (u64, NodeId), line: impl Into<String>) {
+        let text = line.into();
+        let order = Some(order);
+        self.0
+            .send(OutputLine {
+                order,
+                chat: true,
+                mention: true,
+                image: None,
+                text,
+            })
+            .ok();
+    }
 
-        // Send message with OpenHAB state
-        let message = Message::Message {
-            from: endpoint.node_id(),
-            text: format!("{} - OpenHAB state: {}", text, openhab_state),
+    /// Like [`Output::say`], followed by the image at `path`.
+    fn image(&self, path: &Path, line: impl Into<String>) {
+        let text = line.into();
+        self.0
+            .send(OutputLine {
+                order: None,
+                chat: false,
+                mention: false,
+                image: Some(path.to_path_buf()),
+                text,
+            })
+            .ok();
+    }
+}
+
+fn room_cipher(passphrase: Option<&str>, topic: &TopicId) -> Result<Option<RoomCipher>> {
+    passphrase
+        .map(|passphrase| RoomCipher::from_passphrase(passphrase, topic))
+        .transpose()
+}
+
+/// Describes how the node is connected, for `status` and `/status`.
+async fn status_report(node: &ChatNode, discovery: &DiscoveryConfig) -> Vec<String> {
+    let mut lines = vec![format!("node id: {}", node.node_id())];
+    let (v4, v6) = node.endpoint().bound_sockets();
+    match v6 {
+        Some(v6) => lines.push(format!("sockets: {v4}, {v6}")),
+        None => lines.push(format!("sockets: {v4}")),
+    }
+    match node.home_relay().await {
+        Some(relay) => lines.push(format!("relay: {relay}")),
+        None => lines.push("relay: none".to_string()),
+    }
+    let mut services = Vec::new();
+    match (&discovery.pkarr_relay, discovery.dns) {
+        (Some(url), _) => services.push(format!("pkarr relay {url}")),
+        (None, true) => services.push("DNS".to_string()),
+        (None, false) => {}
+    }
+    if discovery.publish {
+        services.push("publishing to pkarr".to_string());
+    }
+    if discovery.mdns {
+        services.push("mDNS".to_string());
+    }
+    match services.is_empty() {
+        true => lines.push("discovery: none".to_string()),
+        false => lines.push(format!("discovery: {}", services.join(", "))),
+    }
+    let neighbors: Vec<String> = node
+        .neighbors()
+        .iter()
+        .map(|id| node.name_of(id).unwrap_or_else(|| id.fmt_short()))
+        .collect();
+    match neighbors.is_empty() {
+        true => lines.push("neighbors: none".to_string()),
+        false => lines.push(format!("neighbors: {}", neighbors.join(", "))),
+    }
+    match node.home() {
+        Some(home) => match home.check().await {
+            Ok(()) => lines.push(format!("{}: {} is reachable", home.name(), home.url())),
+            Err(err) => lines.push(format!(
+                "{}: {} is unreachable: {err:#}",
+                home.name(),
+                home.url()
+            )),
+        },
+        None => lines.push("smart home: disabled".to_string()),
+    }
+    lines
+}
+
+fn format_peer(peer: &PeerInfo) -> String {
+    let name = peer.name.as_deref().unwrap_or("(unnamed)");
+    let link = match peer.neighbor {
+        true => "neighbor",
+        false => "via gossip",
+    };
+    match peer.last_seen {
+        Some(ago) => format!(
+            "{name} {} {link}, seen {}s ago",
+            peer.node_id,
+            ago.as_secs()
+        ),
+        None => format!("{name} {} {link}, not heard from yet", peer.node_id),
+    }
+}
+
+fn short_topic(topic: &TopicId) -> String {
+    topic.to_string()[..8].to_string()
+}
+
+/// Prints every event as a line of JSON, for scripts.
+async fn print_json_events(mut events: impl futures_lite::Stream<Item = Event> + Unpin) {
+    while let Some(event) = events.next().await {
+        println!("{}", http::event_json(&event));
+    }
+}
+
+async fn print_events(
+    mut events: impl futures_lite::Stream<Item = Event> + Unpin,
+    node: ChatNode,
+    output: Output,
+    notifier: Notifier,
+    downloads: PathBuf,
+    voice: VoiceConfig,
+) {
+    // Our last message each peer was told about having seen, by room.
+    let mut receipts = HashMap::new();
+    let home = node.home().map_or("openHAB", |home| home.name());
+    while let Some(event) = events.next().await {
+        // Only tag lines with their room once there is more than one.
+        let room = |topic: &TopicId| match node.rooms().len() {
+            0 | 1 => String::new(),
+            _ => format!("[{}] ", short_topic(topic)),
         };
-        sender.broadcast(message.to_vec().into()).await?;
-        println!("> sent: {text} - OpenHAB state: {openhab_state}");
+        match event {
+            Event::NameChanged { topic, from, name } => {
+                output.say(format!(
+                    "{}> {} is now known as {}",
+                    room(&topic),
+                    from.fmt_short(),
+                    name
+                ));
+            }
+            Event::Message {
+                topic,
+                from,
+                name,
+                clock,
+                text,
+                readings,
+                in_reply_to,
+            } => {
+                // Print received message with the sender's smart home state
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                let mentioned = node.mentions(&text).contains(&node.node_id());
+                let text = with_readings(&text, &readings, home);
+                let mut context = in_reply_to
+                    .map(|target| reply_context(&node, &target))
+                    .unwrap_or_default();
+                let round_trip =
+                    in_reply_to.and_then(|target| echo::round_trip(&node, &text, &target));
+                if let Some(round_trip) = round_trip {
+                    context.push_str(&format!(
+                        " (round trip {} ms)",
+                        round_trip.num_milliseconds()
+                    ));
+                }
+                notifier.room(&topic, mentioned, name.clone(), text.clone());
+                let line = format!("{}{}{}: {}", room(&topic), name, context, text);
+                match mentioned {
+                    true => output.mention((clock, from), line),
+                    false => output.say_at((clock, from), line),
+                }
+                node.mark_seen(topic, clock);
+            }
+            Event::Seen {
+                topic,
+                from,
+                name,
+                clock,
+            } => {
+                // Report each of our messages once per peer.
+                let Some(ours) = node.last_sent(&topic) else {
+                    continue;
+                };
+                if clock < ours || receipts.insert((topic, from), ours) == Some(ours) {
+                    continue;
+                }
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                output.say(format!("{}> seen by {name}", room(&topic)));
+            }
+            Event::Reaction {
+                topic,
+                from,
+                name,
+                target,
+                emoji,
+            } => {
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                let author = match target.from == node.node_id() {
+                    true => "your".to_string(),
+                    false => {
+                        let author = node.name_of(&target.from);
+                        format!("{}'s", author.unwrap_or_else(|| target.from.fmt_short()))
+                    }
+                };
+                output.say(format!(
+                    "{}> {name} reacted {emoji} to {author} message ({})",
+                    room(&topic),
+                    format_reactions(&node.reactions(&target))
+                ));
+            }
+            Event::Edited {
+                topic,
+                from,
+                name,
+                text,
+                ..
+            } => {
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                output.say(format!("{}> {name} edited a message: {text}", room(&topic)));
+            }
+            Event::Deleted {
+                topic, from, name, ..
+            } => {
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                output.say(format!("{}> {name} deleted a message", room(&topic)));
+            }
+            Event::Typing { topic, from, name } => {
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                output.say(format!("{}> {name} is typing…", room(&topic)));
+            }
+            Event::FileOffered {
+                topic,
+                from,
+                name,
+                offer,
+            } => {
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                output.say(format!(
+                    "{}> {name} shared {} ({} bytes), /get {} to download",
+                    room(&topic),
+                    offer.name,
+                    offer.size,
+                    &offer.hash().to_string()[..10]
+                ));
+            }
+            Event::ImageShared {
+                topic,
+                from,
+                name,
+                image,
+            } => {
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                let room = room(&topic);
+                let shared = format!(
+                    "{room}> {name} shared an image ({}x{}, {} bytes)",
+                    image.width, image.height, image.file.size
+                );
+                if image.file.size > AUTO_FETCH_IMAGE_SIZE {
+                    output.say(format!(
+                        "{shared}, /get {} to download",
+                        &image.file.hash().to_string()[..10]
+                    ));
+                    continue;
+                }
+                output.say(shared);
+                let node = node.clone();
+                let output = output.clone();
+                let downloads = downloads.clone();
+                tokio::spawn(async move {
+                    let fetched = match std::fs::create_dir_all(&downloads) {
+                        // The announced size is only the sender's word.
+                        Ok(()) => {
+                            node.fetch_file(&image.file, &downloads, Some(AUTO_FETCH_IMAGE_SIZE))
+                                .await
+                        }
+                        Err(err) => Err(err.into()),
+                    };
+                    match fetched {
+                        Ok(path) => {
+                            output.image(&path, format!("{room}> saved {}", path.display()))
+                        }
+                        Err(err) => output.say(format!("{room}> failed to fetch image: {err:#}")),
+                    }
+                });
+            }
+            Event::VoiceNote {
+                topic,
+                from,
+                name,
+                note,
+            } => {
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                let hash = &note.file.hash().to_string()[..10];
+                notifier.room(&topic, false, name.clone(), "voice note".to_string());
+                output.say(format!(
+                    "{}> {name} sent a {}s voice note, /play {hash} to listen",
+                    room(&topic),
+                    note.secs
+                ));
+                if voice.autoplay {
+                    tokio::spawn(play_voice_note(
+                        node.clone(),
+                        note.file,
+                        downloads.clone(),
+                        voice.clone(),
+                        output.clone(),
+                    ));
+                }
+            }
+            Event::Backfilled {
+                topic,
+                from,
+                mut entries,
+            } => {
+                entries.sort_by_key(|entry| (entry.clock, entry.from));
+                let peer = node.name_of(&from).unwrap_or_else(|| from.fmt_short());
+                output.say(format!(
+                    "{}> {} earlier messages, as seen by {peer}:",
+                    room(&topic),
+                    entries.len()
+                ));
+                for entry in entries {
+                    let time = entry.timestamp.with_timezone(&chrono::Local);
+                    let name = entry.name.clone().unwrap_or_else(|| entry.from.fmt_short());
+                    let context = entry
+                        .in_reply_to
+                        .map(|target| reply_context(&node, &target))
+                        .unwrap_or_default();
+                    output.say_at(
+                        (entry.clock, entry.from),
+                        format!(
+                            "{}  {} {name}{context}: {}",
+                            room(&topic),
+                            time.format("%H:%M"),
+                            entry_text(&entry, home)
+                        ),
+                    );
+                }
+            }
+            Event::ItemCommand {
+                topic,
+                from,
+                name,
+                item,
+                value,
+                result,
+            } => {
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                let outcome = match result {
+                    Some(Ok(())) => format!("forwarded to {home}"),
+                    Some(Err(err)) => format!("failed: {err}"),
+                    None => "ignored".to_string(),
+                };
+                output.say(format!(
+                    "{}> {name} set {item} to {value} ({outcome})",
+                    room(&topic)
+                ));
+            }
+            Event::RuleFired {
+                topic,
+                from,
+                name,
+                rule,
+                item,
+                command,
+                result,
+            } => {
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                let outcome = match result {
+                    Ok(()) => "done".to_string(),
+                    Err(err) => format!("failed: {err}"),
+                };
+                output.say(format!(
+                    "{}> rule {rule} triggered by {name}: {item} set to {command} ({outcome})",
+                    room(&topic)
+                ));
+            }
+            Event::ItemChanged {
+                topic,
+                from,
+                name,
+                item,
+                old,
+                new,
+                ts: _,
+            } => {
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                output.say(format!(
+                    "{}> {name}: {item} changed from {old} to {new}",
+                    room(&topic)
+                ));
+            }
+            Event::ThingStatusChanged {
+                topic,
+                from,
+                name,
+                thing,
+                label,
+                status,
+                detail,
+                ts: _,
+            } => {
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                let online = status == "ONLINE";
+                let status = format_thing_status(&thing, label.as_deref(), &status, &detail);
+                if !online {
+                    notifier.room(&topic, false, format!("openHAB at {name}"), status.clone());
+                }
+                output.say(format!("{}> {name}: {status}", room(&topic)));
+            }
+            Event::Banned {
+                topic,
+                from,
+                name,
+                node_id,
+            } => {
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                let banned = node
+                    .name_of(&node_id)
+                    .unwrap_or_else(|| node_id.fmt_short());
+                output.say(format!("{}> {name} banned {banned}", room(&topic)));
+            }
+            Event::ProtocolMismatch {
+                topic,
+                from,
+                name,
+                protocol,
+                agent,
+            } => {
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                let advice = if protocol > PROTOCOL_VERSION {
+                    "upgrade to understand all of its messages"
+                } else {
+                    "it may not understand all of our messages"
+                };
+                output.say(format!(
+                    "{}> {name} runs {agent} with protocol version {protocol}, we speak {PROTOCOL_VERSION}; {advice}",
+                    room(&topic)
+                ));
+            }
+            Event::InviteReused { topic, from, name } => {
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                output.say(format!(
+                    "{}> {name} joined with an invite that was already used, ignoring them",
+                    room(&topic)
+                ));
+            }
+            Event::DirectMessage { from, name, text } => {
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                notifier.show(format!("{name} (direct)"), text.clone());
+                output.say(format!("[dm] {name}: {text}"));
+            }
+            Event::Dropped {
+                topic: _,
+                delivered_from,
+                reason,
+            } => {
+                output.say(format!(
+                    "> dropping message from {}: {reason}",
+                    delivered_from.fmt_short()
+                ));
+            }
+            Event::PeerJoined { topic, from, name } => {
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                output.say(format!("{}> {name} joined", room(&topic)));
+            }
+            Event::PeerLeft { topic, from, name } => {
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                output.say(format!("{}> {name} left", room(&topic)));
+            }
+            Event::PeerOffline { topic, from, name } => {
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                output.say(format!("{}> {name} went offline", room(&topic)));
+            }
+            Event::OpenHab(OpenHabEvent::ItemStateChanged {
+                item,
+                old_state,
+                state,
+            }) => {
+                output.say(format!(
+                    "> {home}: {item} changed from {old_state} to {state}"
+                ));
+            }
+            Event::OpenHab(OpenHabEvent::ItemCommand { item, command }) => {
+                output.say(format!("> {home}: {item} received command {command}"));
+            }
+            Event::Rejoining { topic, delay } => {
+                output.say(format!(
+                    "{}> lost contact with the room, rejoining in {}s",
+                    room(&topic),
+                    delay.as_secs()
+                ));
+            }
+            Event::OpenHab(OpenHabEvent::Other { .. })
+            | Event::NeighborUp { .. }
+            | Event::NeighborDown { .. } => {}
+        }
     }
+}
 
-    router.shutdown().await?;
+fn print_history(history: &History, entries: Vec<HistoryEntry>, home: &str) -> Result<()> {
+    let names = history.names()?;
+    for entry in entries {
+        let time = entry.timestamp.with_timezone(&chrono::Local);
+        let name = entry.name.clone().unwrap_or_else(|| entry.from.fmt_short());
+        let context = match entry.in_reply_to {
+            Some(target) => {
+                let author = names.get(&target.from).cloned();
+                let author = author.unwrap_or_else(|| target.from.fmt_short());
+                quote(&author, history.text(&target)?.as_deref())
+            }
+            None => String::new(),
+        };
+        println!(
+            "{} [{}] {}{}: {}",
+            time.format("%Y-%m-%d %H:%M:%S"),
+            short_topic(&entry.topic),
+            name,
+            context,
+            entry_text(&entry, home)
+        );
+        if !entry.reactions.is_empty() {
+            println!("    {}", format_reactions(&entry.reactions));
+        }
+    }
     Ok(())
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-enum Message {
-    AboutMe { from: NodeId, name: String },
-    Message { from: NodeId, text: String },
+/// Context shown after the sender of a reply, from what the node knows
+/// about the message it answers.
+fn reply_context(node: &ChatNode, target: &MessageRef) -> String {
+    let author = match target.from == node.node_id() {
+        true => "you".to_string(),
+        false => node
+            .name_of(&target.from)
+            .unwrap_or_else(|| target.from.fmt_short()),
+    };
+    quote(&author, node.message_text(target).as_deref())
 }
 
-impl Message {
-    fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        serde_json::from_slice(bytes).map_err(Into::into)
+/// Reply context such as ` (re alice: "lights are on")`, with the start of
+/// the answered message if it is known.
+fn quote(author: &str, text: Option<&str>) -> String {
+    let Some(text) = text else {
+        return format!(" (re {author})");
+    };
+    let mut snippet: String = text.chars().take(QUOTE_LEN).collect();
+    if text.chars().count() > QUOTE_LEN {
+        snippet.push('…');
     }
+    format!(" (re {author}: \"{snippet}\")")
+}
 
-    pub fn to_vec(&self) -> Vec<u8> {
-        serde_json::to_vec(self).expect("serialization should not fail")
+/// The text of a history entry with its readings, marked if it was edited
+/// or deleted.
+fn entry_text(entry: &HistoryEntry, home: &str) -> String {
+    let text = with_readings(&entry.text, &entry.readings, home);
+    match (entry.deleted, entry.edited) {
+        (true, _) => "(deleted)".to_string(),
+        (false, true) => format!("{text} (edited)"),
+        (false, false) => text,
     }
 }
 
-async fn subscribe_loop(mut receiver: GossipReceiver) -> Result<()> {
-    let mut names = HashMap::new();
-    while let Some(event) = receiver.try_next().await? {
-        if let Event::Gossip(GossipEvent::Received(msg)) = event {
-            match Message::from_bytes(&msg.content)? {
-                Message::AboutMe { from, name } => {
-                    names.insert(from, name.clone());
-                    println!("> {} is now known as {}", from.fmt_short(), name);
-                }
-                Message::Message { from, text } => {
-                    // Fetch OpenHAB state when receiving a message
-                    let openhab_state = get_item_state().await.unwrap_or_else(|_| "Error fetching state".to_string());
+/// Reaction counts such as `👍 2, 🎉 1`.
+fn format_reactions(reactions: &BTreeMap<String, usize>) -> String {
+    let counts: Vec<String> = reactions
+        .iter()
+        .map(|(emoji, count)| format!("{emoji} {count}"))
+        .collect();
+    counts.join(", ")
+}
 
-                    // Print received message with OpenHAB state
-                    let name = names.get(&from).map_or_else(|| from.fmt_short(), String::to_string);
-                    println!("{}: {} - OpenHAB state: {}", name, text, openhab_state);
-                }
+/// Keeps `states` up to date with the items of our smart home server and
+/// feeds its events into the node's event stream, reconnecting whenever the
+/// connection drops.
+async fn follow_home(
+    home: Arc<dyn HomeProvider>,
+    states: watch::Sender<ItemStates>,
+    node: ChatNode,
+    output: Output,
+) {
+    let mut failing = false;
+    loop {
+        let events = node.clone();
+        let on_event = Box::new(move |event| events.publish_openhab_event(event));
+        if let Err(err) = home.subscribe_events(states.clone(), on_event).await {
+            // Report the first failure only, not every retry.
+            warn!("{} connection failed: {err:#}", home.name());
+            if !failing {
+                output.say(format!("> {} connection failed: {err:#}", home.name()));
             }
+            failing = true;
+            let error = STATE_ERROR.to_string();
+            states.send_replace(
+                home.items()
+                    .iter()
+                    .map(|item| (item.clone(), error.clone()))
+                    .collect(),
+            );
         }
+        tokio::time::sleep(OPENHAB_RETRY).await;
+    }
+}
+
+/// Shares status changes of the openHAB Things with the rooms, retrying
+/// whenever the server cannot be reached.
+async fn watch_things(openhab: OpenHab, interval: Duration, node: ChatNode, output: Output) {
+    let mut failing = false;
+    loop {
+        let result = openhab::watch_things(&openhab, interval, |thing| {
+            output.say(format!(
+                "> openHAB: {}",
+                format_thing_status(
+                    &thing.uid,
+                    thing.label.as_deref(),
+                    &thing.status.status,
+                    &thing.status.detail
+                )
+            ));
+            let node = node.clone();
+            tokio::spawn(async move { node.share_thing_status(thing).await });
+        })
+        .await;
+        if let Err(err) = result {
+            warn!("checking openHAB Things failed: {err:#}");
+            if !failing {
+                output.say(format!("> checking openHAB Things failed: {err:#}"));
+            }
+            failing = true;
+        }
+        tokio::time::sleep(OPENHAB_RETRY).await;
+    }
+}
+
+/// Saves a voice note to `downloads` and plays it.
+async fn play_voice_note(
+    node: ChatNode,
+    file: FileOffer,
+    downloads: PathBuf,
+    voice: VoiceConfig,
+    output: Output,
+) {
+    let played = async {
+        std::fs::create_dir_all(&downloads)?;
+        let path = node.fetch_file(&file, &downloads, None).await?;
+        output.say(format!("> playing {}", path.display()));
+        voice::play(&voice, &path).await
+    };
+    if let Err(err) = played.await {
+        output.say(format!("> failed to play voice note: {err:#}"));
+    }
+}
+
+/// Runs a bridge forever, reconnecting after failures. Only the first
+/// failure in a row is shown to the user.
+async fn keep_bridging<F: Future<Output = Result<()>>>(
+    bridge: &str,
+    output: Output,
+    mut run: impl FnMut() -> F,
+) {
+    let mut failing = false;
+    loop {
+        if let Err(err) = run().await {
+            warn!("{bridge} bridge failed: {err:#}");
+            if !failing {
+                output.say(format!("> {bridge} bridge failed: {err:#}"));
+            }
+            failing = true;
+        }
+        tokio::time::sleep(BRIDGE_RETRY).await;
+    }
+}
+
+/// Bridges the rooms with the MQTT broker, reconnecting whenever the
+/// connection drops.
+async fn bridge_mqtt(mqtt: MqttConfig, node: ChatNode, output: Output) {
+    keep_bridging("MQTT", output, || mqtt::run_bridge(&mqtt, node.clone())).await
+}
+
+async fn bridge_matrix(config: MatrixConfig, node: ChatNode, topic: TopicId, output: Output) {
+    keep_bridging("Matrix", output, || {
+        matrix::run_bridge(&config, node.clone(), topic)
+    })
+    .await
+}
+
+async fn bridge_irc(config: IrcConfig, node: ChatNode, topic: TopicId, output: Output) {
+    keep_bridging("IRC", output, || {
+        irc::run_bridge(&config, node.clone(), topic)
+    })
+    .await
+}
+
+async fn bridge_telegram(config: TelegramConfig, node: ChatNode, topic: TopicId, output: Output) {
+    keep_bridging("Telegram", output, || {
+        telegram::run_bridge(&config, node.clone(), topic)
+    })
+    .await
+}
+
+fn format_thing_status(thing: &str, label: Option<&str>, status: &str, detail: &str) -> String {
+    let thing = label.unwrap_or(thing);
+    match detail {
+        "" | "NONE" => format!("Thing {thing} is {status}"),
+        _ => format!("Thing {thing} is {status} ({detail})"),
+    }
+}
+
+/// Asks for the passphrase of the secret key file on the terminal.
+fn read_key_passphrase(path: &Path) -> Result<String> {
+    ensure!(
+        std::io::stdin().is_terminal(),
+        "the secret key {} needs a passphrase, set SECRET_KEY_PASSPHRASE",
+        path.display()
+    );
+    let passphrase = rpassword::prompt_password(format!("passphrase for {}: ", path.display()))
+        .context("failed to read the passphrase")?;
+    ensure!(!passphrase.is_empty(), "empty passphrase");
+    Ok(passphrase)
+}
+
+fn format_item(item: &ItemInfo) -> String {
+    match &item.label {
+        Some(label) if label != &item.name => {
+            format!("{} ({}) [{}]: {}", item.name, label, item.kind, item.state)
+        }
+        _ => format!("{} [{}]: {}", item.name, item.kind, item.state),
+    }
+}
+
+/// Appends the smart home state attached to a chat message, if any, named
+/// after our `home` system.
+fn with_readings(text: &str, readings: &[SensorReading], home: &str) -> String {
+    if readings.is_empty() {
+        return text.to_string();
+    }
+    let readings: Vec<String> = readings.iter().map(ToString::to_string).collect();
+    format!("{text} - {home} state: {}", readings.join(", "))
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM as sent by systemd and docker.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
+
+/// Sends diagnostics to stderr, keeping stdout for the chat.
+fn init_logging(args: &Args) -> Result<()> {
+    let filter = EnvFilter::try_new(args.log_level.as_deref().unwrap_or("off"))
+        .context("invalid --log-level")?;
+    let logs = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    if args.log_json {
+        logs.json().init();
+    } else {
+        logs.init();
     }
     Ok(())
 }
 
-fn input_loop(tx: tokio::sync::mpsc::Sender<String>) {
+fn input_loop(tx: mpsc::Sender<String>) {
     let stdin = std::io::stdin();
     let mut buffer = String::new();
-    while stdin.read_line(&mut buffer).is_ok() {
+    // Stop at the end of the input, so piped input ends the program.
+    while let Ok(1..) = stdin.read_line(&mut buffer) {
         let text = buffer.trim().to_string();
+        buffer.clear();
+        if text.is_empty() {
+            continue;
+        }
         if tx.blocking_send(text).is_err() {
             break;
         }
-        buffer.clear();
     }
-}
\ No newline at end of file
+}
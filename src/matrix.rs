@@ -0,0 +1,237 @@
+//! Bridges a room with a Matrix room, so people without the CLI can take
+//! part from Element or any other Matrix client.
+//!
+//! The bridge logs in as a regular Matrix account. Chat messages from peers
+//! are posted with the peer's name as a per-message profile, which Element
+//! shows as the sender, and with the name in the text for other clients.
+//! Messages from the Matrix room are sent to the room as `<name> text`.
+
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{Context, Result};
+use futures_lite::StreamExt;
+use iroh_gossip::proto::TopicId;
+use reqwest::{Client, Method, RequestBuilder};
+use serde_json::{json, Value};
+use tracing::{debug, info, instrument};
+use url::Url;
+
+use crate::{config::MatrixConfig, ChatNode, Event};
+
+/// How long the homeserver may hold a sync request open.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A logged-in Matrix account in the bridged room.
+#[derive(Debug, Clone)]
+struct Matrix {
+    client: Client,
+    homeserver: Url,
+    access_token: String,
+    /// The bridge's own account, whose messages are not relayed back.
+    user_id: String,
+    room_id: String,
+}
+
+impl Matrix {
+    /// A request to the client-server API, `path` being the segments after
+    /// `/_matrix/client/v3`.
+    fn request(&self, method: Method, path: &[&str]) -> Result<RequestBuilder> {
+        let mut url = self.homeserver.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("invalid homeserver URL"))?
+            .pop_if_empty()
+            .extend(["_matrix", "client", "v3"])
+            .extend(path);
+        Ok(self
+            .client
+            .request(method, url)
+            .bearer_auth(&self.access_token))
+    }
+
+    async fn send(&self, name: &str, id: &str, text: &str) -> Result<()> {
+        let txn_id = rand::random::<u64>().to_string();
+        let content = json!({
+            "msgtype": "m.text",
+            "body": format!("{name}: {text}"),
+            "com.beeper.per_message_profile": { "id": id, "displayname": name },
+        });
+        self.request(
+            Method::PUT,
+            &["rooms", &self.room_id, "send", "m.room.message", &txn_id],
+        )?
+        .json(&content)
+        .send()
+        .await?
+        .error_for_status()
+        .context("failed to send to Matrix")?;
+        Ok(())
+    }
+
+    /// The display name of `user_id`, its localpart if it has none.
+    async fn display_name(&self, user_id: &str) -> String {
+        let name = async {
+            let profile: Value = self
+                .request(Method::GET, &["profile", user_id, "displayname"])?
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            anyhow::Ok(profile["displayname"].as_str().map(str::to_string))
+        };
+        match name.await {
+            Ok(Some(name)) => name,
+            _ => localpart(user_id).to_string(),
+        }
+    }
+}
+
+/// Logs in to Matrix and bridges its room with `topic`.
+///
+/// Only returns when the connection fails.
+#[instrument(skip_all, fields(room = ?config.room))]
+pub async fn run_bridge(config: &MatrixConfig, node: ChatNode, topic: TopicId) -> Result<()> {
+    let homeserver = config.homeserver.clone().context("no Matrix homeserver")?;
+    let access_token = config
+        .access_token
+        .clone()
+        .context("no Matrix access token")?;
+    let room = config.room.clone().context("no Matrix room")?;
+    let mut matrix = Matrix {
+        client: Client::new(),
+        homeserver,
+        access_token,
+        user_id: String::new(),
+        room_id: String::new(),
+    };
+    let whoami: Value = matrix
+        .request(Method::GET, &["account", "whoami"])?
+        .send()
+        .await?
+        .error_for_status()
+        .context("Matrix login failed")?
+        .json()
+        .await?;
+    matrix.user_id = whoami["user_id"]
+        .as_str()
+        .context("no user id in whoami")?
+        .to_string();
+    let joined: Value = matrix
+        .request(Method::POST, &["join", &room])?
+        .json(&json!({}))
+        .send()
+        .await?
+        .error_for_status()
+        .with_context(|| format!("failed to join Matrix room {room}"))?
+        .json()
+        .await?;
+    matrix.room_id = joined["room_id"]
+        .as_str()
+        .context("no room id in join response")?
+        .to_string();
+    info!(user = %matrix.user_id, room = %matrix.room_id, "joined Matrix room");
+
+    let forwarder = tokio::spawn(forward_messages(matrix.clone(), node.clone(), topic));
+    let result = relay_matrix(&matrix, &node, topic).await;
+    forwarder.abort();
+    result
+}
+
+/// Posts the chat messages of peers in `topic` to Matrix.
+async fn forward_messages(matrix: Matrix, node: ChatNode, topic: TopicId) -> Result<()> {
+    let mut events = node.events();
+    while let Some(event) = events.next().await {
+        if let Event::Message {
+            topic: room,
+            from,
+            name,
+            text,
+            ..
+        } = event
+        {
+            if room != topic {
+                continue;
+            }
+            let name = name.unwrap_or_else(|| from.fmt_short());
+            matrix.send(&name, &from.to_string(), &text).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends the messages posted in the Matrix room to `topic`, skipping the
+/// ones sent before the bridge started.
+async fn relay_matrix(matrix: &Matrix, node: &ChatNode, topic: TopicId) -> Result<()> {
+    let filter = json!({
+        "room": {
+            "rooms": [matrix.room_id],
+            "timeline": { "types": ["m.room.message"] },
+            "state": { "types": [] },
+            "ephemeral": { "types": [] },
+            "account_data": { "types": [] },
+        },
+        "presence": { "types": [] },
+        "account_data": { "types": [] },
+    })
+    .to_string();
+    let mut names: HashMap<String, String> = HashMap::new();
+    let mut since: Option<String> = None;
+    loop {
+        let mut query = vec![("filter", filter.clone())];
+        if let Some(since) = &since {
+            query.push(("since", since.clone()));
+            query.push(("timeout", SYNC_TIMEOUT.as_millis().to_string()));
+        }
+        let sync: Value = matrix
+            .request(Method::GET, &["sync"])?
+            .query(&query)
+            .timeout(SYNC_TIMEOUT * 2)
+            .send()
+            .await?
+            .error_for_status()
+            .context("Matrix sync failed")?
+            .json()
+            .await?;
+        let initial = since.is_none();
+        since = Some(
+            sync["next_batch"]
+                .as_str()
+                .context("no next_batch in sync")?
+                .to_string(),
+        );
+        if initial {
+            continue;
+        }
+        let events = &sync["rooms"]["join"][&matrix.room_id]["timeline"]["events"];
+        for event in events.as_array().into_iter().flatten() {
+            let sender = event["sender"].as_str().unwrap_or_default();
+            let content = &event["content"];
+            let body = content["body"].as_str().unwrap_or_default();
+            if sender == matrix.user_id || body.is_empty() {
+                continue;
+            }
+            let text = match content["msgtype"].as_str() {
+                Some("m.text" | "m.notice") => body.to_string(),
+                Some("m.emote") => format!("* {body}"),
+                _ => continue,
+            };
+            let name = match names.get(sender) {
+                Some(name) => name.clone(),
+                None => {
+                    let name = matrix.display_name(sender).await;
+                    names.insert(sender.to_string(), name.clone());
+                    name
+                }
+            };
+            debug!(%sender, "Matrix message");
+            node.send_text(topic, format!("<{name}> {text}"), Vec::new())
+                .await?;
+        }
+    }
+}
+
+/// `alice` for `@alice:example.org`.
+fn localpart(user_id: &str) -> &str {
+    let user = user_id.strip_prefix('@').unwrap_or(user_id);
+    user.split(':').next().unwrap_or(user)
+}
@@ -0,0 +1,520 @@
+use anyhow::{bail, ensure, Result};
+use chrono::{DateTime, Utc};
+use ed25519_dalek::Signature;
+use iroh::{NodeAddr, NodeId, PublicKey, SecretKey};
+use iroh_blobs::{ticket::BlobTicket, Hash};
+use serde::{Deserialize, Serialize};
+
+use crate::openhab::SensorReading;
+
+/// Version byte prepended to every postcard-encoded message.
+const WIRE_VERSION: u8 = 4;
+
+/// Version of the [`Message`] schema, announced in [`Message::Hello`].
+///
+/// Unlike [`WIRE_VERSION`], which covers the envelope, this is bumped
+/// whenever message variants are added, so peers can tell why they fail to
+/// decode each other's messages.
+pub const PROTOCOL_VERSION: u16 = 17;
+
+/// Lowest protocol version able to decode compressed messages. Messages
+/// are only compressed when every peer in the room speaks it.
+pub const COMPRESSION_PROTOCOL_VERSION: u16 = 14;
+
+/// Flag bit set in the version byte when the rest of the message is zstd
+/// compressed.
+const COMPRESSED: u8 = 0x80;
+
+/// Messages whose encoding is at least this long are compressed, smaller
+/// ones rarely shrink.
+pub const COMPRESSION_THRESHOLD: usize = 256;
+
+/// zstd level, fast enough for every message.
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Largest size a compressed message may expand to, so a small message
+/// cannot make us allocate a lot of memory.
+const MAX_DECOMPRESSED_SIZE: usize = 1 << 20;
+
+/// Name and version of this program, announced in [`Message::Hello`].
+pub const AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Version byte of messages sent before replies were added.
+const WIRE_VERSION_NO_REPLIES: u8 = 3;
+
+/// Version byte of messages sent before sensor readings were added.
+const WIRE_VERSION_NO_READINGS: u8 = 2;
+
+/// Version byte of messages sent before Lamport clocks were added.
+const WIRE_VERSION_UNSTAMPED: u8 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Message {
+    AboutMe {
+        from: NodeId,
+        name: String,
+    },
+    Message {
+        from: NodeId,
+        text: String,
+    },
+    /// Sent when joining a room. Members already present answer with
+    /// `reply: true` so the newcomer learns about them.
+    Joined {
+        from: NodeId,
+        reply: bool,
+    },
+    /// Sent periodically so peers can tell we are still online.
+    Heartbeat {
+        from: NodeId,
+    },
+    /// Sent when leaving a room or shutting down.
+    Left {
+        from: NodeId,
+    },
+    /// Asks nodes with openHAB access to send `value` to `item`.
+    Command {
+        from: NodeId,
+        item: String,
+        value: String,
+    },
+    /// An item on the sender's openHAB server changed its state.
+    ItemChanged {
+        from: NodeId,
+        item: String,
+        old: String,
+        new: String,
+        ts: DateTime<Utc>,
+    },
+    /// A Thing on the sender's openHAB server, such as a bridge or device,
+    /// changed its status, e.g. went `OFFLINE`.
+    ThingStatus {
+        from: NodeId,
+        thing: String,
+        label: Option<String>,
+        status: String,
+        detail: String,
+        ts: DateTime<Utc>,
+    },
+    /// Offers a file, fetched from the sender over iroh-blobs.
+    File {
+        from: NodeId,
+        name: String,
+        size: u64,
+        ticket: BlobTicket,
+    },
+    /// Bans `node_id` from every room. Only honored when the sender is a
+    /// moderator.
+    Ban {
+        from: NodeId,
+        node_id: NodeId,
+    },
+    /// Sent along with [`Message::Joined`] so peers learn which protocol
+    /// version we speak.
+    Hello {
+        from: NodeId,
+        protocol: u16,
+        agent: String,
+    },
+    /// Asks `node_id` to announce its name again with [`Message::AboutMe`].
+    WhoIs {
+        from: NodeId,
+        node_id: NodeId,
+    },
+    /// Sent after joining with a single-use invite, so the inviting node can
+    /// tell when it is used twice.
+    Redeem {
+        from: NodeId,
+        nonce: u64,
+    },
+    /// A chat message the sender wants acknowledged with [`Message::Ack`].
+    /// It is sent again under the same `msg_id` until enough peers did.
+    Reliable {
+        from: NodeId,
+        msg_id: MessageId,
+        text: String,
+    },
+    /// Confirms that we received the [`Message::Reliable`] `msg_id`.
+    Ack {
+        from: NodeId,
+        msg_id: MessageId,
+    },
+    /// Read receipt: we displayed the room's messages up to Lamport clock
+    /// `clock`.
+    Seen {
+        from: NodeId,
+        clock: u64,
+    },
+    /// Sent every few seconds while the user is composing a message.
+    Typing {
+        from: NodeId,
+    },
+    /// Reacts to a chat message with an emoji.
+    Reaction {
+        from: NodeId,
+        target_msg_id: MessageRef,
+        emoji: String,
+    },
+    /// Replaces the text of one of the sender's own chat messages.
+    Edit {
+        from: NodeId,
+        target: MessageRef,
+        new_text: String,
+    },
+    /// Retracts one of the sender's own chat messages.
+    Delete {
+        from: NodeId,
+        target: MessageRef,
+    },
+    /// An image to show inline, fetched from the sender like a shared file.
+    Image {
+        from: NodeId,
+        blob_hash: Hash,
+        mime: String,
+        width: u32,
+        height: u32,
+        size: u64,
+        /// Addresses of the sender to fetch the image from.
+        addr: NodeAddr,
+    },
+    /// A recorded voice note in WAV format, fetched from the sender like a
+    /// shared file.
+    Voice {
+        from: NodeId,
+        blob_hash: Hash,
+        secs: u32,
+        size: u64,
+        /// Addresses of the sender to fetch the recording from.
+        addr: NodeAddr,
+    },
+    /// Part `index` of `total` of a message too large for a single gossip
+    /// message. Together the parts form its signed envelope, whose id is
+    /// `msg_id`.
+    Chunk {
+        from: NodeId,
+        msg_id: MessageId,
+        index: u32,
+        total: u32,
+        data: Vec<u8>,
+    },
+}
+
+impl Message {
+    /// The node that claims to have sent this message.
+    pub fn sender(&self) -> NodeId {
+        match self {
+            Message::AboutMe { from, .. }
+            | Message::Message { from, .. }
+            | Message::Joined { from, .. }
+            | Message::Heartbeat { from }
+            | Message::Left { from }
+            | Message::Command { from, .. }
+            | Message::ItemChanged { from, .. }
+            | Message::ThingStatus { from, .. }
+            | Message::File { from, .. }
+            | Message::Ban { from, .. }
+            | Message::Hello { from, .. }
+            | Message::WhoIs { from, .. }
+            | Message::Redeem { from, .. }
+            | Message::Reliable { from, .. }
+            | Message::Ack { from, .. }
+            | Message::Seen { from, .. }
+            | Message::Typing { from }
+            | Message::Reaction { from, .. }
+            | Message::Edit { from, .. }
+            | Message::Delete { from, .. }
+            | Message::Image { from, .. }
+            | Message::Voice { from, .. }
+            | Message::Chunk { from, .. } => *from,
+        }
+    }
+}
+
+/// Refers to a chat message by its sender and Lamport clock, which no two
+/// messages of the same sender share.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct MessageRef {
+    pub from: NodeId,
+    pub clock: u64,
+}
+
+/// The names and node ids mentioned with `@` in a chat message, e.g.
+/// `alice` and `1ae4957613` in "@alice, @1ae4957613: lights?".
+pub fn mentions(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| c.is_whitespace())
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|word| word.trim_end_matches(|c: char| !c.is_alphanumeric() && c != '_' && c != '-'))
+        .filter(|mention| !mention.is_empty())
+}
+
+/// A [`Message`] with the sender's Lamport clock at the time it was sent.
+///
+/// Gossip delivers messages in no particular order, ordering by `clock` and
+/// then by sender gives every peer the same view of a conversation.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Stamped {
+    pub clock: u64,
+    pub message: Message,
+    /// Sensor readings taken when the message was sent. Only chat messages
+    /// carry any.
+    pub readings: Vec<SensorReading>,
+    /// The chat message this one answers, only set on chat messages.
+    pub in_reply_to: Option<MessageRef>,
+}
+
+impl Stamped {
+    /// Decodes a message in the current postcard format, or in one of the
+    /// formats sent by older nodes, which carry no replies, no readings and
+    /// maybe no clock.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let unstamped = |message| Stamped {
+            clock: 0,
+            message,
+            readings: Vec::new(),
+            in_reply_to: None,
+        };
+        match bytes.split_first() {
+            Some((&version, rest)) if version & COMPRESSED != 0 => {
+                let mut decompressed = vec![version & !COMPRESSED];
+                decompressed.extend(zstd::bulk::decompress(rest, MAX_DECOMPRESSED_SIZE)?);
+                Self::from_bytes(&decompressed)
+            }
+            Some((&WIRE_VERSION, rest)) => postcard::from_bytes(rest).map_err(Into::into),
+            Some((&WIRE_VERSION_NO_REPLIES, rest)) => {
+                let (clock, message, readings) = postcard::from_bytes(rest)?;
+                Ok(Stamped {
+                    clock,
+                    message,
+                    readings,
+                    in_reply_to: None,
+                })
+            }
+            Some((&WIRE_VERSION_NO_READINGS, rest)) => {
+                let (clock, message) = postcard::from_bytes(rest)?;
+                Ok(Stamped {
+                    clock,
+                    message,
+                    readings: Vec::new(),
+                    in_reply_to: None,
+                })
+            }
+            Some((&WIRE_VERSION_UNSTAMPED, rest)) => Ok(unstamped(postcard::from_bytes(rest)?)),
+            Some((b'{', _)) => Ok(unstamped(serde_json::from_slice(bytes)?)),
+            Some((version, _)) if *version > WIRE_VERSION => {
+                bail!("unsupported wire version {version}, the sender runs a newer version")
+            }
+            Some((version, _)) => bail!("unsupported wire version {version}"),
+            None => bail!("empty message"),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        postcard::to_extend(self, vec![WIRE_VERSION]).expect("serialization should not fail")
+    }
+
+    /// Like [`Stamped::to_vec`], but zstd compresses messages of at least
+    /// [`COMPRESSION_THRESHOLD`] bytes if that makes them smaller.
+    pub fn to_vec_compressed(&self) -> Vec<u8> {
+        let bytes = self.to_vec();
+        if bytes.len() < COMPRESSION_THRESHOLD {
+            return bytes;
+        }
+        let mut compressed = vec![WIRE_VERSION | COMPRESSED];
+        match zstd::bulk::compress(&bytes[1..], COMPRESSION_LEVEL) {
+            Ok(data) if data.len() + 1 < bytes.len() => {
+                compressed.extend(data);
+                compressed
+            }
+            _ => bytes,
+        }
+    }
+}
+
+/// Identifies a message by the BLAKE3 hash of its signed envelope.
+pub type MessageId = [u8; 32];
+
+/// Envelope carrying an encoded [`Stamped`] message together with a signature by the
+/// sending node's secret key.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignedMessage {
+    from: PublicKey,
+    data: Vec<u8>,
+    signature: Signature,
+}
+
+impl SignedMessage {
+    /// Signs and encodes `message`, compressing it if `compress` is set and
+    /// it is large enough.
+    pub fn sign_and_encode(secret_key: &SecretKey, message: &Stamped, compress: bool) -> Vec<u8> {
+        let data = match compress {
+            true => message.to_vec_compressed(),
+            false => message.to_vec(),
+        };
+        let signature = secret_key.sign(&data);
+        let signed = SignedMessage {
+            from: secret_key.public(),
+            data,
+            signature,
+        };
+        postcard::to_stdvec(&signed).expect("serialization should not fail")
+    }
+
+    /// The id of an encoded envelope. A message relayed by several
+    /// neighbors arrives with the same id each time.
+    pub fn id(bytes: &[u8]) -> MessageId {
+        *blake3::hash(bytes).as_bytes()
+    }
+
+    /// Checks the signature, leaving the message itself undecoded.
+    pub fn verify(bytes: &[u8]) -> Result<Self> {
+        let signed: SignedMessage = postcard::from_bytes(bytes)?;
+        signed.from.verify(&signed.data, &signed.signature)?;
+        Ok(signed)
+    }
+
+    /// The node that signed the message.
+    pub fn signer(&self) -> PublicKey {
+        self.from
+    }
+
+    /// Decodes a verified message and checks that the signer is the node
+    /// named in its `from` field.
+    pub fn decode(&self) -> Result<Stamped> {
+        let stamped = Stamped::from_bytes(&self.data)?;
+        let sender = stamped.message.sender();
+        ensure!(
+            sender == self.from,
+            "message from {} was signed by {}",
+            sender.fmt_short(),
+            self.from.fmt_short()
+        );
+        Ok(stamped)
+    }
+
+    /// Checks the signature and that the signer is the node named in the
+    /// message's `from` field.
+    pub fn verify_and_decode(bytes: &[u8]) -> Result<Stamped> {
+        Self::verify(bytes)?.decode()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(from: NodeId, text: &str) -> Message {
+        Message::Message {
+            from,
+            text: text.to_string(),
+        }
+    }
+
+    fn text_of(stamped: &Stamped) -> &str {
+        match &stamped.message {
+            Message::Message { text, .. } => text,
+            other => panic!("unexpected message {other:?}"),
+        }
+    }
+
+    fn node_id() -> NodeId {
+        SecretKey::generate(rand::rngs::OsRng).public()
+    }
+
+    fn stamped(from: NodeId, text: &str) -> Stamped {
+        Stamped {
+            clock: 7,
+            message: chat(from, text),
+            readings: Vec::new(),
+            in_reply_to: Some(MessageRef { from, clock: 3 }),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let from = node_id();
+        let bytes = stamped(from, "hello").to_vec();
+        assert_eq!(bytes[0], WIRE_VERSION);
+        let decoded = Stamped::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.clock, 7);
+        assert_eq!(text_of(&decoded), "hello");
+        assert_eq!(decoded.in_reply_to, Some(MessageRef { from, clock: 3 }));
+    }
+
+    #[test]
+    fn compresses_only_large_messages() {
+        let from = node_id();
+        let small = stamped(from, "hello").to_vec_compressed();
+        assert_eq!(small[0], WIRE_VERSION);
+
+        let text = "the lights are on ".repeat(100);
+        let large = stamped(from, &text).to_vec_compressed();
+        assert_eq!(large[0], WIRE_VERSION | COMPRESSED);
+        assert!(large.len() < text.len());
+        assert_eq!(text_of(&Stamped::from_bytes(&large).unwrap()), text);
+    }
+
+    #[test]
+    fn refuses_oversized_decompression() {
+        let mut bytes = vec![WIRE_VERSION | COMPRESSED];
+        bytes.extend(zstd::bulk::compress(&vec![0; MAX_DECOMPRESSED_SIZE + 1], 3).unwrap());
+        assert!(Stamped::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn decodes_legacy_versions() {
+        let from = node_id();
+        let message = chat(from, "old");
+
+        let bytes = postcard::to_extend(
+            &(5u64, &message, Vec::<SensorReading>::new()),
+            vec![WIRE_VERSION_NO_REPLIES],
+        )
+        .unwrap();
+        let decoded = Stamped::from_bytes(&bytes).unwrap();
+        assert_eq!((decoded.clock, text_of(&decoded)), (5, "old"));
+
+        let bytes = postcard::to_extend(&(6u64, &message), vec![WIRE_VERSION_NO_READINGS]).unwrap();
+        let decoded = Stamped::from_bytes(&bytes).unwrap();
+        assert_eq!((decoded.clock, text_of(&decoded)), (6, "old"));
+
+        let bytes = postcard::to_extend(&message, vec![WIRE_VERSION_UNSTAMPED]).unwrap();
+        let decoded = Stamped::from_bytes(&bytes).unwrap();
+        assert_eq!((decoded.clock, text_of(&decoded)), (0, "old"));
+
+        let json = serde_json::to_vec(&message).unwrap();
+        let decoded = Stamped::from_bytes(&json).unwrap();
+        assert_eq!((decoded.clock, text_of(&decoded)), (0, "old"));
+        assert!(decoded.in_reply_to.is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let newer = Stamped::from_bytes(&[WIRE_VERSION + 1, 0]).unwrap_err();
+        assert!(newer.to_string().contains("newer version"));
+        assert!(Stamped::from_bytes(&[0, 0]).is_err());
+        assert!(Stamped::from_bytes(&[]).is_err());
+    }
+
+    #[test]
+    fn signature_must_match_sender() {
+        let key = SecretKey::generate(rand::rngs::OsRng);
+        let bytes = SignedMessage::sign_and_encode(&key, &stamped(key.public(), "hi"), true);
+        let decoded = SignedMessage::verify_and_decode(&bytes).unwrap();
+        assert_eq!(text_of(&decoded), "hi");
+
+        let forged = SignedMessage::sign_and_encode(&key, &stamped(node_id(), "hi"), true);
+        assert!(SignedMessage::verify_and_decode(&forged).is_err());
+
+        let mut tampered = bytes.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(SignedMessage::verify_and_decode(&tampered).is_err());
+    }
+
+    #[test]
+    fn finds_mentions() {
+        let found: Vec<_> =
+            mentions("@alice, @1ae4957613: lights? mail@example.com @ @bob_2!").collect();
+        assert_eq!(found, ["alice", "1ae4957613", "bob_2"]);
+    }
+}
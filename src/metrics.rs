@@ -0,0 +1,153 @@
+//! Prometheus metrics, served on `/metrics` by the HTTP API.
+
+use std::sync::LazyLock;
+
+use anyhow::Result;
+use iroh::NodeId;
+use prometheus_client::{
+    encoding::text::encode,
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+
+/// Content type of [`Metrics::encode`]'s output.
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Labels of the per-peer metrics, the peer's node id.
+type PeerLabels = Vec<(&'static str, String)>;
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// The metrics of this process.
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    /// Gossip messages we broadcast.
+    pub messages_sent: Counter,
+    /// Gossip messages received and verified, without duplicates.
+    pub messages_received: Counter,
+    /// Broadcasts that failed to reach the gossip layer.
+    pub broadcast_errors: Counter,
+    /// Gossip messages dropped because their sender exceeded the rate limit.
+    pub messages_rate_limited: Counter,
+    /// Direct gossip neighbors across all rooms.
+    pub neighbors: Gauge,
+    /// Time until the openHAB REST API answered, in seconds.
+    pub openhab_request_duration: Histogram,
+    /// Gossip messages broadcast while the peer was a neighbor, by peer.
+    peer_messages_sent: Family<PeerLabels, Counter>,
+    peer_bytes_sent: Family<PeerLabels, Counter>,
+    /// Gossip messages received from the peer, by author.
+    peer_messages_received: Family<PeerLabels, Counter>,
+    peer_bytes_received: Family<PeerLabels, Counter>,
+    registry: Registry,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let messages_sent = Counter::default();
+        let messages_received = Counter::default();
+        let broadcast_errors = Counter::default();
+        let messages_rate_limited = Counter::default();
+        let neighbors = Gauge::default();
+        let openhab_request_duration = Histogram::new(exponential_buckets(0.005, 2.0, 12));
+        let peer_messages_sent = Family::<PeerLabels, Counter>::default();
+        let peer_bytes_sent = Family::<PeerLabels, Counter>::default();
+        let peer_messages_received = Family::<PeerLabels, Counter>::default();
+        let peer_bytes_received = Family::<PeerLabels, Counter>::default();
+
+        let mut registry = Registry::with_prefix("iroh_gossip_chat");
+        registry.register(
+            "messages_sent",
+            "Gossip messages broadcast",
+            messages_sent.clone(),
+        );
+        registry.register(
+            "messages_received",
+            "Gossip messages received",
+            messages_received.clone(),
+        );
+        registry.register(
+            "broadcast_errors",
+            "Failed broadcasts",
+            broadcast_errors.clone(),
+        );
+        registry.register(
+            "messages_rate_limited",
+            "Gossip messages dropped by the rate limit",
+            messages_rate_limited.clone(),
+        );
+        registry.register("neighbors", "Direct gossip neighbors", neighbors.clone());
+        registry.register(
+            "openhab_request_duration_seconds",
+            "Latency of openHAB REST requests",
+            openhab_request_duration.clone(),
+        );
+        registry.register(
+            "peer_messages_sent",
+            "Gossip messages broadcast to a neighbor",
+            peer_messages_sent.clone(),
+        );
+        registry.register(
+            "peer_bytes_sent",
+            "Bytes broadcast to a neighbor",
+            peer_bytes_sent.clone(),
+        );
+        registry.register(
+            "peer_messages_received",
+            "Gossip messages received from a peer",
+            peer_messages_received.clone(),
+        );
+        registry.register(
+            "peer_bytes_received",
+            "Bytes received from a peer",
+            peer_bytes_received.clone(),
+        );
+        Self {
+            messages_sent,
+            messages_received,
+            broadcast_errors,
+            messages_rate_limited,
+            neighbors,
+            openhab_request_duration,
+            peer_messages_sent,
+            peer_bytes_sent,
+            peer_messages_received,
+            peer_bytes_received,
+            registry,
+        }
+    }
+
+    /// Counts a message of `bytes` broadcast to the neighbor `peer`.
+    pub fn record_sent(&self, peer: &NodeId, bytes: usize) {
+        let labels = vec![("peer", peer.to_string())];
+        self.peer_messages_sent.get_or_create(&labels).inc();
+        self.peer_bytes_sent
+            .get_or_create(&labels)
+            .inc_by(bytes as u64);
+    }
+
+    /// Counts a message of `bytes` received from `peer`.
+    pub fn record_received(&self, peer: &NodeId, bytes: usize) {
+        let labels = vec![("peer", peer.to_string())];
+        self.peer_messages_received.get_or_create(&labels).inc();
+        self.peer_bytes_received
+            .get_or_create(&labels)
+            .inc_by(bytes as u64);
+    }
+
+    /// All metrics in the OpenMetrics text format.
+    pub fn encode(&self) -> Result<String> {
+        let mut text = String::new();
+        encode(&mut text, &self.registry)?;
+        Ok(text)
+    }
+}
@@ -0,0 +1,117 @@
+//! Bridges the rooms with an MQTT broker.
+//!
+//! Chat messages and item changes from peers are published to the broker,
+//! and messages on the subscribed MQTT topics are shared with the rooms as
+//! item changes, so existing home-automation setups can take part.
+
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{Context, Result};
+use futures_lite::StreamExt;
+use rumqttc::{AsyncClient, Event as MqttEvent, MqttOptions, Packet, QoS};
+use tracing::{debug, info, instrument};
+
+use crate::{config::MqttConfig, http, ChatNode, Event};
+
+/// Keep-alive interval of the broker connection.
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+/// Connects to the broker and bridges it with the rooms of `node`.
+///
+/// Only returns when the connection fails. Do not subscribe to the topics
+/// the bridge publishes to, or changes would echo between the peers.
+#[instrument(skip_all, fields(host = ?config.host, port = config.port))]
+pub async fn run_bridge(config: &MqttConfig, node: ChatNode) -> Result<()> {
+    let host = config.host.clone().context("no MQTT broker configured")?;
+    let mut options = MqttOptions::new(&config.client_id, host, config.port);
+    options.set_keep_alive(KEEP_ALIVE);
+    if let Some(username) = &config.username {
+        let password = config.password.clone().unwrap_or_default();
+        options.set_credentials(username, password);
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, 64);
+
+    let publisher = tokio::spawn(publish_events(config.clone(), client.clone(), node.clone()));
+    let result = async {
+        // Last payload per topic, reported as the old state of a change.
+        let mut states: HashMap<String, String> = HashMap::new();
+        loop {
+            match eventloop.poll().await.context("MQTT connection failed")? {
+                MqttEvent::Incoming(Packet::ConnAck(_)) => {
+                    info!("connected to MQTT broker");
+                    for topic in &config.subscribe {
+                        client.subscribe(topic, QoS::AtLeastOnce).await?;
+                    }
+                }
+                MqttEvent::Incoming(Packet::Publish(publish)) => {
+                    let new = String::from_utf8_lossy(&publish.payload).trim().to_string();
+                    debug!(topic = %publish.topic, %new, "MQTT message");
+                    let old = states
+                        .insert(publish.topic.clone(), new.clone())
+                        .unwrap_or_else(|| "NULL".to_string());
+                    if old != new {
+                        node.share_item_change(publish.topic, old, new).await;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    .await;
+    publisher.abort();
+    result
+}
+
+/// Publishes chat messages and item changes from our peers.
+async fn publish_events(config: MqttConfig, client: AsyncClient, node: ChatNode) -> Result<()> {
+    let mut events = node.events();
+    while let Some(event) = events.next().await {
+        let (topic, payload) = match event {
+            Event::Message {
+                topic,
+                from,
+                name,
+                clock,
+                text,
+                readings,
+                in_reply_to,
+            } => {
+                let payload = serde_json::json!({
+                    "room": topic.to_string(),
+                    "from": from.to_string(),
+                    "name": name,
+                    "clock": clock,
+                    "text": text,
+                    "readings": readings,
+                    "in_reply_to": in_reply_to.as_ref().map(http::message_ref_json),
+                });
+                (config.message_topic.clone(), payload)
+            }
+            Event::ItemChanged {
+                topic,
+                from,
+                name,
+                item,
+                old,
+                new,
+                ts,
+            } => {
+                let payload = serde_json::json!({
+                    "room": topic.to_string(),
+                    "from": from.to_string(),
+                    "name": name,
+                    "item": item,
+                    "old": old,
+                    "new": new,
+                    "timestamp": ts,
+                });
+                (config.item_topic.replace("{item}", &item), payload)
+            }
+            _ => continue,
+        };
+        client
+            .publish(topic, QoS::AtLeastOnce, false, payload.to_string())
+            .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,3008 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    net::{Ipv4Addr, SocketAddrV4, SocketAddrV6},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use chrono::{DateTime, Utc};
+use futures_lite::{stream::Boxed, StreamExt};
+use iroh::{
+    discovery::{
+        dns::DnsDiscovery,
+        local_swarm_discovery::LocalSwarmDiscovery,
+        pkarr::{PkarrPublisher, PkarrResolver},
+        ConcurrentDiscovery, Discovery,
+    },
+    protocol::Router,
+    Endpoint, NodeAddr, NodeId, RelayMode, RelayUrl, SecretKey,
+};
+use iroh_blobs::{ticket::BlobTicket, BlobFormat, Hash};
+use iroh_gossip::{
+    net::{Event as GossipNetEvent, Gossip, GossipEvent, GossipReceiver, GossipSender},
+    proto::TopicId,
+};
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, instrument, warn};
+use url::Url;
+
+use crate::{
+    backfill::{self, Backfill, DEFAULT_BACKFILL_LIMIT},
+    config::{AccessConfig, RateLimitConfig},
+    crypto::RoomCipher,
+    direct::{self, DirectMessages},
+    files::{self, BlobStore, FileOffer, ImageOffer, VoiceNote},
+    handler::{self, MessageHandler},
+    history::{History, HistoryEntry},
+    home::HomeProvider,
+    message::{
+        self, Message, MessageId, MessageRef, SignedMessage, Stamped, AGENT,
+        COMPRESSION_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    },
+    metrics::metrics,
+    openhab::{ItemStates, OpenHabEvent, SensorReading, ThingInfo},
+    ping::{self, Ping, Pong},
+    rules::Rules,
+    ticket::Ticket,
+};
+
+/// Capacity of the event channel, slow subscribers skip older events.
+const EVENT_CAPACITY: usize = 256;
+
+/// Number of message ids remembered per room to drop duplicates.
+const SEEN_CAPACITY: usize = 1024;
+
+/// Number of recent chat messages kept in memory to quote in replies.
+const TEXTS_CAPACITY: usize = 1024;
+
+/// Default interval between heartbeats.
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Default time without any message after which a peer counts as offline.
+pub const DEFAULT_PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default limit for the size of an encoded room message, in bytes.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 4096;
+
+/// Room for the gossip layer's own framing on top of our messages.
+const GOSSIP_FRAME_OVERHEAD: usize = 1024;
+
+/// How long to wait for a home relay before handing out a ticket without
+/// one.
+const HOME_RELAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time given to the gossip layer to deliver our goodbyes before the
+/// endpoint closes.
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(500);
+
+/// First delay before rejoining a room we lost contact with, doubled on
+/// every further attempt.
+const REJOIN_MIN_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between two attempts to rejoin a room.
+const REJOIN_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// How long to wait for acks before sending a reliable message again.
+const RETRANSMIT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How often a reliable message is sent before giving up.
+const RELIABLE_ATTEMPTS: u32 = 5;
+
+/// Delay for collecting displayed messages into one read receipt.
+const RECEIPT_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest reaction accepted, in bytes, enough for emoji sequences such as
+/// flags or skin tones.
+const MAX_EMOJI_LEN: usize = 32;
+
+/// Least time between two typing notifications we send to a room.
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Time after its last typing notification that a peer stops counting as
+/// typing.
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Room for the chunk header, signature and encryption in each chunk of an
+/// oversized message.
+const CHUNK_OVERHEAD: usize = 512;
+
+/// Most chunks a message may be split into.
+const MAX_CHUNKS: u32 = 256;
+
+/// How long the chunks of a message may take to arrive.
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Most chunked messages from all peers being reassembled at once.
+const MAX_PARTIAL_MESSAGES: usize = 64;
+
+/// Furthest a received Lamport clock may be ahead of ours. Beyond that the
+/// message is dropped, so a peer cannot push our clock towards overflow.
+const MAX_CLOCK_JUMP: u64 = 1 << 32;
+
+/// Shortest node id prefix taken as a mention, so `@a` or `@dead` do not
+/// match a random node.
+const MIN_MENTION_PREFIX: usize = 6;
+
+/// Something that happened in one of our rooms, delivered through
+/// [`ChatNode::events`].
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A peer announced its display name.
+    NameChanged {
+        topic: TopicId,
+        from: NodeId,
+        name: String,
+    },
+    /// A chat message, with the sender's display name if it is known.
+    ///
+    /// Order messages by `clock`, then by `from`, to show them the same way
+    /// as every other peer.
+    Message {
+        topic: TopicId,
+        from: NodeId,
+        name: Option<String>,
+        clock: u64,
+        text: String,
+        /// openHAB item states the sender attached.
+        readings: Vec<SensorReading>,
+        /// The message this one answers, see [`ChatNode::message_text`].
+        in_reply_to: Option<MessageRef>,
+    },
+    /// A peer displayed the room's messages up to Lamport clock `clock`.
+    /// Compare with [`ChatNode::last_sent`] to tell if it saw ours.
+    Seen {
+        topic: TopicId,
+        from: NodeId,
+        name: Option<String>,
+        clock: u64,
+    },
+    /// A peer reacted to a chat message, see [`ChatNode::reactions`] for
+    /// the counts.
+    Reaction {
+        topic: TopicId,
+        from: NodeId,
+        name: Option<String>,
+        target: MessageRef,
+        emoji: String,
+    },
+    /// A peer changed the text of one of its messages.
+    Edited {
+        topic: TopicId,
+        from: NodeId,
+        name: Option<String>,
+        target: MessageRef,
+        text: String,
+    },
+    /// A peer retracted one of its messages.
+    Deleted {
+        topic: TopicId,
+        from: NodeId,
+        name: Option<String>,
+        target: MessageRef,
+    },
+    /// A peer started typing, see [`ChatNode::typing_peers`] for who still
+    /// is.
+    Typing {
+        topic: TopicId,
+        from: NodeId,
+        name: Option<String>,
+    },
+    /// A peer announced that it joined the room.
+    PeerJoined {
+        topic: TopicId,
+        from: NodeId,
+        name: Option<String>,
+    },
+    /// A peer announced that it left the room.
+    PeerLeft {
+        topic: TopicId,
+        from: NodeId,
+        name: Option<String>,
+    },
+    /// A peer was not heard from within the peer timeout.
+    PeerOffline {
+        topic: TopicId,
+        from: NodeId,
+        name: Option<String>,
+    },
+    /// We lost contact with the room and try to rejoin it after `delay`.
+    Rejoining { topic: TopicId, delay: Duration },
+    /// A direct gossip neighbor connected.
+    NeighborUp { topic: TopicId, node_id: NodeId },
+    /// A direct gossip neighbor disconnected.
+    NeighborDown { topic: TopicId, node_id: NodeId },
+    /// A peer shared a file, fetch it with [`ChatNode::fetch_file`].
+    FileOffered {
+        topic: TopicId,
+        from: NodeId,
+        name: Option<String>,
+        offer: FileOffer,
+    },
+    /// A peer shared an image, which can be fetched like a file.
+    ImageShared {
+        topic: TopicId,
+        from: NodeId,
+        name: Option<String>,
+        image: ImageOffer,
+    },
+    /// A peer sent a voice note, which can be fetched like a file.
+    VoiceNote {
+        topic: TopicId,
+        from: NodeId,
+        name: Option<String>,
+        note: VoiceNote,
+    },
+    /// Earlier messages of a room we just joined, replayed by a member.
+    Backfilled {
+        topic: TopicId,
+        from: NodeId,
+        entries: Vec<HistoryEntry>,
+    },
+    /// A peer asked to send a command to an openHAB item.
+    ///
+    /// `result` is set when this node forwarded the command to its own
+    /// openHAB server.
+    ItemCommand {
+        topic: TopicId,
+        from: NodeId,
+        name: Option<String>,
+        item: String,
+        value: String,
+        result: Option<Result<(), String>>,
+    },
+    /// A peer's message matched an automation rule, which sent its command
+    /// to our openHAB server.
+    RuleFired {
+        topic: TopicId,
+        from: NodeId,
+        name: Option<String>,
+        rule: String,
+        item: String,
+        command: String,
+        result: Result<(), String>,
+    },
+    /// A Thing on a peer's openHAB server changed its status.
+    ThingStatusChanged {
+        topic: TopicId,
+        from: NodeId,
+        name: Option<String>,
+        thing: String,
+        label: Option<String>,
+        status: String,
+        detail: String,
+        ts: DateTime<Utc>,
+    },
+    /// An item on a peer's openHAB server changed its state.
+    ItemChanged {
+        topic: TopicId,
+        from: NodeId,
+        name: Option<String>,
+        item: String,
+        old: String,
+        new: String,
+        ts: DateTime<Utc>,
+    },
+    /// A moderator banned `node_id`, its messages are ignored from now on.
+    Banned {
+        topic: TopicId,
+        from: NodeId,
+        name: Option<String>,
+        node_id: NodeId,
+    },
+    /// A peer speaks another protocol version than we do, so some of its
+    /// messages may not be understood.
+    ProtocolMismatch {
+        topic: TopicId,
+        from: NodeId,
+        name: Option<String>,
+        protocol: u16,
+        agent: String,
+    },
+    /// A node joined with one of our invites after another node already
+    /// did, its messages are ignored from now on.
+    InviteReused {
+        topic: TopicId,
+        from: NodeId,
+        name: Option<String>,
+    },
+    /// A private message sent straight to us with `/msg`.
+    DirectMessage {
+        from: NodeId,
+        name: Option<String>,
+        text: String,
+    },
+    /// An event reported by the local openHAB server.
+    OpenHab(OpenHabEvent),
+    /// A payload failed decryption or signature verification.
+    Dropped {
+        topic: TopicId,
+        delivered_from: NodeId,
+        reason: String,
+    },
+}
+
+/// Configures and spawns a [`ChatNode`].
+pub struct NodeBuilder {
+    secret_key: Option<SecretKey>,
+    relay_mode: RelayMode,
+    bind_addr_v4: SocketAddrV4,
+    bind_addr_v6: Option<SocketAddrV6>,
+    dns_discovery: bool,
+    mdns_discovery: bool,
+    publish_pkarr: bool,
+    pkarr_relay: Option<Url>,
+    discovery: Vec<Box<dyn Discovery>>,
+    handlers: Vec<Arc<dyn MessageHandler>>,
+    home: Option<Arc<dyn HomeProvider>>,
+    rules: Rules,
+    read_receipts: bool,
+    history: Option<History>,
+    blobs_dir: Option<PathBuf>,
+    access: AccessConfig,
+    rate_limit: RateLimitConfig,
+    max_message_size: usize,
+    backfill_limit: usize,
+    heartbeat_interval: Duration,
+    peer_timeout: Duration,
+}
+
+impl Default for NodeBuilder {
+    fn default() -> Self {
+        Self {
+            secret_key: None,
+            relay_mode: RelayMode::Default,
+            bind_addr_v4: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+            bind_addr_v6: None,
+            dns_discovery: true,
+            mdns_discovery: true,
+            publish_pkarr: false,
+            pkarr_relay: None,
+            discovery: Vec::new(),
+            handlers: Vec::new(),
+            home: None,
+            rules: Rules::default(),
+            read_receipts: false,
+            history: None,
+            blobs_dir: None,
+            access: AccessConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            backfill_limit: DEFAULT_BACKFILL_LIMIT,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            peer_timeout: DEFAULT_PEER_TIMEOUT,
+        }
+    }
+}
+
+impl NodeBuilder {
+    /// Secret key of the node, a fresh one is generated if unset.
+    pub fn secret_key(mut self, secret_key: SecretKey) -> Self {
+        self.secret_key = Some(secret_key);
+        self
+    }
+
+    /// Relay servers to use, defaults to the n0 production relays.
+    pub fn relay_mode(mut self, relay_mode: RelayMode) -> Self {
+        self.relay_mode = relay_mode;
+        self
+    }
+
+    /// UDP port to bind on all IPv4 interfaces, 0 picks a random port.
+    pub fn bind_port(mut self, bind_port: u16) -> Self {
+        self.bind_addr_v4.set_port(bind_port);
+        self
+    }
+
+    /// IPv4 address and port to bind, defaults to `0.0.0.0:0`.
+    pub fn bind_addr_v4(mut self, addr: SocketAddrV4) -> Self {
+        self.bind_addr_v4 = addr;
+        self
+    }
+
+    /// IPv6 address and port to bind. Defaults to all interfaces on the port
+    /// after the IPv4 one; binding IPv6 is skipped if that fails.
+    pub fn bind_addr_v6(mut self, addr: SocketAddrV6) -> Self {
+        self.bind_addr_v6 = Some(addr);
+        self
+    }
+
+    /// Resolve nodes through the n0 DNS server, enabled by default.
+    pub fn dns_discovery(mut self, enabled: bool) -> Self {
+        self.dns_discovery = enabled;
+        self
+    }
+
+    /// Find nodes on the local network via mDNS, enabled by default.
+    pub fn mdns_discovery(mut self, enabled: bool) -> Self {
+        self.mdns_discovery = enabled;
+        self
+    }
+
+    /// Registers a plugin called for room events, after the ones registered
+    /// before it.
+    pub fn handler(mut self, handler: impl MessageHandler) -> Self {
+        self.handlers.push(Arc::new(handler));
+        self
+    }
+
+    /// Adds a custom discovery service next to the built-in ones.
+    pub fn add_discovery(mut self, discovery: impl Discovery + 'static) -> Self {
+        self.discovery.push(Box::new(discovery));
+        self
+    }
+
+    /// Sets the smart home server whose items we share, such as
+    /// [`OpenHab`](crate::openhab::OpenHab). Off by default.
+    pub fn home(mut self, home: Option<Arc<dyn HomeProvider>>) -> Self {
+        self.home = home;
+        self
+    }
+
+    /// Rules turning peers' messages into item commands, applied when a
+    /// smart home server is set.
+    pub fn rules(mut self, rules: Rules) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// Tells peers which messages we displayed, see
+    /// [`ChatNode::mark_seen`]. Off by default.
+    pub fn read_receipts(mut self, read_receipts: bool) -> Self {
+        self.read_receipts = read_receipts;
+        self
+    }
+
+    /// Records sent and received messages, off by default.
+    pub fn history(mut self, history: Option<History>) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Directory of the store for the files we share and fetch, a directory
+    /// named after the node in the system's temporary directory by default.
+    pub fn blobs_dir(mut self, dir: PathBuf) -> Self {
+        self.blobs_dir = Some(dir);
+        self
+    }
+
+    /// Which nodes' room messages to accept, everyone's by default.
+    pub fn access(mut self, access: AccessConfig) -> Self {
+        self.access = access;
+        self
+    }
+
+    /// How many messages per second each peer may send us.
+    pub fn rate_limit(mut self, rate_limit: RateLimitConfig) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    /// Largest encoded room message we send or accept, in bytes.
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    /// How many earlier messages to ask for when joining a room, 0 disables
+    /// backfill.
+    pub fn backfill_limit(mut self, limit: usize) -> Self {
+        self.backfill_limit = limit;
+        self
+    }
+
+    /// How often to broadcast a heartbeat to every room.
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self
+    }
+
+    /// How long a peer may stay silent before it is marked offline.
+    pub fn peer_timeout(mut self, timeout: Duration) -> Self {
+        self.peer_timeout = timeout;
+        self
+    }
+
+    /// Publish our addresses as a signed pkarr record, so peers can find us
+    /// by node id through DNS discovery. Disabled by default.
+    pub fn publish_pkarr(mut self, enabled: bool) -> Self {
+        self.publish_pkarr = enabled;
+        self
+    }
+
+    /// Pkarr relay to publish to and resolve from, instead of the n0 one.
+    pub fn pkarr_relay(mut self, url: Url) -> Self {
+        self.pkarr_relay = Some(url);
+        self
+    }
+
+    /// Binds the endpoint and starts the gossip protocol.
+    pub async fn spawn(self) -> Result<ChatNode> {
+        let secret_key = self
+            .secret_key
+            .unwrap_or_else(|| SecretKey::generate(rand::rngs::OsRng));
+
+        let mut services = self.discovery;
+        if self.dns_discovery {
+            match &self.pkarr_relay {
+                Some(url) => services.push(Box::new(PkarrResolver::new(url.clone()))),
+                None => services.push(Box::new(DnsDiscovery::n0_dns())),
+            }
+        }
+        if self.publish_pkarr {
+            let secret_key = secret_key.clone();
+            match &self.pkarr_relay {
+                Some(url) => services.push(Box::new(PkarrPublisher::new(secret_key, url.clone()))),
+                None => services.push(Box::new(PkarrPublisher::n0_dns(secret_key))),
+            }
+        }
+        if self.mdns_discovery {
+            services.push(Box::new(LocalSwarmDiscovery::new(secret_key.public())?));
+        }
+
+        let mut endpoint = Endpoint::builder()
+            .secret_key(secret_key)
+            .relay_mode(self.relay_mode.clone())
+            .bind_addr_v4(self.bind_addr_v4);
+        if !services.is_empty() {
+            let discovery = ConcurrentDiscovery::from_services(services);
+            endpoint = endpoint.discovery(Box::new(discovery));
+        }
+        if let Some(addr) = self.bind_addr_v6 {
+            endpoint = endpoint.bind_addr_v6(addr);
+        }
+        let endpoint = endpoint.bind().await?;
+        let (v4, v6) = endpoint.bound_sockets();
+        info!(%v4, ?v6, "bound sockets");
+        info!(node_id = %endpoint.node_id(), "endpoint bound");
+
+        let gossip = Gossip::builder()
+            // Lower limits are enforced by us, the gossip layer would drop the
+            // whole connection on an oversized frame.
+            .max_message_size(
+                self.max_message_size.max(DEFAULT_MAX_MESSAGE_SIZE) + GOSSIP_FRAME_OVERHEAD,
+            )
+            .spawn(endpoint.clone())
+            .await?;
+        let blobs_dir = self.blobs_dir.unwrap_or_else(|| {
+            std::env::temp_dir().join(format!("iroh-gossip-chat-{}", endpoint.node_id()))
+        });
+        let blobs = BlobStore::persistent(&blobs_dir)
+            .await
+            .with_context(|| format!("failed to open the file store {}", blobs_dir.display()))?
+            .build(&endpoint);
+
+        // Keep counting from where we stopped, so our new messages sort
+        // after the ones we sent before a restart.
+        let clock = match &self.history {
+            Some(history) => history.max_clock()?,
+            None => 0,
+        };
+
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        // Names announced in earlier sessions, until peers announce again.
+        let names = match &self.history {
+            Some(history) => history.names()?,
+            None => HashMap::new(),
+        };
+        let names = Arc::new(Mutex::new(names));
+        let access = Arc::new(Access::new(self.access));
+        let direct = DirectMessages {
+            names: Arc::clone(&names),
+            events: events.clone(),
+            access: Arc::clone(&access),
+        };
+        let rooms = Arc::default();
+        let backfill = Backfill {
+            rooms: Arc::clone(&rooms),
+            history: self.history.clone(),
+            access: Arc::clone(&access),
+        };
+
+        let router = Router::builder(endpoint.clone())
+            .accept(iroh_gossip::ALPN, gossip.clone())
+            .accept(direct::ALPN, direct)
+            .accept(iroh_blobs::ALPN, blobs.clone())
+            .accept(backfill::ALPN, backfill)
+            .accept(ping::ALPN, Ping)
+            .spawn()
+            .await?;
+
+        let node = ChatNode {
+            endpoint,
+            gossip,
+            blobs,
+            router,
+            home: self.home,
+            rules: Arc::new(self.rules),
+            read_receipts: self.read_receipts,
+            receipts: Default::default(),
+            typing: Default::default(),
+            latest: Default::default(),
+            reactions: Default::default(),
+            tombstones: Default::default(),
+            texts: Default::default(),
+            history: self.history,
+            access,
+            protocols: Default::default(),
+            invites: Default::default(),
+            rate_limit: self.rate_limit,
+            relays: !matches!(self.relay_mode, RelayMode::Disabled),
+            max_message_size: self.max_message_size,
+            backfill_limit: self.backfill_limit,
+            heartbeat_interval: self.heartbeat_interval,
+            peer_timeout: self.peer_timeout,
+            rooms,
+            names,
+            clock: Arc::new(AtomicU64::new(clock)),
+            files: Default::default(),
+            peer_items: Default::default(),
+            traffic: Default::default(),
+            pending_acks: Default::default(),
+            handlers: Arc::new(Mutex::new(self.handlers)),
+            events,
+        };
+        tokio::spawn(handler::dispatch(node.clone()));
+        Ok(node)
+    }
+}
+
+/// Who may talk to us: the configured allow and block lists, plus the nodes
+/// banned by a moderator while we were running.
+#[derive(Debug)]
+pub(crate) struct Access {
+    config: AccessConfig,
+    banned: Mutex<HashSet<NodeId>>,
+}
+
+impl Access {
+    fn new(config: AccessConfig) -> Self {
+        Self {
+            config,
+            banned: Default::default(),
+        }
+    }
+
+    /// Whether `node_id` may send us room messages, direct messages and
+    /// backfill requests.
+    pub(crate) fn permits(&self, node_id: &NodeId) -> bool {
+        self.config.permits(node_id) && !self.banned.lock().unwrap().contains(node_id)
+    }
+
+    fn ban(&self, node_id: NodeId) {
+        self.banned.lock().unwrap().insert(node_id);
+    }
+}
+
+/// A room this node has joined.
+#[derive(Clone)]
+pub(crate) struct Room {
+    sender: Arc<GossipSender>,
+    pub(crate) cipher: Option<RoomCipher>,
+    neighbors: Arc<Mutex<BTreeSet<NodeId>>>,
+    /// Peers present in the room, with the time we last heard from them.
+    roster: Arc<Mutex<BTreeMap<NodeId, Instant>>>,
+    /// Nodes from the ticket we joined with.
+    bootstrap: Arc<Vec<NodeId>>,
+    /// Set while we try to reconnect to lost neighbors.
+    rejoining: Arc<AtomicBool>,
+    /// Cancelled when we leave, stopping the tasks of this join even if the
+    /// room is joined again before they notice.
+    left: CancellationToken,
+}
+
+impl Room {
+    /// Nodes to rejoin the room through: the bootstrap nodes and everyone
+    /// we have seen in the room.
+    fn known_peers(&self) -> Vec<NodeId> {
+        let roster = self.roster.lock().unwrap();
+        let mut peers: BTreeSet<NodeId> = roster.keys().copied().collect();
+        peers.extend(self.bootstrap.iter().copied());
+        peers.into_iter().collect()
+    }
+
+    /// Peers that can be expected to answer: our neighbors and everyone we
+    /// heard from within `timeout`. Bootstrap nodes only count once seen.
+    fn live_peers(&self, timeout: Duration) -> BTreeSet<NodeId> {
+        let roster = self.roster.lock().unwrap();
+        let mut peers: BTreeSet<NodeId> = roster
+            .iter()
+            .filter(|(_, seen)| seen.elapsed() < timeout)
+            .map(|(node_id, _)| *node_id)
+            .collect();
+        peers.extend(self.neighbors.lock().unwrap().iter().copied());
+        peers
+    }
+}
+
+/// A peer in one of our rooms, as listed by [`ChatNode::peers`].
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub node_id: NodeId,
+    pub name: Option<String>,
+    /// Whether we are directly connected to it in the gossip swarm.
+    pub neighbor: bool,
+    /// How long ago we last heard from it, unset if we never did.
+    pub last_seen: Option<Duration>,
+}
+
+/// Outcome of [`ChatNode::send_reliable`].
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    /// Lamport clock of the first transmission, under which it is recorded.
+    pub clock: u64,
+    /// How often the message was sent.
+    pub attempts: u32,
+    /// Peers that acknowledged the message.
+    pub acked: BTreeSet<NodeId>,
+    /// Peers live in the room when the message was sent.
+    pub peers: usize,
+}
+
+/// Nodes that reacted to a message, by emoji.
+type Reactions = BTreeMap<String, BTreeSet<NodeId>>;
+
+/// Gossip traffic exchanged with a peer since we started, as listed by
+/// [`ChatNode::traffic`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PeerTraffic {
+    /// Messages we broadcast while it was our neighbor.
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    /// Messages it wrote, whichever neighbor delivered them.
+    pub messages_received: u64,
+    pub bytes_received: u64,
+}
+
+/// A running chat node.
+///
+/// Cloning is cheap and all clones share the same endpoint and rooms.
+#[derive(Clone)]
+pub struct ChatNode {
+    endpoint: Endpoint,
+    gossip: Gossip,
+    blobs: BlobStore,
+    router: Router,
+    home: Option<Arc<dyn HomeProvider>>,
+    rules: Arc<Rules>,
+    read_receipts: bool,
+    /// Highest clock displayed per room since the last read receipt.
+    receipts: Arc<Mutex<HashMap<TopicId, u64>>>,
+    /// When we last told each room that we are typing, and when each peer
+    /// last told us, by room and node.
+    typing: Arc<Mutex<HashMap<(TopicId, NodeId), Instant>>>,
+    /// Clock of the latest chat message from each node in each room.
+    latest: Arc<Mutex<HashMap<(TopicId, NodeId), u64>>>,
+    reactions: Arc<Mutex<HashMap<MessageRef, Reactions>>>,
+    /// Messages deleted by their sender, ignored should they arrive late.
+    tombstones: Arc<Mutex<HashSet<MessageRef>>>,
+    /// Texts of recent chat messages, to quote in replies.
+    texts: Arc<Mutex<RecentTexts>>,
+    history: Option<History>,
+    access: Arc<Access>,
+    /// Protocol versions announced by peers in their hello.
+    protocols: Arc<Mutex<HashMap<NodeId, u16>>>,
+    /// Single-use invites we handed out, by nonce, with the node that
+    /// redeemed each.
+    invites: Arc<Mutex<HashMap<u64, Option<NodeId>>>>,
+    rate_limit: RateLimitConfig,
+    /// Whether we connect to relays at all.
+    relays: bool,
+    max_message_size: usize,
+    backfill_limit: usize,
+    heartbeat_interval: Duration,
+    peer_timeout: Duration,
+    rooms: Arc<Mutex<HashMap<TopicId, Room>>>,
+    names: Arc<Mutex<HashMap<NodeId, String>>>,
+    /// Lamport clock, advanced on every message sent or received.
+    clock: Arc<AtomicU64>,
+    /// Files offered in any of our rooms, by hash.
+    files: Arc<Mutex<HashMap<Hash, FileOffer>>>,
+    /// Item states mirrored from the openHAB servers of our peers.
+    peer_items: Arc<Mutex<BTreeMap<NodeId, ItemStates>>>,
+    /// Traffic exchanged with each peer.
+    traffic: Arc<Mutex<BTreeMap<NodeId, PeerTraffic>>>,
+    /// Reliable messages waiting for acks, fed with the nodes that acked.
+    pending_acks: Arc<Mutex<HashMap<MessageId, mpsc::UnboundedSender<NodeId>>>>,
+    /// Plugins called for every event, in order.
+    handlers: Arc<Mutex<Vec<Arc<dyn MessageHandler>>>>,
+    events: broadcast::Sender<Event>,
+}
+
+impl ChatNode {
+    pub fn builder() -> NodeBuilder {
+        NodeBuilder::default()
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.endpoint.node_id()
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// Our smart home server, if we have one.
+    pub fn home(&self) -> Option<&Arc<dyn HomeProvider>> {
+        self.home.as_ref()
+    }
+
+    /// Sends `command` to `item` on our smart home server, `None` without
+    /// one.
+    async fn command_home(&self, item: &str, command: &str) -> Option<Result<()>> {
+        let home = self.home.as_ref()?;
+        Some(home.send_command(item, command).await)
+    }
+
+    /// The message history, if enabled.
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// Subscribes to the room on `topic`, bootstrapping from `nodes`.
+    ///
+    /// Resolves once at least one neighbor is connected, unless `nodes` is
+    /// empty, in which case it waits for the first peer to join us.
+    #[instrument(skip_all, fields(topic = %topic))]
+    pub async fn join(
+        &self,
+        topic: TopicId,
+        nodes: Vec<NodeAddr>,
+        cipher: Option<RoomCipher>,
+    ) -> Result<()> {
+        ensure!(
+            !self.rooms.lock().unwrap().contains_key(&topic),
+            "already in room {topic}"
+        );
+        let node_ids: Vec<NodeId> = nodes.iter().map(|p| p.node_id).collect();
+        // Nodes given by id only are left to discovery.
+        for node in nodes.into_iter().filter(|node| !node.is_empty()) {
+            self.endpoint.add_node_addr(node)?;
+        }
+        let (sender, receiver) = self
+            .gossip
+            .subscribe_and_join(topic, node_ids.clone())
+            .await?
+            .split();
+        let neighbors: BTreeSet<NodeId> = receiver.neighbors().collect();
+        info!(neighbors = neighbors.len(), "joined room");
+        let room = Room {
+            sender: Arc::new(sender),
+            cipher,
+            neighbors: Arc::new(Mutex::new(neighbors.clone())),
+            roster: Default::default(),
+            bootstrap: Arc::new(node_ids),
+            rejoining: Default::default(),
+            left: CancellationToken::new(),
+        };
+        self.rooms.lock().unwrap().insert(topic, room.clone());
+        self.count_neighbors();
+        // Catch up on what was said before we arrived.
+        if let Some(&peer) = neighbors.iter().find(|peer| self.permits(peer)) {
+            if self.backfill_limit > 0 {
+                tokio::spawn(self.clone().backfill(topic, peer, room.cipher.clone()));
+            }
+        }
+        tokio::spawn(
+            self.clone()
+                .subscription_loop(topic, room.clone(), receiver),
+        );
+        tokio::spawn(self.clone().heartbeat_loop(topic, room));
+
+        let joined = Message::Joined {
+            from: self.node_id(),
+            reply: false,
+        };
+        self.broadcast(topic, &joined).await?;
+        self.say_hello(topic).await?;
+        Ok(())
+    }
+
+    /// Tells the room which protocol version we speak.
+    async fn say_hello(&self, topic: TopicId) -> Result<()> {
+        let hello = Message::Hello {
+            from: self.node_id(),
+            protocol: PROTOCOL_VERSION,
+            agent: AGENT.to_string(),
+        };
+        self.broadcast(topic, &hello).await?;
+        Ok(())
+    }
+
+    /// Announces that we leave the room on `topic` and unsubscribes from it.
+    pub async fn leave(&self, topic: TopicId) -> Result<()> {
+        let left = Message::Left {
+            from: self.node_id(),
+        };
+        let result = self.broadcast(topic, &left).await;
+        // Dropping the sender and the receive loop's receiver unsubscribes.
+        // Cancelled under the lock, so the room's tasks never see a later
+        // join of the same topic as theirs.
+        if let Some(room) = self.rooms.lock().unwrap().remove(&topic) {
+            room.left.cancel();
+        }
+        self.count_neighbors();
+        result.map(drop)
+    }
+
+    /// Topics of all rooms we joined.
+    pub fn rooms(&self) -> Vec<TopicId> {
+        self.rooms.lock().unwrap().keys().copied().collect()
+    }
+
+    /// Direct gossip neighbors across all rooms.
+    pub fn neighbors(&self) -> BTreeSet<NodeId> {
+        self.rooms
+            .lock()
+            .unwrap()
+            .values()
+            .flat_map(|room| room.neighbors.lock().unwrap().clone())
+            .collect()
+    }
+
+    /// Peers present in any of our rooms, as announced by presence messages.
+    pub fn roster(&self) -> BTreeSet<NodeId> {
+        self.rooms
+            .lock()
+            .unwrap()
+            .values()
+            .flat_map(|room| {
+                room.roster
+                    .lock()
+                    .unwrap()
+                    .keys()
+                    .copied()
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Gossip traffic exchanged with each peer since we started.
+    pub fn traffic(&self) -> BTreeMap<NodeId, PeerTraffic> {
+        self.traffic.lock().unwrap().clone()
+    }
+
+    /// Everyone present in or connected to any of our rooms.
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let neighbors = self.neighbors();
+        let mut last_seen: BTreeMap<NodeId, Instant> = BTreeMap::new();
+        for room in self.rooms.lock().unwrap().values() {
+            for (&node_id, &seen) in room.roster.lock().unwrap().iter() {
+                let latest = last_seen.entry(node_id).or_insert(seen);
+                *latest = (*latest).max(seen);
+            }
+        }
+        let node_ids: BTreeSet<NodeId> = last_seen.keys().chain(&neighbors).copied().collect();
+        node_ids
+            .into_iter()
+            .map(|node_id| PeerInfo {
+                node_id,
+                name: self.name_of(&node_id),
+                neighbor: neighbors.contains(&node_id),
+                last_seen: last_seen.get(&node_id).map(Instant::elapsed),
+            })
+            .collect()
+    }
+
+    /// The relay peers can reach us through. Right after startup we may not
+    /// have picked one yet, so this waits a moment for it.
+    pub async fn home_relay(&self) -> Option<RelayUrl> {
+        if !self.relays {
+            return None;
+        }
+        let mut home_relay = self.endpoint.home_relay();
+        tokio::time::timeout(HOME_RELAY_TIMEOUT, home_relay.initialized())
+            .await
+            .ok()?
+            .ok()
+    }
+
+    /// A ticket for the room on `topic` that points at this node.
+    pub async fn ticket(&self, topic: TopicId) -> Result<Ticket> {
+        let mut me = self.endpoint.node_addr().await?;
+        if me.relay_url.is_none() && self.relays {
+            me.relay_url = self.home_relay().await;
+            if me.relay_url.is_none() {
+                warn!("no home relay yet, the ticket only has direct addresses");
+            }
+        }
+        Ok(Ticket::new(topic, vec![me]))
+    }
+
+    /// Creates a single-use invite to the room on `topic` that expires after
+    /// `ttl`.
+    ///
+    /// A second node joining with it is ignored from then on.
+    pub async fn invite(&self, topic: TopicId, ttl: Duration) -> Result<Ticket> {
+        let mut ticket = self.ticket(topic).await?;
+        let nonce = rand::random();
+        ticket.expires = Some((Utc::now() + ttl).timestamp().try_into()?);
+        ticket.nonce = Some(nonce);
+        self.invites.lock().unwrap().insert(nonce, None);
+        Ok(ticket)
+    }
+
+    /// Tells the inviting node that we joined with its invite.
+    pub async fn redeem(&self, topic: TopicId, nonce: u64) -> Result<()> {
+        let redeem = Message::Redeem {
+            from: self.node_id(),
+            nonce,
+        };
+        self.broadcast(topic, &redeem).await?;
+        Ok(())
+    }
+
+    /// Stamps, signs, optionally encrypts, and broadcasts a message to a room.
+    ///
+    /// Returns the Lamport clock the message was stamped with.
+    pub async fn broadcast(&self, topic: TopicId, message: &Message) -> Result<u64> {
+        self.broadcast_stamped(topic, message, Vec::new(), None)
+            .await
+    }
+
+    async fn broadcast_stamped(
+        &self,
+        topic: TopicId,
+        message: &Message,
+        readings: Vec<SensorReading>,
+        in_reply_to: Option<MessageRef>,
+    ) -> Result<u64> {
+        let room = self
+            .rooms
+            .lock()
+            .unwrap()
+            .get(&topic)
+            .cloned()
+            .with_context(|| format!("not in room {topic}"))?;
+        let clock = self.tick();
+        let stamped = Stamped {
+            clock,
+            message: message.clone(),
+            readings,
+            in_reply_to,
+        };
+        let compress = self.can_compress(&room);
+        let bytes = encode_message(
+            self.endpoint.secret_key(),
+            room.cipher.as_ref(),
+            compress,
+            &stamped,
+        );
+        if bytes.len() <= self.max_message_size {
+            self.send_bytes(&room, bytes).await?;
+            return Ok(clock);
+        }
+        // Split the signed envelope into messages of their own, which the
+        // receivers put back together.
+        let envelope =
+            SignedMessage::sign_and_encode(self.endpoint.secret_key(), &stamped, compress);
+        let chunk_size = self.max_message_size.saturating_sub(CHUNK_OVERHEAD).max(1);
+        let total = envelope.len().div_ceil(chunk_size);
+        if total > MAX_CHUNKS as usize {
+            warn!(size = envelope.len(), "not sending oversized message");
+            bail!(
+                "message too large ({} bytes, at most {})",
+                envelope.len(),
+                MAX_CHUNKS as usize * chunk_size
+            );
+        }
+        let msg_id = SignedMessage::id(&envelope);
+        debug!(size = envelope.len(), total, "sending message in chunks");
+        for (index, data) in envelope.chunks(chunk_size).enumerate() {
+            let chunk = Stamped {
+                clock: self.tick(),
+                message: Message::Chunk {
+                    from: self.node_id(),
+                    msg_id,
+                    index: index as u32,
+                    total: total as u32,
+                    data: data.to_vec(),
+                },
+                readings: Vec::new(),
+                in_reply_to: None,
+            };
+            let bytes = encode_message(
+                self.endpoint.secret_key(),
+                room.cipher.as_ref(),
+                false,
+                &chunk,
+            );
+            self.send_bytes(&room, bytes).await?;
+        }
+        Ok(clock)
+    }
+
+    /// Hands an encoded message to the gossip layer and counts it.
+    async fn send_bytes(&self, room: &Room, bytes: Vec<u8>) -> Result<()> {
+        let size = bytes.len();
+        if let Err(err) = room.sender.broadcast(bytes.into()).await {
+            metrics().broadcast_errors.inc();
+            return Err(err.into());
+        }
+        metrics().messages_sent.inc();
+        let neighbors = room.neighbors.lock().unwrap().clone();
+        let mut traffic = self.traffic.lock().unwrap();
+        for neighbor in neighbors {
+            let peer = traffic.entry(neighbor).or_default();
+            peer.messages_sent += 1;
+            peer.bytes_sent += size as u64;
+            metrics().record_sent(&neighbor, size);
+        }
+        Ok(())
+    }
+
+    /// Broadcasts a chat message with optional sensor readings and returns
+    /// its Lamport clock.
+    pub async fn send_text(
+        &self,
+        topic: TopicId,
+        text: String,
+        readings: Vec<SensorReading>,
+    ) -> Result<u64> {
+        self.send_chat(topic, text, readings, None).await
+    }
+
+    /// Broadcasts a chat message answering `in_reply_to` and returns its
+    /// Lamport clock.
+    pub async fn send_reply(
+        &self,
+        topic: TopicId,
+        text: String,
+        readings: Vec<SensorReading>,
+        in_reply_to: MessageRef,
+    ) -> Result<u64> {
+        self.send_chat(topic, text, readings, Some(in_reply_to))
+            .await
+    }
+
+    async fn send_chat(
+        &self,
+        topic: TopicId,
+        text: String,
+        readings: Vec<SensorReading>,
+        in_reply_to: Option<MessageRef>,
+    ) -> Result<u64> {
+        let message = Message::Message {
+            from: self.node_id(),
+            text: text.clone(),
+        };
+        let clock = self
+            .broadcast_stamped(topic, &message, readings.clone(), in_reply_to)
+            .await?;
+        self.record(topic, self.node_id(), clock, text, readings, in_reply_to);
+        Ok(clock)
+    }
+
+    /// The text of a recent chat message, or of one in the history, unless
+    /// it was deleted.
+    pub fn message_text(&self, target: &MessageRef) -> Option<String> {
+        if let Some(text) = self.texts.lock().unwrap().get(target) {
+            return Some(text.to_string());
+        }
+        self.history.as_ref()?.text(target).ok().flatten()
+    }
+
+    /// Tells a room that we are composing a message. Call it on every
+    /// keystroke, notifications are sent every few seconds at most.
+    pub async fn typing(&self, topic: TopicId) -> Result<()> {
+        let key = (topic, self.node_id());
+        {
+            let mut typing = self.typing.lock().unwrap();
+            if let Some(last) = typing.get(&key) {
+                if last.elapsed() < TYPING_INTERVAL {
+                    return Ok(());
+                }
+            }
+            typing.insert(key, Instant::now());
+        }
+        let message = Message::Typing {
+            from: self.node_id(),
+        };
+        self.broadcast(topic, &message).await?;
+        Ok(())
+    }
+
+    /// Peers currently typing in a room.
+    pub fn typing_peers(&self, topic: &TopicId) -> Vec<NodeId> {
+        let mut typing = self.typing.lock().unwrap();
+        typing.retain(|_, last| last.elapsed() < TYPING_TIMEOUT);
+        typing
+            .keys()
+            .filter(|(room, node_id)| room == topic && *node_id != self.node_id())
+            .map(|(_, node_id)| *node_id)
+            .collect()
+    }
+
+    /// Clock of the last chat message we sent to a room.
+    pub fn last_sent(&self, topic: &TopicId) -> Option<u64> {
+        self.latest
+            .lock()
+            .unwrap()
+            .get(&(*topic, self.node_id()))
+            .copied()
+    }
+
+    /// The latest chat message in a room from `from`, or from anyone but us
+    /// when unset.
+    pub fn latest_message(&self, topic: &TopicId, from: Option<NodeId>) -> Option<MessageRef> {
+        self.latest
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((room, sender), _)| {
+                room == topic && from.map_or(*sender != self.node_id(), |from| *sender == from)
+            })
+            .map(|((_, from), clock)| MessageRef {
+                from: *from,
+                clock: *clock,
+            })
+            .max_by_key(|target| (target.clock, target.from))
+    }
+
+    /// Reacts to a chat message in a room with an emoji.
+    pub async fn react(&self, topic: TopicId, target: MessageRef, emoji: String) -> Result<()> {
+        ensure!(valid_reaction(&emoji), "not an emoji: {emoji}");
+        let message = Message::Reaction {
+            from: self.node_id(),
+            target_msg_id: target,
+            emoji: emoji.clone(),
+        };
+        self.broadcast(topic, &message).await?;
+        self.add_reaction(target, self.node_id(), emoji);
+        Ok(())
+    }
+
+    /// Replaces the text of one of our messages in a room.
+    pub async fn edit(&self, topic: TopicId, target: MessageRef, text: String) -> Result<()> {
+        ensure!(
+            target.from == self.node_id(),
+            "can only edit our own messages"
+        );
+        let message = Message::Edit {
+            from: self.node_id(),
+            target,
+            new_text: text.clone(),
+        };
+        self.broadcast(topic, &message).await?;
+        self.texts.lock().unwrap().insert(target, text.clone());
+        if let Some(history) = &self.history {
+            history.edit(&target, &text).ok();
+        }
+        Ok(())
+    }
+
+    /// Retracts one of our messages in a room.
+    pub async fn delete(&self, topic: TopicId, target: MessageRef) -> Result<()> {
+        ensure!(
+            target.from == self.node_id(),
+            "can only delete our own messages"
+        );
+        let message = Message::Delete {
+            from: self.node_id(),
+            target,
+        };
+        self.broadcast(topic, &message).await?;
+        self.tombstones.lock().unwrap().insert(target);
+        self.texts.lock().unwrap().remove(&target);
+        if let Some(history) = &self.history {
+            history.delete(&target).ok();
+        }
+        Ok(())
+    }
+
+    /// Number of nodes that reacted to a message with each emoji, as far as
+    /// we heard since we started.
+    pub fn reactions(&self, target: &MessageRef) -> BTreeMap<String, usize> {
+        self.reactions
+            .lock()
+            .unwrap()
+            .get(target)
+            .map(|emojis| {
+                emojis
+                    .iter()
+                    .map(|(emoji, nodes)| (emoji.clone(), nodes.len()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Counts a reaction, returning false if it was counted before.
+    fn add_reaction(&self, target: MessageRef, from: NodeId, emoji: String) -> bool {
+        if let Some(history) = &self.history {
+            history.insert_reaction(&target, from, &emoji).ok();
+        }
+        self.reactions
+            .lock()
+            .unwrap()
+            .entry(target)
+            .or_default()
+            .entry(emoji)
+            .or_default()
+            .insert(from)
+    }
+
+    /// Notes that the room's messages up to `clock` were displayed, and
+    /// tells peers shortly after if read receipts are enabled.
+    ///
+    /// Messages displayed in quick succession share one receipt.
+    pub fn mark_seen(&self, topic: TopicId, clock: u64) {
+        if !self.read_receipts {
+            return;
+        }
+        let mut receipts = self.receipts.lock().unwrap();
+        let due = receipts.is_empty();
+        let seen = receipts.entry(topic).or_default();
+        *seen = (*seen).max(clock);
+        if !due {
+            return;
+        }
+        let node = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(RECEIPT_DELAY).await;
+            let receipts = std::mem::take(&mut *node.receipts.lock().unwrap());
+            for (topic, clock) in receipts {
+                let seen = Message::Seen {
+                    from: node.node_id(),
+                    clock,
+                };
+                node.broadcast(topic, &seen).await.ok();
+            }
+        });
+    }
+
+    /// Broadcasts a chat message and sends it again until a majority of the
+    /// peers live in the room acknowledged it: our neighbors and those heard
+    /// from within the peer timeout.
+    ///
+    /// Fails if too few peers acked after [`RELIABLE_ATTEMPTS`] attempts,
+    /// though the message may still have reached some.
+    pub async fn send_reliable(&self, topic: TopicId, text: String) -> Result<Delivery> {
+        let known: BTreeSet<NodeId> = {
+            let rooms = self.rooms.lock().unwrap();
+            let room = rooms
+                .get(&topic)
+                .with_context(|| format!("not in room {topic}"))?;
+            let mut known = room.live_peers(self.peer_timeout);
+            known.remove(&self.node_id());
+            known
+        };
+        ensure!(!known.is_empty(), "no peers in the room to acknowledge");
+        let quorum = known.len() / 2 + 1;
+        let msg_id: MessageId = rand::random();
+        let (acks_tx, mut acks) = mpsc::unbounded_channel();
+        self.pending_acks.lock().unwrap().insert(msg_id, acks_tx);
+        let message = Message::Reliable {
+            from: self.node_id(),
+            msg_id,
+            text: text.clone(),
+        };
+        let mut acked = BTreeSet::new();
+        let mut first_clock = None;
+        let mut attempts = 0;
+        let result = async {
+            while attempts < RELIABLE_ATTEMPTS && acked.len() < quorum {
+                attempts += 1;
+                let clock = self.broadcast(topic, &message).await?;
+                if first_clock.is_none() {
+                    first_clock = Some(clock);
+                    self.record(topic, self.node_id(), clock, text.clone(), Vec::new(), None);
+                }
+                let deadline = tokio::time::Instant::now() + RETRANSMIT_INTERVAL;
+                while acked.len() < quorum {
+                    match tokio::time::timeout_at(deadline, acks.recv()).await {
+                        Ok(Some(node_id)) if known.contains(&node_id) => {
+                            acked.insert(node_id);
+                        }
+                        Ok(Some(_)) => {}
+                        Ok(None) | Err(_) => break,
+                    }
+                }
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        self.pending_acks.lock().unwrap().remove(&msg_id);
+        result?;
+        ensure!(
+            acked.len() >= quorum,
+            "only {} of {} peers acknowledged after {attempts} attempts, {quorum} needed",
+            acked.len(),
+            known.len()
+        );
+        Ok(Delivery {
+            clock: first_clock.unwrap_or_default(),
+            attempts,
+            acked,
+            peers: known.len(),
+        })
+    }
+
+    /// Advances our clock for a message we send and returns its new value.
+    fn tick(&self) -> u64 {
+        let advance = |clock: u64| Some(clock.saturating_add(1));
+        let previous = self
+            .clock
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, advance)
+            .expect("the update always succeeds");
+        previous.saturating_add(1)
+    }
+
+    /// Advances our clock past one seen on a received message, failing if
+    /// it is implausibly far ahead of ours.
+    fn witness(&self, clock: u64) -> Result<()> {
+        let advance = |ours: u64| {
+            (clock <= ours.saturating_add(MAX_CLOCK_JUMP))
+                .then(|| ours.max(clock).saturating_add(1))
+        };
+        self.clock
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, advance)
+            .map_err(|ours| anyhow!("clock {clock} is too far ahead of ours ({ours})"))?;
+        Ok(())
+    }
+
+    /// Announces our display name to a room.
+    pub async fn announce_name(&self, topic: TopicId, name: String) -> Result<()> {
+        self.names
+            .lock()
+            .unwrap()
+            .insert(self.node_id(), name.clone());
+        let message = Message::AboutMe {
+            from: self.node_id(),
+            name,
+        };
+        self.broadcast(topic, &message).await?;
+        Ok(())
+    }
+
+    /// Asks the room to send `value` to an openHAB item, and sends it to our
+    /// own openHAB server if we have one.
+    pub async fn send_command(&self, topic: TopicId, item: String, value: String) -> Result<()> {
+        let message = Message::Command {
+            from: self.node_id(),
+            item: item.clone(),
+            value: value.clone(),
+        };
+        self.broadcast(topic, &message).await?;
+        if let Some(result) = self.command_home(&item, &value).await {
+            result.context("sent to the room, but our own smart home server failed")?;
+        }
+        Ok(())
+    }
+
+    /// Bans `node_id` from every room: peers that list us as a moderator
+    /// ignore its messages from now on, and so do we.
+    pub async fn ban(&self, topic: TopicId, node_id: NodeId) -> Result<()> {
+        ensure!(
+            self.access.config.moderators.contains(&self.node_id()),
+            "only moderators can ban"
+        );
+        ensure!(node_id != self.node_id(), "cannot ban ourselves");
+        self.access.ban(node_id);
+        let message = Message::Ban {
+            from: self.node_id(),
+            node_id,
+        };
+        self.broadcast(topic, &message).await?;
+        Ok(())
+    }
+
+    /// Whether room messages from `node_id` are accepted.
+    fn permits(&self, node_id: &NodeId) -> bool {
+        self.access.permits(node_id)
+    }
+
+    /// Shares the file at `path` with a room.
+    pub async fn share_file(&self, topic: TopicId, path: &Path) -> Result<FileOffer> {
+        let name = path
+            .file_name()
+            .context("not a file")?
+            .to_string_lossy()
+            .into_owned();
+        let (hash, size) = files::import(&self.blobs, path).await?;
+        let offer = FileOffer {
+            name,
+            size,
+            ticket: files::ticket(&self.blobs, hash).await?,
+        };
+        let message = Message::File {
+            from: self.node_id(),
+            name: offer.name.clone(),
+            size,
+            ticket: offer.ticket.clone(),
+        };
+        self.broadcast(topic, &message).await?;
+        Ok(offer)
+    }
+
+    /// Shares the image at `path` with a room, for peers to show inline.
+    pub async fn share_image(&self, topic: TopicId, path: &Path) -> Result<ImageOffer> {
+        let (mime, width, height) = files::image_info(path)?;
+        let (hash, size) = files::import(&self.blobs, path).await?;
+        let ticket = files::ticket(&self.blobs, hash).await?;
+        let image = ImageOffer {
+            file: FileOffer {
+                name: files::image_name(&hash, &mime)?,
+                size,
+                ticket: ticket.clone(),
+            },
+            mime,
+            width,
+            height,
+        };
+        let message = Message::Image {
+            from: self.node_id(),
+            blob_hash: hash,
+            mime: image.mime.clone(),
+            width,
+            height,
+            size,
+            addr: ticket.node_addr().clone(),
+        };
+        self.broadcast(topic, &message).await?;
+        Ok(image)
+    }
+
+    /// Shares the voice note recorded at `path`, `secs` seconds long.
+    pub async fn share_voice(&self, topic: TopicId, path: &Path, secs: u32) -> Result<VoiceNote> {
+        let (hash, size) = files::import(&self.blobs, path).await?;
+        let ticket = files::ticket(&self.blobs, hash).await?;
+        let message = Message::Voice {
+            from: self.node_id(),
+            blob_hash: hash,
+            secs,
+            size,
+            addr: ticket.node_addr().clone(),
+        };
+        self.broadcast(topic, &message).await?;
+        Ok(VoiceNote {
+            file: FileOffer {
+                name: files::voice_name(&hash),
+                size,
+                ticket,
+            },
+            secs,
+        })
+    }
+
+    /// Finds a file offered in one of our rooms by a prefix of its hash.
+    pub fn file_offer(&self, prefix: &str) -> Result<FileOffer> {
+        let files = self.files.lock().unwrap();
+        let matches: Vec<&FileOffer> = files
+            .iter()
+            .filter(|(hash, _)| hash.to_string().starts_with(prefix))
+            .map(|(_, offer)| offer)
+            .collect();
+        match matches[..] {
+            [offer] => Ok(offer.clone()),
+            [] => bail!("no file matches {prefix}"),
+            _ => bail!("{prefix} matches several files"),
+        }
+    }
+
+    /// Downloads an offered file into `dir` and returns where it was written.
+    ///
+    /// Fails without writing anything if the file is larger than `max_size`.
+    pub async fn fetch_file(
+        &self,
+        offer: &FileOffer,
+        dir: &Path,
+        max_size: Option<u64>,
+    ) -> Result<PathBuf> {
+        files::download(&self.blobs, offer, dir, max_size).await
+    }
+
+    /// Sends a private message over a direct connection to `node_id`.
+    pub async fn send_direct(&self, node_id: NodeId, text: String) -> Result<()> {
+        direct::send(&self.endpoint, node_id, text).await
+    }
+
+    /// Measures the round-trip time to `node_id` over a direct connection.
+    pub async fn ping(&self, node_id: NodeId) -> Result<Pong> {
+        ping::ping(&self.endpoint, node_id).await
+    }
+
+    /// Finds a peer by full node id, display name or short node id prefix.
+    pub fn resolve_peer(&self, query: &str) -> Result<NodeId> {
+        if let Ok(node_id) = query.parse() {
+            return Ok(node_id);
+        }
+        let by_name: Vec<NodeId> = self
+            .names
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, name)| name.as_str() == query)
+            .map(|(id, _)| *id)
+            .collect();
+        let candidates = if by_name.is_empty() {
+            self.roster()
+                .union(&self.neighbors())
+                .filter(|id| id.to_string().starts_with(query))
+                .copied()
+                .collect()
+        } else {
+            by_name
+        };
+        match candidates[..] {
+            [node_id] => Ok(node_id),
+            [] => bail!("no peer matches {query}"),
+            _ => bail!("{query} matches several peers"),
+        }
+    }
+
+    /// Nodes mentioned in `text` by display name or node id, see
+    /// [`message::mentions`]. Node ids may be shortened to a prefix of at
+    /// least six characters that matches a single node.
+    pub fn mentions(&self, text: &str) -> BTreeSet<NodeId> {
+        message::mentions(text)
+            .filter_map(|mention| self.resolve_mention(mention))
+            .collect()
+    }
+
+    fn resolve_mention(&self, mention: &str) -> Option<NodeId> {
+        if let Ok(node_id) = mention.parse() {
+            return Some(node_id);
+        }
+        let names = self.names.lock().unwrap();
+        if let Some((node_id, _)) = names
+            .iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(mention))
+        {
+            return Some(*node_id);
+        }
+        drop(names);
+        if mention.len() < MIN_MENTION_PREFIX {
+            return None;
+        }
+        let mut known = self.roster();
+        known.extend(self.neighbors());
+        known.insert(self.node_id());
+        let mut matching = known
+            .into_iter()
+            .filter(|id| id.to_string().starts_with(mention));
+        match (matching.next(), matching.next()) {
+            (Some(node_id), None) => Some(node_id),
+            _ => None,
+        }
+    }
+
+    /// Display name announced by `node_id`, if any.
+    pub fn name_of(&self, node_id: &NodeId) -> Option<String> {
+        self.names.lock().unwrap().get(node_id).cloned()
+    }
+
+    /// Publishes an event from the openHAB server to [`ChatNode::events`]
+    /// subscribers.
+    ///
+    /// State changes of monitored items are also broadcast to all rooms, so
+    /// peers can mirror them.
+    pub fn publish_openhab_event(&self, event: OpenHabEvent) {
+        if let OpenHabEvent::ItemStateChanged {
+            item,
+            old_state,
+            state,
+        } = &event
+        {
+            let monitored = self
+                .home
+                .as_ref()
+                .is_some_and(|home| home.items().contains(item));
+            if monitored {
+                let node = self.clone();
+                let (item, old, new) = (item.clone(), old_state.clone(), state.clone());
+                tokio::spawn(async move { node.share_item_change(item, old, new).await });
+            }
+        }
+        self.emit(Event::OpenHab(event));
+    }
+
+    /// Broadcasts a state change to all rooms, as an
+    /// [`Event::ItemChanged`] for our peers.
+    pub async fn share_item_change(&self, item: String, old: String, new: String) {
+        let message = Message::ItemChanged {
+            from: self.node_id(),
+            item,
+            old,
+            new,
+            ts: Utc::now(),
+        };
+        for topic in self.rooms() {
+            self.broadcast(topic, &message).await.ok();
+        }
+    }
+
+    /// Broadcasts a Thing status change to all rooms, as an
+    /// [`Event::ThingStatusChanged`] for our peers.
+    pub async fn share_thing_status(&self, thing: ThingInfo) {
+        let message = Message::ThingStatus {
+            from: self.node_id(),
+            thing: thing.uid,
+            label: thing.label,
+            status: thing.status.status,
+            detail: thing.status.detail,
+            ts: Utc::now(),
+        };
+        for topic in self.rooms() {
+            self.broadcast(topic, &message).await.ok();
+        }
+    }
+
+    /// Item states announced by each peer's openHAB server.
+    pub fn peer_item_states(&self) -> BTreeMap<NodeId, ItemStates> {
+        self.peer_items.lock().unwrap().clone()
+    }
+
+    /// Registers a plugin called for room events, after the ones already
+    /// registered.
+    pub fn add_handler(&self, handler: impl MessageHandler) {
+        self.handlers.lock().unwrap().push(Arc::new(handler));
+    }
+
+    pub(crate) fn handlers(&self) -> Vec<Arc<dyn MessageHandler>> {
+        self.handlers.lock().unwrap().clone()
+    }
+
+    /// A stream of everything happening in our rooms from now on.
+    pub fn events(&self) -> Boxed<Event> {
+        futures_lite::stream::unfold(self.events.subscribe(), |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+        .boxed()
+    }
+
+    /// Leaves all rooms and stops the node.
+    ///
+    /// Peers are told that we left, and the endpoint closes its connections
+    /// cleanly so they do not have to wait for a timeout.
+    pub async fn shutdown(&self) -> Result<()> {
+        let rooms = self.rooms();
+        for &topic in &rooms {
+            self.leave(topic).await.ok();
+        }
+        if !rooms.is_empty() {
+            tokio::time::sleep(SHUTDOWN_GRACE).await;
+        }
+        self.router.shutdown().await?;
+        info!("node shut down");
+        Ok(())
+    }
+
+    /// Receives the messages of a room, and subscribes again with backoff
+    /// whenever the gossip subscription ends while we are still in the room.
+    #[instrument(skip_all, fields(topic = %topic))]
+    async fn subscription_loop(self, topic: TopicId, mut room: Room, mut receiver: GossipReceiver) {
+        let mut delay = REJOIN_MIN_DELAY;
+        loop {
+            let started = Instant::now();
+            let result = self
+                .clone()
+                .receive_loop(topic, room.clone(), receiver)
+                .await;
+            if room.left.is_cancelled() {
+                break;
+            }
+            match result {
+                Ok(()) => warn!("gossip subscription ended"),
+                Err(err) => warn!("gossip subscription failed: {err:#}"),
+            }
+            // A subscription that lasted a while was not a failed attempt.
+            if started.elapsed() > REJOIN_MAX_DELAY {
+                delay = REJOIN_MIN_DELAY;
+            }
+            receiver = loop {
+                self.emit(Event::Rejoining { topic, delay });
+                tokio::select! {
+                    () = room.left.cancelled() => return,
+                    () = tokio::time::sleep(delay) => {}
+                }
+                delay = (delay * 2).min(REJOIN_MAX_DELAY);
+                match self.gossip.subscribe(topic, room.known_peers()) {
+                    Ok(subscription) => {
+                        let (sender, receiver) = subscription.split();
+                        room.sender = Arc::new(sender);
+                        room.neighbors.lock().unwrap().clear();
+                        self.count_neighbors();
+                        info!("subscribed to room again");
+                        break receiver;
+                    }
+                    Err(err) => warn!("failed to subscribe again: {err:#}"),
+                }
+            };
+            // Broadcasts look the room up by topic and pick up the new sender.
+            let mut rooms = self.rooms.lock().unwrap();
+            match rooms.get_mut(&topic) {
+                Some(current) if !room.left.is_cancelled() => *current = room.clone(),
+                _ => break,
+            }
+        }
+    }
+
+    /// Asks the gossip layer to dial the known peers of a room again, with
+    /// backoff, until a neighbor is back or we left the room.
+    async fn rejoin_peers(self, topic: TopicId, room: Room) {
+        let mut delay = REJOIN_MIN_DELAY;
+        loop {
+            self.emit(Event::Rejoining { topic, delay });
+            tokio::select! {
+                () = room.left.cancelled() => break,
+                () = tokio::time::sleep(delay) => {}
+            }
+            delay = (delay * 2).min(REJOIN_MAX_DELAY);
+            // The subscription loop may have replaced the sender meanwhile.
+            let current = self.rooms.lock().unwrap().get(&topic).cloned();
+            let Some(current) = current.filter(|_| !room.left.is_cancelled()) else {
+                break;
+            };
+            if !current.neighbors.lock().unwrap().is_empty() {
+                break;
+            }
+            let peers = current.known_peers();
+            debug!(peers = peers.len(), "rejoining room");
+            if let Err(err) = current.sender.join_peers(peers).await {
+                warn!("failed to rejoin room: {err:#}");
+            }
+        }
+        room.rejoining.store(false, Ordering::SeqCst);
+    }
+
+    async fn receive_loop(
+        self,
+        topic: TopicId,
+        room: Room,
+        mut receiver: GossipReceiver,
+    ) -> Result<()> {
+        let mut seen = SeenMessages::default();
+        let mut limiter = RateLimiter::new(self.rate_limit);
+        // Peers we asked for their name, to ask only once.
+        let mut asked = HashSet::new();
+        // Reliable messages already shown, as they arrive once per attempt.
+        let mut delivered = SeenMessages::default();
+        let mut chunks = Chunks::default();
+        loop {
+            // Stop once the room was left.
+            let event = tokio::select! {
+                () = room.left.cancelled() => break,
+                event = receiver.try_next() => event?,
+            };
+            let Some(event) = event else {
+                break;
+            };
+            let msg = match event {
+                GossipNetEvent::Gossip(GossipEvent::Received(msg)) => msg,
+                GossipNetEvent::Gossip(GossipEvent::NeighborUp(node_id)) => {
+                    debug!(node_id = %node_id.fmt_short(), "neighbor up");
+                    room.neighbors.lock().unwrap().insert(node_id);
+                    self.count_neighbors();
+                    self.emit(Event::NeighborUp { topic, node_id });
+                    continue;
+                }
+                GossipNetEvent::Gossip(GossipEvent::NeighborDown(node_id)) => {
+                    debug!(node_id = %node_id.fmt_short(), "neighbor down");
+                    let alone = {
+                        let mut neighbors = room.neighbors.lock().unwrap();
+                        neighbors.remove(&node_id);
+                        neighbors.is_empty()
+                    };
+                    self.count_neighbors();
+                    if alone && !room.rejoining.swap(true, Ordering::SeqCst) {
+                        tokio::spawn(self.clone().rejoin_peers(topic, room.clone()));
+                    }
+                    self.emit(Event::NeighborDown { topic, node_id });
+                    continue;
+                }
+                _ => continue,
+            };
+            let dropped = |err: anyhow::Error| {
+                let reason = format!("{err:#}");
+                warn!(delivered_from = %msg.delivered_from.fmt_short(), %reason, "dropped message");
+                Event::Dropped {
+                    topic,
+                    delivered_from: msg.delivered_from,
+                    reason,
+                }
+            };
+            if msg.content.len() > self.max_message_size {
+                self.emit(dropped(anyhow!(
+                    "message too large ({} bytes, at most {})",
+                    msg.content.len(),
+                    self.max_message_size
+                )));
+                continue;
+            }
+            let signed = match unseal(room.cipher.as_ref(), &msg.content) {
+                Ok(signed) => signed,
+                Err(err) => {
+                    self.emit(dropped(err));
+                    continue;
+                }
+            };
+            // Rejoins and relays through several neighbors repeat messages.
+            if !seen.insert(SignedMessage::id(&signed)) {
+                continue;
+            }
+            let signed = match SignedMessage::verify(&signed) {
+                Ok(signed) => signed,
+                Err(err) => {
+                    self.emit(dropped(err));
+                    continue;
+                }
+            };
+            if !self.permits(&signed.signer()) {
+                debug!(from = %signed.signer().fmt_short(), "ignored message from blocked node");
+                continue;
+            }
+            {
+                let mut traffic = self.traffic.lock().unwrap();
+                let peer = traffic.entry(signed.signer()).or_default();
+                peer.messages_received += 1;
+                peer.bytes_received += msg.content.len() as u64;
+                metrics().record_received(&signed.signer(), msg.content.len());
+            }
+            // Checked before decoding, so a flood costs as little as possible.
+            if !limiter.check(signed.signer()) {
+                metrics().messages_rate_limited.inc();
+                continue;
+            }
+            let stamped = match signed.decode() {
+                Ok(stamped) => stamped,
+                Err(err) => {
+                    let protocol = self
+                        .protocols
+                        .lock()
+                        .unwrap()
+                        .get(&signed.signer())
+                        .copied();
+                    let err = match protocol {
+                        Some(protocol) if protocol > PROTOCOL_VERSION => err.context(format!(
+                            "sender speaks protocol version {protocol}, we only know {PROTOCOL_VERSION}"
+                        )),
+                        _ => err,
+                    };
+                    self.emit(dropped(err));
+                    continue;
+                }
+            };
+            // Continue with the whole message once its last chunk arrived.
+            let stamped = match stamped.message {
+                Message::Chunk {
+                    from,
+                    msg_id,
+                    index,
+                    total,
+                    data,
+                } => {
+                    let envelope = match chunks.insert(from, msg_id, index, total, data) {
+                        Ok(Some(envelope)) => envelope,
+                        Ok(None) => continue,
+                        Err(err) => {
+                            self.emit(dropped(err));
+                            continue;
+                        }
+                    };
+                    match reassembled(&envelope, from, msg_id) {
+                        Ok(stamped) => stamped,
+                        Err(err) => {
+                            self.emit(dropped(err));
+                            continue;
+                        }
+                    }
+                }
+                _ => stamped,
+            };
+            let Stamped {
+                clock,
+                message,
+                readings,
+                in_reply_to,
+            } = stamped;
+            metrics().messages_received.inc();
+            debug!(from = %message.sender().fmt_short(), clock, "received message");
+            if let Err(err) = self.witness(clock) {
+                self.emit(dropped(err));
+                continue;
+            }
+            // Any message but a goodbye shows that the sender is around.
+            if !matches!(message, Message::Left { .. }) {
+                self.touch(topic, &room, message.sender());
+            }
+            let sender = message.sender();
+            if !matches!(message, Message::AboutMe { .. })
+                && self.name_of(&sender).is_none()
+                && asked.insert(sender)
+            {
+                let who_is = Message::WhoIs {
+                    from: self.node_id(),
+                    node_id: sender,
+                };
+                self.broadcast(topic, &who_is).await.ok();
+            }
+            // Acked on every attempt, so the sender hears about it even if
+            // our first ack got lost, but only shown once.
+            let message = match message {
+                Message::Reliable { from, msg_id, text } => {
+                    let ack = Message::Ack {
+                        from: self.node_id(),
+                        msg_id,
+                    };
+                    self.broadcast(topic, &ack).await.ok();
+                    if !delivered.insert(msg_id) {
+                        continue;
+                    }
+                    Message::Message { from, text }
+                }
+                message => message,
+            };
+            match message {
+                Message::AboutMe { from, name } => {
+                    let previous = self.names.lock().unwrap().insert(from, name.clone());
+                    // Names are announced again on request, and remembered
+                    // across restarts.
+                    if previous.as_ref() == Some(&name) {
+                        continue;
+                    }
+                    if let Some(history) = &self.history {
+                        history.save_name(from, &name).ok();
+                    }
+                    self.emit(Event::NameChanged { topic, from, name });
+                }
+                Message::Message { from, text } => {
+                    if self
+                        .tombstones
+                        .lock()
+                        .unwrap()
+                        .contains(&MessageRef { from, clock })
+                    {
+                        continue;
+                    }
+                    self.typing.lock().unwrap().remove(&(topic, from));
+                    self.record(
+                        topic,
+                        from,
+                        clock,
+                        text.clone(),
+                        readings.clone(),
+                        in_reply_to,
+                    );
+                    self.apply_rules(topic, from, &text);
+                    let name = self.name_of(&from);
+                    self.emit(Event::Message {
+                        topic,
+                        from,
+                        name,
+                        clock,
+                        text,
+                        readings,
+                        in_reply_to,
+                    });
+                }
+                Message::Joined { reply, .. } => {
+                    // Let the newcomer know we are here too.
+                    if !reply {
+                        let here = Message::Joined {
+                            from: self.node_id(),
+                            reply: true,
+                        };
+                        self.broadcast(topic, &here).await.ok();
+                        self.say_hello(topic).await.ok();
+                    }
+                }
+                Message::Hello {
+                    from,
+                    protocol,
+                    agent,
+                } => {
+                    let previous = self.protocols.lock().unwrap().insert(from, protocol);
+                    if protocol != PROTOCOL_VERSION && previous != Some(protocol) {
+                        warn!(from = %from.fmt_short(), protocol, %agent, "peer speaks another protocol version");
+                        let name = self.name_of(&from);
+                        self.emit(Event::ProtocolMismatch {
+                            topic,
+                            from,
+                            name,
+                            protocol,
+                            agent,
+                        });
+                    }
+                }
+                Message::Heartbeat { .. } => {}
+                Message::ItemChanged {
+                    from,
+                    item,
+                    old,
+                    new,
+                    ts,
+                } => {
+                    self.peer_items
+                        .lock()
+                        .unwrap()
+                        .entry(from)
+                        .or_default()
+                        .insert(item.clone(), new.clone());
+                    let name = self.name_of(&from);
+                    self.emit(Event::ItemChanged {
+                        topic,
+                        from,
+                        name,
+                        item,
+                        old,
+                        new,
+                        ts,
+                    });
+                }
+                Message::ThingStatus {
+                    from,
+                    thing,
+                    label,
+                    status,
+                    detail,
+                    ts,
+                } => {
+                    let name = self.name_of(&from);
+                    self.emit(Event::ThingStatusChanged {
+                        topic,
+                        from,
+                        name,
+                        thing,
+                        label,
+                        status,
+                        detail,
+                        ts,
+                    });
+                }
+                Message::Command { from, item, value } => {
+                    tokio::spawn(self.clone().handle_command(topic, from, item, value));
+                }
+                Message::File {
+                    from,
+                    name: file_name,
+                    size,
+                    ticket,
+                } => {
+                    let offer = FileOffer {
+                        name: file_name,
+                        size,
+                        ticket,
+                    };
+                    self.files
+                        .lock()
+                        .unwrap()
+                        .insert(offer.hash(), offer.clone());
+                    let name = self.name_of(&from);
+                    self.emit(Event::FileOffered {
+                        topic,
+                        from,
+                        name,
+                        offer,
+                    });
+                }
+                Message::Image {
+                    from,
+                    blob_hash,
+                    mime,
+                    width,
+                    height,
+                    size,
+                    addr,
+                } => {
+                    let offer = files::image_name(&blob_hash, &mime).and_then(|file_name| {
+                        Ok((
+                            file_name,
+                            BlobTicket::new(addr, blob_hash, BlobFormat::Raw)?,
+                        ))
+                    });
+                    let (file_name, ticket) = match offer {
+                        Ok(offer) => offer,
+                        Err(err) => {
+                            self.emit(dropped(err));
+                            continue;
+                        }
+                    };
+                    let image = ImageOffer {
+                        file: FileOffer {
+                            name: file_name,
+                            size,
+                            ticket,
+                        },
+                        mime,
+                        width,
+                        height,
+                    };
+                    self.files
+                        .lock()
+                        .unwrap()
+                        .insert(blob_hash, image.file.clone());
+                    let name = self.name_of(&from);
+                    self.emit(Event::ImageShared {
+                        topic,
+                        from,
+                        name,
+                        image,
+                    });
+                }
+                Message::Voice {
+                    from,
+                    blob_hash,
+                    secs,
+                    size,
+                    addr,
+                } => {
+                    let ticket = match BlobTicket::new(addr, blob_hash, BlobFormat::Raw) {
+                        Ok(ticket) => ticket,
+                        Err(err) => {
+                            self.emit(dropped(err));
+                            continue;
+                        }
+                    };
+                    let note = VoiceNote {
+                        file: FileOffer {
+                            name: files::voice_name(&blob_hash),
+                            size,
+                            ticket,
+                        },
+                        secs,
+                    };
+                    self.files
+                        .lock()
+                        .unwrap()
+                        .insert(blob_hash, note.file.clone());
+                    let name = self.name_of(&from);
+                    self.emit(Event::VoiceNote {
+                        topic,
+                        from,
+                        name,
+                        note,
+                    });
+                }
+                Message::Left { from } => {
+                    if room.roster.lock().unwrap().remove(&from).is_some() {
+                        let name = self.name_of(&from);
+                        self.emit(Event::PeerLeft { topic, from, name });
+                    }
+                }
+                Message::WhoIs { node_id, .. } => {
+                    if node_id == self.node_id() {
+                        if let Some(name) = self.name_of(&node_id) {
+                            let about_me = Message::AboutMe {
+                                from: node_id,
+                                name,
+                            };
+                            self.broadcast(topic, &about_me).await.ok();
+                        }
+                    }
+                }
+                Message::Redeem { from, nonce } => {
+                    let reused = match self.invites.lock().unwrap().get_mut(&nonce) {
+                        Some(redeemed_by @ None) => {
+                            *redeemed_by = Some(from);
+                            false
+                        }
+                        Some(Some(redeemed_by)) => *redeemed_by != from,
+                        None => false,
+                    };
+                    if reused {
+                        warn!(from = %from.fmt_short(), "invite used twice");
+                        self.access.ban(from);
+                        room.roster.lock().unwrap().remove(&from);
+                        let name = self.name_of(&from);
+                        self.emit(Event::InviteReused { topic, from, name });
+                    }
+                }
+                Message::Ban { from, node_id } => {
+                    if !self.access.config.moderators.contains(&from) {
+                        debug!(from = %from.fmt_short(), "ignored ban from non-moderator");
+                        continue;
+                    }
+                    // A moderator banning us cannot keep us from listening.
+                    if node_id != self.node_id() {
+                        self.access.ban(node_id);
+                        room.roster.lock().unwrap().remove(&node_id);
+                    }
+                    info!(node_id = %node_id.fmt_short(), by = %from.fmt_short(), "node banned");
+                    let name = self.name_of(&from);
+                    self.emit(Event::Banned {
+                        topic,
+                        from,
+                        name,
+                        node_id,
+                    });
+                }
+                Message::Ack { from, msg_id } => {
+                    if let Some(acks) = self.pending_acks.lock().unwrap().get(&msg_id) {
+                        acks.send(from).ok();
+                    }
+                }
+                // Turned into a plain message above.
+                Message::Reliable { .. } => {}
+                // Reassembled above, a chunk of chunks is ignored.
+                Message::Chunk { .. } => {}
+                Message::Reaction {
+                    from,
+                    target_msg_id,
+                    emoji,
+                } => {
+                    if !valid_reaction(&emoji) {
+                        continue;
+                    }
+                    if !self.add_reaction(target_msg_id, from, emoji.clone()) {
+                        continue;
+                    }
+                    let name = self.name_of(&from);
+                    self.emit(Event::Reaction {
+                        topic,
+                        from,
+                        name,
+                        target: target_msg_id,
+                        emoji,
+                    });
+                }
+                // The signature shows that `from` sent the edit, so it must
+                // also have sent the message.
+                Message::Edit {
+                    from,
+                    target,
+                    new_text,
+                } => {
+                    if target.from != from {
+                        debug!(from = %from.fmt_short(), "ignored edit of another node's message");
+                        continue;
+                    }
+                    if self.tombstones.lock().unwrap().contains(&target) {
+                        continue;
+                    }
+                    self.texts.lock().unwrap().insert(target, new_text.clone());
+                    if let Some(history) = &self.history {
+                        history.edit(&target, &new_text).ok();
+                    }
+                    let name = self.name_of(&from);
+                    self.emit(Event::Edited {
+                        topic,
+                        from,
+                        name,
+                        target,
+                        text: new_text,
+                    });
+                }
+                Message::Delete { from, target } => {
+                    if target.from != from {
+                        debug!(from = %from.fmt_short(), "ignored deletion of another node's message");
+                        continue;
+                    }
+                    if !self.tombstones.lock().unwrap().insert(target) {
+                        continue;
+                    }
+                    self.texts.lock().unwrap().remove(&target);
+                    if let Some(history) = &self.history {
+                        history.delete(&target).ok();
+                    }
+                    let name = self.name_of(&from);
+                    self.emit(Event::Deleted {
+                        topic,
+                        from,
+                        name,
+                        target,
+                    });
+                }
+                Message::Typing { from } => {
+                    let previous = self
+                        .typing
+                        .lock()
+                        .unwrap()
+                        .insert((topic, from), Instant::now());
+                    if previous.is_some_and(|last| last.elapsed() < TYPING_TIMEOUT) {
+                        continue;
+                    }
+                    let name = self.name_of(&from);
+                    self.emit(Event::Typing { topic, from, name });
+                }
+                Message::Seen { from, clock } => {
+                    let name = self.name_of(&from);
+                    self.emit(Event::Seen {
+                        topic,
+                        from,
+                        name,
+                        clock,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the commands of the rules matching a peer's message to our
+    /// openHAB server.
+    fn apply_rules(&self, topic: TopicId, from: NodeId, text: &str) {
+        if self.home.is_none() {
+            return;
+        }
+        for rule in self.rules.matching(text) {
+            if !rule.permits(&from) {
+                debug!(rule = %rule.name, from = %from.fmt_short(), "rule not allowed for sender");
+                continue;
+            }
+            let this = self.clone();
+            let rule = rule.clone();
+            tokio::spawn(async move {
+                let Some(result) = this.command_home(&rule.item, &rule.command).await else {
+                    return;
+                };
+                let result = result.map_err(|err| format!("{err:#}"));
+                let name = this.name_of(&from);
+                this.emit(Event::RuleFired {
+                    topic,
+                    from,
+                    name,
+                    rule: rule.name,
+                    item: rule.item,
+                    command: rule.command,
+                    result,
+                });
+            });
+        }
+    }
+
+    /// Forwards a peer's command to our openHAB server, if allowed.
+    #[instrument(skip(self, topic), fields(from = %from.fmt_short()))]
+    async fn handle_command(self, topic: TopicId, from: NodeId, item: String, value: String) {
+        let accepts = self
+            .home
+            .as_ref()
+            .is_some_and(|home| home.accepts_commands());
+        let result = match accepts {
+            // Peers may only control the items we share with them.
+            true if !self
+                .home
+                .as_ref()
+                .is_some_and(|home| home.items().contains(&item)) =>
+            {
+                Some(Err(format!("{item} is not one of our shared items")))
+            }
+            true => self
+                .command_home(&item, &value)
+                .await
+                .map(|result| result.map_err(|err| format!("{err:#}"))),
+            false => None,
+        };
+        let name = self.name_of(&from);
+        self.emit(Event::ItemCommand {
+            topic,
+            from,
+            name,
+            item,
+            value,
+            result,
+        });
+    }
+
+    /// Fetches earlier messages of a room from `peer`.
+    async fn backfill(self, topic: TopicId, peer: NodeId, cipher: Option<RoomCipher>) {
+        let entries = backfill::request(
+            &self.endpoint,
+            peer,
+            topic,
+            self.backfill_limit,
+            cipher.as_ref(),
+        )
+        .await
+        .map(|mut entries| {
+            entries.retain(|entry| {
+                self.permits(&entry.from) && entry.text.len() <= self.max_message_size
+            });
+            entries
+        });
+        match entries {
+            // The entries carry no signatures, so their clocks are not
+            // witnessed.
+            Ok(entries) if !entries.is_empty() => {
+                self.emit(Event::Backfilled {
+                    topic,
+                    from: peer,
+                    entries,
+                });
+            }
+            Ok(_) => {}
+            Err(err) => {
+                warn!(peer = %peer.fmt_short(), "backfill failed: {err:#}");
+                self.emit(Event::Dropped {
+                    topic,
+                    delivered_from: peer,
+                    reason: format!("backfill failed: {err:#}"),
+                })
+            }
+        }
+    }
+
+    /// Records that `from` is present, announcing it if it was not before.
+    fn touch(&self, topic: TopicId, room: &Room, from: NodeId) {
+        let is_new = room
+            .roster
+            .lock()
+            .unwrap()
+            .insert(from, Instant::now())
+            .is_none();
+        if is_new {
+            let name = self.name_of(&from);
+            self.emit(Event::PeerJoined { topic, from, name });
+        }
+    }
+
+    /// Broadcasts heartbeats and marks peers that went silent as offline.
+    async fn heartbeat_loop(self, topic: TopicId, room: Room) {
+        let mut interval = tokio::time::interval(self.heartbeat_interval);
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                () = room.left.cancelled() => break,
+                _ = interval.tick() => {}
+            }
+            let heartbeat = Message::Heartbeat {
+                from: self.node_id(),
+            };
+            self.broadcast(topic, &heartbeat).await.ok();
+
+            let stale: Vec<NodeId> = {
+                let mut roster = room.roster.lock().unwrap();
+                let stale = roster
+                    .iter()
+                    .filter(|(_, seen)| seen.elapsed() > self.peer_timeout)
+                    .map(|(id, _)| *id)
+                    .collect::<Vec<_>>();
+                for id in &stale {
+                    roster.remove(id);
+                }
+                stale
+            };
+            for from in stale {
+                let name = self.name_of(&from);
+                self.emit(Event::PeerOffline { topic, from, name });
+            }
+        }
+    }
+
+    /// Remembers a chat message as the latest of its sender, and saves it
+    /// to the history if enabled.
+    fn record(
+        &self,
+        topic: TopicId,
+        from: NodeId,
+        clock: u64,
+        text: String,
+        readings: Vec<SensorReading>,
+        in_reply_to: Option<MessageRef>,
+    ) {
+        {
+            let mut latest = self.latest.lock().unwrap();
+            let latest = latest.entry((topic, from)).or_default();
+            *latest = (*latest).max(clock);
+        }
+        self.texts
+            .lock()
+            .unwrap()
+            .insert(MessageRef { from, clock }, text.clone());
+        let Some(history) = &self.history else {
+            return;
+        };
+        let entry = HistoryEntry {
+            topic,
+            from,
+            name: self.name_of(&from),
+            timestamp: Utc::now(),
+            clock,
+            text,
+            readings,
+            reactions: BTreeMap::new(),
+            edited: false,
+            deleted: false,
+            in_reply_to,
+        };
+        // Losing a history line must not interrupt the chat.
+        history.insert(&entry).ok();
+    }
+
+    /// Whether every peer in `room` announced that it decodes compressed
+    /// messages.
+    fn can_compress(&self, room: &Room) -> bool {
+        let protocols = self.protocols.lock().unwrap();
+        let mut peers = room
+            .roster
+            .lock()
+            .unwrap()
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        peers.extend(room.neighbors.lock().unwrap().iter().copied());
+        peers.iter().all(|peer| {
+            protocols
+                .get(peer)
+                .is_some_and(|protocol| *protocol >= COMPRESSION_PROTOCOL_VERSION)
+        })
+    }
+
+    /// Updates the neighbor gauge after the neighbors changed.
+    fn count_neighbors(&self) {
+        metrics().neighbors.set(self.neighbors().len() as i64);
+    }
+
+    fn emit(&self, event: Event) {
+        self.events.send(event).ok();
+    }
+}
+
+/// Signs the message and, in encrypted rooms, seals the signed envelope.
+fn encode_message(
+    secret_key: &SecretKey,
+    cipher: Option<&RoomCipher>,
+    compress: bool,
+    message: &Stamped,
+) -> Vec<u8> {
+    let bytes = SignedMessage::sign_and_encode(secret_key, message, compress);
+    match cipher {
+        Some(cipher) => cipher.encrypt(&bytes),
+        None => bytes,
+    }
+}
+
+/// Decodes a message put together from chunks, checking that it is the one
+/// `from` announced.
+fn reassembled(envelope: &[u8], from: NodeId, msg_id: MessageId) -> Result<Stamped> {
+    ensure!(
+        SignedMessage::id(envelope) == msg_id,
+        "chunks from {} do not match their message id",
+        from.fmt_short()
+    );
+    let signed = SignedMessage::verify(envelope)?;
+    ensure!(
+        signed.signer() == from,
+        "chunks from {} carry a message signed by {}",
+        from.fmt_short(),
+        signed.signer().fmt_short()
+    );
+    signed.decode()
+}
+
+/// Whether `emoji` can be a reaction: short and without whitespace or
+/// control characters, so it shows as a single symbol.
+fn valid_reaction(emoji: &str) -> bool {
+    !emoji.is_empty()
+        && emoji.len() <= MAX_EMOJI_LEN
+        && !emoji.contains(|c: char| c.is_whitespace() || c.is_control())
+}
+
+/// Removes the room encryption, if any, leaving the signed envelope.
+fn unseal(cipher: Option<&RoomCipher>, bytes: &[u8]) -> Result<Vec<u8>> {
+    match cipher {
+        Some(cipher) => cipher.decrypt(bytes),
+        None => Ok(bytes.to_vec()),
+    }
+}
+
+/// Token buckets limiting how many messages each peer may send.
+struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: HashMap<NodeId, Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Whether the peer was already reported as over the limit.
+    limited: bool,
+}
+
+impl RateLimiter {
+    fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Takes a token from `from`'s bucket, returning false if it is empty.
+    fn check(&mut self, from: NodeId) -> bool {
+        if self.config.messages_per_sec <= 0.0 {
+            return true;
+        }
+        let now = Instant::now();
+        let burst = self.config.burst.max(1.0);
+        let bucket = self.buckets.entry(from).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+            limited: false,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.config.messages_per_sec).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.limited = false;
+            return true;
+        }
+        if !bucket.limited {
+            warn!(from = %from.fmt_short(), "peer exceeds the rate limit, dropping its messages");
+            bucket.limited = true;
+        }
+        false
+    }
+}
+
+/// Texts of recent chat messages, forgetting the oldest ones once full.
+#[derive(Default)]
+struct RecentTexts {
+    texts: HashMap<MessageRef, String>,
+    order: VecDeque<MessageRef>,
+}
+
+impl RecentTexts {
+    fn insert(&mut self, target: MessageRef, text: String) {
+        if self.texts.insert(target, text).is_some() {
+            return;
+        }
+        self.order.push_back(target);
+        if self.order.len() > TEXTS_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.texts.remove(&oldest);
+            }
+        }
+    }
+
+    fn get(&self, target: &MessageRef) -> Option<&str> {
+        self.texts.get(target).map(String::as_str)
+    }
+
+    fn remove(&mut self, target: &MessageRef) {
+        self.texts.remove(target);
+    }
+}
+
+/// Chunks of oversized messages that are still missing parts.
+#[derive(Default)]
+struct Chunks {
+    partial: HashMap<(NodeId, MessageId), PartialMessage>,
+}
+
+struct PartialMessage {
+    parts: Vec<Option<Vec<u8>>>,
+    missing: usize,
+    started: Instant,
+}
+
+impl Chunks {
+    /// Stores a chunk, returning the whole envelope once all chunks of the
+    /// message arrived.
+    fn insert(
+        &mut self,
+        from: NodeId,
+        msg_id: MessageId,
+        index: u32,
+        total: u32,
+        data: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>> {
+        ensure!(
+            index < total && total <= MAX_CHUNKS,
+            "invalid chunk {index} of {total}"
+        );
+        self.partial
+            .retain(|_, partial| partial.started.elapsed() < CHUNK_TIMEOUT);
+        let key = (from, msg_id);
+        if !self.partial.contains_key(&key) {
+            ensure!(
+                self.partial.len() < MAX_PARTIAL_MESSAGES,
+                "too many chunked messages in flight"
+            );
+            self.partial.insert(
+                key,
+                PartialMessage {
+                    parts: vec![None; total as usize],
+                    missing: total as usize,
+                    started: Instant::now(),
+                },
+            );
+        }
+        let partial = self.partial.get_mut(&key).expect("just inserted");
+        ensure!(
+            partial.parts.len() == total as usize,
+            "chunk {index} of {total} does not match the earlier ones"
+        );
+        let part = &mut partial.parts[index as usize];
+        if part.is_none() {
+            *part = Some(data);
+            partial.missing -= 1;
+        }
+        if partial.missing > 0 {
+            return Ok(None);
+        }
+        let partial = self.partial.remove(&key).expect("present");
+        Ok(Some(
+            partial.parts.into_iter().flatten().flatten().collect(),
+        ))
+    }
+}
+
+/// Ids of recently received messages, forgetting the oldest ones once full.
+#[derive(Default)]
+struct SeenMessages {
+    ids: HashSet<MessageId>,
+    order: VecDeque<MessageId>,
+}
+
+impl SeenMessages {
+    /// Remembers `id`, returning false if it was seen before.
+    fn insert(&mut self, id: MessageId) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > SEEN_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_id() -> NodeId {
+        SecretKey::generate(rand::rngs::OsRng).public()
+    }
+
+    #[test]
+    fn forgets_the_oldest_seen_messages() {
+        let id = |i: usize| -> MessageId {
+            let mut id = [0; 32];
+            id[..8].copy_from_slice(&i.to_le_bytes());
+            id
+        };
+        let mut seen = SeenMessages::default();
+        assert!(seen.insert(id(0)));
+        assert!(!seen.insert(id(0)));
+        assert!((1..=SEEN_CAPACITY).all(|i| seen.insert(id(i))));
+        assert_eq!(seen.ids.len(), SEEN_CAPACITY);
+        // The first id made room for the last one.
+        assert!(!seen.insert(id(SEEN_CAPACITY)));
+        assert!(seen.insert(id(0)));
+    }
+
+    #[test]
+    fn rate_limiter_allows_bursts_then_refills() {
+        let mut limiter = RateLimiter::new(RateLimitConfig {
+            messages_per_sec: 1.0,
+            burst: 3.0,
+        });
+        let (a, b) = (node_id(), node_id());
+        assert!((0..3).all(|_| limiter.check(a)));
+        assert!(!limiter.check(a));
+        // Each peer has a bucket of its own.
+        assert!(limiter.check(b));
+
+        let bucket = limiter.buckets.get_mut(&a).unwrap();
+        bucket.updated -= Duration::from_secs(2);
+        assert!(limiter.check(a));
+        assert!(limiter.check(a));
+        assert!(!limiter.check(a));
+    }
+
+    #[test]
+    fn rate_limiter_can_be_disabled() {
+        let mut limiter = RateLimiter::new(RateLimitConfig {
+            messages_per_sec: 0.0,
+            burst: 0.0,
+        });
+        let a = node_id();
+        assert!((0..1000).all(|_| limiter.check(a)));
+    }
+
+    #[test]
+    fn forgets_the_oldest_texts() {
+        let from = node_id();
+        let at = |clock| MessageRef { from, clock };
+        let mut texts = RecentTexts::default();
+        texts.insert(at(0), "hello".to_string());
+        // An edit replaces the text without moving it up.
+        texts.insert(at(0), "hello, world".to_string());
+        assert_eq!(texts.get(&at(0)), Some("hello, world"));
+        for clock in 1..=TEXTS_CAPACITY as u64 {
+            texts.insert(at(clock), clock.to_string());
+        }
+        assert_eq!(texts.get(&at(0)), None);
+        assert_eq!(texts.get(&at(1)), Some("1"));
+
+        texts.remove(&at(1));
+        assert_eq!(texts.get(&at(1)), None);
+    }
+
+    #[test]
+    fn reassembles_chunks_in_any_order() {
+        let mut chunks = Chunks::default();
+        let (from, msg_id) = (node_id(), [1; 32]);
+        assert_eq!(
+            chunks.insert(from, msg_id, 2, 3, b"c".to_vec()).unwrap(),
+            None
+        );
+        assert_eq!(
+            chunks.insert(from, msg_id, 0, 3, b"a".to_vec()).unwrap(),
+            None
+        );
+        // A chunk relayed twice changes nothing.
+        assert_eq!(
+            chunks.insert(from, msg_id, 0, 3, b"x".to_vec()).unwrap(),
+            None
+        );
+        let whole = chunks.insert(from, msg_id, 1, 3, b"b".to_vec()).unwrap();
+        assert_eq!(whole.as_deref(), Some(&b"abc"[..]));
+        assert!(chunks.partial.is_empty());
+    }
+
+    #[test]
+    fn refuses_invalid_chunks() {
+        let mut chunks = Chunks::default();
+        let (from, msg_id) = (node_id(), [2; 32]);
+        assert!(chunks.insert(from, msg_id, 3, 3, Vec::new()).is_err());
+        assert!(chunks
+            .insert(from, msg_id, 0, MAX_CHUNKS + 1, Vec::new())
+            .is_err());
+        chunks.insert(from, msg_id, 0, 3, Vec::new()).unwrap();
+        assert!(chunks.insert(from, msg_id, 1, 4, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn limits_messages_in_flight() {
+        let mut chunks = Chunks::default();
+        let from = node_id();
+        for n in 0..MAX_PARTIAL_MESSAGES {
+            chunks
+                .insert(from, [n as u8; 32], 0, 2, Vec::new())
+                .unwrap();
+        }
+        assert!(chunks.insert(from, [255; 32], 0, 2, Vec::new()).is_err());
+        // Chunks of messages already in flight are still taken.
+        assert!(chunks
+            .insert(from, [0; 32], 1, 2, Vec::new())
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn reassembled_message_must_match_id_and_sender() {
+        let key = SecretKey::generate(rand::rngs::OsRng);
+        let stamped = Stamped {
+            clock: 1,
+            message: Message::Message {
+                from: key.public(),
+                text: "hi".to_string(),
+            },
+            readings: Vec::new(),
+            in_reply_to: None,
+        };
+        let envelope = SignedMessage::sign_and_encode(&key, &stamped, false);
+        let msg_id = SignedMessage::id(&envelope);
+        assert!(reassembled(&envelope, key.public(), msg_id).is_ok());
+        assert!(reassembled(&envelope, key.public(), [0; 32]).is_err());
+        assert!(reassembled(&envelope, node_id(), msg_id).is_err());
+    }
+
+    #[test]
+    fn reactions_are_single_symbols() {
+        assert!(valid_reaction("👍"));
+        assert!(valid_reaction("🇫🇷"));
+        assert!(!valid_reaction(""));
+        assert!(!valid_reaction("   "));
+        assert!(!valid_reaction("\u{3000}"));
+        assert!(!valid_reaction("👍 👍"));
+        assert!(!valid_reaction("\u{1b}[2J"));
+        assert!(!valid_reaction(&"👍".repeat(9)));
+    }
+}
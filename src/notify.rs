@@ -0,0 +1,92 @@
+//! Desktop notifications for messages and openHAB alerts, so they reach the
+//! user while the terminal is in the background.
+
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use iroh_gossip::proto::TopicId;
+use iroh_gossip_chat::config::NotificationConfig;
+use notify_rust::Notification;
+use tracing::debug;
+
+/// Name notifications are shown under.
+const APP_NAME: &str = "iroh-gossip-chat";
+
+/// Shows desktop notifications unless the chat is in focus or the room is
+/// muted.
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    enabled: bool,
+    /// Muted rooms, as topics or topic prefixes in hex.
+    muted: Arc<Mutex<BTreeSet<String>>>,
+    /// Whether the terminal has focus. Only the TUI can tell, the console
+    /// always notifies.
+    focused: Arc<AtomicBool>,
+}
+
+impl Notifier {
+    pub fn new(config: &NotificationConfig) -> Self {
+        Self {
+            enabled: config.enabled,
+            muted: Arc::new(Mutex::new(config.muted.iter().cloned().collect())),
+            focused: Arc::default(),
+        }
+    }
+
+    pub fn set_focused(&self, focused: bool) {
+        self.focused.store(focused, Ordering::Relaxed);
+    }
+
+    pub fn is_muted(&self, topic: &TopicId) -> bool {
+        let topic = topic.to_string();
+        self.muted
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|muted| topic.starts_with(muted.as_str()))
+    }
+
+    /// Stops notifying about messages in `topic`, except for mentions.
+    pub fn mute(&self, topic: &TopicId) {
+        self.muted.lock().unwrap().insert(topic.to_string());
+    }
+
+    pub fn unmute(&self, topic: &TopicId) {
+        let topic = topic.to_string();
+        self.muted
+            .lock()
+            .unwrap()
+            .retain(|muted| !topic.starts_with(muted.as_str()));
+    }
+
+    /// Notifies about a message in `topic`. Mentions get through muted
+    /// rooms.
+    pub fn room(&self, topic: &TopicId, mention: bool, summary: String, body: String) {
+        if mention || !self.is_muted(topic) {
+            self.show(summary, body);
+        }
+    }
+
+    /// Notifies about something outside of any room, like a direct message.
+    pub fn show(&self, summary: String, body: String) {
+        if !self.enabled || self.focused.load(Ordering::Relaxed) {
+            return;
+        }
+        // Talking to the notification daemon blocks.
+        tokio::task::spawn_blocking(move || {
+            let shown = Notification::new()
+                .appname(APP_NAME)
+                .summary(&summary)
+                .body(&body)
+                .show();
+            if let Err(err) = shown {
+                debug!("failed to show notification: {err:#}");
+            }
+        });
+    }
+}
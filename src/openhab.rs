@@ -0,0 +1,977 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use futures_lite::future::Boxed as BoxedFuture;
+use futures_util::{SinkExt, StreamExt};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rand::Rng;
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::{net::TcpStream, sync::watch};
+use tokio_tungstenite::{
+    tungstenite::{client::IntoClientRequest, http::header, Message as WsMessage},
+    Connector,
+};
+use tracing::{debug, instrument, warn};
+
+use crate::{
+    config::OpenHabConfig,
+    home::{EventCallback, HomeProvider},
+    metrics::metrics,
+};
+
+/// An item as returned by the openHAB REST API.
+#[derive(Debug, Deserialize)]
+struct Item {
+    state: String,
+}
+
+/// An item listed by [`list_items`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct ItemInfo {
+    pub name: String,
+    /// Item type, such as `Switch` or `Number:Temperature`.
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub state: String,
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+/// A Thing, such as a bridge or device, as listed by [`list_things`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct ThingInfo {
+    #[serde(rename = "UID")]
+    pub uid: String,
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(rename = "statusInfo")]
+    pub status: ThingStatus,
+}
+
+/// Whether a Thing works, and why not.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ThingStatus {
+    /// `ONLINE`, `OFFLINE`, `UNKNOWN`, `UNINITIALIZED` and so on.
+    pub status: String,
+    /// Reason for the status, such as `COMMUNICATION_ERROR`, or `NONE`.
+    #[serde(rename = "statusDetail", default)]
+    pub detail: String,
+}
+
+/// An event from the openHAB event bus, as sent over SSE and WebSocket.
+#[derive(Debug, Deserialize)]
+struct BusEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    topic: String,
+    /// JSON encoded again as a string.
+    payload: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct StateChanged {
+    value: String,
+    #[serde(rename = "oldValue", default)]
+    old_value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ItemCommand {
+    value: String,
+}
+
+/// Something that happened on the openHAB server.
+#[derive(Debug, Clone)]
+pub enum OpenHabEvent {
+    /// An item changed its state.
+    ItemStateChanged {
+        item: String,
+        old_state: String,
+        state: String,
+    },
+    /// A command was sent to an item.
+    ItemCommand { item: String, command: String },
+    /// Any other event, by type and topic.
+    Other { kind: String, topic: String },
+}
+
+impl BusEvent {
+    fn into_event(self) -> Result<OpenHabEvent> {
+        // Item topics look like `openhab/items/<item>/<event>`.
+        let item = self
+            .topic
+            .strip_prefix("openhab/items/")
+            .and_then(|rest| rest.split('/').next())
+            .map(str::to_string);
+        let event = match (self.kind.as_str(), item) {
+            ("ItemStateChangedEvent", Some(item)) => {
+                let changed: StateChanged = serde_json::from_str(&self.payload)?;
+                OpenHabEvent::ItemStateChanged {
+                    item,
+                    old_state: changed.old_value,
+                    state: changed.value,
+                }
+            }
+            ("ItemCommandEvent", Some(item)) => {
+                let command: ItemCommand = serde_json::from_str(&self.payload)?;
+                OpenHabEvent::ItemCommand {
+                    item,
+                    command: command.value,
+                }
+            }
+            _ => OpenHabEvent::Other {
+                kind: self.kind,
+                topic: self.topic,
+            },
+        };
+        Ok(event)
+    }
+}
+
+/// Latest known state of each monitored item.
+pub type ItemStates = BTreeMap<String, String>;
+
+/// State shown for items that could not be fetched.
+pub const STATE_ERROR: &str = "Error fetching state";
+
+/// How far a [`SensorReading`] can be trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Quality {
+    /// The item reported a state.
+    Good,
+    /// The item exists but has no state yet (`NULL` or `UNDEF`).
+    Undefined,
+    /// The openHAB server could not be reached.
+    Unavailable,
+}
+
+/// The state of an item, attached to chat messages so peers can use it
+/// without parsing the text.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SensorReading {
+    pub item: String,
+    /// The state without its unit, such as `21.5` or `ON`.
+    pub value: String,
+    /// Unit of quantity items, such as `°C`.
+    pub unit: Option<String>,
+    pub quality: Quality,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl SensorReading {
+    /// Splits an openHAB state like `21.5 °C` into value and unit.
+    pub fn from_state(item: &str, state: &str, timestamp: DateTime<Utc>) -> Self {
+        let quality = match state {
+            "NULL" | "UNDEF" => Quality::Undefined,
+            STATE_ERROR => Quality::Unavailable,
+            _ => Quality::Good,
+        };
+        let (value, unit) = match state.split_once(' ') {
+            Some((value, unit)) if value.parse::<f64>().is_ok() => {
+                (value.to_string(), Some(unit.to_string()))
+            }
+            _ => (state.to_string(), None),
+        };
+        Self {
+            item: item.to_string(),
+            value,
+            unit,
+            quality,
+            timestamp,
+        }
+    }
+
+    /// Readings for all items in `states`, taken now.
+    pub fn from_states(states: &ItemStates) -> Vec<Self> {
+        let now = Utc::now();
+        states
+            .iter()
+            .map(|(item, state)| Self::from_state(item, state, now))
+            .collect()
+    }
+}
+
+impl std::fmt::Display for SensorReading {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.unit {
+            Some(unit) => write!(f, "{}={} {}", self.item, self.value, unit),
+            None => write!(f, "{}={}", self.item, self.value),
+        }
+    }
+}
+
+/// An openHAB server as the [`HomeProvider`] of a node.
+#[derive(Debug, Clone)]
+pub struct OpenHab {
+    pub config: OpenHabConfig,
+    /// Shared by all requests to the server.
+    client: Client,
+}
+
+impl OpenHab {
+    /// Fails if the configured CA certificate cannot be read.
+    pub fn new(config: OpenHabConfig) -> Result<Self> {
+        let client = client(&config)?;
+        Ok(Self { config, client })
+    }
+}
+
+impl HomeProvider for OpenHab {
+    fn name(&self) -> &'static str {
+        "openHAB"
+    }
+
+    fn url(&self) -> &str {
+        &self.config.url
+    }
+
+    fn items(&self) -> &[String] {
+        &self.config.items
+    }
+
+    fn accepts_commands(&self) -> bool {
+        self.config.accept_commands
+    }
+
+    fn check(&self) -> BoxedFuture<Result<()>> {
+        let openhab = self.clone();
+        Box::pin(async move { check(&openhab).await })
+    }
+
+    fn get_state(&self, item: &str) -> BoxedFuture<Result<String>> {
+        let (openhab, item) = (self.clone(), item.to_string());
+        Box::pin(async move { get_item_state(&openhab, &item).await })
+    }
+
+    fn send_command(&self, item: &str, command: &str) -> BoxedFuture<Result<()>> {
+        let (openhab, item, command) = (self.clone(), item.to_string(), command.to_string());
+        Box::pin(async move { send_command(&openhab, &item, &command).await })
+    }
+
+    /// Follows the event stream for the states and the WebSocket, or the
+    /// poller if configured, for the events, until either fails.
+    fn subscribe_events(
+        &self,
+        states: watch::Sender<ItemStates>,
+        mut on_event: EventCallback,
+    ) -> BoxedFuture<Result<()>> {
+        let openhab = self.clone();
+        Box::pin(async move {
+            let events = async {
+                match openhab.config.poll_interval() {
+                    Some(interval) => poll_item_states(&openhab, interval, &mut on_event)
+                        .await
+                        .context("openHAB poller failed"),
+                    None => connect_websocket(&openhab.config, &mut on_event)
+                        .await
+                        .context("openHAB WebSocket failed"),
+                }
+            };
+            let states = async {
+                follow_item_states(&openhab, &states)
+                    .await
+                    .context("openHAB event stream failed")
+            };
+            tokio::select! {
+                result = states => result,
+                result = events => result,
+            }
+        })
+    }
+}
+
+/// Several openHAB servers as one [`HomeProvider`], for households with
+/// more than one controller.
+///
+/// The items of the main server keep their names, those of the others are
+/// prefixed with the server's name, as in `garage:Light1`. Commands and
+/// state queries go to the server an item belongs to. Whether peers may
+/// send commands is up to the main server's `accept_commands`.
+#[derive(Debug, Clone)]
+pub struct OpenHabServers {
+    main: OpenHab,
+    others: Vec<(String, OpenHab)>,
+    /// The items of every server, prefixed.
+    items: Vec<String>,
+}
+
+impl OpenHabServers {
+    pub fn new(
+        main: OpenHab,
+        others: impl IntoIterator<Item = (String, OpenHabConfig)>,
+    ) -> Result<Self> {
+        let others = others
+            .into_iter()
+            .map(|(name, config)| Ok((name, OpenHab::new(config)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let mut items = main.config.items.clone();
+        for (name, server) in &others {
+            items.extend(
+                server
+                    .config
+                    .items
+                    .iter()
+                    .map(|item| format!("{name}:{item}")),
+            );
+        }
+        Ok(Self {
+            main,
+            others,
+            items,
+        })
+    }
+
+    /// The server `item` belongs to, and its name there.
+    fn route<'a>(&self, item: &'a str) -> (&OpenHab, &'a str) {
+        let other = item.split_once(':').and_then(|(prefix, item)| {
+            let (_, server) = self.others.iter().find(|(name, _)| name == prefix)?;
+            Some((server, item))
+        });
+        other.unwrap_or((&self.main, item))
+    }
+}
+
+impl HomeProvider for OpenHabServers {
+    fn name(&self) -> &'static str {
+        "openHAB"
+    }
+
+    fn url(&self) -> &str {
+        &self.main.config.url
+    }
+
+    fn items(&self) -> &[String] {
+        &self.items
+    }
+
+    fn accepts_commands(&self) -> bool {
+        self.main.config.accept_commands
+    }
+
+    fn check(&self) -> BoxedFuture<Result<()>> {
+        let servers = self.clone();
+        Box::pin(async move {
+            servers.main.check().await?;
+            for (name, server) in &servers.others {
+                server
+                    .check()
+                    .await
+                    .with_context(|| format!("openHAB server {name} failed"))?;
+            }
+            Ok(())
+        })
+    }
+
+    fn get_state(&self, item: &str) -> BoxedFuture<Result<String>> {
+        let (server, item) = self.route(item);
+        server.get_state(item)
+    }
+
+    fn send_command(&self, item: &str, command: &str) -> BoxedFuture<Result<()>> {
+        let (server, item) = self.route(item);
+        server.send_command(item, command)
+    }
+
+    /// Follows every server, retrying each on its own so one going down
+    /// only marks its own items as failed. Never returns.
+    fn subscribe_events(
+        &self,
+        states: watch::Sender<ItemStates>,
+        on_event: EventCallback,
+    ) -> BoxedFuture<Result<()>> {
+        let on_event = Arc::new(Mutex::new(on_event));
+        let servers = std::iter::once((None, self.main.clone()))
+            .chain(
+                self.others
+                    .iter()
+                    .map(|(name, server)| (Some(name.clone()), server.clone())),
+            )
+            .map(|(prefix, server)| follow_server(prefix, server, states.clone(), on_event.clone()))
+            .collect::<Vec<_>>();
+        Box::pin(async move {
+            futures_util::future::join_all(servers).await;
+            Ok(())
+        })
+    }
+}
+
+/// Mirrors the states and events of one of several servers, with their
+/// items prefixed, reconnecting whenever the connection drops.
+async fn follow_server(
+    prefix: Option<String>,
+    server: OpenHab,
+    states: watch::Sender<ItemStates>,
+    on_event: Arc<Mutex<EventCallback>>,
+) {
+    let name = |item: &str| match &prefix {
+        Some(prefix) => format!("{prefix}:{item}"),
+        None => item.to_string(),
+    };
+    let mut failing = false;
+    loop {
+        let (server_tx, mut server_rx) = watch::channel(ItemStates::new());
+        let events = {
+            let (on_event, prefix) = (on_event.clone(), prefix.clone());
+            Box::new(move |event| {
+                let event = match (event, &prefix) {
+                    (
+                        OpenHabEvent::ItemStateChanged {
+                            item,
+                            old_state,
+                            state,
+                        },
+                        Some(prefix),
+                    ) => OpenHabEvent::ItemStateChanged {
+                        item: format!("{prefix}:{item}"),
+                        old_state,
+                        state,
+                    },
+                    (OpenHabEvent::ItemCommand { item, command }, Some(prefix)) => {
+                        OpenHabEvent::ItemCommand {
+                            item: format!("{prefix}:{item}"),
+                            command,
+                        }
+                    }
+                    (event, _) => event,
+                };
+                (on_event.lock().unwrap())(event)
+            })
+        };
+        let mirror = async {
+            while server_rx.changed().await.is_ok() {
+                let server_states = server_rx.borrow_and_update().clone();
+                states.send_modify(|states| {
+                    for (item, state) in server_states {
+                        states.insert(name(&item), state);
+                    }
+                });
+                failing = false;
+            }
+        };
+        let result = tokio::select! {
+            result = server.subscribe_events(server_tx, events) => result,
+            () = mirror => Ok(()),
+        };
+        if let Err(err) = result {
+            // Report the first failure only, not every retry.
+            let server = prefix.as_deref().unwrap_or("main");
+            match failing {
+                true => debug!(server, "openHAB server still failing: {err:#}"),
+                false => warn!(server, "openHAB server failed: {err:#}"),
+            }
+            failing = true;
+        }
+        states.send_modify(|states| {
+            for item in &server.config.items {
+                states.insert(name(item), STATE_ERROR.to_string());
+            }
+        });
+        tokio::time::sleep(SERVER_RETRY).await;
+    }
+}
+
+/// Delay before reconnecting to one of several servers.
+const SERVER_RETRY: Duration = Duration::from_secs(10);
+/// How often to tell the openHAB WebSocket that we are still listening.
+const WEBSOCKET_HEARTBEAT: Duration = Duration::from_secs(5);
+
+// Function to retrieve OpenHAB item state
+#[instrument(skip(openhab), fields(url = %openhab.config.url))]
+pub async fn get_item_state(openhab: &OpenHab, item: &str) -> Result<String> {
+    let request = request(
+        openhab,
+        Method::GET,
+        &format!("items/{}", path_segment(item)),
+    )?
+    .header("Accept", "application/json");
+    let response = send(&openhab.config, request).await?;
+    let item: Item = response.json().await.context("unexpected item JSON")?;
+    Ok(item.state)
+}
+
+/// Fetches the state of every configured item concurrently.
+pub async fn get_item_states(openhab: &OpenHab) -> Result<ItemStates> {
+    let items = &openhab.config.items;
+    let states =
+        futures_util::future::try_join_all(items.iter().map(|item| get_item_state(openhab, item)))
+            .await?;
+    Ok(items.iter().cloned().zip(states).collect())
+}
+
+/// Lists every item on the server, sorted by name.
+#[instrument(skip_all, fields(url = %openhab.config.url))]
+pub async fn list_items(openhab: &OpenHab) -> Result<Vec<ItemInfo>> {
+    let request = request(openhab, Method::GET, "items")?.header("Accept", "application/json");
+    let response = send(&openhab.config, request).await?;
+    let mut items: Vec<ItemInfo> = response.json().await.context("unexpected items JSON")?;
+    items.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(items)
+}
+
+/// Lists every Thing on the server with its status.
+#[instrument(skip_all, fields(url = %openhab.config.url))]
+pub async fn list_things(openhab: &OpenHab) -> Result<Vec<ThingInfo>> {
+    let request = request(openhab, Method::GET, "things")?.header("Accept", "application/json");
+    let response = send(&openhab.config, request).await?;
+    response.json().await.context("unexpected things JSON")
+}
+
+/// Checks the Things every `interval` and passes those whose status
+/// changed since the last check to `on_change`.
+///
+/// Only returns when a check fails.
+#[instrument(skip_all, fields(url = %openhab.config.url))]
+pub async fn watch_things(
+    openhab: &OpenHab,
+    interval: Duration,
+    mut on_change: impl FnMut(ThingInfo),
+) -> Result<()> {
+    let mut known: BTreeMap<String, ThingStatus> = BTreeMap::new();
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut first = true;
+    loop {
+        interval.tick().await;
+        for thing in list_things(openhab).await? {
+            let previous = known.insert(thing.uid.clone(), thing.status.clone());
+            if !first && previous.as_ref() != Some(&thing.status) {
+                debug!(thing = %thing.uid, status = %thing.status.status, "thing status changed");
+                on_change(thing);
+            }
+        }
+        first = false;
+    }
+}
+
+/// Sends `command` to `item`, like pressing a switch in the openHAB UI.
+#[instrument(skip(openhab), fields(url = %openhab.config.url))]
+pub async fn send_command(openhab: &OpenHab, item: &str, command: &str) -> Result<()> {
+    let request = request(
+        openhab,
+        Method::POST,
+        &format!("items/{}", path_segment(item)),
+    )?
+    .header("Content-Type", "text/plain")
+    .body(command.to_string());
+    send(&openhab.config, request).await?;
+    Ok(())
+}
+
+/// Checks that the server answers, without retrying.
+#[instrument(skip_all, fields(url = %openhab.config.url))]
+pub async fn check(openhab: &OpenHab) -> Result<()> {
+    let config = OpenHabConfig {
+        retries: 0,
+        ..openhab.config.clone()
+    };
+    let request = request(openhab, Method::GET, "")?.header("Accept", "application/json");
+    send(&config, request).await?;
+    Ok(())
+}
+
+/// Publishes the current state of the configured items to `states`, then
+/// follows the openHAB event bus and publishes every change as it happens.
+///
+/// `states` serves as the cache of the item states. The REST API is only
+/// asked again once they are older than [`OpenHabConfig::state_ttl`], in case
+/// the event bus missed a change.
+///
+/// Only returns when the connection fails or the event stream ends.
+#[instrument(skip_all, fields(url = %openhab.config.url))]
+pub async fn follow_item_states(
+    openhab: &OpenHab,
+    states: &watch::Sender<ItemStates>,
+) -> Result<()> {
+    let config = &openhab.config;
+    states.send_replace(get_item_states(openhab).await?);
+
+    let url = format!(
+        "{}/rest/events?topics=openhab/items/*/statechanged",
+        config.url.trim_end_matches('/'),
+    );
+    let request = openhab
+        .client
+        .get(url)
+        .header("Accept", "text/event-stream");
+    let mut body = send(config, request).await?.bytes_stream();
+
+    // Server-sent events are separated by blank lines, their payload is in
+    // the `data:` lines.
+    let mut buffer = Vec::new();
+    let mut expires = tokio::time::Instant::now() + config.state_ttl();
+    loop {
+        let chunk = match tokio::time::timeout_at(expires, body.next()).await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(_) => {
+                debug!("item states expired");
+                states.send_replace(get_item_states(openhab).await?);
+                expires = tokio::time::Instant::now() + config.state_ttl();
+                continue;
+            }
+        };
+        buffer.extend(chunk?.iter().filter(|&&b| b != b'\r'));
+        while let Some(end) = buffer.windows(2).position(|w| w == b"\n\n") {
+            let event: Vec<u8> = buffer.drain(..end + 2).collect();
+            let data: String = String::from_utf8_lossy(&event)
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(str::trim_start)
+                .collect();
+            if data.is_empty() {
+                continue;
+            }
+            let event: BusEvent = serde_json::from_str(&data).context("unexpected event JSON")?;
+            if let OpenHabEvent::ItemStateChanged { item, state, .. } = event.into_event()? {
+                debug!(%item, %state, "item state changed");
+                if config.items.contains(&item) {
+                    states.send_modify(|states| {
+                        states.insert(item, state);
+                    });
+                }
+            }
+        }
+    }
+    bail!("openHAB closed the event stream")
+}
+
+/// Connects to the openHAB WebSocket at `/ws` and passes every item event to
+/// `on_event`.
+///
+/// Only returns when the connection fails or the server closes it.
+#[instrument(skip_all, fields(url = %config.url))]
+pub async fn connect_websocket(
+    config: &OpenHabConfig,
+    mut on_event: impl FnMut(OpenHabEvent),
+) -> Result<()> {
+    let mut url = url::Url::parse(&config.url).context("invalid openHAB URL")?;
+    let scheme = match url.scheme() {
+        "https" => "wss",
+        _ => "ws",
+    };
+    url.set_scheme(scheme)
+        .map_err(|()| anyhow::anyhow!("invalid openHAB URL"))?;
+    url.set_path("/ws");
+    if let Some(token) = &config.token {
+        url.query_pairs_mut().append_pair("accessToken", token);
+    }
+    let mut request = url.as_str().into_client_request()?;
+    if let Some(basic_auth) = config.basic_auth() {
+        request
+            .headers_mut()
+            .insert(header::AUTHORIZATION, basic_auth.parse()?);
+    }
+    let host = url.host_str().context("invalid openHAB URL")?;
+    let port = url.port_or_known_default().context("invalid openHAB URL")?;
+    let (mut socket, _) = async {
+        let stream =
+            tokio::time::timeout(config.connect_timeout(), TcpStream::connect((host, port)))
+                .await
+                .context("connecting timed out")??;
+        let connector = Connector::NativeTls(tls_connector(config)?);
+        let socket =
+            tokio_tungstenite::client_async_tls_with_config(request, stream, None, Some(connector))
+                .await?;
+        anyhow::Ok(socket)
+    }
+    .await
+    .context("failed to connect to the openHAB WebSocket")?;
+
+    let filter = serde_json::json!({
+        "type": "WebSocketEvent",
+        "topic": "openhab/websocket/filter/type",
+        "payload": r#"["ItemStateChangedEvent","ItemCommandEvent"]"#,
+        "source": "iroh-gossip-chat",
+    });
+    socket.send(WsMessage::Text(filter.to_string())).await?;
+
+    let heartbeat = serde_json::json!({
+        "type": "WebSocketEvent",
+        "topic": "openhab/websocket/heartbeat",
+        "payload": "PING",
+        "source": "iroh-gossip-chat",
+    })
+    .to_string();
+    let mut interval = tokio::time::interval(WEBSOCKET_HEARTBEAT);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                socket.send(WsMessage::Text(heartbeat.clone())).await?;
+            }
+            message = socket.next() => {
+                let text = match message.transpose()? {
+                    Some(WsMessage::Text(text)) => text,
+                    Some(WsMessage::Close(_)) | None => break,
+                    Some(_) => continue,
+                };
+                let event: BusEvent =
+                    serde_json::from_str(&text).context("unexpected event JSON")?;
+                // Our own heartbeats and filters are echoed back.
+                if event.kind == "WebSocketEvent" {
+                    continue;
+                }
+                let event = event.into_event()?;
+                debug!(?event, "openHAB event");
+                on_event(event);
+            }
+        }
+    }
+    bail!("openHAB closed the WebSocket")
+}
+
+/// Fetches the configured items every `interval` and passes those whose
+/// state changed since the last poll to `on_event`.
+///
+/// Only returns when a poll fails.
+#[instrument(skip_all, fields(url = %openhab.config.url))]
+pub async fn poll_item_states(
+    openhab: &OpenHab,
+    interval: Duration,
+    mut on_event: impl FnMut(OpenHabEvent),
+) -> Result<()> {
+    let mut known = get_item_states(openhab).await?;
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        for (item, state) in get_item_states(openhab).await? {
+            let old_state = known
+                .insert(item.clone(), state.clone())
+                .unwrap_or_default();
+            if old_state != state {
+                debug!(%item, %state, "polled item changed");
+                on_event(OpenHabEvent::ItemStateChanged {
+                    item,
+                    old_state,
+                    state,
+                });
+            }
+        }
+    }
+}
+
+/// Characters left as they are in a name put into a URL path. Dots are
+/// encoded too, so a name cannot be `..` and step out of its endpoint.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'_').remove(b'-');
+
+/// `name`, such as an item name from a peer, encoded as a single segment
+/// of a URL path.
+pub(crate) fn path_segment(name: &str) -> String {
+    utf8_percent_encode(name, PATH_SEGMENT).to_string()
+}
+
+/// A request to `path` under the REST API, which has to be answered within
+/// the read timeout.
+fn request(openhab: &OpenHab, method: Method, path: &str) -> Result<RequestBuilder> {
+    let config = &openhab.config;
+    let url = format!("{}/rest/{path}", config.url.trim_end_matches('/'));
+    Ok(openhab
+        .client
+        .request(method, url)
+        .timeout(config.read_timeout()))
+}
+
+/// An HTTP client for the server, trusting the configured certificates.
+fn client(config: &OpenHabConfig) -> Result<Client> {
+    let mut builder = Client::builder()
+        .connect_timeout(config.connect_timeout())
+        .danger_accept_invalid_certs(config.accept_invalid_certs);
+    if let Some(path) = &config.ca_cert {
+        let pem = std::fs::read(path)
+            .with_context(|| format!("failed to read CA certificate {}", path.display()))?;
+        builder = builder.add_root_certificate(
+            reqwest::Certificate::from_pem(&pem).context("invalid CA certificate")?,
+        );
+    }
+    Ok(builder.build()?)
+}
+
+/// A TLS connector for the WebSocket trusting the configured certificates.
+fn tls_connector(config: &OpenHabConfig) -> Result<native_tls::TlsConnector> {
+    let mut builder = native_tls::TlsConnector::builder();
+    builder.danger_accept_invalid_certs(config.accept_invalid_certs);
+    if let Some(path) = &config.ca_cert {
+        let pem = std::fs::read(path)
+            .with_context(|| format!("failed to read CA certificate {}", path.display()))?;
+        builder.add_root_certificate(
+            native_tls::Certificate::from_pem(&pem).context("invalid CA certificate")?,
+        );
+    }
+    Ok(builder.build()?)
+}
+
+/// Sends a request with the configured credentials, retrying transient
+/// failures with jittered exponential backoff.
+async fn send(config: &OpenHabConfig, mut request: RequestBuilder) -> Result<Response> {
+    let mut delay = config.retry_delay();
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        // Requests with streaming bodies cannot be cloned, nor retried.
+        let retry = match attempt <= config.retries {
+            true => request.try_clone(),
+            false => None,
+        };
+        let err = match send_once(config, request).await {
+            Ok(response) => return Ok(response),
+            Err(err) => err,
+        };
+        let Some(retry) = retry.filter(|_| is_transient(&err)) else {
+            return Err(err);
+        };
+        let jittered = delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0));
+        debug!(
+            attempt,
+            ?jittered,
+            "openHAB request failed, retrying: {err:#}"
+        );
+        tokio::time::sleep(jittered).await;
+        delay *= 2;
+        request = retry;
+    }
+}
+
+/// Whether a failed request may succeed when tried again.
+fn is_transient(err: &anyhow::Error) -> bool {
+    if err.is::<tokio::time::error::Elapsed>() {
+        return true;
+    }
+    err.downcast_ref::<reqwest::Error>().is_some_and(|err| {
+        err.is_timeout()
+            || err.is_connect()
+            || err.status().is_some_and(|status| status.is_server_error())
+    })
+}
+
+/// A single attempt of [`send`].
+async fn send_once(config: &OpenHabConfig, mut request: RequestBuilder) -> Result<Response> {
+    // Basic auth takes the `Authorization` header, openHAB also accepts the
+    // token in its own one.
+    match (config.basic_auth(), &config.token) {
+        (Some(basic_auth), token) => {
+            request = request.header(reqwest::header::AUTHORIZATION, basic_auth);
+            if let Some(token) = token {
+                request = request.header("X-OPENHAB-TOKEN", token);
+            }
+        }
+        (None, Some(token)) => request = request.bearer_auth(token),
+        (None, None) => {}
+    }
+    let start = Instant::now();
+    // Only waiting for the response head is limited, as event streams stay
+    // open for good.
+    let response = tokio::time::timeout(config.attempt_timeout(), request.send())
+        .await
+        .with_context(|| {
+            format!(
+                "openHAB did not answer within {}s",
+                config.attempt_timeout().as_secs()
+            )
+        })??;
+    let elapsed = start.elapsed();
+    metrics()
+        .openhab_request_duration
+        .observe(elapsed.as_secs_f64());
+    debug!(status = %response.status(), ?elapsed, "openHAB answered");
+    if response.status() == StatusCode::UNAUTHORIZED {
+        match (&config.username, &config.token) {
+            (Some(_), _) => {
+                bail!("openHAB rejected the user or API token (401 Unauthorized)")
+            }
+            (None, Some(_)) => bail!("openHAB rejected the API token (401 Unauthorized)"),
+            (None, None) => bail!(
+                "openHAB requires an API token or user, set --openhab-token or --openhab-user (401 Unauthorized)"
+            ),
+        }
+    }
+    Ok(response.error_for_status()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_quantities_into_value_and_unit() {
+        let now = Utc::now();
+        let reading = SensorReading::from_state("Temp", "21.5 °C", now);
+        assert_eq!(reading.value, "21.5");
+        assert_eq!(reading.unit.as_deref(), Some("°C"));
+        assert_eq!(reading.quality, Quality::Good);
+        assert_eq!(reading.to_string(), "Temp=21.5 °C");
+
+        let reading = SensorReading::from_state("Door", "OPEN", now);
+        assert_eq!((reading.value.as_str(), reading.unit), ("OPEN", None));
+
+        // Only numbers have units, text states keep their spaces.
+        let reading = SensorReading::from_state("Scene", "movie night", now);
+        assert_eq!(
+            (reading.value.as_str(), reading.unit),
+            ("movie night", None)
+        );
+    }
+
+    #[test]
+    fn rates_the_quality_of_states() {
+        let now = Utc::now();
+        for (state, quality) in [
+            ("NULL", Quality::Undefined),
+            ("UNDEF", Quality::Undefined),
+            (STATE_ERROR, Quality::Unavailable),
+            ("ON", Quality::Good),
+        ] {
+            assert_eq!(
+                SensorReading::from_state("Item", state, now).quality,
+                quality
+            );
+        }
+    }
+
+    fn server(url: &str, items: &[&str]) -> OpenHabConfig {
+        OpenHabConfig {
+            url: url.to_string(),
+            items: items.iter().map(|item| item.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn routes_items_to_their_server() {
+        let main = OpenHab::new(server("http://main", &["Temp"])).unwrap();
+        let servers = OpenHabServers::new(
+            main,
+            [("garage".to_string(), server("http://garage", &["Door"]))],
+        )
+        .unwrap();
+        assert_eq!(servers.items(), ["Temp", "garage:Door"]);
+
+        let (openhab, item) = servers.route("garage:Door");
+        assert_eq!(
+            (openhab.config.url.as_str(), item),
+            ("http://garage", "Door")
+        );
+        let (openhab, item) = servers.route("Temp");
+        assert_eq!((openhab.config.url.as_str(), item), ("http://main", "Temp"));
+        // Unknown prefixes are left to the main server.
+        let (openhab, item) = servers.route("cellar:Pump");
+        assert_eq!(
+            (openhab.config.url.as_str(), item),
+            ("http://main", "cellar:Pump")
+        );
+    }
+
+    #[test]
+    fn encodes_names_as_one_path_segment() {
+        assert_eq!(path_segment("Living_Room-1"), "Living_Room-1");
+        assert_eq!(path_segment("../things/x"), "%2E%2E%2Fthings%2Fx");
+        assert_eq!(path_segment("a b?c"), "a%20b%3Fc");
+    }
+}
@@ -0,0 +1,74 @@
+//! Round-trip time to a single peer, measured over a direct stream.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Result};
+use futures_lite::future::Boxed as BoxedFuture;
+use iroh::{
+    endpoint::{Connecting, ConnectionType},
+    protocol::ProtocolHandler,
+    Endpoint, NodeId,
+};
+use tracing::instrument;
+
+/// ALPN of the ping protocol.
+pub const ALPN: &[u8] = b"iroh-gossip-chat/ping/0";
+
+/// Size of the random payload echoed back.
+const PING_SIZE: usize = 8;
+
+/// Outcome of a [`ping`].
+#[derive(Debug, Clone)]
+pub struct Pong {
+    /// Time from sending the ping until its echo arrived.
+    pub rtt: Duration,
+    /// Time it took to connect, including the handshake.
+    pub connect: Duration,
+    /// How packets reach the peer, directly or through a relay.
+    pub path: ConnectionType,
+}
+
+/// Sends a ping to `node_id` and waits for the echo.
+#[instrument(skip_all, fields(to = %node_id.fmt_short()))]
+pub async fn ping(endpoint: &Endpoint, node_id: NodeId) -> Result<Pong> {
+    let start = Instant::now();
+    let conn = endpoint.connect(node_id, ALPN).await?;
+    let connect = start.elapsed();
+    let (mut send, mut recv) = conn.open_bi().await?;
+    let payload: [u8; PING_SIZE] = rand::random();
+    let start = Instant::now();
+    send.write_all(&payload).await?;
+    send.finish()?;
+    let echo = recv.read_to_end(PING_SIZE).await?;
+    let rtt = start.elapsed();
+    ensure!(
+        echo == payload,
+        "unexpected reply from {}",
+        node_id.fmt_short()
+    );
+    let path = endpoint
+        .conn_type(node_id)
+        .ok()
+        .and_then(|conn_type| conn_type.get().ok())
+        .unwrap_or_default();
+    conn.close(0u32.into(), b"bye");
+    Ok(Pong { rtt, connect, path })
+}
+
+/// Echoes pings back to their sender.
+#[derive(Debug, Clone)]
+pub(crate) struct Ping;
+
+impl ProtocolHandler for Ping {
+    fn accept(&self, connecting: Connecting) -> BoxedFuture<Result<()>> {
+        Box::pin(async move {
+            let conn = connecting.await?;
+            let (mut send, mut recv) = conn.accept_bi().await?;
+            let payload = recv.read_to_end(PING_SIZE).await?;
+            send.write_all(&payload).await?;
+            send.finish()?;
+            conn.closed().await;
+            Ok(())
+        })
+    }
+}
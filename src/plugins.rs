@@ -0,0 +1,310 @@
+//! Sandboxed WebAssembly plugins, for automations we do not fully trust,
+//! such as community plugins on a gateway node.
+//!
+//! A plugin has no WASI, files or network. It only sees these functions,
+//! imported from the `chat` module:
+//!
+//! - `log(ptr, len)` logs a UTF-8 line,
+//! - `send(room_ptr, room_len, text_ptr, text_len) -> i32` sends a chat
+//!   message to a room we are in, given as a hex topic,
+//! - `command(item_ptr, item_len, value_ptr, value_len) -> i32` sends a
+//!   command to an openHAB item,
+//! - `state(item_ptr, item_len, out_ptr, out_len) -> i32` reads the state
+//!   of an openHAB item into `out` and returns its length, which may exceed
+//!   `out_len` if the buffer was too small.
+//!
+//! Only the items listed in the plugin's [`PluginConfig`] can be read or
+//! commanded. The functions return -1 when refused or failed.
+//!
+//! A plugin exports its `memory`, `alloc(len) -> ptr` for the host to place
+//! events in, and `on_event(ptr, len)`, called with each event as JSON in
+//! the format of the HTTP API's event stream. Every call may execute a
+//! limited number of instructions, and memory is capped.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use futures_lite::future::Boxed as BoxedFuture;
+use iroh_gossip::proto::TopicId;
+use tokio::runtime::Handle;
+use tracing::{debug, info, warn};
+use wasmi::{
+    Caller, Config, Engine, Instance, Linker, Module, Store, StoreLimits, StoreLimitsBuilder,
+};
+
+use crate::{
+    config::PluginConfig,
+    handler::{ChatMessage, DirectMessage, ItemChange, MessageHandler, Presence},
+    http, ChatNode, Event,
+};
+
+/// Instructions, roughly, a plugin may execute per event.
+const FUEL_PER_CALL: u64 = 10_000_000;
+/// Largest linear memory a plugin may grow, in bytes.
+const MAX_MEMORY: usize = 16 * 1024 * 1024;
+/// Longest string the host reads out of a plugin's memory.
+const MAX_STRING: usize = 64 * 1024;
+/// Returned by host functions for refused or failed calls.
+const REFUSED: i32 = -1;
+
+/// What host functions can reach.
+struct Host {
+    /// File name of the plugin, used in logs.
+    name: String,
+    items: Vec<String>,
+    limits: StoreLimits,
+    /// The node the current event came from, unset while starting.
+    node: Option<ChatNode>,
+    runtime: Handle,
+}
+
+impl Host {
+    fn may_access(&self, item: &str) -> bool {
+        let allowed = self.items.iter().any(|allowed| allowed == item);
+        if !allowed {
+            warn!(plugin = %self.name, "refused access to item {item}");
+        }
+        allowed
+    }
+}
+
+struct Loaded {
+    store: Store<Host>,
+    instance: Instance,
+}
+
+/// A loaded plugin, registered as a [`MessageHandler`].
+#[derive(Clone)]
+pub struct Plugin {
+    name: String,
+    loaded: Arc<Mutex<Loaded>>,
+}
+
+impl Plugin {
+    /// Compiles and instantiates the plugin at `config.path`. Must be called
+    /// from within the runtime.
+    pub fn load(config: &PluginConfig) -> Result<Self> {
+        let path = &config.path;
+        let name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        let wasm = std::fs::read(path)
+            .with_context(|| format!("failed to read plugin {}", path.display()))?;
+        let mut engine_config = Config::default();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config);
+        let module = Module::new(&engine, wasm)
+            .with_context(|| format!("invalid plugin {}", path.display()))?;
+        let host = Host {
+            name: name.clone(),
+            items: config.items.clone(),
+            limits: StoreLimitsBuilder::new()
+                .memory_size(MAX_MEMORY)
+                .instances(1)
+                .build(),
+            node: None,
+            runtime: Handle::current(),
+        };
+        let mut store = Store::new(&engine, host);
+        store.limiter(|host| &mut host.limits);
+        store.set_fuel(FUEL_PER_CALL)?;
+        let instance = linker(&engine)?
+            .instantiate_and_start(&mut store, &module)
+            .with_context(|| format!("failed to start plugin {}", path.display()))?;
+        instance
+            .get_memory(&store, "memory")
+            .with_context(|| format!("plugin {} exports no memory", path.display()))?;
+        instance
+            .get_typed_func::<i32, i32>(&store, "alloc")
+            .with_context(|| format!("plugin {} exports no alloc", path.display()))?;
+        instance
+            .get_typed_func::<(i32, i32), ()>(&store, "on_event")
+            .with_context(|| format!("plugin {} exports no on_event", path.display()))?;
+        Ok(Self {
+            name,
+            loaded: Arc::new(Mutex::new(Loaded { store, instance })),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Passes `event` to the plugin.
+    fn call(&self, node: ChatNode, event: Event) -> BoxedFuture<Result<()>> {
+        let this = self.clone();
+        let json = http::event_json(&event).to_string();
+        Box::pin(async move {
+            let name = this.name.clone();
+            // The plugin and the host functions it calls block.
+            tokio::task::spawn_blocking(move || this.call_blocking(node, json))
+                .await?
+                .with_context(|| format!("plugin {name} failed"))
+        })
+    }
+
+    fn call_blocking(&self, node: ChatNode, json: String) -> Result<()> {
+        let mut loaded = self.loaded.lock().unwrap();
+        let Loaded { store, instance } = &mut *loaded;
+        store.data_mut().node = Some(node);
+        store.set_fuel(FUEL_PER_CALL)?;
+        let memory = instance
+            .get_memory(&*store, "memory")
+            .context("no memory")?;
+        let alloc = instance.get_typed_func::<i32, i32>(&*store, "alloc")?;
+        let on_event = instance.get_typed_func::<(i32, i32), ()>(&*store, "on_event")?;
+        let len = i32::try_from(json.len())?;
+        let ptr = alloc.call(&mut *store, len)?;
+        memory
+            .write(&mut *store, ptr as u32 as usize, json.as_bytes())
+            .map_err(|err| anyhow!("alloc returned an invalid pointer: {err}"))?;
+        let result = on_event.call(&mut *store, (ptr, len));
+        store.data_mut().node = None;
+        result?;
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Plugin").field("name", &self.name).finish()
+    }
+}
+
+impl MessageHandler for Plugin {
+    fn on_message(&self, node: ChatNode, message: ChatMessage) -> BoxedFuture<Result<()>> {
+        self.call(node, message.into())
+    }
+
+    fn on_direct_message(&self, node: ChatNode, message: DirectMessage) -> BoxedFuture<Result<()>> {
+        self.call(node, message.into())
+    }
+
+    fn on_presence(&self, node: ChatNode, presence: Presence) -> BoxedFuture<Result<()>> {
+        self.call(node, presence.into())
+    }
+
+    fn on_item_change(&self, node: ChatNode, change: ItemChange) -> BoxedFuture<Result<()>> {
+        self.call(node, change.into())
+    }
+}
+
+/// The host functions plugins may import.
+fn linker(engine: &Engine) -> Result<Linker<Host>> {
+    let mut linker = Linker::new(engine);
+    linker.func_wrap(
+        "chat",
+        "log",
+        |caller: Caller<'_, Host>, ptr: i32, len: i32| {
+            if let Some(line) = read_str(&caller, ptr, len) {
+                info!(target: "plugin", plugin = %caller.data().name, "{line}");
+            }
+        },
+    )?;
+    linker.func_wrap(
+        "chat",
+        "send",
+        |caller: Caller<'_, Host>, room_ptr: i32, room_len: i32, text_ptr: i32, text_len: i32| {
+            let (Some(room), Some(text)) = (
+                read_str(&caller, room_ptr, room_len),
+                read_str(&caller, text_ptr, text_len),
+            ) else {
+                return REFUSED;
+            };
+            let host = caller.data();
+            let Some(node) = &host.node else {
+                return REFUSED;
+            };
+            let Ok(topic) = room.parse::<TopicId>() else {
+                return REFUSED;
+            };
+            if !node.rooms().contains(&topic) {
+                warn!(plugin = %host.name, "refused sending to room {topic}, we are not in it");
+                return REFUSED;
+            }
+            let sent = host
+                .runtime
+                .block_on(node.send_text(topic, text, Vec::new()));
+            status(host, sent.map(|_| ()))
+        },
+    )?;
+    linker.func_wrap(
+        "chat",
+        "command",
+        |caller: Caller<'_, Host>, item_ptr: i32, item_len: i32, value_ptr: i32, value_len: i32| {
+            let (Some(item), Some(value)) = (
+                read_str(&caller, item_ptr, item_len),
+                read_str(&caller, value_ptr, value_len),
+            ) else {
+                return REFUSED;
+            };
+            let host = caller.data();
+            if !host.may_access(&item) {
+                return REFUSED;
+            }
+            let Some(home) = host.node.as_ref().and_then(|node| node.home()) else {
+                return REFUSED;
+            };
+            let sent = host.runtime.block_on(home.send_command(&item, &value));
+            status(host, sent)
+        },
+    )?;
+    linker.func_wrap(
+        "chat",
+        "state",
+        |mut caller: Caller<'_, Host>, item_ptr: i32, item_len: i32, out_ptr: i32, out_len: i32| {
+            let Some(item) = read_str(&caller, item_ptr, item_len) else {
+                return REFUSED;
+            };
+            let host = caller.data();
+            if !host.may_access(&item) {
+                return REFUSED;
+            }
+            let Some(home) = host.node.as_ref().and_then(|node| node.home()) else {
+                return REFUSED;
+            };
+            let state = match host.runtime.block_on(home.get_state(&item)) {
+                Ok(state) => state,
+                Err(err) => return status(host, Err(err)),
+            };
+            let Ok(len) = i32::try_from(state.len()) else {
+                return REFUSED;
+            };
+            if len <= out_len {
+                let Some(memory) = caller.get_export("memory").and_then(|e| e.into_memory()) else {
+                    return REFUSED;
+                };
+                if memory
+                    .write(&mut caller, out_ptr as u32 as usize, state.as_bytes())
+                    .is_err()
+                {
+                    return REFUSED;
+                }
+            }
+            len
+        },
+    )?;
+    Ok(linker)
+}
+
+/// Reads a UTF-8 string out of the calling plugin's memory.
+fn read_str(caller: &Caller<'_, Host>, ptr: i32, len: i32) -> Option<String> {
+    let len = usize::try_from(len).ok().filter(|len| *len <= MAX_STRING)?;
+    let memory = caller.get_export("memory")?.into_memory()?;
+    let mut buf = vec![0; len];
+    memory.read(caller, ptr as u32 as usize, &mut buf).ok()?;
+    String::from_utf8(buf).ok()
+}
+
+/// The return value of a host function that did `result`.
+fn status(host: &Host, result: Result<()>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            debug!(plugin = %host.name, "host call failed: {err:#}");
+            REFUSED
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Profiles, separate identities on one machine such as a "home" and a
+//! "work" persona.
+//!
+//! Each profile has its own data directory, holding its secret key, its
+//! history with the rooms it was in, the tickets of the rooms to rejoin on
+//! the next start, and optionally a `config.toml` with its nickname, room
+//! and other settings.
+
+use std::{path::PathBuf, str::FromStr};
+
+use anyhow::{ensure, Context, Result};
+use iroh_gossip::proto::TopicId;
+
+use crate::ticket::Ticket;
+
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub name: String,
+    /// `iroh-gossip-chat/profiles/<name>` in the user data directory.
+    pub dir: PathBuf,
+}
+
+impl Profile {
+    pub fn new(name: &str) -> Result<Self> {
+        ensure!(
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+            "invalid profile name {name:?}, use letters, digits, '-' and '_'"
+        );
+        let dir = dirs::data_dir()
+            .context("no data directory found for the profile")?
+            .join("iroh-gossip-chat")
+            .join("profiles")
+            .join(name);
+        Ok(Self {
+            name: name.to_string(),
+            dir,
+        })
+    }
+
+    /// The profile's config file, used when no other is given.
+    pub fn config_path(&self) -> PathBuf {
+        self.dir.join("config.toml")
+    }
+
+    pub fn secret_key_path(&self) -> PathBuf {
+        self.dir.join("secret.key")
+    }
+
+    pub fn history_path(&self) -> PathBuf {
+        self.dir.join("history.db")
+    }
+
+    /// The tickets of the rooms joined and not left, one per line.
+    pub fn rooms_path(&self) -> PathBuf {
+        self.dir.join("rooms.txt")
+    }
+
+    /// The rooms to rejoin on start.
+    pub fn saved_rooms(&self) -> Result<Vec<Ticket>> {
+        let path = self.rooms_path();
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+        text.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                Ticket::from_str(line.trim())
+                    .with_context(|| format!("invalid room in {}", path.display()))
+            })
+            .collect()
+    }
+
+    /// Remembers the room of `ticket`, replacing an earlier ticket to it.
+    pub fn save_room(&self, ticket: Ticket) -> Result<()> {
+        let mut rooms = self.saved_rooms()?;
+        rooms.retain(|room| room.topic != ticket.topic);
+        rooms.push(ticket);
+        self.write_rooms(&rooms)
+    }
+
+    /// Stops rejoining the room on `topic`.
+    pub fn forget_room(&self, topic: TopicId) -> Result<()> {
+        let mut rooms = self.saved_rooms()?;
+        rooms.retain(|room| room.topic != topic);
+        self.write_rooms(&rooms)
+    }
+
+    fn write_rooms(&self, rooms: &[Ticket]) -> Result<()> {
+        let path = self.rooms_path();
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("failed to create {}", self.dir.display()))?;
+        let text: String = rooms.iter().map(|room| format!("{room}\n")).collect();
+        std::fs::write(&path, text).with_context(|| format!("failed to write {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_rooms_round_trip() {
+        let profile = Profile {
+            name: "test".to_string(),
+            dir: std::env::temp_dir()
+                .join(format!("iroh-gossip-chat-test-{}", rand::random::<u64>())),
+        };
+        assert!(profile.saved_rooms().unwrap().is_empty());
+
+        let (a, b) = (TopicId::from_bytes([1; 32]), TopicId::from_bytes([2; 32]));
+        profile.save_room(Ticket::new(a, vec![])).unwrap();
+        profile.save_room(Ticket::new(b, vec![])).unwrap();
+        // Joining again replaces the earlier ticket.
+        profile.save_room(Ticket::new(a, vec![])).unwrap();
+        let topics = |profile: &Profile| -> Vec<TopicId> {
+            profile
+                .saved_rooms()
+                .unwrap()
+                .iter()
+                .map(|room| room.topic)
+                .collect()
+        };
+        assert_eq!(topics(&profile), [b, a]);
+
+        profile.forget_room(b).unwrap();
+        assert_eq!(topics(&profile), [a]);
+        std::fs::remove_dir_all(&profile.dir).unwrap();
+    }
+}
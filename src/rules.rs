@@ -0,0 +1,145 @@
+//! Automation rules that turn chat messages into openHAB item commands,
+//! e.g. "lights off" sending `OFF` to `LivingRoom_Light`.
+
+use anyhow::{bail, Context, Result};
+use iroh::NodeId;
+use regex::Regex;
+
+use crate::config::RuleConfig;
+
+/// How a rule recognizes the messages it acts on.
+#[derive(Debug, Clone)]
+enum Matcher {
+    /// The text contains the keyword, ignoring case.
+    Keyword(String),
+    Pattern(Regex),
+}
+
+/// A rule ready to be matched against messages.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub name: String,
+    matcher: Matcher,
+    pub item: String,
+    pub command: String,
+    /// Nodes that may trigger the rule, everyone in the room when empty.
+    allow: Vec<NodeId>,
+}
+
+impl Rule {
+    fn new(config: &RuleConfig) -> Result<Self> {
+        let matcher = match (&config.keyword, &config.pattern) {
+            (Some(keyword), None) => Matcher::Keyword(keyword.to_lowercase()),
+            (None, Some(pattern)) => Matcher::Pattern(
+                Regex::new(pattern).with_context(|| format!("invalid rule pattern {pattern}"))?,
+            ),
+            _ => bail!(
+                "rule for item {} needs either a keyword or a pattern",
+                config.item
+            ),
+        };
+        let name = config
+            .name
+            .clone()
+            .or_else(|| config.keyword.clone())
+            .or_else(|| config.pattern.clone())
+            .unwrap_or_default();
+        Ok(Self {
+            name,
+            matcher,
+            item: config.item.clone(),
+            command: config.command.clone(),
+            allow: config.allow.clone(),
+        })
+    }
+
+    pub fn matches(&self, text: &str) -> bool {
+        match &self.matcher {
+            Matcher::Keyword(keyword) => text.to_lowercase().contains(keyword),
+            Matcher::Pattern(pattern) => pattern.is_match(text),
+        }
+    }
+
+    pub fn permits(&self, node_id: &NodeId) -> bool {
+        self.allow.is_empty() || self.allow.contains(node_id)
+    }
+}
+
+/// The configured rules, in order.
+#[derive(Debug, Clone, Default)]
+pub struct Rules(Vec<Rule>);
+
+impl Rules {
+    pub fn new(configs: &[RuleConfig]) -> Result<Self> {
+        configs
+            .iter()
+            .map(Rule::new)
+            .collect::<Result<_>>()
+            .map(Self)
+    }
+
+    /// Rules whose pattern matches `text`, whether or not the sender may
+    /// trigger them.
+    pub fn matching<'a>(&'a self, text: &'a str) -> impl Iterator<Item = &'a Rule> + 'a {
+        self.0.iter().filter(move |rule| rule.matches(text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::SecretKey;
+
+    use super::*;
+
+    fn rule(keyword: Option<&str>, pattern: Option<&str>) -> RuleConfig {
+        RuleConfig {
+            name: None,
+            keyword: keyword.map(Into::into),
+            pattern: pattern.map(Into::into),
+            item: "LivingRoom_Light".to_string(),
+            command: "OFF".to_string(),
+            allow: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn keywords_match_ignoring_case() {
+        let rules = Rules::new(&[rule(Some("Lights Off"), None)]).unwrap();
+        assert_eq!(rules.matching("please, LIGHTS OFF now").count(), 1);
+        assert_eq!(rules.matching("lights on").count(), 0);
+    }
+
+    #[test]
+    fn patterns_are_regular_expressions() {
+        let rules = Rules::new(&[rule(None, Some(r"^temp \d+$"))]).unwrap();
+        assert_eq!(rules.matching("temp 21").count(), 1);
+        assert_eq!(rules.matching("the temp 21").count(), 0);
+        assert!(Rules::new(&[rule(None, Some("(unclosed"))]).is_err());
+    }
+
+    #[test]
+    fn needs_either_a_keyword_or_a_pattern() {
+        assert!(Rules::new(&[rule(None, None)]).is_err());
+        assert!(Rules::new(&[rule(Some("off"), Some("off"))]).is_err());
+    }
+
+    #[test]
+    fn allow_list_limits_who_triggers_a_rule() {
+        let alice = SecretKey::generate(rand::rngs::OsRng).public();
+        let bob = SecretKey::generate(rand::rngs::OsRng).public();
+        let rules = Rules::new(&[
+            rule(Some("off"), None),
+            RuleConfig {
+                allow: vec![alice],
+                ..rule(None, Some("^off$"))
+            },
+        ])
+        .unwrap();
+        let matching: Vec<_> = rules.matching("off").collect();
+        assert_eq!(matching[0].name, "off");
+        assert!(matching[0].permits(&bob));
+        assert_eq!(matching[1].name, "^off$");
+        assert!(matching[1].permits(&alice));
+        assert!(!matching[1].permits(&bob));
+    }
+}
@@ -0,0 +1,272 @@
+//! Automations written in [Rhai](https://rhai.rs), loaded from the `.rhai`
+//! files of a directory so they can be changed without rebuilding.
+//!
+//! A script defines any of `on_message(msg)`, `on_direct_message(msg)`,
+//! `on_presence(event)` and `on_item_change(change)`, which get the event
+//! as an object map, and acts through these functions:
+//!
+//! - `send(room, text)` sends a chat message to a room, e.g. `msg.room`,
+//! - `send_direct(peer, text)` sends a private message to a peer, given by
+//!   name or node id,
+//! - `command(item, value)` sends a command to our openHAB server,
+//! - `print(text)` logs a line.
+//!
+//! ```rhai
+//! fn on_message(msg) {
+//!     if msg.text == "ping" {
+//!         send(msg.room, "pong");
+//!     }
+//! }
+//! ```
+
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures_lite::future::Boxed as BoxedFuture;
+use iroh::NodeId;
+use iroh_gossip::proto::TopicId;
+use rhai::{CallFnOptions, Dynamic, Engine, Map, Scope, AST};
+use tracing::{info, warn};
+
+use crate::{
+    handler::{ChatMessage, DirectMessage, ItemChange, MessageHandler, Presence, PresenceChange},
+    ChatNode,
+};
+
+/// Operations a script may run per event, so a runaway loop cannot hang
+/// the node.
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// Something a script asked for, done once it has returned.
+#[derive(Debug)]
+enum Action {
+    Send { room: String, text: String },
+    SendDirect { peer: String, text: String },
+    Command { item: String, value: String },
+}
+
+impl Action {
+    async fn run(self, node: &ChatNode) -> Result<()> {
+        match self {
+            Action::Send { room, text } => {
+                let topic: TopicId = room.parse().map_err(|_| anyhow!("invalid room {room}"))?;
+                node.send_text(topic, text, Vec::new()).await?;
+            }
+            Action::SendDirect { peer, text } => {
+                let node_id = node.resolve_peer(&peer)?;
+                node.send_direct(node_id, text).await?;
+            }
+            Action::Command { item, value } => {
+                let Some(home) = node.home() else {
+                    bail!("cannot send {value} to {item}: no smart home server");
+                };
+                home.send_command(&item, &value).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A compiled script.
+#[derive(Debug)]
+struct Script {
+    /// File name, used in logs.
+    name: String,
+    ast: AST,
+}
+
+/// The scripts of a directory, registered as a [`MessageHandler`].
+#[derive(Clone)]
+pub struct Scripts {
+    engine: Arc<Engine>,
+    scripts: Arc<Vec<Script>>,
+    /// Actions requested by the script running.
+    actions: Arc<Mutex<Vec<Action>>>,
+}
+
+impl Scripts {
+    /// Compiles the `.rhai` files in `dir`, in the order of their names.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let actions = Arc::new(Mutex::new(Vec::new()));
+        let engine = engine(actions.clone());
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| format!("failed to read scripts dir {}", dir.display()))?;
+        let mut paths = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "rhai") {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        let mut scripts = Vec::new();
+        for path in paths {
+            let ast = engine
+                .compile_file(path.clone())
+                .with_context(|| format!("failed to compile script {}", path.display()))?;
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            scripts.push(Script {
+                name: name.into_owned(),
+                ast,
+            });
+        }
+        Ok(Self {
+            engine: Arc::new(engine),
+            scripts: Arc::new(scripts),
+            actions,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.scripts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scripts.is_empty()
+    }
+
+    /// Calls `function` in every script defining it, then runs the actions
+    /// they requested.
+    fn call(&self, node: ChatNode, function: &'static str, event: Map) -> BoxedFuture<Result<()>> {
+        let this = self.clone();
+        Box::pin(async move {
+            // Scripts run synchronously, keep them off the async threads.
+            let actions =
+                tokio::task::spawn_blocking(move || this.call_blocking(function, event)).await?;
+            for action in actions {
+                if let Err(err) = action.run(&node).await {
+                    warn!("script action failed: {err:#}");
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn call_blocking(&self, function: &str, event: Map) -> Vec<Action> {
+        for script in self.scripts.iter() {
+            let defined = script
+                .ast
+                .iter_functions()
+                .any(|f| f.name == function && f.params.len() == 1);
+            if !defined {
+                continue;
+            }
+            let result = self.engine.call_fn_with_options::<Dynamic>(
+                // Top-level statements are not run again for each event.
+                CallFnOptions::new().eval_ast(false),
+                &mut Scope::new(),
+                &script.ast,
+                function,
+                (event.clone(),),
+            );
+            if let Err(err) = result {
+                warn!(script = %script.name, "{function} failed: {err}");
+            }
+        }
+        std::mem::take(&mut *self.actions.lock().unwrap())
+    }
+}
+
+impl std::fmt::Debug for Scripts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&str> = self.scripts.iter().map(|s| s.name.as_str()).collect();
+        f.debug_struct("Scripts").field("scripts", &names).finish()
+    }
+}
+
+impl MessageHandler for Scripts {
+    fn on_message(&self, node: ChatNode, message: ChatMessage) -> BoxedFuture<Result<()>> {
+        let event = map([
+            ("room", message.topic.to_string().into()),
+            ("from", message.from.to_string().into()),
+            ("name", optional(message.name)),
+            ("clock", (message.clock as i64).into()),
+            ("text", message.text.into()),
+        ]);
+        self.call(node, "on_message", event)
+    }
+
+    fn on_direct_message(&self, node: ChatNode, message: DirectMessage) -> BoxedFuture<Result<()>> {
+        let event = map([
+            ("from", message.from.to_string().into()),
+            ("name", optional(message.name)),
+            ("text", message.text.into()),
+        ]);
+        self.call(node, "on_direct_message", event)
+    }
+
+    fn on_presence(&self, node: ChatNode, presence: Presence) -> BoxedFuture<Result<()>> {
+        let change = match presence.change {
+            PresenceChange::Joined => "joined",
+            PresenceChange::Left => "left",
+            PresenceChange::Offline => "offline",
+        };
+        let event = map([
+            ("room", presence.topic.to_string().into()),
+            ("from", presence.from.to_string().into()),
+            ("name", optional(presence.name)),
+            ("change", change.into()),
+        ]);
+        self.call(node, "on_presence", event)
+    }
+
+    fn on_item_change(&self, node: ChatNode, change: ItemChange) -> BoxedFuture<Result<()>> {
+        let (room, from) = change.source.unzip();
+        let event = map([
+            ("room", optional(room.map(|room: TopicId| room.to_string()))),
+            ("from", optional(from.map(|from: NodeId| from.to_string()))),
+            ("name", optional(change.name)),
+            ("item", change.item.into()),
+            ("old", change.old.into()),
+            ("new", change.new.into()),
+        ]);
+        self.call(node, "on_item_change", event)
+    }
+}
+
+/// An engine with the functions scripts act through, which queue their
+/// actions in `actions`.
+fn engine(actions: Arc<Mutex<Vec<Action>>>) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.on_print(|text| info!(target: "script", "{text}"));
+    engine.on_debug(
+        |text, source, pos| info!(target: "script", "{}:{pos}: {text}", source.unwrap_or_default()),
+    );
+    let queue = actions.clone();
+    engine.register_fn("send", move |room: &str, text: &str| {
+        queue.lock().unwrap().push(Action::Send {
+            room: room.to_string(),
+            text: text.to_string(),
+        });
+    });
+    let queue = actions.clone();
+    engine.register_fn("send_direct", move |peer: &str, text: &str| {
+        queue.lock().unwrap().push(Action::SendDirect {
+            peer: peer.to_string(),
+            text: text.to_string(),
+        });
+    });
+    engine.register_fn("command", move |item: &str, value: &str| {
+        actions.lock().unwrap().push(Action::Command {
+            item: item.to_string(),
+            value: value.to_string(),
+        });
+    });
+    engine
+}
+
+fn map<const N: usize>(fields: [(&str, Dynamic); N]) -> Map {
+    fields
+        .into_iter()
+        .map(|(key, value)| (key.into(), value))
+        .collect()
+}
+
+/// `()` for a missing value, so scripts can test `msg.name == ()`.
+fn optional(value: Option<String>) -> Dynamic {
+    value.map(Dynamic::from).unwrap_or(Dynamic::UNIT)
+}
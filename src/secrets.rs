@@ -0,0 +1,44 @@
+//! Secrets kept in the platform keyring: the Secret Service on Linux, the
+//! Keychain on macOS and the Credential Manager on Windows.
+//!
+//! Entries belong to the `iroh-gossip-chat` service and are named after
+//! what they hold, such as `openhab-token:<url>`. Callers fall back to
+//! files and the config when the keyring is unavailable, e.g. on a
+//! headless server without a Secret Service.
+
+use anyhow::{Context, Result};
+use keyring::{Entry, Error};
+
+/// Service the entries are stored under.
+const SERVICE: &str = "iroh-gossip-chat";
+
+/// Keyring entry of the node secret key stored at `path` without a
+/// keyring, so each key file has its own.
+pub fn secret_key_entry(path: &std::path::Path) -> String {
+    format!("secret-key:{}", path.display())
+}
+
+/// Keyring entry of the API token for the openHAB server at `url`.
+pub fn openhab_token_entry(url: &str) -> String {
+    format!("openhab-token:{}", url.trim_end_matches('/'))
+}
+
+/// The secret stored as `name`, if any.
+pub async fn get(name: &str) -> Result<Option<String>> {
+    let name = name.to_string();
+    tokio::task::spawn_blocking(move || match Entry::new(SERVICE, &name)?.get_password() {
+        Ok(secret) => Ok(Some(secret)),
+        Err(Error::NoEntry) => Ok(None),
+        Err(err) => Err(err),
+    })
+    .await?
+    .context("failed to read from the keyring")
+}
+
+/// Stores `secret` as `name`, replacing any previous one.
+pub async fn set(name: &str, secret: &str) -> Result<()> {
+    let (name, secret) = (name.to_string(), secret.to_string());
+    tokio::task::spawn_blocking(move || Entry::new(SERVICE, &name)?.set_password(&secret))
+        .await?
+        .context("failed to write to the keyring")
+}
@@ -0,0 +1,170 @@
+//! Bridges a room with a Telegram chat through a bot, for notifications on
+//! the phone without running a node there.
+//!
+//! Chat messages from peers and their openHAB Things going offline are
+//! posted to the chat, and messages in the chat are sent to the room as
+//! `<name> text`.
+
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use futures_lite::StreamExt;
+use iroh_gossip::proto::TopicId;
+use reqwest::Client;
+use serde_json::{json, Value};
+use tracing::{debug, info, instrument};
+use url::Url;
+
+use crate::{config::TelegramConfig, ChatNode, Event};
+
+/// How long Telegram may hold a `getUpdates` request open.
+const POLL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A bot posting to one chat.
+#[derive(Debug, Clone)]
+struct Telegram {
+    client: Client,
+    /// Bot API base URL, including the token.
+    api: Url,
+    chat_id: i64,
+}
+
+impl Telegram {
+    /// Calls a Bot API method, returning its result.
+    async fn call(&self, method: &str, params: Value) -> Result<Value> {
+        let url = self.api.join(method)?;
+        let mut response: Value = self
+            .client
+            .post(url)
+            .json(&params)
+            .timeout(POLL_TIMEOUT * 2)
+            .send()
+            .await?
+            .json()
+            .await
+            .with_context(|| format!("invalid {method} response"))?;
+        if response["ok"].as_bool() != Some(true) {
+            let description = response["description"].as_str().unwrap_or("unknown error");
+            bail!("Telegram {method} failed: {description}");
+        }
+        Ok(response["result"].take())
+    }
+
+    async fn send(&self, text: String) -> Result<()> {
+        self.call(
+            "sendMessage",
+            json!({ "chat_id": self.chat_id, "text": text }),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// Connects the bot and bridges its chat with `topic`.
+///
+/// Only returns when the connection fails.
+#[instrument(skip_all, fields(chat_id = ?config.chat_id))]
+pub async fn run_bridge(config: &TelegramConfig, node: ChatNode, topic: TopicId) -> Result<()> {
+    let token = config.token.as_deref().context("no Telegram bot token")?;
+    let chat_id = config.chat_id.context("no Telegram chat id")?;
+    let telegram = Telegram {
+        client: Client::new(),
+        // Tokens contain a colon, joining would take it for a scheme.
+        api: Url::parse(&format!(
+            "{}/bot{token}/",
+            config.api_url.as_str().trim_end_matches('/')
+        ))?,
+        chat_id,
+    };
+    let me = telegram.call("getMe", json!({})).await?;
+    info!(bot = ?me["username"].as_str(), "connected to Telegram");
+
+    let forwarder = tokio::spawn(forward_events(telegram.clone(), node.clone(), topic));
+    let result = relay_chat(&telegram, &node, topic).await;
+    forwarder.abort();
+    result
+}
+
+/// Posts chat messages of peers in `topic`, and Things of any peer that
+/// are not online, to the chat.
+async fn forward_events(telegram: Telegram, node: ChatNode, topic: TopicId) -> Result<()> {
+    let mut events = node.events();
+    while let Some(event) = events.next().await {
+        let text = match event {
+            Event::Message {
+                topic: room,
+                from,
+                name,
+                text,
+                ..
+            } if room == topic => {
+                format!("{}: {text}", name.unwrap_or_else(|| from.fmt_short()))
+            }
+            Event::ThingStatusChanged {
+                from,
+                name,
+                thing,
+                label,
+                status,
+                detail,
+                ..
+            } if status != "ONLINE" => {
+                let name = name.unwrap_or_else(|| from.fmt_short());
+                let thing = label.unwrap_or(thing);
+                match detail.as_str() {
+                    "" | "NONE" => format!("⚠ openHAB at {name}: {thing} is {status}"),
+                    _ => format!("⚠ openHAB at {name}: {thing} is {status} ({detail})"),
+                }
+            }
+            _ => continue,
+        };
+        telegram.send(text).await?;
+    }
+    Ok(())
+}
+
+/// Sends the messages posted in the chat to `topic`, skipping the ones
+/// sent before the bridge started.
+async fn relay_chat(telegram: &Telegram, node: &ChatNode, topic: TopicId) -> Result<()> {
+    // Only the latest pending update, to start after it.
+    let latest = telegram
+        .call("getUpdates", json!({ "offset": -1, "timeout": 0 }))
+        .await?;
+    let mut offset = latest[0]["update_id"].as_i64().map_or(0, |id| id + 1);
+    loop {
+        let updates = telegram
+            .call(
+                "getUpdates",
+                json!({
+                    "offset": offset,
+                    "timeout": POLL_TIMEOUT.as_secs(),
+                    "allowed_updates": ["message"],
+                }),
+            )
+            .await?;
+        for update in updates.as_array().into_iter().flatten() {
+            if let Some(id) = update["update_id"].as_i64() {
+                offset = offset.max(id + 1);
+            }
+            let message = &update["message"];
+            let Some(text) = message["text"].as_str() else {
+                continue;
+            };
+            if message["chat"]["id"].as_i64() != Some(telegram.chat_id) {
+                debug!(chat = %message["chat"]["id"], "ignoring message from another chat");
+                continue;
+            }
+            if message["from"]["is_bot"].as_bool() == Some(true) {
+                continue;
+            }
+            let from = &message["from"];
+            let name = match (from["first_name"].as_str(), from["last_name"].as_str()) {
+                (Some(first), Some(last)) => format!("{first} {last}"),
+                (Some(first), None) => first.to_string(),
+                _ => from["username"].as_str().unwrap_or("telegram").to_string(),
+            };
+            node.send_text(topic, format!("<{name}> {text}"), Vec::new())
+                .await?;
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, ensure, Context, Result};
+use chrono::{DateTime, Utc};
+use iroh::NodeAddr;
+use iroh_gossip::proto::TopicId;
+use serde::{Deserialize, Serialize};
+
+/// Prefix of the compact ticket encoding, bumped whenever the binary layout
+/// of [`Ticket`] changes.
+const TICKET_PREFIX: &str = "chat2";
+
+/// Prefix of tickets from before invites could expire.
+const TICKET_PREFIX_NO_EXPIRY: &str = "chat1";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Ticket {
+    pub topic: TopicId,
+    pub nodes: Vec<NodeAddr>,
+    /// Unix time in seconds after which the ticket is refused.
+    #[serde(default)]
+    pub expires: Option<u64>,
+    /// Random id of a single-use invite, redeemed with the inviting node
+    /// after joining.
+    #[serde(default)]
+    pub nonce: Option<u64>,
+}
+
+/// Several tickets to the same room, merged to join through all their nodes
+/// at once.
+#[derive(Debug)]
+pub struct Tickets {
+    pub topic: TopicId,
+    /// The nodes of all tickets, with the addresses of the same node
+    /// combined.
+    pub nodes: Vec<NodeAddr>,
+    /// Nonces of the single-use invites among the tickets.
+    pub nonces: Vec<u64>,
+}
+
+impl Tickets {
+    /// Merges tickets, which must all be for the same room and unexpired.
+    pub fn merge(tickets: Vec<Ticket>) -> Result<Self> {
+        let Some(first) = tickets.first() else {
+            bail!("no ticket given");
+        };
+        let topic = first.topic;
+        let mut nodes: Vec<NodeAddr> = Vec::new();
+        let mut nonces = Vec::new();
+        for ticket in tickets {
+            ensure!(
+                ticket.topic == topic,
+                "tickets are for different rooms, {topic} and {}",
+                ticket.topic
+            );
+            ticket.check_expiry()?;
+            nonces.extend(ticket.nonce);
+            for node in ticket.nodes {
+                match nodes.iter_mut().find(|n| n.node_id == node.node_id) {
+                    Some(known) => {
+                        known.relay_url = known.relay_url.take().or(node.relay_url);
+                        known.direct_addresses.extend(node.direct_addresses);
+                    }
+                    None => nodes.push(node),
+                }
+            }
+        }
+        Ok(Self {
+            topic,
+            nodes,
+            nonces,
+        })
+    }
+}
+
+impl FromStr for Tickets {
+    type Err = anyhow::Error;
+
+    /// Parses tickets separated by whitespace.
+    fn from_str(s: &str) -> Result<Self> {
+        let tickets = s
+            .split_whitespace()
+            .map(Ticket::from_str)
+            .collect::<Result<_>>()?;
+        Self::merge(tickets)
+    }
+}
+
+/// Layout of `chat1` tickets.
+#[derive(Deserialize)]
+struct TicketNoExpiry {
+    topic: TopicId,
+    nodes: Vec<NodeAddr>,
+}
+
+impl Ticket {
+    /// A ticket that never expires.
+    pub fn new(topic: TopicId, nodes: Vec<NodeAddr>) -> Self {
+        Self {
+            topic,
+            nodes,
+            expires: None,
+            nonce: None,
+        }
+    }
+
+    /// Strips the relay URLs and direct addresses, leaving peers to find the
+    /// nodes through discovery. Makes for much shorter tickets.
+    pub fn minimal(mut self) -> Self {
+        for node in &mut self.nodes {
+            *node = NodeAddr::new(node.node_id);
+        }
+        self
+    }
+
+    /// The time after which the ticket is refused, if any.
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        let expires = i64::try_from(self.expires?).ok()?;
+        DateTime::from_timestamp(expires, 0)
+    }
+
+    /// Fails if the ticket has expired.
+    pub fn check_expiry(&self) -> Result<()> {
+        if let Some(expires) = self.expires_at() {
+            ensure!(Utc::now() < expires, "ticket expired at {expires}");
+        }
+        Ok(())
+    }
+
+    /// Encodes the ticket as postcard bytes in lowercase base32, prefixed with
+    /// [`TICKET_PREFIX`].
+    pub fn to_compact(&self) -> String {
+        let bytes = postcard::to_stdvec(self).expect("serialization should not fail");
+        let mut out = TICKET_PREFIX.to_string();
+        out.push_str(
+            &data_encoding::BASE32_NOPAD
+                .encode(&bytes)
+                .to_ascii_lowercase(),
+        );
+        out
+    }
+
+    /// Renders the compact encoding as a QR code made of unicode half blocks,
+    /// for scanning straight off the terminal.
+    pub fn to_qr(&self) -> Result<String> {
+        let code = qrcode::QrCode::new(self.to_compact().to_ascii_uppercase())
+            .context("ticket too large for a QR code")?;
+        Ok(code
+            .render::<qrcode::render::unicode::Dense1x2>()
+            .dark_color(qrcode::render::unicode::Dense1x2::Light)
+            .light_color(qrcode::render::unicode::Dense1x2::Dark)
+            .build())
+    }
+
+    fn from_compact(s: &str) -> Result<Self> {
+        postcard::from_bytes(&decode_base32(s)?).context("invalid ticket payload")
+    }
+
+    fn from_compact_no_expiry(s: &str) -> Result<Self> {
+        let TicketNoExpiry { topic, nodes } =
+            postcard::from_bytes(&decode_base32(s)?).context("invalid ticket payload")?;
+        Ok(Self::new(topic, nodes))
+    }
+}
+
+/// Accepts both the compact encoding and the legacy JSON tickets.
+impl FromStr for Ticket {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s.starts_with('{') {
+            return serde_json::from_str(s).map_err(Into::into);
+        }
+        // QR codes carry the ticket in upper case, which encodes more densely.
+        let s = s.to_ascii_lowercase();
+        if let Some(rest) = s.strip_prefix(TICKET_PREFIX) {
+            return Self::from_compact(rest);
+        }
+        match s.strip_prefix(TICKET_PREFIX_NO_EXPIRY) {
+            Some(rest) => Self::from_compact_no_expiry(rest),
+            None => bail!("unsupported ticket format, expected a `{TICKET_PREFIX}` ticket"),
+        }
+    }
+}
+
+fn decode_base32(s: &str) -> Result<Vec<u8>> {
+    data_encoding::BASE32_NOPAD
+        .decode(s.to_ascii_uppercase().as_bytes())
+        .context("invalid base32 in ticket")
+}
+
+impl fmt::Display for Ticket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_compact())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::SecretKey;
+
+    use super::*;
+
+    fn node_addr() -> NodeAddr {
+        let node_id = SecretKey::generate(rand::rngs::OsRng).public();
+        NodeAddr::new(node_id).with_direct_addresses(["127.0.0.1:4433".parse().unwrap()])
+    }
+
+    #[test]
+    fn compact_round_trip() {
+        let ticket = Ticket {
+            topic: TopicId::from_bytes([7; 32]),
+            nodes: vec![node_addr()],
+            expires: Some(4_000_000_000),
+            nonce: Some(42),
+        };
+        let encoded = ticket.to_string();
+        assert!(encoded.starts_with(TICKET_PREFIX));
+        let decoded: Ticket = encoded.parse().unwrap();
+        assert_eq!(decoded.topic, ticket.topic);
+        assert_eq!(decoded.nodes, ticket.nodes);
+        assert_eq!(decoded.expires, ticket.expires);
+        assert_eq!(decoded.nonce, ticket.nonce);
+    }
+
+    #[test]
+    fn parses_upper_case_from_qr_codes() {
+        let ticket = Ticket::new(TopicId::from_bytes([1; 32]), vec![node_addr()]);
+        let decoded: Ticket = ticket.to_string().to_ascii_uppercase().parse().unwrap();
+        assert_eq!(decoded.nodes, ticket.nodes);
+    }
+
+    #[test]
+    fn parses_tickets_without_expiry() {
+        let (topic, nodes) = (TopicId::from_bytes([2; 32]), vec![node_addr()]);
+        let bytes = postcard::to_stdvec(&(topic, &nodes)).unwrap();
+        let encoded = format!(
+            "{TICKET_PREFIX_NO_EXPIRY}{}",
+            data_encoding::BASE32_NOPAD.encode(&bytes)
+        );
+        let decoded: Ticket = encoded.parse().unwrap();
+        assert_eq!(decoded.topic, topic);
+        assert_eq!(decoded.nodes, nodes);
+        assert_eq!(decoded.expires, None);
+    }
+
+    #[test]
+    fn parses_json_tickets() {
+        let ticket = Ticket::new(TopicId::from_bytes([3; 32]), vec![node_addr()]);
+        let json = serde_json::to_string(&ticket).unwrap();
+        let decoded: Ticket = json.parse().unwrap();
+        assert_eq!(decoded.topic, ticket.topic);
+        assert_eq!(decoded.nodes, ticket.nodes);
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        assert!("chat9abc".parse::<Ticket>().is_err());
+        assert!(format!("{TICKET_PREFIX}!!!").parse::<Ticket>().is_err());
+        assert!(format!("{TICKET_PREFIX}aaaa").parse::<Ticket>().is_err());
+    }
+
+    #[test]
+    fn merges_addresses_of_the_same_node() {
+        let topic = TopicId::from_bytes([4; 32]);
+        let node = node_addr();
+        let other_addr =
+            NodeAddr::new(node.node_id).with_direct_addresses(["10.0.0.2:4433".parse().unwrap()]);
+        let mut invite = Ticket::new(topic, vec![other_addr]);
+        invite.nonce = Some(9);
+        let tickets = Tickets::merge(vec![Ticket::new(topic, vec![node]), invite]).unwrap();
+        assert_eq!(tickets.nodes.len(), 1);
+        assert_eq!(tickets.nodes[0].direct_addresses.len(), 2);
+        assert_eq!(tickets.nonces, vec![9]);
+    }
+
+    #[test]
+    fn refuses_mixed_rooms_and_expired_tickets() {
+        let a = Ticket::new(TopicId::from_bytes([5; 32]), vec![]);
+        let b = Ticket::new(TopicId::from_bytes([6; 32]), vec![]);
+        assert!(Tickets::merge(vec![a, b]).is_err());
+
+        let mut expired = Ticket::new(TopicId::from_bytes([5; 32]), vec![]);
+        expired.expires = Some(1);
+        assert!(Tickets::merge(vec![expired]).is_err());
+        assert!(Tickets::merge(vec![]).is_err());
+    }
+}
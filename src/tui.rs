@@ -0,0 +1,255 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use crossterm::event::{
+    DisableFocusChange, EnableFocusChange, Event as TermEvent, EventStream, KeyCode, KeyEventKind,
+    KeyModifiers,
+};
+use futures_lite::StreamExt;
+use iroh_gossip::proto::TopicId;
+use iroh_gossip_chat::{message::MessageRef, openhab::ItemStates, ChatNode};
+use ratatui::{
+    layout::{Constraint, Layout, Position},
+    style::{Color, Modifier, Style},
+    text::Line,
+    widgets::{Block, List, ListItem, Paragraph, Wrap},
+    DefaultTerminal, Frame,
+};
+use tokio::sync::{mpsc, watch};
+
+use crate::{format_reactions, notify::Notifier, OutputLine};
+
+/// Width of the peer and openHAB sidebar.
+const SIDEBAR_WIDTH: u16 = 30;
+
+/// Style of chat messages that mention us.
+const MENTION_STYLE: Style = Style::new().fg(Color::Yellow).add_modifier(Modifier::BOLD);
+
+/// Interval for redrawing, so typing indicators expire.
+const TICK: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct App {
+    /// Output lines in conversation order.
+    messages: Vec<OutputLine>,
+    /// Lines scrolled up from the bottom of the message pane.
+    scroll: usize,
+    input: String,
+}
+
+/// Runs the terminal UI until the user quits with Esc or Ctrl-C.
+///
+/// Submitted lines are sent to `lines`, and everything the chat loop wants to
+/// show arrives on `output`. Typing is announced to the room in `current`.
+pub async fn run(
+    node: ChatNode,
+    lines: mpsc::Sender<String>,
+    output: mpsc::UnboundedReceiver<OutputLine>,
+    item_state: watch::Receiver<ItemStates>,
+    current: watch::Receiver<TopicId>,
+    notifier: Notifier,
+) -> Result<()> {
+    let mut terminal = ratatui::init();
+    // Notify only while another window has focus.
+    crossterm::execute!(std::io::stdout(), EnableFocusChange)?;
+    notifier.set_focused(true);
+    let result = run_app(
+        &mut terminal,
+        node,
+        lines,
+        output,
+        item_state,
+        current,
+        notifier,
+    )
+    .await;
+    crossterm::execute!(std::io::stdout(), DisableFocusChange).ok();
+    ratatui::restore();
+    result
+}
+
+async fn run_app(
+    terminal: &mut DefaultTerminal,
+    node: ChatNode,
+    lines: mpsc::Sender<String>,
+    mut output: mpsc::UnboundedReceiver<OutputLine>,
+    mut item_state: watch::Receiver<ItemStates>,
+    current: watch::Receiver<TopicId>,
+    notifier: Notifier,
+) -> Result<()> {
+    let mut app = App::default();
+    let mut keys = EventStream::new();
+    let mut events = node.events();
+    let mut tick = tokio::time::interval(TICK);
+
+    loop {
+        let state = item_state.borrow_and_update().clone();
+        let topic = *current.borrow();
+        terminal.draw(|frame| app.draw(frame, &node, state, &topic))?;
+        tokio::select! {
+            Some(key) = keys.next() => {
+                let key = match key? {
+                    TermEvent::Key(key) => key,
+                    TermEvent::FocusGained => {
+                        notifier.set_focused(true);
+                        continue;
+                    }
+                    TermEvent::FocusLost => {
+                        notifier.set_focused(false);
+                        continue;
+                    }
+                    _ => continue,
+                };
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match key.code {
+                    KeyCode::Esc => break,
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => break,
+                    KeyCode::Char(c) => {
+                        app.input.push(c);
+                        let node = node.clone();
+                        tokio::spawn(async move { node.typing(topic).await });
+                    }
+                    KeyCode::Backspace => {
+                        app.input.pop();
+                    }
+                    KeyCode::Enter => {
+                        let line = std::mem::take(&mut app.input);
+                        if line.trim().is_empty() {
+                            continue;
+                        }
+                        if lines.send(line).await.is_err() {
+                            break;
+                        }
+                    }
+                    KeyCode::Up => app.scroll_up(1),
+                    KeyCode::Down => app.scroll_down(1),
+                    KeyCode::PageUp => app.scroll_up(10),
+                    KeyCode::PageDown => app.scroll_down(10),
+                    _ => {}
+                }
+            }
+            Some(line) = output.recv() => app.push(line),
+            // Redraw on room activity, the peer list is read from the node.
+            Some(_) = events.next() => {}
+            Ok(()) = item_state.changed() => {}
+            _ = tick.tick() => {}
+            // The chat loop stopped, e.g. on SIGTERM.
+            _ = lines.closed() => break,
+        }
+    }
+    Ok(())
+}
+
+impl App {
+    /// Places chat messages by their clock, other lines go at the end and
+    /// stay after the message they followed.
+    fn push(&mut self, mut line: OutputLine) {
+        let index = match line.order {
+            Some(order) => self.messages.partition_point(|m| m.order <= Some(order)),
+            None => {
+                line.order = self.messages.last().and_then(|m| m.order);
+                self.messages.len()
+            }
+        };
+        self.messages.insert(index, line);
+    }
+
+    fn scroll_up(&mut self, lines: usize) {
+        self.scroll = (self.scroll + lines).min(self.messages.len().saturating_sub(1));
+    }
+
+    fn scroll_down(&mut self, lines: usize) {
+        self.scroll = self.scroll.saturating_sub(lines);
+    }
+
+    fn draw(&self, frame: &mut Frame, node: &ChatNode, item_state: ItemStates, topic: &TopicId) {
+        let [main, input_area] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(frame.area());
+        let [messages_area, sidebar] =
+            Layout::horizontal([Constraint::Min(10), Constraint::Length(SIDEBAR_WIDTH)])
+                .areas(main);
+        let item_lines = node.home().map_or(1, |home| home.items().len().max(1));
+        let [peers_area, item_area] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(item_lines as u16 + 2),
+        ])
+        .areas(sidebar);
+
+        // Chat messages get a line with their reactions below them.
+        let mut messages: Vec<Line> = Vec::new();
+        for m in &self.messages {
+            match m.mention {
+                true => messages.push(Line::styled(m.text.as_str(), MENTION_STYLE)),
+                false => messages.push(Line::raw(m.text.as_str())),
+            }
+            if let (true, Some((clock, from))) = (m.chat, m.order) {
+                let reactions = node.reactions(&MessageRef { from, clock });
+                if !reactions.is_empty() {
+                    messages.push(Line::raw(format!("  {}", format_reactions(&reactions))));
+                }
+            }
+        }
+        // Show the newest messages at the bottom, `scroll` lines up from the end.
+        let height = messages_area.height.saturating_sub(2) as usize;
+        let top = messages.len().saturating_sub(height + self.scroll);
+        let title = match self.scroll {
+            0 => "messages".to_string(),
+            n => format!("messages (+{n})"),
+        };
+        frame.render_widget(
+            Paragraph::new(messages)
+                .block(Block::bordered().title(title))
+                .scroll((top as u16, 0)),
+            messages_area,
+        );
+
+        let roster = node.roster();
+        let peers: Vec<ListItem> = roster
+            .iter()
+            .map(|id| ListItem::new(node.name_of(id).unwrap_or_else(|| id.fmt_short())))
+            .collect();
+        let title = format!("peers ({})", roster.len());
+        frame.render_widget(
+            List::new(peers).block(Block::bordered().title(title)),
+            peers_area,
+        );
+
+        let items: Vec<Line> = match node.home() {
+            Some(home) => home
+                .items()
+                .iter()
+                .map(|item| {
+                    let state = item_state.get(item).map_or("loading...", String::as_str);
+                    Line::raw(format!("{item}: {state}"))
+                })
+                .collect(),
+            None => vec![Line::raw("disabled")],
+        };
+        frame.render_widget(
+            Paragraph::new(items)
+                .wrap(Wrap { trim: true })
+                .block(Block::bordered().title(node.home().map_or("openHAB", |home| home.name()))),
+            item_area,
+        );
+
+        let typing: Vec<String> = node
+            .typing_peers(topic)
+            .iter()
+            .map(|id| node.name_of(id).unwrap_or_else(|| id.fmt_short()))
+            .collect();
+        let mut input_block = Block::bordered().title("message (Enter to send, Esc to quit)");
+        if !typing.is_empty() {
+            input_block = input_block.title_bottom(format!("{} typing…", typing.join(", ")));
+        }
+        frame.render_widget(
+            Paragraph::new(self.input.as_str()).block(input_block),
+            input_area,
+        );
+        frame.set_cursor_position(Position::new(
+            input_area.x + 1 + self.input.chars().count() as u16,
+            input_area.y + 1,
+        ));
+    }
+}
@@ -0,0 +1,74 @@
+//! Voice notes, recorded and played with external programs such as
+//! `arecord` and `aplay` from alsa-utils, so no audio library is needed.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context, Result};
+use tracing::instrument;
+
+use crate::{config::VoiceConfig, hooks};
+
+/// Records a clip of `secs` seconds into a temporary WAV file and returns
+/// its path. The caller removes the file.
+#[instrument(skip(config))]
+pub async fn record(config: &VoiceConfig, secs: u32) -> Result<PathBuf> {
+    let command = config
+        .record
+        .as_deref()
+        .context("no command to record with, set record in [voice]")?;
+    let path = std::env::temp_dir().join(format!(
+        "iroh-gossip-chat-voice-{:016x}.wav",
+        rand::random::<u64>()
+    ));
+    run(command, &path, secs).await?;
+    ensure!(
+        path.exists(),
+        "{command:?} did not write {}",
+        path.display()
+    );
+    Ok(path)
+}
+
+/// Plays the voice note at `path`.
+#[instrument(skip(config))]
+pub async fn play(config: &VoiceConfig, path: &Path) -> Result<()> {
+    let command = config
+        .play
+        .as_deref()
+        .context("no command to play with, set play in [voice]")?;
+    run(command, path, 0).await
+}
+
+/// Runs a command template with `{path}` and `{secs}` filled in.
+async fn run(command: &str, path: &Path, secs: u32) -> Result<()> {
+    let path = path.to_string_lossy().replace('\'', r"'\''");
+    let command = command
+        .replace("{path}", &format!("'{path}'"))
+        .replace("{secs}", &secs.to_string());
+    let status = hooks::shell(&command)
+        .kill_on_drop(true)
+        .status()
+        .await
+        .with_context(|| format!("failed to run {command:?}"))?;
+    ensure!(status.success(), "{command:?} exited with {status}");
+    Ok(())
+}
+
+/// Command recording `{secs}` seconds of mono audio to the WAV file
+/// `{path}` on this platform.
+pub fn default_record_command() -> Option<String> {
+    match std::env::consts::OS {
+        "linux" => Some("arecord -q -f S16_LE -r 16000 -c 1 -d {secs} {path}".to_string()),
+        "macos" => Some("sox -q -d -r 16000 -c 1 {path} trim 0 {secs}".to_string()),
+        _ => None,
+    }
+}
+
+/// Command playing the WAV file `{path}` on this platform.
+pub fn default_play_command() -> Option<String> {
+    match std::env::consts::OS {
+        "linux" => Some("aplay -q {path}".to_string()),
+        "macos" => Some("afplay {path}".to_string()),
+        _ => None,
+    }
+}
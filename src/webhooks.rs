@@ -0,0 +1,200 @@
+//! Webhooks posting room events to external services, such as a home
+//! automation server or a logging pipeline, as JSON in the format of the
+//! HTTP API's event stream.
+//!
+//! Failed deliveries are retried with growing delays, and bodies can be
+//! signed so receivers can check they come from us.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{bail, Result};
+use futures_lite::future::Boxed as BoxedFuture;
+use hmac::{Hmac, Mac};
+use reqwest::{Client, StatusCode};
+use sha2::Sha256;
+use tracing::{debug, instrument, warn};
+
+use crate::{
+    config::{WebhookConfig, WebhookEvent},
+    handler::{ChatMessage, DirectMessage, ItemChange, MessageHandler, Presence, PresenceChange},
+    http, ChatNode, Event,
+};
+
+/// Deliveries tried per event, the first one included.
+const MAX_ATTEMPTS: u32 = 5;
+/// Delay before the first retry, doubled for each one after it.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posts events to the configured webhooks, registered as a
+/// [`MessageHandler`].
+///
+/// Each delivery runs in its own task, so a slow receiver does not hold up
+/// the others.
+#[derive(Debug, Clone)]
+pub struct Webhooks {
+    client: Client,
+    webhooks: Arc<Vec<WebhookConfig>>,
+}
+
+impl Webhooks {
+    pub fn new(configs: &[WebhookConfig]) -> Self {
+        Self {
+            client: Client::new(),
+            webhooks: Arc::new(configs.to_vec()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.webhooks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.webhooks.is_empty()
+    }
+
+    fn post(&self, kind: WebhookEvent, event: Event) {
+        let wanted = self
+            .webhooks
+            .iter()
+            .filter(|webhook| webhook.events.is_empty() || webhook.events.contains(&kind));
+        let mut body = None;
+        for webhook in wanted {
+            let body = body
+                .get_or_insert_with(|| http::event_json(&event).to_string())
+                .clone();
+            let (client, webhook) = (self.client.clone(), webhook.clone());
+            tokio::spawn(async move {
+                if let Err(err) = deliver(&client, &webhook, body).await {
+                    warn!("webhook {} failed: {err:#}", webhook.url);
+                }
+            });
+        }
+    }
+}
+
+impl MessageHandler for Webhooks {
+    fn on_message(&self, _node: ChatNode, message: ChatMessage) -> BoxedFuture<Result<()>> {
+        self.post(WebhookEvent::Message, message.into());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn on_direct_message(
+        &self,
+        _node: ChatNode,
+        message: DirectMessage,
+    ) -> BoxedFuture<Result<()>> {
+        self.post(WebhookEvent::DirectMessage, message.into());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn on_presence(&self, _node: ChatNode, presence: Presence) -> BoxedFuture<Result<()>> {
+        let kind = match presence.change {
+            PresenceChange::Joined => WebhookEvent::PeerJoined,
+            PresenceChange::Left => WebhookEvent::PeerLeft,
+            PresenceChange::Offline => WebhookEvent::PeerOffline,
+        };
+        self.post(kind, presence.into());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn on_item_change(&self, _node: ChatNode, change: ItemChange) -> BoxedFuture<Result<()>> {
+        self.post(WebhookEvent::ItemChanged, change.into());
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Posts `body` to the webhook, retrying on connection failures, server
+/// errors and rate limiting.
+#[instrument(skip_all, fields(url = %webhook.url))]
+async fn deliver(client: &Client, webhook: &WebhookConfig, body: String) -> Result<()> {
+    let signature = webhook.secret.as_ref().map(|secret| sign(secret, &body));
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let mut request = client
+            .post(webhook.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .timeout(REQUEST_TIMEOUT)
+            .body(body.clone());
+        if let Some(signature) = &signature {
+            request = request.header("X-Signature-256", signature);
+        }
+        let err = match request.send().await {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => {
+                let status = response.status();
+                if !status.is_server_error() && status != StatusCode::TOO_MANY_REQUESTS {
+                    bail!("rejected with {status}");
+                }
+                anyhow::anyhow!("answered {status}")
+            }
+            Err(err) => err.into(),
+        };
+        if attempt == MAX_ATTEMPTS {
+            return Err(err.context(format!("gave up after {MAX_ATTEMPTS} attempts")));
+        }
+        debug!(attempt, "webhook failed, retrying in {delay:?}: {err:#}");
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+/// The `X-Signature-256` header value for `body`.
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac = hmac(secret);
+    mac.update(body.as_bytes());
+    format!(
+        "sha256={}",
+        data_encoding::HEXLOWER.encode(&mac.finalize().into_bytes())
+    )
+}
+
+/// Whether `signature`, an `X-Signature-256` header value, signs `body`
+/// with `secret`.
+pub(crate) fn verify(secret: &str, body: &[u8], signature: &str) -> bool {
+    let Some(hex) = signature.strip_prefix("sha256=") else {
+        return false;
+    };
+    let Ok(signature) = data_encoding::HEXLOWER_PERMISSIVE.decode(hex.as_bytes()) else {
+        return false;
+    };
+    let mut mac = hmac(secret);
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+fn hmac(secret: &str) -> Hmac<Sha256> {
+    Hmac::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_bodies_with_hmac_sha256() {
+        assert_eq!(
+            sign("key", "The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn verifies_signatures() {
+        let body = r#"{"text":"hi"}"#;
+        let signature = sign("secret", body);
+        assert!(verify("secret", body.as_bytes(), &signature));
+        let upper = format!("sha256={}", signature["sha256=".len()..].to_uppercase());
+        assert!(verify("secret", body.as_bytes(), &upper));
+        assert!(!verify("other", body.as_bytes(), &signature));
+        assert!(!verify("secret", b"{}", &signature));
+        assert!(!verify(
+            "secret",
+            body.as_bytes(),
+            &signature["sha256=".len()..]
+        ));
+        assert!(!verify("secret", body.as_bytes(), "sha256=zz"));
+    }
+}